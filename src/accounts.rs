@@ -0,0 +1,125 @@
+//! Where accounts are kept between restarts. `LoginService` keeps them all in
+//! memory and writes every change through to an `AccountStore`.
+
+use crate::json;
+
+use chrono::prelude::*;
+
+use rusqlite::{Connection, NO_PARAMS, params};
+
+use std::{
+    collections::HashMap,
+    io,
+    path::Path,
+};
+
+pub struct Account {
+    pub login: Box<str>,
+    /// The password, hashed with Argon2 in its encoded form, salt included.
+    pub password_hash: Box<str>,
+    pub created: DateTime<Utc>,
+    /// Per-account settings by name, left to whoever uses them to interpret.
+    pub settings: HashMap<String, String>,
+}
+
+/// Storage for accounts. `MemoryAccounts` keeps nothing, so accounts only last
+/// as long as the server; `SqliteAccounts` keeps them in a database file.
+pub trait AccountStore: Send {
+    /// Every account there is; called once, at startup.
+    fn load(&mut self) -> io::Result<Vec<Account>>;
+
+    /// Adds the account, or replaces the one with the same login.
+    fn save(&mut self, account: &Account) -> io::Result<()>;
+
+    fn delete(&mut self, login: &str) -> io::Result<()>;
+}
+
+pub struct MemoryAccounts;
+
+impl AccountStore for MemoryAccounts {
+    fn load(&mut self) -> io::Result<Vec<Account>> {
+        Ok(Vec::new())
+    }
+
+    fn save(&mut self, _account: &Account) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn delete(&mut self, _login: &str) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct SqliteAccounts {
+    connection: Connection,
+}
+
+impl SqliteAccounts {
+    /// Opens the database, creating it and its table if need be.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let connection = Connection::open(path).map_err(sql_error)?;
+        connection.execute("CREATE TABLE IF NOT EXISTS accounts (
+                                login TEXT PRIMARY KEY,
+                                password_hash TEXT NOT NULL,
+                                created INTEGER NOT NULL,
+                                settings TEXT NOT NULL
+                            )", NO_PARAMS)
+            .map_err(sql_error)?;
+        Ok(SqliteAccounts{connection})
+    }
+}
+
+impl AccountStore for SqliteAccounts {
+    fn load(&mut self) -> io::Result<Vec<Account>> {
+        let mut statement = self.connection
+            .prepare("SELECT login, password_hash, created, settings FROM accounts")
+            .map_err(sql_error)?;
+        let rows = statement
+            .query_map(NO_PARAMS, |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?,
+                    row.get::<_, i64>(2)?, row.get::<_, String>(3)?))
+            })
+            .map_err(sql_error)?;
+        let mut accounts = Vec::new();
+        for row in rows {
+            let (login, password_hash, created, settings) = row.map_err(sql_error)?;
+            accounts.push(Account {
+                login: login.into(),
+                password_hash: password_hash.into(),
+                created: Utc.timestamp(created, 0),
+                settings: json::parse_object(&settings).unwrap_or_default().into_iter().collect(),
+            });
+        }
+        Ok(accounts)
+    }
+
+    fn save(&mut self, account: &Account) -> io::Result<()> {
+        self.connection
+            .execute("INSERT OR REPLACE INTO accounts (login, password_hash, created, settings)
+                      VALUES (?1, ?2, ?3, ?4)",
+                     params![&*account.login, &*account.password_hash,
+                             account.created.timestamp(), encode_settings(&account.settings)])
+            .map_err(sql_error)?;
+        Ok(())
+    }
+
+    fn delete(&mut self, login: &str) -> io::Result<()> {
+        self.connection.execute("DELETE FROM accounts WHERE login = ?1", params![login])
+            .map_err(sql_error)?;
+        Ok(())
+    }
+}
+
+/// Writes the settings as a flat JSON object, sorted so that the same settings
+/// always come out the same.
+fn encode_settings(settings: &HashMap<String, String>) -> String {
+    let mut fields: Vec<String> = settings.iter()
+        .map(|(key, value)| format!("{}:{}", json::quote(key), json::quote(value)))
+        .collect();
+    fields.sort();
+    format!("{{{}}}", fields.join(","))
+}
+
+fn sql_error(err: rusqlite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err.to_string())
+}
@@ -0,0 +1,184 @@
+//! A console for the server's operators, on a port of its own and behind a
+//! token of its own, apart from the players' accounts. It asks the services
+//! what they are up to, and passes shutdown and reload on to `main`.
+
+use crate::telnet;
+
+use futures::{
+    prelude::*,
+    channel::{
+        mpsc::UnboundedSender,
+        oneshot,
+    },
+};
+
+use runtime::net::{TcpListener, TcpStream};
+
+use std::sync::Arc;
+
+const HELP: &str = "Commands:\n\
+                    \x20 connections      open connections\n\
+                    \x20 users            users logged in\n\
+                    \x20 game             the stage the game is at\n\
+                    \x20 player <login>   a player's state in the game\n\
+                    \x20 reload           read the config, MOTD and bans again, as SIGHUP does\n\
+                    \x20 shutdown         stop the server\n\
+                    \x20 quit             close the console\n";
+/// Wrong tokens a connection may give before it's closed.
+const MAX_FAILURES: u32 = 3;
+
+/// A question for one of the services, answered with text for the console.
+pub struct Query {
+    pub subject: Subject,
+    pub reply: oneshot::Sender<String>,
+}
+
+pub enum Subject {
+    /// For `SocketService`.
+    Connections,
+    /// For `LoginService`.
+    Users,
+    /// For `GameService`.
+    Game,
+    /// For `GameService`: the player with this login.
+    Player(Box<str>),
+    /// For any service: whether it is still answering, for the health checks.
+    Ping,
+}
+
+/// What the console asks `main` to do.
+pub enum Control {
+    Reload,
+    Shutdown,
+}
+
+/// Where the console sends its questions and orders.
+#[derive(Clone)]
+pub struct Handlers {
+    pub sockets: UnboundedSender<Query>,
+    pub logins: UnboundedSender<Query>,
+    pub game: UnboundedSender<Query>,
+    pub control: UnboundedSender<Control>,
+}
+
+pub struct AdminConsole {
+    listener: TcpListener,
+    token: Arc<str>,
+    handlers: Handlers,
+}
+
+impl AdminConsole {
+    pub fn new(listener: TcpListener, token: &str, handlers: Handlers) -> Self {
+        AdminConsole {
+            listener,
+            token: token.into(),
+            handlers,
+        }
+    }
+
+    pub async fn run(mut self) {
+        let mut connections = self.listener.incoming();
+        while let Some(stream) = connections.next().await {
+            match stream {
+                Ok(stream) => {
+                    let token = self.token.clone();
+                    let handlers = self.handlers.clone();
+                    #[allow(unused)] {
+                        runtime::spawn(async move {
+                            let peer = stream.peer_addr().ok();
+                            if let Err(err) = serve(stream, token, handlers).await {
+                                warn!("Admin console connection from {:?} failed: {}", peer, err);
+                            }
+                        });
+                    }
+                },
+                Err(err) => error!("Admin console failed to accept a connection: {}", err),
+            }
+        }
+    }
+}
+
+async fn serve(stream: TcpStream, token: Arc<str>, handlers: Handlers) -> std::io::Result<()> {
+    let peer = stream.peer_addr()?;
+    let (mut reader, mut writer) = stream.split();
+    let mut decoder = telnet::Decoder::new();
+    let mut buffer = [0; 1024];
+    let mut authenticated = false;
+    let mut failures = 0;
+    writer.write_all(b"Token: ").await?;
+    loop {
+        let count = reader.read(&mut buffer).await?;
+        if count == 0 {
+            return Ok(());
+        }
+        let decoded = decoder.decode(&buffer[..count]);
+        writer.write_all(&decoded.reply).await?;
+        for line in decoded.lines {
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim();
+            if !authenticated {
+                if same_token(line, &token) {
+                    info!(conn = peer; "Admin console opened");
+                    authenticated = true;
+                    writer.write_all(b"Welcome. Type help for the commands.\n> ").await?;
+                    continue;
+                }
+                warn!(conn = peer; "Wrong admin console token");
+                failures += 1;
+                if failures >= MAX_FAILURES {
+                    writer.write_all(b"Goodbye.\n").await?;
+                    return Ok(());
+                }
+                writer.write_all(b"Wrong token.\nToken: ").await?;
+                continue;
+            }
+            let reply = match execute(line, &handlers).await {
+                Some(reply) => reply,
+                None => return Ok(()),
+            };
+            writer.write_all(reply.as_bytes()).await?;
+            writer.write_all(b"> ").await?;
+        }
+    }
+}
+
+/// Carries out a console command and returns its output, or `None` if the
+/// console should close.
+async fn execute(line: &str, handlers: &Handlers) -> Option<String> {
+    let mut words = line.splitn(2, char::is_whitespace);
+    let command = words.next().unwrap_or("");
+    let args = words.next().unwrap_or("").trim();
+    let reply = match (command, args) {
+        ("", _) => String::new(),
+        ("help", _) => HELP.to_string(),
+        ("connections", "") => ask(&handlers.sockets, Subject::Connections).await,
+        ("users", "") => ask(&handlers.logins, Subject::Users).await,
+        ("game", "") => ask(&handlers.game, Subject::Game).await,
+        ("player", login) if !login.is_empty() =>
+            ask(&handlers.game, Subject::Player(login.into())).await,
+        ("reload", "") => {
+            handlers.control.unbounded_send(Control::Reload).expect("AdminConsole control failed");
+            "Reloading.\n".to_string()
+        },
+        ("shutdown", "") => {
+            handlers.control.unbounded_send(Control::Shutdown).expect("AdminConsole control failed");
+            "Shutting down.\n".to_string()
+        },
+        ("quit", "") => return None,
+        _ => "Unknown command; type help for the list.\n".to_string(),
+    };
+    Some(reply)
+}
+
+async fn ask(handler: &UnboundedSender<Query>, subject: Subject) -> String {
+    let (reply, answer) = oneshot::channel();
+    handler.unbounded_send(Query{subject, reply}).expect("AdminConsole query handler failed");
+    answer.await.unwrap_or_else(|_| "No answer.\n".to_string())
+}
+
+/// Compares every byte, so that how long it takes doesn't give away how much
+/// of the token was right.
+fn same_token(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
@@ -0,0 +1,37 @@
+//! ANSI colours for text clients whose terminals show them.
+
+use crate::protocol::MessageKind;
+
+const RESET: &str = "\x1b[0m";
+
+/// The escape that starts each kind of message, or `None` to leave it plain.
+fn style(kind: MessageKind) -> Option<&'static str> {
+    match kind {
+        MessageKind::Chat => None,
+        MessageKind::System | MessageKind::VoteUpdate => Some("\x1b[36m"),
+        MessageKind::PhaseChange => Some("\x1b[1;33m"),
+        MessageKind::Private => Some("\x1b[35m"),
+        MessageKind::Role => Some("\x1b[1;31m"),
+        MessageKind::Emote => Some("\x1b[32m"),
+    }
+}
+
+/// Colours a message line by line, so that nothing bleeds into the next one
+/// if the client misses a reset.
+pub fn paint(kind: MessageKind, message: &str) -> String {
+    let style = match style(kind) {
+        Some(style) => style,
+        None => return message.to_string(),
+    };
+    let mut painted = String::with_capacity(message.len() + 16);
+    for line in message.split_terminator('\n') {
+        painted.push_str(style);
+        painted.push_str(line);
+        painted.push_str(RESET);
+        painted.push('\n');
+    }
+    if !message.ends_with('\n') {
+        painted.pop();
+    }
+    painted
+}
@@ -0,0 +1,220 @@
+use crate::accounts::{Account, AccountStore};
+use crate::util::Rng;
+
+use futures::{
+    prelude::*,
+    future::{self, BoxFuture},
+};
+
+use chrono::prelude::*;
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+pub enum AuthResult {
+    Accepted,
+    Created,
+    Rejected,
+}
+
+/// Hook for plugging an external user system (SSO, web backend, ...) into
+/// `LoginService`. The credential is whatever the user typed at the password
+/// prompt: a password for the built-in database, a token for external backends.
+///
+/// Lookups and checks return futures, which `LoginService` awaits off its own
+/// loop, so a slow backend only holds up the connection waiting for it.
+pub trait Authenticator: Send {
+    /// Whether `login` already has an account. Unknown logins get the
+    /// "creating player" prompt and are expected to be registered by `verify`.
+    fn is_registered(&self, login: &str) -> BoxFuture<'static, bool>;
+
+    /// The registered spelling of `login`, if an account matches it regardless
+    /// of case.
+    fn find_login(&self, login: &str) -> BoxFuture<'static, Option<Box<str>>> {
+        let login: Box<str> = login.into();
+        self.is_registered(&login)
+            .map(move |registered| if registered { Some(login) } else { None })
+            .boxed()
+    }
+
+    fn verify(&mut self, login: &str, credential: &str) -> BoxFuture<'static, AuthResult>;
+
+    /// Replaces the account's password. Comes to false if the backend doesn't
+    /// let users do that, as external ones mostly don't.
+    fn set_password(&mut self, _login: &str, _credential: &str) -> BoxFuture<'static, bool> {
+        future::ready(false).boxed()
+    }
+
+    /// Deletes the account. Returns false if the backend doesn't let users
+    /// do that.
+    fn delete(&mut self, _login: &str) -> bool {
+        false
+    }
+
+    /// The account's settings, as `UserSettings::to_map` gives them. Backends
+    /// that can't keep settings have none, so users get the defaults.
+    fn settings(&self, _login: &str) -> HashMap<String, String> {
+        HashMap::new()
+    }
+
+    fn set_settings(&mut self, _login: &str, _settings: HashMap<String, String>) {}
+}
+
+/// Default password database: accounts are kept in memory and written through
+/// to an `AccountStore`.
+pub struct PasswordDb {
+    /// Shared with the futures hashing new passwords, which store them once
+    /// they're done.
+    accounts: Arc<Mutex<Accounts>>,
+    /// For salts, which only have to be unique.
+    rng: Rng,
+}
+
+struct Accounts {
+    /// By lowercased login, since logins differing only in case are the same.
+    by_key: HashMap<Box<str>, Account>,
+    store: Box<dyn AccountStore>,
+}
+
+impl PasswordDb {
+    /// Loads every account from the store.
+    pub fn new(mut store: Box<dyn AccountStore>) -> io::Result<Self> {
+        let by_key = store.load()?
+            .into_iter()
+            .map(|account| (account.login.to_lowercase().into(), account))
+            .collect();
+        Ok(PasswordDb {
+            accounts: Arc::new(Mutex::new(Accounts { by_key, store })),
+            rng: Rng::from_time(),
+        })
+    }
+
+    fn accounts(&self) -> MutexGuard<Accounts> {
+        self.accounts.lock().expect("PasswordDb lock poisoned")
+    }
+
+    fn salt(&mut self) -> [u8; 16] {
+        let mut salt = [0; 16];
+        salt[..8].copy_from_slice(&self.rng.next_u64().to_le_bytes());
+        salt[8..].copy_from_slice(&self.rng.next_u64().to_le_bytes());
+        salt
+    }
+}
+
+fn hash(password: &str, salt: &[u8]) -> Box<str> {
+    argon2::hash_encoded(password.as_bytes(), salt, &argon2::Config::default())
+        .expect("PasswordDb failed to hash a password")
+        .into()
+}
+
+impl Accounts {
+    /// Writes the account through to the store. The account stays usable if
+    /// that fails; it just won't outlive the server.
+    fn save(&mut self, key: &str) {
+        if let Some(account) = self.by_key.get(key) {
+            if let Err(err) = self.store.save(account) {
+                error!(login = account.login; "Failed to save account: {}", err);
+            }
+        }
+    }
+}
+
+impl Authenticator for PasswordDb {
+    fn is_registered(&self, login: &str) -> BoxFuture<'static, bool> {
+        future::ready(self.accounts().by_key.contains_key(&*login.to_lowercase())).boxed()
+    }
+
+    fn find_login(&self, login: &str) -> BoxFuture<'static, Option<Box<str>>> {
+        let login = self.accounts().by_key.get(&*login.to_lowercase())
+            .map(|account| account.login.clone());
+        future::ready(login).boxed()
+    }
+
+    fn verify(&mut self, login: &str, credential: &str) -> BoxFuture<'static, AuthResult> {
+        let key = login.to_lowercase();
+        let stored = self.accounts().by_key.get(&*key)
+            .map(|account| account.password_hash.clone());
+        // Hashing is slow on purpose, so it's left to the future.
+        let credential = credential.to_string();
+        if let Some(stored) = stored {
+            return future::lazy(move |_| {
+                match argon2::verify_encoded(&stored, credential.as_bytes()) {
+                    Ok(true) => AuthResult::Accepted,
+                    _ => AuthResult::Rejected,
+                }
+            }).boxed();
+        }
+        let accounts = self.accounts.clone();
+        let login: Box<str> = login.into();
+        let salt = self.salt();
+        future::lazy(move |_| hash(&credential, &salt))
+            .map(move |password_hash| {
+                let mut accounts = accounts.lock().expect("PasswordDb lock poisoned");
+                // Somebody else took the login while the password was hashed.
+                if accounts.by_key.contains_key(&*key) {
+                    return AuthResult::Rejected;
+                }
+                let account = Account {
+                    login,
+                    password_hash,
+                    created: Utc::now(),
+                    settings: HashMap::new(),
+                };
+                accounts.by_key.insert(key.as_str().into(), account);
+                accounts.save(&key);
+                AuthResult::Created
+            })
+            .boxed()
+    }
+
+    fn set_password(&mut self, login: &str, credential: &str) -> BoxFuture<'static, bool> {
+        let key = login.to_lowercase();
+        if !self.accounts().by_key.contains_key(&*key) {
+            return future::ready(false).boxed();
+        }
+        let accounts = self.accounts.clone();
+        let credential = credential.to_string();
+        let salt = self.salt();
+        future::lazy(move |_| hash(&credential, &salt))
+            .map(move |password_hash| {
+                let mut accounts = accounts.lock().expect("PasswordDb lock poisoned");
+                // The account may have been deleted while the password was hashed.
+                match accounts.by_key.get_mut(&*key) {
+                    Some(account) => account.password_hash = password_hash,
+                    None => return false,
+                }
+                accounts.save(&key);
+                true
+            })
+            .boxed()
+    }
+
+    fn delete(&mut self, login: &str) -> bool {
+        let key = login.to_lowercase();
+        let mut accounts = self.accounts();
+        if accounts.by_key.remove(&*key).is_none() {
+            return false;
+        }
+        if let Err(err) = accounts.store.delete(login) {
+            error!(login = login; "Failed to delete account: {}", err);
+        }
+        true
+    }
+
+    fn settings(&self, login: &str) -> HashMap<String, String> {
+        self.accounts().by_key.get(&*login.to_lowercase())
+            .map_or_else(HashMap::new, |account| account.settings.clone())
+    }
+
+    fn set_settings(&mut self, login: &str, settings: HashMap<String, String>) {
+        let key = login.to_lowercase();
+        let mut accounts = self.accounts();
+        if let Some(account) = accounts.by_key.get_mut(&*key) {
+            account.settings = settings;
+            accounts.save(&key);
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Bans, of logins and of addresses. They are kept in a file, one per line, as
+//! `<target> [<until>]`, where the target is a login, an address or a CIDR
+//! block like `10.0.0.0/8`, and the optional end of the ban is an RFC 3339
+//! time. The file is read again whenever the server gets SIGHUP.
+
+use chrono::prelude::*;
+
+use std::{
+    fs,
+    io,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+/// Handle to the ban list; clones share it.
+#[derive(Clone)]
+pub struct BanList {
+    path: Option<PathBuf>,
+    bans: Arc<RwLock<Vec<Ban>>>,
+}
+
+struct Ban {
+    target: Target,
+    /// Banned for good if `None`.
+    until: Option<DateTime<Utc>>,
+}
+
+#[derive(PartialEq)]
+enum Target {
+    /// Lowercased, since logins differing only in case are the same.
+    Login(Box<str>),
+    /// An address and the length of the prefix that has to match.
+    Network(IpAddr, u8),
+}
+
+impl BanList {
+    /// Reads the bans from `path`, if there is one; a missing file means
+    /// there are none yet. Without a path, bans last until the server stops.
+    pub fn load(path: Option<PathBuf>) -> io::Result<Self> {
+        let bans = match &path {
+            Some(path) => read_bans(path)?,
+            None => Vec::new(),
+        };
+        Ok(BanList {
+            path,
+            bans: Arc::new(RwLock::new(bans)),
+        })
+    }
+
+    /// Reads the file again. If it can't be read, the old bans stay.
+    pub fn reload(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        match read_bans(path) {
+            Ok(bans) => {
+                info!("Loaded {} bans from {}", bans.len(), path.display());
+                *self.bans.write().expect("BanList lock poisoned") = bans;
+            },
+            Err(err) => error!("Failed to read bans from {}: {}", path.display(), err),
+        }
+    }
+
+    /// Bans a login, address or CIDR block, for `duration` or for good.
+    /// A ban already on the target is replaced.
+    pub fn ban(&self, target: &str, duration: Option<Duration>) -> Result<(), &'static str> {
+        let target = parse_target(target)?;
+        let until = match duration {
+            Some(duration) => Some(chrono::Duration::from_std(duration).ok()
+                .and_then(|duration| Utc::now().checked_add_signed(duration))
+                .ok_or("That ban is too long.")?),
+            None => None,
+        };
+        let mut bans = self.bans.write().expect("BanList lock poisoned");
+        bans.retain(|ban| ban.target != target);
+        bans.push(Ban{target, until});
+        self.save(&bans);
+        Ok(())
+    }
+
+    /// Lifts the ban on exactly this target. Returns false if there wasn't one.
+    pub fn unban(&self, target: &str) -> bool {
+        let target = match parse_target(target) {
+            Ok(target) => target,
+            Err(_) => return false,
+        };
+        let mut bans = self.bans.write().expect("BanList lock poisoned");
+        let count = bans.len();
+        bans.retain(|ban| ban.target != target);
+        if bans.len() == count {
+            return false;
+        }
+        self.save(&bans);
+        true
+    }
+
+    /// Whether connections from `ip` are refused.
+    pub fn is_ip_banned(&self, ip: IpAddr) -> bool {
+        let ip = canonical(ip);
+        self.find(|target| match *target {
+            Target::Network(network, prefix) => in_network(ip, network, prefix),
+            Target::Login(_) => false,
+        }).is_some()
+    }
+
+    /// If `login` is banned, when the ban ends: `Some(None)` if it never does.
+    pub fn login_ban(&self, login: &str) -> Option<Option<DateTime<Utc>>> {
+        let login = login.to_lowercase();
+        self.find(|target| match target {
+            Target::Login(banned) => **banned == *login,
+            Target::Network(..) => false,
+        })
+    }
+
+    /// The bans in force, as they are written in the file.
+    pub fn list(&self) -> Vec<String> {
+        let now = Utc::now();
+        self.bans.read().expect("BanList lock poisoned")
+            .iter()
+            .filter(|ban| ban.until.map_or(true, |until| until > now))
+            .map(format_ban)
+            .collect()
+    }
+
+    fn find<F: Fn(&Target) -> bool>(&self, matches: F) -> Option<Option<DateTime<Utc>>> {
+        let now = Utc::now();
+        self.bans.read().expect("BanList lock poisoned")
+            .iter()
+            .filter(|ban| ban.until.map_or(true, |until| until > now))
+            .find(|ban| matches(&ban.target))
+            .map(|ban| ban.until)
+    }
+
+    /// Writes the bans in force to the file, if there is one.
+    fn save(&self, bans: &[Ban]) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let now = Utc::now();
+        let contents: String = bans.iter()
+            .filter(|ban| ban.until.map_or(true, |until| until > now))
+            .map(|ban| format!("{}\n", format_ban(ban)))
+            .collect();
+        if let Err(err) = fs::write(path, contents) {
+            error!("Failed to save bans to {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// Parses a duration like `30m`, `12h` or `7d`; a bare number is minutes.
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let number: u64 = text[..split].parse().ok()?;
+    let unit = match &text[split..] {
+        "s" => 1,
+        "" | "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(number.checked_mul(unit)?))
+}
+
+fn read_bans(path: &PathBuf) -> io::Result<Vec<Ban>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let mut bans = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_ban(line) {
+            Some(ban) => bans.push(ban),
+            None => warn!("Skipping malformed ban on line {} of {}", number + 1, path.display()),
+        }
+    }
+    Ok(bans)
+}
+
+fn parse_ban(line: &str) -> Option<Ban> {
+    let mut words = line.split_whitespace();
+    let target = parse_target(words.next()?).ok()?;
+    let until = match words.next() {
+        Some(until) => Some(DateTime::parse_from_rfc3339(until).ok()?.with_timezone(&Utc)),
+        None => None,
+    };
+    if words.next().is_some() {
+        return None;
+    }
+    Some(Ban{target, until})
+}
+
+fn format_ban(ban: &Ban) -> String {
+    let target = match &ban.target {
+        Target::Login(login) => login.to_string(),
+        Target::Network(ip, prefix) => format!("{}/{}", ip, prefix),
+    };
+    match ban.until {
+        Some(until) => format!("{} {}", target, until.to_rfc3339_opts(SecondsFormat::Secs, true)),
+        None => target,
+    }
+}
+
+/// An address or CIDR block if it parses as one, and a login otherwise.
+fn parse_target(text: &str) -> Result<Target, &'static str> {
+    let (address, prefix) = match text.find('/') {
+        Some(slash) => (&text[..slash], Some(&text[slash + 1..])),
+        None => (text, None),
+    };
+    let ip = match address.parse::<IpAddr>() {
+        Ok(ip) => canonical(ip),
+        Err(_) if prefix.is_none() && !text.is_empty() =>
+            return Ok(Target::Login(text.to_lowercase().into())),
+        Err(_) => return Err("Not a valid address or CIDR block."),
+    };
+    let bits = if ip.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => match prefix.parse::<u8>() {
+            Ok(prefix) if prefix <= bits => prefix,
+            _ => return Err("Not a valid address or CIDR block."),
+        },
+        None => bits,
+    };
+    Ok(Target::Network(ip, prefix))
+}
+
+/// IPv4 addresses seen through an IPv6 socket are written as IPv6 ones;
+/// this turns them back, so that IPv4 bans apply to them.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => {
+            let octets = v6.octets();
+            if octets[..10].iter().all(|&byte| byte == 0) && octets[10..12] == [0xff, 0xff] {
+                IpAddr::from([octets[12], octets[13], octets[14], octets[15]])
+            } else {
+                ip
+            }
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = (!0u32).checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        },
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = (!0u128).checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        },
+        _ => false,
+    }
+}
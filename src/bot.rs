@@ -0,0 +1,226 @@
+//! Computer players, for testing the game loop and filling up the lobby. A bot
+//! is a user with no connection behind it: `GameService` decides what it does
+//! and types it into the chat on its behalf, so bots go through the same
+//! commands and checks as everyone else.
+
+use crate::chat_service::PlayerId;
+use crate::login_service::UserId;
+use crate::roles::Ability;
+use crate::game_log::LogEvent;
+use crate::util::Rng;
+
+const DISCUSSION_LINES: &[&str] = &[
+    "Good morning, everyone.",
+    "Somebody here is lying.",
+    "I'm just a simple civilian, I swear.",
+    "Who has been quiet so far?",
+    "I have a bad feeling about this.",
+    "Let's not rush the vote today.",
+];
+
+const LAST_WORDS_LINES: &[&str] = &[
+    "You are making a mistake.",
+    "I was innocent! Remember that tonight.",
+    "Fine. Good luck finding the real killers.",
+];
+
+/// How a bot makes up its mind. To make a new kind of bot available to
+/// `!addbot`, implement this and add it to `load_strategy`.
+pub trait BotStrategy: Send {
+    /// Who to vote against today, out of `view.alive`, or `None` to vote to skip
+    /// the lynch. A dead hunter is asked this too, for who to shoot.
+    fn decide_vote<'a>(&mut self, view: &View<'a>, rng: &mut Rng) -> Option<&'a str>;
+
+    /// Who to use the ability on tonight, out of `targets`: one player, or two
+    /// for matchmaking. Nobody means passing.
+    fn decide_night_action<'a>(&mut self, view: &View<'a>, ability: Ability,
+                               targets: &[&'a str], rng: &mut Rng) -> Vec<&'a str>;
+
+    /// Called with everything the bot gets to know about as the game goes: what
+    /// is announced to everyone, and the results of its own checks.
+    fn on_event(&mut self, _event: &LogEvent) {}
+}
+
+/// Names `!addbot` accepts.
+pub const STRATEGIES: &[&str] = &["random", "follow-the-crowd"];
+
+/// Picks at random among the players it may choose.
+pub struct RandomStrategy;
+
+/// Sides with whoever has the most votes already, and with its team's picks at night.
+pub struct FollowTheCrowd;
+
+/// What a bot gets to see when it has to decide something.
+pub struct View<'a> {
+    /// Living players other than the bot.
+    pub alive: Vec<&'a str>,
+    /// Fellow mafiosi, if the bot is in the mafia.
+    pub team: Vec<&'a str>,
+    /// Votes cast so far as (voter, suspect): today's public votes by day, the
+    /// team's kill picks by night.
+    pub votes: Vec<(&'a str, &'a str)>,
+    /// The mafia's victim, for the roles that are told it.
+    pub victim: Option<&'a str>,
+}
+
+pub struct Bot {
+    strategy: Box<dyn BotStrategy>,
+    rng: Rng,
+}
+
+pub fn load_strategy(name: &str) -> Option<Box<dyn BotStrategy>> {
+    match name {
+        "random" => Some(Box::new(RandomStrategy)),
+        "follow-the-crowd" => Some(Box::new(FollowTheCrowd)),
+        _ => None,
+    }
+}
+
+impl Bot {
+    pub fn new(strategy: Box<dyn BotStrategy>) -> Self {
+        Bot {
+            strategy,
+            rng: Rng::from_time(),
+        }
+    }
+
+    /// How long to wait before acting, so that bots don't all act at once.
+    pub fn delay_ms(&mut self) -> u64 {
+        2000 + self.rng.below(4000) as u64
+    }
+
+    /// Something to say at the start of the day, sometimes.
+    pub fn chat(&mut self) -> Option<&'static str> {
+        if self.rng.below(2) == 0 {
+            Some(DISCUSSION_LINES[self.rng.below(DISCUSSION_LINES.len())])
+        } else {
+            None
+        }
+    }
+
+    pub fn last_words(&mut self) -> &'static str {
+        LAST_WORDS_LINES[self.rng.below(LAST_WORDS_LINES.len())]
+    }
+
+    /// The bot's vote for the day, as a command.
+    pub fn vote(&mut self, view: &View) -> String {
+        match self.strategy.decide_vote(view, &mut self.rng) {
+            Some(suspect) => format!("!vote {}", suspect),
+            None => "!vote skip".to_string(),
+        }
+    }
+
+    /// The bot's night actions, one command per ability, given who each
+    /// ability may target.
+    pub fn act(&mut self, view: &View, abilities: &[(Ability, Vec<&str>)]) -> Vec<String> {
+        let mut lines = Vec::new();
+        for (ability, targets) in abilities {
+            let picks = self.strategy.decide_night_action(view, *ability, targets, &mut self.rng);
+            if !picks.is_empty() {
+                lines.push(format!("!!{} {}", ability.keyword(), picks.join(" ")));
+            }
+        }
+        if lines.len() < abilities.len() {
+            lines.push("!!pass".to_string());
+        }
+        lines
+    }
+
+    /// Who a dead hunter bot takes down with them.
+    pub fn shoot(&mut self, view: &View) -> String {
+        match self.strategy.decide_vote(view, &mut self.rng) {
+            Some(target) => format!("!!{}", target),
+            None => "!!pass".to_string(),
+        }
+    }
+
+    pub fn on_event(&mut self, event: &LogEvent) {
+        self.strategy.on_event(event);
+    }
+}
+
+impl BotStrategy for RandomStrategy {
+    fn decide_vote<'a>(&mut self, view: &View<'a>, rng: &mut Rng) -> Option<&'a str> {
+        pick(rng, &outsiders(view, &view.alive))
+    }
+
+    fn decide_night_action<'a>(&mut self, view: &View<'a>, ability: Ability,
+                               targets: &[&'a str], rng: &mut Rng) -> Vec<&'a str> {
+        match ability {
+            Ability::Matchmake => {
+                let mut targets = targets.to_vec();
+                rng.shuffle(&mut targets);
+                targets.truncate(2);
+                if targets.len() < 2 {
+                    targets.clear();
+                }
+                targets
+            },
+            Ability::Heal => view.victim.filter(|victim| targets.contains(victim)).into_iter().collect(),
+            // Poison is only good once, so it's kept for later more often than not.
+            Ability::Poison if rng.below(3) != 0 => Vec::new(),
+            _ => pick(rng, &outsiders(view, targets)).into_iter().collect(),
+        }
+    }
+}
+
+impl BotStrategy for FollowTheCrowd {
+    fn decide_vote<'a>(&mut self, view: &View<'a>, rng: &mut Rng) -> Option<&'a str> {
+        let candidates = outsiders(view, &view.alive);
+        most_voted(view, &candidates).or_else(|| pick(rng, &candidates))
+    }
+
+    fn decide_night_action<'a>(&mut self, view: &View<'a>, ability: Ability,
+                               targets: &[&'a str], rng: &mut Rng) -> Vec<&'a str> {
+        if ability == Ability::Kill {
+            if let Some(victim) = most_voted(view, &outsiders(view, targets)) {
+                return vec![victim];
+            }
+        }
+        RandomStrategy.decide_night_action(view, ability, targets, rng)
+    }
+}
+
+/// The `targets` who aren't on the bot's team.
+fn outsiders<'a>(view: &View, targets: &[&'a str]) -> Vec<&'a str> {
+    targets.iter()
+        .cloned()
+        .filter(|target| !view.team.contains(target))
+        .collect()
+}
+
+/// The candidate with the most votes in the view, if anyone has any.
+fn most_voted<'a>(view: &View, candidates: &[&'a str]) -> Option<&'a str> {
+    let mut best: Option<(&'a str, usize)> = None;
+    for &candidate in candidates {
+        let count = view.votes.iter().filter(|&&(_, suspect)| suspect == candidate).count();
+        if count > 0 && best.map_or(true, |(_, top)| count > top) {
+            best = Some((candidate, count));
+        }
+    }
+    best.map(|(candidate, _)| candidate)
+}
+
+fn pick<'a>(rng: &mut Rng, candidates: &[&'a str]) -> Option<&'a str> {
+    if candidates.is_empty() {
+        None
+    } else {
+        Some(candidates[rng.below(candidates.len())])
+    }
+}
+
+/// Bots get ids from this one up, far above any `LoginService` hands out.
+const FIRST_BOT_ID: u64 = 1 << 63;
+
+pub fn is_bot(id: PlayerId) -> bool {
+    id.0 >= FIRST_BOT_ID
+}
+
+pub fn bot_id(number: u16) -> PlayerId {
+    UserId(FIRST_BOT_ID + u64::from(number))
+}
+
+/// Logins of the form `bot<number>` are kept for bots.
+pub fn is_bot_login(login: &str) -> bool {
+    login.len() > 3 && login.starts_with("bot") && login[3..].chars().all(|c| c.is_ascii_digit())
+}
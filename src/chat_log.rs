@@ -0,0 +1,68 @@
+//! Chat kept on disk: public messages, comings and goings and announcements,
+//! as the users saw them, in a file per day named after the UTC date, like
+//! `2019-07-01.log`. Private messages go in only if the operator asks for it.
+
+use chrono::prelude::*;
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{LineWriter, Write},
+    path::PathBuf,
+};
+
+pub struct ChatLog {
+    dir: PathBuf,
+    /// Private messages are logged too.
+    private: bool,
+    /// The day the open file is for.
+    day: Option<Date<Utc>>,
+    /// `None` if the day's file couldn't be opened; it isn't tried again
+    /// until the next day.
+    file: Option<LineWriter<File>>,
+}
+
+impl ChatLog {
+    pub fn new(dir: PathBuf, private: bool) -> Self {
+        ChatLog {
+            dir,
+            private,
+            day: None,
+            file: None,
+        }
+    }
+
+    /// Logs a line of public chat or an announcement, as it was sent.
+    pub fn record(&mut self, line: &str) {
+        let now = Utc::now();
+        if self.day != Some(now.date()) {
+            self.day = Some(now.date());
+            self.file = self.open(now.date());
+        }
+        if let Some(file) = &mut self.file {
+            let line = line.trim_end();
+            if let Err(err) = writeln!(file, "{} {}", now.format("%H:%M:%S"), line) {
+                error!("Failed to write the chat log: {}", err);
+            }
+        }
+    }
+
+    /// Logs a private message, if they are logged.
+    pub fn record_private(&mut self, line: &str) {
+        if self.private {
+            self.record(line);
+        }
+    }
+
+    fn open(&self, day: Date<Utc>) -> Option<LineWriter<File>> {
+        let path = self.dir.join(format!("{}.log", day.format("%Y-%m-%d")));
+        let file = fs::create_dir_all(&self.dir)
+            .and_then(|_| OpenOptions::new().create(true).append(true).open(&path));
+        match file {
+            Ok(file) => Some(LineWriter::new(file)),
+            Err(err) => {
+                error!("Failed to open chat log {}: {}", path.display(), err);
+                None
+            },
+        }
+    }
+}
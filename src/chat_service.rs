@@ -1,5 +1,13 @@
 use crate::login_service::{User, UserId, UserEvent};
 use crate::locale::{Locale, HELP_EN};
+use crate::protocol::MessageKind;
+use crate::motd::Motd;
+use crate::settings::Preferences;
+use crate::bans::{self, BanList};
+use crate::admin_console::{Query, Subject};
+use crate::chat_log::ChatLog;
+#[cfg(test)]
+use crate::socket_service::{Outbox, SocketProxy};
 
 use futures::{
     prelude::*,
@@ -11,8 +19,11 @@ use chrono::prelude::*;
 
 use std::{
     sync::Arc,
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
 };
+#[cfg(test)]
+use std::net::SocketAddr;
 
 pub type PlayerId = UserId;
 
@@ -28,9 +39,17 @@ pub struct ChatService {
     user_receiver: UnboundedReceiver<UserEvent>,
     request_sender: UnboundedSender<ChatRequest>,
     request_receiver: UnboundedReceiver<ChatRequest>,
+    query_sender: UnboundedSender<Query>,
+    query_receiver: UnboundedReceiver<Query>,
     users: HashMap<UserId, UserInfo>,
     login_id: HashMap<Box<str>, UserId>,
+    /// The last public messages, oldest first.
+    history: VecDeque<Said>,
+    chat_log: Option<ChatLog>,
+    graveyard_policy: GraveyardPolicy,
     locale: Locale,
+    motd: Motd,
+    bans: BanList,
 }
 
 pub enum GameEvent {
@@ -39,14 +58,112 @@ pub enum GameEvent {
     Action(PlayerId, Box<str>),
     CommandList(PlayerId),
     CommandObserve(PlayerId),
+    /// Drop out of the game but stay connected.
+    CommandLeave(PlayerId),
     CommandPlay(PlayerId),
     CommandPause(PlayerId),
     CommandStart(PlayerId),
+    CommandVote(PlayerId, Box<str>),
+    CommandVotes(PlayerId),
+    CommandReveal(PlayerId),
+    CommandSetRule(PlayerId, Box<str>),
+    CommandRules(PlayerId),
+    CommandStats(PlayerId, Box<str>),
+    CommandTop(PlayerId),
+    CommandReplay(PlayerId, Box<str>),
+    CommandAddBot(PlayerId, Box<str>),
+    CommandRemoveBot(PlayerId, Box<str>),
+    CommandTournament(PlayerId, Box<str>),
+    CommandPredict(PlayerId, Box<str>),
+    /// Observers and admins out of the game only: each faction's chances.
+    CommandOdds(PlayerId),
+    /// Admins only: start the game now, without a countdown.
+    CommandForceStart(PlayerId),
+    /// Admins only: end the game in progress with no winner.
+    CommandAbort(PlayerId),
+    /// The player said something in chat; lets `GameService` tell who is idle.
+    Activity(PlayerId),
+    /// A public message that went out to everyone, for the replay.
+    Chat(PlayerId, Box<str>),
+}
+
+impl GameEvent {
+    pub fn player_id(&self) -> PlayerId {
+        match self {
+            GameEvent::Connected(player) => player.get_id(),
+            GameEvent::Disconnected(id)
+            | GameEvent::Action(id, _)
+            | GameEvent::CommandList(id)
+            | GameEvent::CommandObserve(id)
+            | GameEvent::CommandLeave(id)
+            | GameEvent::CommandPlay(id)
+            | GameEvent::CommandPause(id)
+            | GameEvent::CommandStart(id)
+            | GameEvent::CommandVote(id, _)
+            | GameEvent::CommandVotes(id)
+            | GameEvent::CommandReveal(id)
+            | GameEvent::CommandSetRule(id, _)
+            | GameEvent::CommandRules(id)
+            | GameEvent::CommandStats(id, _)
+            | GameEvent::CommandTop(id)
+            | GameEvent::CommandReplay(id, _)
+            | GameEvent::CommandAddBot(id, _)
+            | GameEvent::CommandRemoveBot(id, _)
+            | GameEvent::CommandTournament(id, _)
+            | GameEvent::CommandPredict(id, _)
+            | GameEvent::CommandOdds(id)
+            | GameEvent::CommandForceStart(id)
+            | GameEvent::CommandAbort(id)
+            | GameEvent::Activity(id)
+            | GameEvent::Chat(id, _) => *id,
+        }
+    }
 }
 
 struct UserInfo {
     user: User,
     mute: MuteLevel,
+    status: PlayerStatus,
+    team: Option<Team>,
+    /// Muted by an admin, which unlike the game's mutes lasts until they
+    /// say otherwise.
+    silenced: bool,
+    /// Who last sent the user a private message, for `+.`.
+    last_sender: Option<Box<str>>,
+    /// Everyone else in the user's last private conversation, for `++`.
+    conversation: Vec<Box<str>>,
+}
+
+/// Private team channel (`*message`). Membership is managed by `GameService`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Team {
+    Mafia,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum PlayerStatus {
+    Spectator,
+    Alive,
+    Dead,
+}
+
+/// Decides who can talk in the graveyard (`#message`) channel. Dead players
+/// may know things spectators don't, so some rulesets keep them apart.
+#[derive(Clone, Copy)]
+pub enum GraveyardPolicy {
+    /// Dead players and spectators share one channel.
+    Shared,
+    /// Dead players and spectators get a channel each.
+    Separate,
+    /// Spectators have a channel, dead players have none.
+    Silent,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum GraveyardChannel {
+    Common,
+    Spectators,
+    Dead,
 }
 
 pub enum MuteLevel {
@@ -55,30 +172,67 @@ pub enum MuteLevel {
     DenyAll(&'static str),
 }
 
+/// A public message, as it went out.
+struct Said {
+    time: DateTime<Utc>,
+    login: Box<str>,
+    line: Box<str>,
+}
+
 enum ChatRequest {
     MutePlayer(PlayerId, MuteLevel),
+    SetStatus(PlayerId, PlayerStatus),
+    SetTeam(PlayerId, Option<Team>),
+    Countdown(PlayerId, Arc<str>),
 }
 
 enum Message<'a> {
     Public(&'a str),
+    /// `/me shrugs`, `:shrugs`, or one of the shortcuts like `:wave`.
+    Emote(&'a str),
     Private(&'a str, Box<[&'a str]>),
+    Graveyard(&'a str),
+    Team(&'a str),
     Command(&'a str),
     Action(&'a str),
 }
 
 impl ChatService {
-    pub fn new(event_handler: UnboundedSender<GameEvent>, locale: Locale) -> Self {
+    /// Public messages kept for `!history`.
+    const HISTORY_SIZE: usize = 100;
+    /// Public messages shown to users as they connect.
+    const SCROLLBACK: usize = 20;
+    const MAX_IGNORED: usize = 100;
+    /// Commands only admins may use.
+    const ADMIN_COMMANDS: &'static [&'static str] = &[
+        "kick", "ban", "unban", "bans", "mute", "unmute", "announce", "forcestart", "abort",
+    ];
+
+    pub fn new(event_handler: UnboundedSender<GameEvent>,
+               graveyard_policy: GraveyardPolicy,
+               locale: Locale,
+               motd: Motd,
+               bans: BanList,
+               chat_log: Option<ChatLog>) -> Self {
         let (user_sender, user_receiver) = unbounded();
         let (request_sender, request_receiver) = unbounded();
+        let (query_sender, query_receiver) = unbounded();
         ChatService {
             event_handler,
             user_sender,
             user_receiver,
             request_sender,
             request_receiver,
+            query_sender,
+            query_receiver,
+            graveyard_policy,
             locale,
+            motd,
+            bans,
             users: HashMap::new(),
             login_id: HashMap::new(),
+            history: VecDeque::with_capacity(Self::HISTORY_SIZE),
+            chat_log,
         }
     }
 
@@ -86,6 +240,10 @@ impl ChatService {
         self.user_sender.clone()
     }
 
+    pub fn make_query_handler(&self) -> UnboundedSender<Query> {
+        self.query_sender.clone()
+    }
+
     pub async fn run(mut self) {
         loop {
             select! {
@@ -99,16 +257,33 @@ impl ChatService {
                 request = self.request_receiver.next().fuse() =>
                     match request {
                         Some(ChatRequest::MutePlayer(id, level)) => self.handle_mute_request(id, level),
+                        Some(ChatRequest::SetStatus(id, status)) => self.handle_status_request(id, status),
+                        Some(ChatRequest::SetTeam(id, team)) => self.handle_team_request(id, team),
+                        Some(ChatRequest::Countdown(id, message)) => self.handle_countdown(id, message),
                         None => panic!("ChatService request_receiver terminated"),
                     },
+                query = self.query_receiver.next().fuse() =>
+                    self.handle_query(query.expect("ChatService query_receiver terminated")),
             }
         }
     }
 
+    /// Answers the health checks; the chat has nothing to tell the admin
+    /// console that `LoginService` doesn't.
+    fn handle_query(&self, query: Query) {
+        let answer = match query.subject {
+            Subject::Ping => "pong\n".to_string(),
+            _ => "ChatService can't answer that.\n".to_string(),
+        };
+        let _ = query.reply.send(answer);
+    }
+
     fn handle_new_user(&mut self, user: User) {
-        self.broadcast(format!("{} Connected: {}\n",
-                               Local::now().format("%H:%M"),
-                               user.get_login()).into());
+        let notice = format!("Connected: {}\n", user.get_login());
+        self.broadcast_if(&notice, |prefs| prefs.joins);
+        if let Some(chat_log) = &mut self.chat_log {
+            chat_log.record(&notice);
+        }
         // Send event
         let player = Player{user: user.clone(), channel: self.request_sender.clone()};
         let event = GameEvent::Connected(player);
@@ -119,93 +294,441 @@ impl ChatService {
         let info = UserInfo{
             user,
             mute: MuteLevel::DenyAll("Observers are not allowed to use chat.\n"),
+            status: PlayerStatus::Spectator,
+            team: None,
+            silenced: false,
+            last_sender: None,
+            conversation: Vec::new(),
         };
+        if !info.user.settings().notifications.deafen {
+            self.send_history(&info.user, Self::SCROLLBACK);
+        }
         self.users.insert(id, info);
     }
 
-    fn handle_new_message(&self, id: UserId, line: Box<str>) {
+    fn handle_new_message(&mut self, id: UserId, line: Box<str>) {
         let info = match self.users.get(&id) {
             Some(info) => info,
             None => return,
         };
-        match Message::parse(&line) {
-            Message::Public(message) => self.handle_public_message(info, message),
-            Message::Private(message, mut recipients) =>
-                self.handle_private_message(info, message, &mut recipients),
-            Message::Command(command) => self.handle_command(&info.user, command),
+        let message = match Message::parse(&line) {
+            // Only those with a graveyard to talk in mean it by `#`; anyone else
+            // is just starting a line with it, as in "#1 suspect".
+            Message::Graveyard(_) if self.graveyard_channel(info.status).is_none() =>
+                Message::Public(&line),
+            message => message,
+        };
+        match message {
+            Message::Command(_) | Message::Action(_) => (),
+            _ => self.event_handler.unbounded_send(GameEvent::Activity(id))
+                     .expect("ChatService event_hadler failed"),
+        }
+        match message {
+            Message::Public(_) | Message::Emote(_) | Message::Private(..)
+                | Message::Graveyard(_) | Message::Team(_)
+                if info.silenced => info.user.send_static("An admin has muted you.\n"),
+            Message::Public(message) => {
+                if let Some(said) = self.handle_public_message(info, message, MessageKind::Chat) {
+                    self.remember(said);
+                }
+            },
+            Message::Emote(message) => {
+                if let Some(said) = self.handle_public_message(info, message, MessageKind::Emote) {
+                    self.remember(said);
+                }
+            },
+            Message::Private(message, recipients) => {
+                let recipients = match self.resolve_recipients(info, &recipients) {
+                    Ok(recipients) => recipients,
+                    Err(reason) => return info.user.send(reason),
+                };
+                let mut logins: Vec<&str> = recipients.iter().map(|login| &**login).collect();
+                if let Some(sent) = self.handle_private_message(info, message, &mut logins) {
+                    if let Some(chat_log) = &mut self.chat_log {
+                        chat_log.record_private(&sent);
+                    }
+                    self.remember_conversation(id, &recipients);
+                }
+            },
+            Message::Graveyard(message) => self.handle_graveyard_message(info, message),
+            Message::Team(message) => self.handle_team_message(info, message),
+            Message::Command(command) => {
+                let user = info.user.clone();
+                self.handle_command(&user, command);
+            },
             Message::Action(login) => self.handle_action(&info.user, login),
         }
     }
 
-    fn handle_public_message(&self, info: &UserInfo, message: &str) {
+    /// Sends chat, or an emote if `kind` says so, to everyone. Returns the
+    /// message as it went out, if it did.
+    fn handle_public_message(&self, info: &UserInfo, message: &str,
+                             kind: MessageKind) -> Option<Said> {
         let &UserInfo{ref user, ref mute, ..} = info;
         if !mute.public_allowed() {
             user.send_static(mute.get_reason());
-            return;
+            return None;
         }
-        if !message.is_empty() {
-            self.broadcast(format!("{} [{}] {}\n",
-                                   Local::now().format("%H:%M"),
-                                   user.get_login(),
-                                   message).into());
+        if message.is_empty() {
+            return None;
         }
+        self.event_handler.unbounded_send(GameEvent::Chat(user.get_id(), message.into()))
+            .expect("ChatService event_hadler failed");
+        let message = match kind {
+            MessageKind::Emote => format!("* {} {}\n", user.get_login(), message),
+            _ => format!("[{}] {}\n", user.get_login(), message),
+        };
+        let sender = user.get_id();
+        for other in self.users.values() {
+            let is_sender = other.user.get_id() == sender;
+            let prefs = other.user.settings().notifications;
+            if (is_sender && prefs.echo)
+                || (!is_sender && !prefs.deafen && !other.user.ignores(user.get_login())) {
+                send_stamped(&other.user, kind, &message);
+            }
+        }
+        Some(Said{time: Utc::now(), login: user.get_login().into(), line: message.into()})
+    }
+
+    /// Keeps a public message for `!history` and the chat log.
+    fn remember(&mut self, said: Said) {
+        if let Some(chat_log) = &mut self.chat_log {
+            chat_log.record(&said.line);
+        }
+        if self.history.len() == Self::HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        self.history.push_back(said);
+    }
+
+    /// Sends the last `count` public messages, stamped with when they were
+    /// said, leaving out the ones from ignored users. Returns false if there
+    /// were none to send.
+    fn send_history(&self, user: &User, count: usize) -> bool {
+        let settings = user.settings();
+        let mut said: Vec<&Said> = self.history.iter()
+            .rev()
+            .filter(|said| !settings.ignores(&said.login))
+            .take(count)
+            .collect();
+        if said.is_empty() {
+            return false;
+        }
+        said.reverse();
+        let mut scrollback = String::from("Recent chat:\n");
+        for said in said {
+            scrollback.push_str(&settings.timestamp_at(said.time));
+            scrollback.push_str(&said.line);
+        }
+        user.send_typed(MessageKind::Chat, scrollback.into());
+        true
     }
 
-    fn handle_private_message(&self, info: &UserInfo, message: &str, recipients: &mut [&str]) {
+    /// Recipients who aren't online get mail instead, if they have an account.
+    /// Returns the message as it went out, if it did, for the chat log.
+    fn handle_private_message(&self, info: &UserInfo, message: &str,
+                              recipients: &mut [&str]) -> Option<String> {
         let &UserInfo{ref user, ref mute, ..} = info;
         // Do validation
         if !mute.private_allowed() {
             user.send_static(mute.get_reason());
-            return;
+            return None;
         }
         if message.is_empty() {
             user.send_static("Can't send an empty private message.\n");
-            return;
+            return None;
         }
         if recipients.is_empty() {  // Shouldn't happen, but just to be sure
             user.send_static("No recipients in your private message.\n");
-            return;
-        }
-        // Check that all recipients exist
-        let mut unknown_logins = vec![];
-        for &login in recipients.iter() {
-            if !self.login_id.contains_key(login) {
-                unknown_logins.push(login);
-            }
-        }
-        if !unknown_logins.is_empty() {
-            user.send(format!("Unknown user(s): {}\n", unknown_logins.join(", ")));
-            return;
+            return None;
         }
         // Build message
-        let message: Arc<str> = format!("{} [{}]->[{}] {}\n",
-                                        Local::now().format("%H:%M"),
-                                        user.get_login(),
-                                        recipients.join("]+["),
-                                        message).into();
+        let text = message;
+        let message = format!("[{}]->[{}] {}\n",
+                              user.get_login(),
+                              recipients.join("]+["),
+                              message);
         // Delete duplicates and sender from recepients
         recipients.sort();
         let (recipients, _) = recipients.partition_dedup();
+        // Recipients who aren't online get mail, if they have an account
+        let offline: Vec<Box<str>> = recipients.iter()
+            .filter(|&&login| !self.login_id.contains_key(login))
+            .map(|&login| login.into())
+            .collect();
+        if !offline.is_empty() {
+            if user.is_guest() {
+                user.send(format!("Unknown user(s): {}\n", offline.join(", ")));
+                return None;
+            }
+            user.leave_mail(offline, text);
+        }
         // Send message
         for &login in recipients.iter() {
             if login != user.get_login() {
-                let other_user = self.get_user_by_login(login).expect("ChatService user is missing");
-                other_user.send_arc(message.clone());
+                let other_user = match self.get_user_by_login(login) {
+                    Some(other_user) => other_user,
+                    None => continue,
+                };
+                // The sender isn't told, so that ignoring someone stays private.
+                if !other_user.ignores(user.get_login()) {
+                    send_stamped(other_user, MessageKind::Private, &message);
+                }
+            }
+        }
+        if user.settings().notifications.echo {
+            send_stamped(user, MessageKind::Private, &message);
+        }
+        Some(message)
+    }
+
+    /// Expands `+.` into whoever last messaged the user, `++` into everyone
+    /// else in their last private conversation, and prefixes into logins.
+    fn resolve_recipients(&self, info: &UserInfo, recipients: &[&str])
+                          -> Result<Vec<Box<str>>, String> {
+        let mut resolved = Vec::with_capacity(recipients.len());
+        for &login in recipients {
+            match login {
+                "." => match &info.last_sender {
+                    Some(sender) => resolved.push(sender.clone()),
+                    None => return Err("Nobody has sent you a private message yet.\n".into()),
+                },
+                "+" if info.conversation.is_empty() =>
+                    return Err("You haven't had a private conversation yet.\n".into()),
+                "+" => resolved.extend(info.conversation.iter().cloned()),
+                login => resolved.push(self.expand_login(login)?),
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// The login `prefix` stands for: itself if someone online has it, or
+    /// else the only online login starting with it, ignoring case. Prefixes
+    /// that match nobody are returned as they are, for the caller to report.
+    fn expand_login(&self, prefix: &str) -> Result<Box<str>, String> {
+        if prefix.is_empty() {
+            return Ok(prefix.into());
+        }
+        let lowercase = prefix.to_lowercase();
+        let logins = self.login_id.keys().map(|login| &**login);
+        // "ann" is Ann even with Anna online, however it's typed.
+        if let Some(login) = logins.clone().find(|login| login.to_lowercase() == lowercase) {
+            return Ok(login.into());
+        }
+        let mut matches: Vec<&str> = logins
+            .filter(|login| login.to_lowercase().starts_with(&lowercase))
+            .collect();
+        match matches.len() {
+            0 => Ok(prefix.into()),
+            1 => Ok(matches[0].into()),
+            _ => {
+                matches.sort();
+                Err(format!("Ambiguous login {}: {}\n", prefix, matches.join(", ")))
+            },
+        }
+    }
+
+    /// Notes a private message for the `+.` and `++` shortcuts of everyone who
+    /// got it. Those who ignore the sender didn't, so they are left alone.
+    fn remember_conversation(&mut self, sender: UserId, recipients: &[Box<str>]) {
+        let sender_login: Box<str> = match self.users.get(&sender) {
+            Some(info) => info.user.get_login().into(),
+            None => return,
+        };
+        let mut everyone = recipients.to_vec();
+        everyone.push(sender_login.clone());
+        everyone.sort();
+        everyone.dedup();
+        for login in &everyone {
+            let id = match self.login_id.get(login) {
+                Some(&id) => id,
+                None => continue,
+            };
+            let info = match self.users.get_mut(&id) {
+                Some(info) => info,
+                None => continue,
+            };
+            if id != sender {
+                if info.user.ignores(&sender_login) {
+                    continue;
+                }
+                info.last_sender = Some(sender_login.clone());
+            }
+            info.conversation = everyone.iter().filter(|other| *other != login).cloned().collect();
+        }
+    }
+
+    fn handle_graveyard_message(&self, info: &UserInfo, message: &str) {
+        let &UserInfo{ref user, status, ..} = info;
+        let channel = match self.graveyard_channel(status) {
+            Some(channel) => channel,
+            None => return,
+        };
+        if message.is_empty() {
+            return;
+        }
+        let message = format!("#[{}] {}\n", user.get_login(), message);
+        let echo = user.settings().notifications.echo;
+        for other in self.users.values() {
+            let is_sender = other.user.get_id() == user.get_id();
+            if self.graveyard_channel(other.status) == Some(channel) && (!is_sender || echo) {
+                send_stamped(&other.user, MessageKind::Chat, &message);
             }
         }
-        user.send_arc(message);
     }
 
-    fn handle_command(&self, user: &User, command: &str) {
+    fn handle_team_message(&self, info: &UserInfo, message: &str) {
+        let &UserInfo{ref user, ref mute, team, ..} = info;
+        let team = match team {
+            Some(team) => team,
+            None => {
+                user.send_static("You are not in a team channel right now.\n");
+                return;
+            },
+        };
+        if !mute.private_allowed() {
+            user.send_static(mute.get_reason());
+            return;
+        }
+        if message.is_empty() {
+            return;
+        }
+        let message = format!("*[{}] {}\n", user.get_login(), message);
+        let echo = user.settings().notifications.echo;
+        for other in self.users.values() {
+            let is_sender = other.user.get_id() == user.get_id();
+            if other.team == Some(team) && (!is_sender || echo) {
+                send_stamped(&other.user, MessageKind::Chat, &message);
+            }
+        }
+    }
+
+    fn graveyard_channel(&self, status: PlayerStatus) -> Option<GraveyardChannel> {
+        match (status, self.graveyard_policy) {
+            (PlayerStatus::Alive, _) => None,
+            (_, GraveyardPolicy::Shared) => Some(GraveyardChannel::Common),
+            (PlayerStatus::Spectator, _) => Some(GraveyardChannel::Spectators),
+            (PlayerStatus::Dead, GraveyardPolicy::Separate) => Some(GraveyardChannel::Dead),
+            (PlayerStatus::Dead, GraveyardPolicy::Silent) => None,
+        }
+    }
+
+    fn handle_command(&mut self, user: &User, command: &str) {
+        let mut words = command.splitn(2, char::is_whitespace);
+        let name = words.next().unwrap_or("");
+        let args = words.next().unwrap_or("").trim();
+        if Self::ADMIN_COMMANDS.contains(&name) && !user.is_admin() {
+            user.send_static("Only admins can do that.\n");
+            return;
+        }
         let mut game_event = None;
-        match command {
+        match name {
             "help" => user.send_static(HELP_EN),
             "quit" => user.drop(),
             "list" => game_event = Some(GameEvent::CommandList(user.get_id())),
             "observe" => game_event = Some(GameEvent::CommandObserve(user.get_id())),
+            "leave" => game_event = Some(GameEvent::CommandLeave(user.get_id())),
             "play" => game_event = Some(GameEvent::CommandPlay(user.get_id())),
             "pause" => game_event = Some(GameEvent::CommandPause(user.get_id())),
             "start" => game_event = Some(GameEvent::CommandStart(user.get_id())),
+            "vote" => match self.expand_login(args) {
+                Ok(suspect) => game_event = Some(GameEvent::CommandVote(user.get_id(), suspect)),
+                Err(reason) => user.send(reason),
+            },
+            "votes" => game_event = Some(GameEvent::CommandVotes(user.get_id())),
+            "reveal" => game_event = Some(GameEvent::CommandReveal(user.get_id())),
+            "setrule" => game_event = Some(GameEvent::CommandSetRule(user.get_id(), args.into())),
+            "rules" => game_event = Some(GameEvent::CommandRules(user.get_id())),
+            "stats" => game_event = Some(GameEvent::CommandStats(user.get_id(), args.into())),
+            "top" => game_event = Some(GameEvent::CommandTop(user.get_id())),
+            "replay" => game_event = Some(GameEvent::CommandReplay(user.get_id(), args.into())),
+            "addbot" => game_event = Some(GameEvent::CommandAddBot(user.get_id(), args.into())),
+            "removebot" => game_event = Some(GameEvent::CommandRemoveBot(user.get_id(), args.into())),
+            "tournament" =>
+                game_event = Some(GameEvent::CommandTournament(user.get_id(), args.into())),
+            "predict" => game_event = Some(GameEvent::CommandPredict(user.get_id(), args.into())),
+            "odds" => game_event = Some(GameEvent::CommandOdds(user.get_id())),
+            "verbosity" => self.handle_verbosity(user, args),
+            "set" => self.handle_set(user, args),
+            "kick" => self.handle_kick(user, args),
+            "ban" => self.handle_ban(user, args),
+            "unban" => if self.bans.unban(args) {
+                user.send(format!("Lifted the ban on {}.\n", args));
+            } else {
+                user.send(format!("{} isn't banned.\n", args));
+            },
+            "bans" => {
+                let bans = self.bans.list();
+                if bans.is_empty() {
+                    user.send_static("Nobody is banned.\n");
+                } else {
+                    user.send(format!("Bans:\n  {}\n", bans.join("\n  ")));
+                }
+            },
+            "mute" => self.handle_silence(user, args, true),
+            "unmute" => self.handle_silence(user, args, false),
+            "announce" if args.is_empty() => user.send_static("Usage: !announce <text>\n"),
+            "announce" => {
+                let message: Arc<str> = format!("Announcement from {}: {}\n",
+                                                user.get_login(), args).into();
+                for info in self.users.values() {
+                    info.user.send_typed(MessageKind::PhaseChange, message.clone());
+                }
+                if let Some(chat_log) = &mut self.chat_log {
+                    chat_log.record(&message);
+                }
+            },
+            "forcestart" => game_event = Some(GameEvent::CommandForceStart(user.get_id())),
+            "abort" => game_event = Some(GameEvent::CommandAbort(user.get_id())),
+            "settings" => user.send(user.settings().describe()),
+            "history" => {
+                let count = match args {
+                    "" => Some(Self::SCROLLBACK),
+                    _ => args.parse::<usize>().ok().filter(|&count| count > 0),
+                };
+                match count {
+                    Some(count) => if !self.send_history(user, count) {
+                        user.send_static("Nobody has said anything yet.\n");
+                    },
+                    None => user.send_static("Usage: !history [number of messages]\n"),
+                }
+            },
+            "ignore" => self.handle_ignore(user, args),
+            "mail" => match args {
+                "" => user.review_mail(),
+                "clear" => user.clear_mail(),
+                _ => user.send_static("Usage: !mail [clear]\n"),
+            },
+            "unignore" if args.is_empty() => user.send_static("Usage: !unignore <login>\n"),
+            "unignore" => {
+                let login = args.to_lowercase();
+                if user.ignores(&login) {
+                    user.update_settings(|settings| {
+                        settings.ignored.retain(|ignored| **ignored != *login)
+                    });
+                    user.send(format!("You are no longer ignoring {}.\n", args));
+                } else {
+                    user.send(format!("You aren't ignoring {}.\n", args));
+                }
+            },
+            "invite" => self.handle_invite(user, args),
+            "motd" => match self.motd.get() {
+                Some(motd) => user.send_arc(motd),
+                None => user.send_static("There is no message of the day.\n"),
+            },
+            "color" => match args {
+                "on" => {
+                    user.set_color(true);
+                    user.update_settings(|settings| settings.color = Some(true));
+                    user.send_typed(MessageKind::System, "Color is on.\n".into());
+                },
+                "off" => {
+                    user.set_color(false);
+                    user.update_settings(|settings| settings.color = Some(false));
+                    user.send_static("Color is off.\n");
+                },
+                _ => user.send_static("Usage: !color on|off\n"),
+            },
             _ => user.send_static("Unknown command.\n"),
         }
         if let Some(event) = game_event {
@@ -213,16 +736,200 @@ impl ChatService {
         }
     }
 
+    fn handle_verbosity(&self, user: &User, args: &str) {
+        let mut prefs = user.settings().notifications;
+        let mut words = args.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (None, _, _) => (),
+            (Some(preset), None, _) => match Preferences::preset(preset) {
+                Some(preset) => prefs = preset,
+                None => {
+                    user.send_static("Unknown preset. Use quiet, normal or verbose.\n");
+                    return;
+                },
+            },
+            (Some(flag), Some(value), None) => {
+                let value = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => {
+                        user.send_static("Usage: !verbosity <flag> on|off\n");
+                        return;
+                    },
+                };
+                if let Err(message) = prefs.set_flag(flag, value) {
+                    user.send_static(message);
+                    return;
+                }
+            },
+            _ => {
+                user.send_static("Usage: !verbosity [quiet|normal|verbose] or !verbosity <flag> on|off\n");
+                return;
+            },
+        }
+        if prefs != user.settings().notifications {
+            user.update_settings(|settings| settings.notifications = prefs);
+        }
+        user.send(format!("Verbosity: {}\n", prefs.describe()));
+    }
+
+    fn handle_set(&self, user: &User, args: &str) {
+        let mut words = args.split_whitespace();
+        let (key, value) = match (words.next(), words.next(), words.next()) {
+            (Some(key), Some(value), None) => (key, value),
+            _ => {
+                user.send_static("Usage: !set <setting> <value>; !settings lists them.\n");
+                return;
+            },
+        };
+        let mut settings = user.settings();
+        if let Err(message) = settings.set(key, value) {
+            user.send_static(message);
+            return;
+        }
+        if let Some(color) = settings.color {
+            user.set_color(color);
+        }
+        user.update_settings(|current| *current = settings);
+        if user.is_guest() {
+            user.send(format!("Set {} to {} until you log out.\n", key, value));
+        } else {
+            user.send(format!("Set {} to {}.\n", key, value));
+        }
+    }
+
+    fn handle_ignore(&self, user: &User, login: &str) {
+        let ignored = user.settings().ignored;
+        if login.is_empty() {
+            if ignored.is_empty() {
+                user.send_static("You aren't ignoring anyone.\n");
+            } else {
+                user.send(format!("You are ignoring: {}\n", ignored.join(", ")));
+            }
+        } else if login.contains(char::is_whitespace) {
+            user.send_static("Usage: !ignore [login]\n");
+        } else if login.eq_ignore_ascii_case(user.get_login()) {
+            user.send_static("You can't ignore yourself.\n");
+        } else if user.ignores(login) {
+            user.send(format!("You are already ignoring {}.\n", login));
+        } else if ignored.len() >= Self::MAX_IGNORED {
+            user.send(format!("You can't ignore more than {} users.\n", Self::MAX_IGNORED));
+        } else {
+            user.update_settings(|settings| settings.ignored.push(login.to_lowercase().into()));
+            user.send(format!("You are now ignoring {}.\n", login));
+        }
+    }
+
+    fn handle_kick(&self, admin: &User, login: &str) {
+        match self.get_user_by_login(login) {
+            Some(user) => {
+                info!(login = admin.get_login(); "Kicked {}", login);
+                user.send(format!("You were kicked by {}.\n", admin.get_login()));
+                user.drop();
+                admin.send(format!("Kicked {}.\n", login));
+            },
+            None => admin.send(format!("{} is not online.\n", login)),
+        }
+    }
+
+    /// Bans a login or an address, and throws out everyone online the ban
+    /// covers.
+    fn handle_ban(&self, admin: &User, args: &str) {
+        let mut words = args.split_whitespace();
+        let (target, duration) = match (words.next(), words.next(), words.next()) {
+            (Some(target), None, _) => (target, None),
+            (Some(target), Some(text), None) => match bans::parse_duration(text) {
+                Some(duration) => (target, Some((duration, text))),
+                None => {
+                    admin.send_static("Give the duration like 30m, 12h or 7d.\n");
+                    return;
+                },
+            },
+            _ => {
+                admin.send_static("Usage: !ban <login or address> [duration]\n");
+                return;
+            },
+        };
+        if let Err(message) = self.bans.ban(target, duration.map(|(duration, _)| duration)) {
+            admin.send(format!("{}\n", message));
+            return;
+        }
+        info!(login = admin.get_login(); "Banned {}", target);
+        for info in self.users.values() {
+            let user = &info.user;
+            if user.get_id() == admin.get_id() {
+                continue;
+            }
+            let banned = self.bans.login_ban(user.get_login()).is_some()
+                         || user.address().map_or(false, |ip| self.bans.is_ip_banned(ip));
+            if banned {
+                user.send_static("You have been banned from this server.\n");
+                user.drop();
+            }
+        }
+        match duration {
+            Some((_, text)) => admin.send(format!("Banned {} for {}.\n", target, text)),
+            None => admin.send(format!("Banned {}.\n", target)),
+        }
+    }
+
+    fn handle_silence(&mut self, admin: &User, login: &str, silenced: bool) {
+        let info = match self.login_id.get(login) {
+            Some(id) => self.users.get_mut(id).expect("ChatService user is missing"),
+            None => {
+                admin.send(format!("{} is not online.\n", login));
+                return;
+            },
+        };
+        info.silenced = silenced;
+        if silenced {
+            info.user.send(format!("You were muted by {}.\n", admin.get_login()));
+            admin.send(format!("Muted {}.\n", login));
+        } else {
+            info.user.send(format!("You were unmuted by {}.\n", admin.get_login()));
+            admin.send(format!("Unmuted {}.\n", login));
+        }
+    }
+
+    fn handle_invite(&mut self, user: &User, login: &str) {
+        if login.is_empty() || login.contains(char::is_whitespace) {
+            user.send_static("Usage: !invite <login>\n");
+            return;
+        }
+        if login.eq_ignore_ascii_case(user.get_login()) {
+            user.send_static("You can't invite yourself.\n");
+            return;
+        }
+        let online = self.users.values()
+            .map(|info| &info.user)
+            .find(|other| other.get_login().eq_ignore_ascii_case(login));
+        match online {
+            Some(other) => {
+                other.send(format!("{} invited you to play.\n", user.get_login()));
+                user.send(format!("Invitation sent to {}.\n", other.get_login()));
+            },
+            // Kept with the accounts, which know whether the login exists.
+            None => user.invite(login.into()),
+        }
+    }
+
     fn handle_action(&self, user: &User, other: &str) {
-        let event = GameEvent::Action(user.get_id(), other.into());
+        let other = match self.expand_login(other) {
+            Ok(other) => other,
+            Err(reason) => return user.send(reason),
+        };
+        let event = GameEvent::Action(user.get_id(), other);
         self.event_handler.unbounded_send(event).expect("ChatService event_hadler failed");
     }
 
     fn handle_drop_user(&mut self, id: UserId) {
         if let Some(info) = self.users.remove(&id) {
-            self.broadcast(format!("{} Disconnected: {}\n",
-                                   Local::now().format("%H:%M"),
-                                   info.user.get_login()).into());
+            self.login_id.remove(info.user.get_login());
+            let notice = format!("Disconnected: {}\n", info.user.get_login());
+            self.broadcast_if(&notice, |prefs| prefs.joins);
+            if let Some(chat_log) = &mut self.chat_log {
+                chat_log.record(&notice);
+            }
             let event = GameEvent::Disconnected(info.user.get_id());
             self.event_handler.unbounded_send(event).expect("ChatService event_hadler failed");
         }
@@ -234,26 +941,85 @@ impl ChatService {
         }
     }
 
+    fn handle_status_request(&mut self, id: UserId, status: PlayerStatus) {
+        if let Some(info) = self.users.get_mut(&id) {
+            info.status = status;
+        }
+    }
+
+    fn handle_team_request(&mut self, id: UserId, team: Option<Team>) {
+        if let Some(info) = self.users.get_mut(&id) {
+            info.team = team;
+        }
+    }
+
+    fn handle_countdown(&self, id: UserId, message: Arc<str>) {
+        if let Some(info) = self.users.get(&id) {
+            if info.user.settings().notifications.countdown {
+                info.user.send_arc(message);
+            }
+        }
+    }
+
     fn get_user_by_login(&self, login: &str) -> Option<&User> {
         Some(&self.users.get(self.login_id.get(login)?)?.user)
     }
 
-    fn broadcast(&self, message: Arc<str>) {
+    fn broadcast_if<F: Fn(&Preferences) -> bool>(&self, message: &str, filter: F) {
         for info in self.users.values() {
-            info.user.send_arc(message.clone());
+            if filter(&info.user.settings().notifications) {
+                send_stamped(&info.user, MessageKind::System, message);
+            }
         }
     }
 }
 
 impl<'a> Message<'a> {
+    /// Emotes that a single word stands for, like `:wave`.
+    const EMOTES: &'static [(&'static str, &'static str)] = &[
+        ("wave", "waves."),
+        ("shrug", "shrugs."),
+        ("nod", "nods."),
+        ("sigh", "sighs."),
+        ("laugh", "laughs out loud."),
+        ("clap", "applauds."),
+        ("think", "is thinking..."),
+        ("gg", "says good game!"),
+    ];
+
     pub fn parse(line: &'a str) -> Self {
         match line.chars().next() {
             Some('+') => Message::parse_private(line),
+            Some('#') => Message::Graveyard(Message::remove_first_char(line).trim_start()),
+            Some('*') => Message::Team(Message::remove_first_char(line).trim_start()),
             Some('!') => Message::parse_command(line),
+            Some('/') | Some(':') => Message::parse_emote(line),
             _ => Message::Public(line),
         }
     }
 
+    /// Lines that only look like emotes, such as `:D` or a path, are said as
+    /// they are.
+    fn parse_emote(line: &'a str) -> Self {
+        let text = if line.starts_with("/me ") {
+            &line["/me ".len()..]
+        } else if line.starts_with(':') {
+            &line[1..]
+        } else {
+            return Message::Public(line);
+        };
+        let text = text.trim();
+        let word = text.split_whitespace().next().unwrap_or("");
+        if line.starts_with(':') && (word.len() < 2 || !word.chars().all(|c| c.is_alphabetic())) {
+            return Message::Public(line);
+        }
+        match Self::EMOTES.iter().find(|&&(name, _)| name == text) {
+            Some(&(_, emote)) => Message::Emote(emote),
+            None if text.is_empty() => Message::Public(line),
+            None => Message::Emote(text),
+        }
+    }
+
     fn parse_private(line: &'a str) -> Self {
         let mut recipients = vec![];
         for word in line.split_whitespace() {
@@ -320,6 +1086,19 @@ impl Player {
         self.user.get_login()
     }
 
+    pub fn is_guest(&self) -> bool {
+        self.user.is_guest()
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.user.is_admin()
+    }
+
+    /// The address the player is connected from, unless they are local.
+    pub fn address(&self) -> Option<IpAddr> {
+        self.user.address()
+    }
+
     pub fn send(&self, message: String) {
         self.user.send(message)
     }
@@ -336,6 +1115,15 @@ impl Player {
         self.user.send_static(message)
     }
 
+    pub fn send_typed(&self, kind: MessageKind, message: Arc<str>) {
+        self.user.send_typed(kind, message)
+    }
+
+    /// The language the player would rather have the game's messages in.
+    pub fn locale(&self) -> Option<Locale> {
+        self.user.settings().locale
+    }
+
     pub fn disconnect(&self) {
         self.user.drop()
     }
@@ -344,4 +1132,182 @@ impl Player {
         let request = ChatRequest::MutePlayer(self.get_id(), level);
         self.channel.unbounded_send(request).expect("Player channel failed");
     }
+
+    /// Sends a phase timer warning, unless the player has turned them off.
+    pub fn send_countdown(&self, message: Arc<str>) {
+        let request = ChatRequest::Countdown(self.get_id(), message);
+        self.channel.unbounded_send(request).expect("Player channel failed");
+    }
+
+    pub fn set_status(&self, status: PlayerStatus) {
+        let request = ChatRequest::SetStatus(self.get_id(), status);
+        self.channel.unbounded_send(request).expect("Player channel failed");
+    }
+
+    pub fn set_team(&self, team: Option<Team>) {
+        let request = ChatRequest::SetTeam(self.get_id(), team);
+        self.channel.unbounded_send(request).expect("Player channel failed");
+    }
+}
+
+#[cfg(test)]
+impl Player {
+    /// A player with no connection behind it, whose requests to the chat go
+    /// nowhere, for tests.
+    pub fn detached(id: PlayerId, login: &str) -> Self {
+        let (channel, receiver) = unbounded();
+        #[allow(unused)] {
+            runtime::spawn(receiver.for_each(|_| futures::future::ready(())));
+        }
+        Player{user: User::detached(id, login.into()), channel}
+    }
+
+    /// A player whose messages can be read back from the outbox, for tests.
+    pub fn connected(id: PlayerId, login: &str) -> (Self, Outbox) {
+        let (socket, outbox) = SocketProxy::numbered(id.0);
+        (Player::on_socket(id, login, socket), outbox)
+    }
+
+    /// Like `connected`, but from `address`.
+    pub fn remote(id: PlayerId, login: &str, address: SocketAddr) -> (Self, Outbox) {
+        let (socket, outbox) = SocketProxy::remote(id.0, address);
+        (Player::on_socket(id, login, socket), outbox)
+    }
+
+    fn on_socket(id: PlayerId, login: &str, socket: SocketProxy) -> Self {
+        let Player{channel, ..} = Player::detached(id, login);
+        Player{user: User::connected(id, login.into(), socket), channel}
+    }
+}
+
+/// Sends a chat message with the time in front of it, as the user likes it.
+fn send_stamped(user: &User, kind: MessageKind, message: &str) {
+    user.send_typed(kind, format!("{}{}", user.settings().timestamp(), message).into());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socket_service::{Outbox, SocketProxy};
+
+    /// Who is in the chat during a game: a living player, two dead ones and
+    /// two spectators.
+    const ROOM: &[(&str, PlayerStatus)] = &[
+        ("ann", PlayerStatus::Alive),
+        ("bob", PlayerStatus::Dead),
+        ("cat", PlayerStatus::Spectator),
+        ("dan", PlayerStatus::Dead),
+        ("eve", PlayerStatus::Spectator),
+    ];
+
+    fn chat_service(policy: GraveyardPolicy) -> (ChatService, UnboundedReceiver<GameEvent>) {
+        let (event_sender, events) = unbounded();
+        let service = ChatService::new(event_sender, policy, Locale::En, Motd::new(None),
+                                       BanList::load(None).unwrap(), None);
+        (service, events)
+    }
+
+    struct Room {
+        service: ChatService,
+        outboxes: Vec<Outbox>,
+        /// `GameService`'s end, kept open.
+        _events: UnboundedReceiver<GameEvent>,
+    }
+
+    impl Room {
+        fn new(policy: GraveyardPolicy) -> Self {
+            let (mut service, events) = chat_service(policy);
+            let mut outboxes = Vec::new();
+            for (number, &(login, status)) in ROOM.iter().enumerate() {
+                let (socket, outbox) = SocketProxy::numbered(number as u64 + 1);
+                let user = User::connected(UserId(number as u64 + 1), login.into(), socket);
+                let id = user.get_id();
+                service.handle_new_user(user);
+                service.handle_status_request(id, status);
+                let mute = match status {
+                    PlayerStatus::Alive => MuteLevel::AllowAll,
+                    _ => MuteLevel::DenyAll("Only the living may talk here.\n"),
+                };
+                service.handle_mute_request(id, mute);
+                outboxes.push(outbox);
+            }
+            Room{service, outboxes, _events: events}
+        }
+
+        /// Has the user say the line, and returns who saw it, besides them.
+        fn say(&mut self, login: &str, line: &str, seen: &str) -> Vec<&'static str> {
+            for outbox in &mut self.outboxes {
+                outbox.text();
+            }
+            let number = ROOM.iter().position(|&(name, _)| name == login).unwrap();
+            self.service.handle_new_message(UserId(number as u64 + 1), line.into());
+            let mut seen_by = Vec::new();
+            for (&(name, _), outbox) in ROOM.iter().zip(&mut self.outboxes) {
+                if outbox.text().contains(seen) && name != login {
+                    seen_by.push(name);
+                }
+            }
+            seen_by
+        }
+    }
+
+    #[test]
+    fn exact_login_wins_in_any_case() {
+        let (mut service, _events) = chat_service(GraveyardPolicy::Shared);
+        for (number, &login) in ["Ann", "Anna", "Bob"].iter().enumerate() {
+            service.handle_new_user(User::detached(UserId(number as u64 + 1), login.into()));
+        }
+        assert_eq!(service.expand_login("ann"), Ok("Ann".into()));
+        assert_eq!(service.expand_login("ANNA"), Ok("Anna".into()));
+        assert_eq!(service.expand_login("b"), Ok("Bob".into()));
+        assert!(service.expand_login("an").is_err());
+        assert_eq!(service.expand_login("zed"), Ok("zed".into()));
+    }
+
+    #[test]
+    fn shared_graveyard_has_the_dead_and_the_spectators() {
+        let mut room = Room::new(GraveyardPolicy::Shared);
+        assert_eq!(room.say("bob", "#boo", "#[bob] boo"), vec!["cat", "dan", "eve"]);
+        assert_eq!(room.say("cat", "#hi", "#[cat] hi"), vec!["bob", "dan", "eve"]);
+    }
+
+    #[test]
+    fn separate_graveyards_keep_the_dead_apart() {
+        let mut room = Room::new(GraveyardPolicy::Separate);
+        assert_eq!(room.say("bob", "#boo", "#[bob] boo"), vec!["dan"]);
+        assert_eq!(room.say("cat", "#hi", "#[cat] hi"), vec!["eve"]);
+    }
+
+    #[test]
+    fn silent_graveyard_is_for_the_spectators_only() {
+        let mut room = Room::new(GraveyardPolicy::Silent);
+        assert!(room.say("bob", "#boo", "boo").is_empty());
+        assert_eq!(room.say("cat", "#hi", "#[cat] hi"), vec!["eve"]);
+    }
+
+    #[test]
+    fn quiet_preset_still_lets_private_messages_through() {
+        let mut room = Room::new(GraveyardPolicy::Shared);
+        assert!(room.say("eve", "!verbosity quiet", "Verbosity: quiet").is_empty());
+
+        room.service.handle_new_user(User::detached(UserId(9), "zoe".into()));
+        room.service.handle_countdown(UserId(1), "10 seconds left.\n".into());
+        room.service.handle_countdown(UserId(5), "10 seconds left.\n".into());
+        let ann = room.outboxes[0].text();
+        assert!(ann.contains("Connected: zoe") && ann.contains("10 seconds left."));
+        let eve = room.outboxes[4].text();
+        assert!(!eve.contains("Connected: zoe") && !eve.contains("10 seconds left."));
+
+        assert_eq!(room.say("ann", "+eve hi", "[ann]->[eve] hi"), vec!["eve"]);
+    }
+
+    #[test]
+    fn living_players_say_hash_lines_in_public() {
+        for &policy in &[GraveyardPolicy::Shared, GraveyardPolicy::Separate,
+                         GraveyardPolicy::Silent] {
+            let mut room = Room::new(policy);
+            assert_eq!(room.say("ann", "#1 suspect is bob", "[ann] #1 suspect is bob"),
+                       vec!["bob", "cat", "dan", "eve"]);
+        }
+    }
 }
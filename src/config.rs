@@ -0,0 +1,391 @@
+//! The server's configuration, read from a TOML file. Every setting has a
+//! default, so the file only needs the ones that differ, and any of them can be
+//! overridden on the command line with `-o key=value`, where the value is
+//! written as in the file and nested keys are dotted, like `limits.total=100`.
+//!
+//! On SIGHUP the file is read again. The settings in `Live` and the `[log]`
+//! table take effect at once, without dropping anybody; the rest need a
+//! restart.
+
+use crate::socket_service::ConnectionLimits;
+use crate::login_service::IdleTimeouts;
+use crate::chat_service::GraveyardPolicy;
+use crate::locale::Locale;
+use crate::rules::GameRules;
+use crate::logging::{self, LogConfig, Level, Format};
+
+use std::{
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+/// Everything the server is started with.
+pub struct Config {
+    /// Addresses to listen on; IPv4 and IPv6 alike.
+    pub listen: Vec<SocketAddr>,
+    /// Port to serve the JSON protocol on, if any.
+    pub json_port: Option<u16>,
+    /// Port to accept WebSocket connections on, if any.
+    pub ws_port: Option<u16>,
+    /// Unix socket to take local connections on, if any.
+    pub unix_socket: Option<PathBuf>,
+    /// Expect a PROXY protocol header on TCP connections from the trusted
+    /// proxies, as sent by haproxy or nginx, and take the client's address
+    /// from it.
+    pub proxy_protocol: bool,
+    /// Addresses of the proxies whose PROXY headers are believed. Anyone else
+    /// is taken to be the client, so they can't pass for somebody else.
+    pub trusted_proxies: Vec<IpAddr>,
+    /// PEM certificate chain and private key to serve TLS with; plain TCP
+    /// unless both are given.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub locale: Locale,
+    pub graveyard_policy: GraveyardPolicy,
+    pub rules: GameRules,
+    /// Directory to record replays of every game into, if any.
+    pub replay_dir: Option<PathBuf>,
+    /// Public chat goes into the replays too.
+    pub replay_chat: bool,
+    /// Directory to keep a chat log in, a file per day, if any.
+    pub chat_log_dir: Option<PathBuf>,
+    /// Private messages go into the chat log too.
+    pub chat_log_private: bool,
+    /// File with the message of the day, if any.
+    pub motd: Option<PathBuf>,
+    /// SQLite database to keep accounts in. Without one, accounts only last
+    /// until the server stops.
+    pub accounts_db: Option<PathBuf>,
+    /// File of one-time invite tokens, one per line. If given, creating an
+    /// account takes a token, which is then struck off the file.
+    pub invite_tokens: Option<PathBuf>,
+    /// File to keep bans in. Without one, bans only last until the server
+    /// stops.
+    pub ban_list: Option<PathBuf>,
+    /// File to keep mail for offline users in. Without one, mail only lasts
+    /// until the server stops.
+    pub mail: Option<PathBuf>,
+    /// Address to serve the admin console on, if any. It takes
+    /// `admin_token` to get in.
+    pub admin_console: Option<SocketAddr>,
+    pub admin_token: Option<Box<str>>,
+    /// Address to serve `/healthz` and `/readyz` on, if any.
+    pub health: Option<SocketAddr>,
+    /// Directory that relative paths in the configuration are taken from;
+    /// the working directory if not set.
+    pub data_dir: Option<PathBuf>,
+    pub log: LogConfig,
+    pub live: Live,
+}
+
+/// The settings that may change while the server runs.
+#[derive(Clone)]
+pub struct Live {
+    pub limits: ConnectionLimits,
+    pub idle_timeouts: IdleTimeouts,
+    /// Users may log in as `guest`, with no password and a made-up nickname.
+    pub guests: bool,
+    /// Logins of the accounts that may use the admin commands.
+    pub admins: Vec<Box<str>>,
+}
+
+/// Handle to the live settings, which reloading replaces; clones share them.
+#[derive(Clone)]
+pub struct LiveConfig {
+    path: Option<PathBuf>,
+    overrides: Arc<[String]>,
+    live: Arc<RwLock<Live>>,
+}
+
+impl Config {
+    /// Reads the file, if there is one, and applies the overrides on top.
+    pub fn load(path: Option<&Path>, overrides: &[String]) -> Result<Config, String> {
+        let mut config = Config::default();
+        if let Some(path) = path {
+            let text = fs::read_to_string(path)
+                .map_err(|err| format!("can't read {}: {}", path.display(), err))?;
+            let table: toml::Value = text.parse()
+                .map_err(|err| format!("{}: {}", path.display(), err))?;
+            let mut settings = Vec::new();
+            flatten("", table, &mut settings);
+            for (key, value) in settings {
+                config.set(&key, &value)
+                    .map_err(|err| format!("{}: {}", path.display(), err))?;
+            }
+        }
+        for line in overrides {
+            let equals = line.find('=')
+                .ok_or_else(|| format!("-o {}: expected key=value", line))?;
+            let (key, text) = (line[..equals].trim(), line[equals + 1..].trim());
+            // Bare words are taken for strings, so that `-o locale=ru` works.
+            let value = format!("value = {}", text).parse::<toml::Value>().ok()
+                .and_then(|table| table.get("value").cloned())
+                .unwrap_or_else(|| toml::Value::String(text.to_string()));
+            config.set(key, &value).map_err(|err| format!("-o {}", err))?;
+        }
+        config.resolve_paths();
+        Ok(config)
+    }
+
+    /// Moves every listening address to `address`, or `port`, or both, as
+    /// `--address` and `--port` ask.
+    pub fn listen_on(&mut self, address: Option<IpAddr>, port: Option<u16>) {
+        for listen in &mut self.listen {
+            if let Some(address) = address {
+                listen.set_ip(address);
+            }
+            if let Some(port) = port {
+                listen.set_port(port);
+            }
+        }
+        self.listen.dedup();
+    }
+
+    fn resolve_paths(&mut self) {
+        let data_dir = match &self.data_dir {
+            Some(data_dir) => data_dir,
+            None => return,
+        };
+        for path in vec![&mut self.unix_socket, &mut self.tls_cert, &mut self.tls_key,
+                         &mut self.replay_dir, &mut self.chat_log_dir, &mut self.motd,
+                         &mut self.accounts_db, &mut self.invite_tokens, &mut self.ban_list,
+                         &mut self.mail, &mut self.log.file] {
+            if let Some(path) = path {
+                if path.is_relative() {
+                    *path = data_dir.join(&*path);
+                }
+            }
+        }
+    }
+
+    /// A handle to the live settings, which `reload` reads again from the
+    /// same file and overrides.
+    pub fn live_config(&self, path: Option<&Path>, overrides: &[String]) -> LiveConfig {
+        LiveConfig {
+            path: path.map(Path::to_path_buf),
+            overrides: overrides.into(),
+            live: Arc::new(RwLock::new(self.live.clone())),
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &toml::Value) -> Result<(), String> {
+        let expect = |what: &str| format!("{}: expected {}", key, what);
+        match key {
+            "listen" => self.listen = match value {
+                toml::Value::Array(values) => values.iter()
+                    .map(address)
+                    .collect::<Option<_>>()
+                    .ok_or_else(|| expect("addresses like \"127.0.0.1:8080\""))?,
+                value => vec![address(value).ok_or_else(|| expect("an address"))?],
+            },
+            "json_port" => self.json_port = Some(port(value).ok_or_else(|| expect("a port"))?),
+            "ws_port" => self.ws_port = Some(port(value).ok_or_else(|| expect("a port"))?),
+            "unix_socket" => self.unix_socket = Some(path(value).ok_or_else(|| expect("a path"))?),
+            "proxy_protocol" =>
+                self.proxy_protocol = value.as_bool().ok_or_else(|| expect("true or false"))?,
+            "trusted_proxies" => self.trusted_proxies = value.as_array()
+                .and_then(|values| values.iter()
+                          .map(|value| value.as_str()?.parse().ok())
+                          .collect::<Option<Vec<_>>>())
+                .ok_or_else(|| expect("a list of addresses like \"10.0.0.1\""))?,
+            "tls_cert" => self.tls_cert = Some(path(value).ok_or_else(|| expect("a path"))?),
+            "tls_key" => self.tls_key = Some(path(value).ok_or_else(|| expect("a path"))?),
+            "locale" => self.locale = value.as_str()
+                .and_then(Locale::from_code)
+                .ok_or_else(|| expect("en or ru"))?,
+            "graveyard" => self.graveyard_policy = match value.as_str() {
+                Some("shared") => GraveyardPolicy::Shared,
+                Some("separate") => GraveyardPolicy::Separate,
+                Some("silent") => GraveyardPolicy::Silent,
+                _ => return Err(expect("shared, separate or silent")),
+            },
+            "replay_dir" => self.replay_dir = Some(path(value).ok_or_else(|| expect("a path"))?),
+            "replay_chat" =>
+                self.replay_chat = value.as_bool().ok_or_else(|| expect("true or false"))?,
+            "chat_log_dir" =>
+                self.chat_log_dir = Some(path(value).ok_or_else(|| expect("a path"))?),
+            "chat_log_private" =>
+                self.chat_log_private = value.as_bool().ok_or_else(|| expect("true or false"))?,
+            "motd" => self.motd = Some(path(value).ok_or_else(|| expect("a path"))?),
+            "accounts_db" => self.accounts_db = Some(path(value).ok_or_else(|| expect("a path"))?),
+            "invite_tokens" =>
+                self.invite_tokens = Some(path(value).ok_or_else(|| expect("a path"))?),
+            "ban_list" => self.ban_list = Some(path(value).ok_or_else(|| expect("a path"))?),
+            "mail" => self.mail = Some(path(value).ok_or_else(|| expect("a path"))?),
+            "admin_console" =>
+                self.admin_console = Some(address(value).ok_or_else(|| expect("an address"))?),
+            "admin_token" =>
+                self.admin_token = Some(value.as_str().ok_or_else(|| expect("a string"))?.into()),
+            "health" => self.health = Some(address(value).ok_or_else(|| expect("an address"))?),
+            "data_dir" => self.data_dir = Some(path(value).ok_or_else(|| expect("a path"))?),
+            "log.level" => self.log.level = value.as_str()
+                .and_then(Level::from_name)
+                .ok_or_else(|| expect("error, warn, info or debug"))?,
+            "log.format" => self.log.format = match value.as_str() {
+                Some("text") => Format::Text,
+                Some("json") => Format::Json,
+                _ => return Err(expect("text or json")),
+            },
+            "log.file" => self.log.file = Some(path(value).ok_or_else(|| expect("a path"))?),
+            "log.max_size" =>
+                self.log.max_size = count(value).ok_or_else(|| expect("a number of bytes"))? as u64,
+            "log.keep" => self.log.keep = value.as_integer()
+                .filter(|&keep| keep >= 0 && keep <= 1000)
+                .ok_or_else(|| expect("a number of files"))? as u32,
+            "one_player_per_ip" => self.rules.one_player_per_ip =
+                value.as_bool().ok_or_else(|| expect("true or false"))?,
+            "guests" => self.live.guests = value.as_bool().ok_or_else(|| expect("true or false"))?,
+            "admins" => self.live.admins = value.as_array()
+                .and_then(|values| values.iter()
+                          .map(|value| value.as_str().map(Into::into))
+                          .collect::<Option<Vec<_>>>())
+                .ok_or_else(|| expect("a list of logins"))?,
+            "limits.total" =>
+                self.live.limits.total = count(value).ok_or_else(|| expect("a number"))?,
+            "limits.per_ip" =>
+                self.live.limits.per_ip = count(value).ok_or_else(|| expect("a number"))?,
+            "timeouts.login" =>
+                self.live.idle_timeouts.login = seconds(value).ok_or_else(|| expect("seconds"))?,
+            "timeouts.session" =>
+                self.live.idle_timeouts.session = seconds(value).ok_or_else(|| expect("seconds"))?,
+            _ if key.starts_with("log.modules.") => {
+                let level = value.as_str()
+                    .and_then(Level::from_name)
+                    .ok_or_else(|| expect("error, warn, info or debug"))?;
+                let module = &key["log.modules.".len()..];
+                self.log.modules.retain(|(name, _)| name != module);
+                self.log.modules.push((module.to_string(), level));
+            },
+            _ if key.starts_with("rules.") => {
+                let text = match value {
+                    toml::Value::String(text) => text.clone(),
+                    toml::Value::Boolean(true) => "on".to_string(),
+                    toml::Value::Boolean(false) => "off".to_string(),
+                    value => value.to_string(),
+                };
+                self.rules.set(&key["rules.".len()..], &text)
+                    .map_err(|err| format!("{}: {}", key, err.trim_end()))?;
+            },
+            _ => return Err(format!("{}: unknown setting", key)),
+        }
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: vec![SocketAddr::from(([127, 0, 0, 1], 8080))],
+            json_port: None,
+            ws_port: None,
+            unix_socket: None,
+            proxy_protocol: false,
+            trusted_proxies: Vec::new(),
+            tls_cert: None,
+            tls_key: None,
+            locale: Locale::En,
+            graveyard_policy: GraveyardPolicy::Shared,
+            rules: GameRules::default(),
+            replay_dir: None,
+            replay_chat: false,
+            chat_log_dir: None,
+            chat_log_private: false,
+            motd: None,
+            accounts_db: None,
+            invite_tokens: None,
+            ban_list: None,
+            mail: None,
+            admin_console: None,
+            admin_token: None,
+            health: None,
+            data_dir: None,
+            log: LogConfig::default(),
+            live: Live {
+                limits: ConnectionLimits {
+                    total: 1000,
+                    per_ip: 10,
+                },
+                idle_timeouts: IdleTimeouts {
+                    login: Duration::from_secs(2 * 60),
+                    session: Duration::from_secs(30 * 60),
+                },
+                guests: false,
+                admins: Vec::new(),
+            },
+        }
+    }
+}
+
+impl LiveConfig {
+    pub fn limits(&self) -> ConnectionLimits {
+        self.live.read().expect("LiveConfig lock poisoned").limits
+    }
+
+    pub fn idle_timeouts(&self) -> IdleTimeouts {
+        self.live.read().expect("LiveConfig lock poisoned").idle_timeouts
+    }
+
+    pub fn guests(&self) -> bool {
+        self.live.read().expect("LiveConfig lock poisoned").guests
+    }
+
+    /// Logins differing only in case are the same.
+    pub fn is_admin(&self, login: &str) -> bool {
+        self.live.read().expect("LiveConfig lock poisoned")
+            .admins.iter().any(|admin| admin.eq_ignore_ascii_case(login))
+    }
+
+    /// Reads the file again and takes its live settings. If it can't be read,
+    /// or has a mistake in it, the old settings stay.
+    pub fn reload(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        match Config::load(Some(path), &self.overrides) {
+            Ok(config) => {
+                *self.live.write().expect("LiveConfig lock poisoned") = config.live;
+                logging::configure(&config.log);
+                info!("Reloaded the configuration from {}", path.display());
+            },
+            Err(err) => error!("Failed to reload the configuration: {}", err),
+        }
+    }
+}
+
+/// Turns nested tables into dotted keys.
+fn flatten(prefix: &str, value: toml::Value, settings: &mut Vec<(String, toml::Value)>) {
+    match value {
+        toml::Value::Table(table) => for (key, value) in table {
+            let key = if prefix.is_empty() { key } else { format!("{}.{}", prefix, key) };
+            flatten(&key, value, settings);
+        },
+        value => settings.push((prefix.to_string(), value)),
+    }
+}
+
+fn address(value: &toml::Value) -> Option<SocketAddr> {
+    value.as_str()?.parse().ok()
+}
+
+fn port(value: &toml::Value) -> Option<u16> {
+    let port = value.as_integer()?;
+    if port > 0 && port <= i64::from(u16::max_value()) { Some(port as u16) } else { None }
+}
+
+fn path(value: &toml::Value) -> Option<PathBuf> {
+    value.as_str().map(PathBuf::from)
+}
+
+fn count(value: &toml::Value) -> Option<usize> {
+    let count = value.as_integer()?;
+    if count > 0 { Some(count as usize) } else { None }
+}
+
+fn seconds(value: &toml::Value) -> Option<Duration> {
+    let seconds = value.as_integer()?;
+    if seconds > 0 { Some(Duration::from_secs(seconds as u64)) } else { None }
+}
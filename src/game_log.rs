@@ -0,0 +1,122 @@
+use crate::replay::Replay;
+
+use std::fmt;
+
+/// Chronological record of everything that happened in a game, replayed to
+/// everyone as a summary when the game ends and written to the replay file,
+/// if the game is being recorded.
+pub struct GameLog {
+    events: Vec<LogEvent>,
+    replay: Option<Replay>,
+}
+
+pub enum LogEvent {
+    NightStart(u32),
+    DayStart(u32),
+    /// Only recorded in replays, like the other events that aren't part of the summary.
+    Deal { login: Box<str>, role: &'static str },
+    Join(Box<str>),
+    Leave(Box<str>),
+    /// A night pick, by ability keyword; no target means the player passed.
+    Action { actor: Box<str>, ability: &'static str, target: Option<Box<str>> },
+    Vote { voter: Box<str>, suspect: Box<str> },
+    Skip(Box<str>),
+    Lynch(Box<str>),
+    NoLynch,
+    Kill(Box<str>),
+    Abandon(Box<str>),
+    Save(Box<str>),
+    Investigation { detective: Box<str>, suspect: Box<str>, is_mafia: bool },
+    DonCheck { don: Box<str>, suspect: Box<str>, is_detective: bool },
+    Divination { seer: Box<str>, suspect: Box<str>, role: &'static str },
+    Reveal(Box<str>),
+    Lovers(Box<str>, Box<str>),
+    Heartbreak(Box<str>),
+    Shot { hunter: Box<str>, target: Box<str> },
+}
+
+impl GameLog {
+    pub fn new(replay: Option<Replay>) -> Self {
+        GameLog {
+            events: Vec::new(),
+            replay,
+        }
+    }
+
+    pub fn push(&mut self, event: LogEvent) {
+        self.record(&event);
+        self.events.push(event);
+    }
+
+    /// Writes the event to the replay without adding it to the summary.
+    pub fn record(&mut self, event: &LogEvent) {
+        if let Some(replay) = &mut self.replay {
+            replay.record(event);
+        }
+    }
+
+    pub fn record_chat(&mut self, login: &str, text: &str) {
+        if let Some(replay) = &mut self.replay {
+            replay.record_chat(login, text);
+        }
+    }
+
+    pub fn events(&self) -> &[LogEvent] {
+        &self.events
+    }
+
+    /// Who was lynched on the given day, if anyone.
+    pub fn lynched_on(&self, day: u32) -> Option<&str> {
+        let mut current = 0;
+        for event in &self.events {
+            match event {
+                LogEvent::DayStart(number) => current = *number,
+                LogEvent::Lynch(login) if current == day => return Some(login),
+                _ => (),
+            }
+        }
+        None
+    }
+
+    pub fn summary(&self) -> String {
+        let mut summary = String::from("Game summary:\n");
+        for event in &self.events {
+            summary.push_str(&format!("{}\n", event));
+        }
+        summary
+    }
+}
+
+impl fmt::Display for LogEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogEvent::NightStart(night) => write!(f, "Night {}", night),
+            LogEvent::DayStart(day) => write!(f, "Day {}", day),
+            LogEvent::Deal{login, role} => write!(f, "  {} is the {}", login, role),
+            LogEvent::Join(login) => write!(f, "  {} joined", login),
+            LogEvent::Leave(login) => write!(f, "  {} left", login),
+            LogEvent::Action{actor, ability, target: Some(target)} =>
+                write!(f, "  {} chose {} for {}", actor, target, ability),
+            LogEvent::Action{actor, target: None, ..} => write!(f, "  {} passed", actor),
+            LogEvent::Vote{voter, suspect} => write!(f, "  {} voted against {}", voter, suspect),
+            LogEvent::Skip(voter) => write!(f, "  {} voted to skip the lynch", voter),
+            LogEvent::Lynch(login) => write!(f, "  {} was lynched", login),
+            LogEvent::NoLynch => write!(f, "  nobody was lynched"),
+            LogEvent::Kill(login) => write!(f, "  {} was killed", login),
+            LogEvent::Abandon(login) => write!(f, "  {} left and was eliminated", login),
+            LogEvent::Save(login) => write!(f, "  {} was saved", login),
+            LogEvent::Investigation{detective, suspect, is_mafia} =>
+                write!(f, "  {} investigated {}: {}", detective, suspect,
+                       if *is_mafia { "mafia" } else { "not mafia" }),
+            LogEvent::DonCheck{don, suspect, is_detective} =>
+                write!(f, "  the don {} checked {}: {}", don, suspect,
+                       if *is_detective { "detective" } else { "not detective" }),
+            LogEvent::Divination{seer, suspect, role} =>
+                write!(f, "  the seer {} divined {}: {}", seer, suspect, role),
+            LogEvent::Reveal(login) => write!(f, "  {} revealed themselves as the detective", login),
+            LogEvent::Lovers(first, second) => write!(f, "  {} and {} fell in love", first, second),
+            LogEvent::Heartbreak(login) => write!(f, "  {} died of a broken heart", login),
+            LogEvent::Shot{hunter, target} => write!(f, "  the hunter {} shot {}", hunter, target),
+        }
+    }
+}
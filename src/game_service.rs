@@ -1,6 +1,19 @@
-use crate::chat_service::{GameEvent, Player, PlayerId, MuteLevel};
-use crate::locale::Locale;
-use crate::util::Timer;
+use crate::chat_service::{GameEvent, Player, PlayerId, MuteLevel, PlayerStatus, Team};
+use crate::login_service::{User, UserEvent};
+use crate::locale::{Locale, ActionError};
+use crate::roles::{self, Role, Faction, Ability, Priority, RESOLUTION_ORDER};
+use crate::rules::GameRules;
+use crate::game_log::{GameLog, LogEvent};
+use crate::narration;
+use crate::stats::{StatsStore, GameResult};
+use crate::rating::{self, INITIAL_RATING};
+use crate::odds;
+use crate::replay::{self, ReplayRecorder};
+use crate::bot::{self, Bot, View};
+use crate::tournament::Tournament;
+use crate::protocol::MessageKind;
+use crate::util::{Timer, Rng};
+use crate::admin_console::{Query, Subject};
 
 use futures::{
     prelude::*,
@@ -8,55 +21,245 @@ use futures::{
     channel::mpsc::{UnboundedSender, UnboundedReceiver, unbounded}
 };
 
-use std::collections::HashMap;
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+const DEAD_MUTE: &str = "Dead players can't talk to the living. \
+                         Use #<message> to talk in the graveyard.\n";
+const IDLE_CHECK_SECONDS: u64 = 30;
+const MAX_BOTS: usize = 10;
+const OBSERVER_MUTE: &str = "Observers can't talk to players during the game. \
+                             Use #<message> to talk in the graveyard.\n";
 
 pub struct GameService {
     event_sender: UnboundedSender<GameEvent>,
     event_receiver: UnboundedReceiver<GameEvent>,
+    query_sender: UnboundedSender<Query>,
+    query_receiver: UnboundedReceiver<Query>,
     stage: GameStage,
-    timer: Timer<u64>,
+    timer: Timer<TimerEvent>,
+    recorder: Option<ReplayRecorder>,
+    /// Replays being played back, by viewer; setting the flag stops one.
+    playbacks: HashMap<PlayerId, Arc<AtomicBool>>,
+    /// Where bots type their lines, as if they came from a connection; bots
+    /// can't be added until it is set.
+    user_handler: Option<UnboundedSender<UserEvent>>,
+    bots: HashMap<PlayerId, Bot>,
+    /// The point of the game the bots were last given a turn for.
+    bot_turn: Option<Turn>,
+    /// How many of the game log's events the bots have been told about.
+    bots_informed: usize,
 }
 
 enum GameStage {
     Lobby(LobbyStage),
     Day(DayStage),
     Night(NightStage),
+    Shot(ShotStage),
+    Paused(PausedStage),
 }
 
 struct LobbyStage {
     locale: Locale,
     players: HashMap<PlayerId, PlayerInfo>,
+    /// Bumped whenever a countdown starts or is cancelled. Every phase alarm
+    /// carries the epoch it was scheduled in and is ignored once that is out of date.
     epoch: u64,
     can_start: bool,
+    /// The running countdown was started by the `autostart` rule rather than `!start`.
+    auto_start: bool,
+    deadline: Instant,
+    rules: GameRules,
+    rng: Rng,
+    stats: Box<dyn StatsStore>,
+    recorder: Option<ReplayRecorder>,
+    tournament: Option<Tournament>,
+    /// Roles from the previous game, shown by `!list` until the next one starts.
+    last_roles: HashMap<PlayerId, Role>,
+}
+
+struct DayStage {
+    game: Game,
+    votes: HashMap<PlayerId, PlayerId>,
+    /// Players who voted not to lynch anyone today.
+    skips: HashSet<PlayerId>,
+    phase: DayPhase,
+}
+
+enum DayPhase {
+    /// Players talk things over; votes are not accepted yet.
+    Discussion,
+    Voting,
+    /// The lynched player is having their last words; nobody else may speak.
+    LastWords(PlayerId),
+}
+
+struct NightStage {
+    game: Game,
+    /// Tonight's picks by player and ability; `None` if the player passed.
+    actions: HashMap<(PlayerId, Ability), Option<PlayerId>>,
+    step: NightStep,
+}
+
+/// What the night's resolution has done so far, handed from step to step.
+struct Outcome {
+    /// Players that attacks later in the night can't kill.
+    saved: HashSet<PlayerId>,
+    news: Vec<String>,
+}
+
+enum NightStep {
+    /// Everyone acts except for the late roles.
+    Early,
+    /// The late roles act, knowing the mafia's victim, which is settled by now.
+    Late(Option<PlayerId>),
+}
+
+/// A hunter who just died picks who to take down with them before the game moves on.
+struct ShotStage {
+    game: Game,
+    hunter: PlayerId,
+    /// Where the game goes once the shot is resolved.
+    next: Phase,
+}
+
+#[derive(Clone, Copy)]
+enum Phase {
+    Day,
+    Night,
 }
 
-struct DayStage;
-struct NightStage;
+/// A frozen Day, Night or Shot stage, with the time that was left on its clock.
+struct PausedStage {
+    stage: Box<GameStage>,
+    remaining: Duration,
+    /// The player the clock stopped by itself for, until they reconnect,
+    /// rather than for `!pause`.
+    reconnecting: Option<PlayerId>,
+}
+
+/// State of a game in progress, carried over from stage to stage.
+struct Game {
+    locale: Locale,
+    players: HashMap<PlayerId, PlayerInfo>,
+    /// Continues the lobby's epoch and is bumped on every phase change and pause.
+    epoch: u64,
+    deadline: Instant,
+    day: u32,
+    rules: GameRules,
+    rng: Rng,
+    /// Seed of `rng`; revealed at the end so the game can be reproduced.
+    seed: u64,
+    stats: Box<dyn StatsStore>,
+    /// Handed back to the lobby when the game ends; this game's own replay is in `log`.
+    recorder: Option<ReplayRecorder>,
+    /// The series this game is part of, if any; also handed back to the lobby.
+    tournament: Option<Tournament>,
+    /// Who each doctor protected last night; they can't pick the same player twice in a row.
+    last_protected: HashMap<PlayerId, PlayerId>,
+    /// The detective has already used their one `!reveal`.
+    revealed: bool,
+    /// Hunters who died and haven't taken their shot yet. Shots are taken at
+    /// the next phase change, before the winner is decided.
+    pending_shots: Vec<PlayerId>,
+    /// Observers' secret guesses at who the mafia are, by observer login;
+    /// scored when the game ends.
+    predictions: HashMap<Box<str>, Vec<Box<str>>>,
+    log: GameLog,
+}
 
 struct PlayerInfo {
     player: Player,
     state: PlayerState,
+    role: Role,
+    /// Set while the player is disconnected from a game in progress: the time by
+    /// which they have to come back.
+    away: Option<Instant>,
+    /// The player this one was linked to by the cupid; they die together.
+    lover: Option<PlayerId>,
+    /// Once-per-game abilities the player has already spent.
+    used: Vec<Ability>,
+    last_active: Instant,
+}
+
+/// A point of the game at which bots may have something to do. Each bot gets
+/// one turn per point.
+#[derive(Clone, Copy, PartialEq)]
+enum Turn {
+    Discussion(u32),
+    Voting(u32),
+    LastWords(u32),
+    Night(u32),
+    LateNight(u32),
+    Shot(PlayerId),
 }
 
+#[derive(Clone, Copy)]
+enum TimerEvent {
+    /// Phase clock alarm, tagged with the epoch it was scheduled in.
+    Phase(u64),
+    /// A disconnected player's grace period may have run out.
+    AwayCheck,
+    /// The clock has been stopped long enough for a dropped player, tagged
+    /// with the epoch it was stopped in.
+    Reconnect(u64),
+    /// Periodic sweep for idle players in the lobby.
+    IdleCheck,
+    /// A bot's turn has come, once it has pretended to think for a while.
+    Bot(PlayerId, Turn),
+}
+
+enum Alarm {
+    Stale,
+    Warning(u64),
+    Expired,
+}
+
+#[derive(Clone, Copy, PartialEq)]
 enum PlayerState {
     Active,
     Observer,
+    Dead,
 }
 
 impl GameService {
-    pub fn new(locale: Locale) -> Self {
+    pub fn new(locale: Locale, rules: GameRules, stats: Box<dyn StatsStore>,
+               recorder: Option<ReplayRecorder>) -> Self {
         let (event_sender, event_receiver) = unbounded();
+        let (query_sender, query_receiver) = unbounded();
         let stage = GameStage::Lobby(LobbyStage{
             locale: locale,
             players: HashMap::new(),
             epoch: 0,
-            can_start: true
+            can_start: true,
+            auto_start: false,
+            deadline: Instant::now(),
+            rules,
+            rng: Rng::from_time(),
+            stats,
+            recorder: recorder.clone(),
+            tournament: None,
+            last_roles: HashMap::new(),
         });
         GameService {
             event_sender,
             event_receiver,
+            query_sender,
+            query_receiver,
             stage,
             timer: Timer::new(),
+            recorder,
+            playbacks: HashMap::new(),
+            user_handler: None,
+            bots: HashMap::new(),
+            bot_turn: None,
+            bots_informed: 0,
         }
     }
 
@@ -64,28 +267,3297 @@ impl GameService {
         self.event_sender.clone()
     }
 
+    pub fn make_query_handler(&self) -> UnboundedSender<Query> {
+        self.query_sender.clone()
+    }
+
+    /// Lets bots join through the chat service's user handler.
+    pub fn connect_bots(&mut self, user_handler: UnboundedSender<UserEvent>) {
+        self.user_handler = Some(user_handler);
+    }
+
     pub async fn run(mut self) {
+        self.timer.add_alarm(IDLE_CHECK_SECONDS * 1000, TimerEvent::IdleCheck);
         loop {
             select! {
                 maybe_event = self.event_receiver.next().fuse() =>
                     match maybe_event {
-                        Some(event) => self.stage = self.stage.handle_game_event(event, &mut self.timer),
+                        // Replays are watched outside of the game, so the stage never sees them.
+                        Some(GameEvent::CommandReplay(id, args)) => self.handle_replay(id, &args),
+                        Some(GameEvent::CommandAddBot(id, args)) => self.handle_add_bot(id, &args),
+                        Some(GameEvent::CommandRemoveBot(id, login)) => self.handle_remove_bot(id, &login),
+                        Some(event) => {
+                            if let GameEvent::Disconnected(id) = event {
+                                self.stop_replay(id);
+                                self.bots.remove(&id);
+                            }
+                            self.stage = self.stage.handle_game_event(event, &mut self.timer);
+                        },
                         None => panic!("GameService event_receiver terminated"),
                     },
-                _ = self.timer.next().fuse() => {
-                    self.stage = self.stage.handle_timer_event(&mut self.timer);
+                maybe_query = self.query_receiver.next().fuse() =>
+                    match maybe_query {
+                        Some(query) => self.handle_query(query),
+                        None => panic!("GameService query_receiver terminated"),
+                    },
+                maybe_timer_event = self.timer.next().fuse() =>
+                    match maybe_timer_event {
+                        Some(TimerEvent::Bot(id, turn)) => self.handle_bot(id, turn),
+                        Some(event) => self.stage = self.stage.handle_timer_event(event, &mut self.timer),
+                        None => panic!("GameService timer terminated"),
+                    },
+            }
+            self.inform_bots();
+            self.wake_bots();
+        }
+    }
+}
+
+impl GameService {
+    /// `!replay` lists recorded games, `!replay <id> [speed]` plays one back
+    /// privately and `!replay stop` stops it. Players in a game in progress
+    /// can't watch replays.
+    fn handle_replay(&mut self, id: PlayerId, args: &str) {
+        let info = match self.stage.players().get(&id) {
+            Some(info) => info,
+            None => return,
+        };
+        let player = &info.player;
+        let recorder = match &self.recorder {
+            Some(recorder) => recorder,
+            None => {
+                player.send_static("Games are not recorded on this server.\n");
+                return;
+            },
+        };
+        if info.state == PlayerState::Active && !self.stage.in_lobby() {
+            player.send_static("You can watch replays once your game is over.\n");
+            return;
+        }
+        let mut words = args.split_whitespace();
+        let (replay_id, speed) = match (words.next(), words.next(), words.next()) {
+            (None, _, _) => {
+                let ids = recorder.list();
+                if ids.is_empty() {
+                    player.send_static("No games have been recorded yet.\n");
+                } else {
+                    let recent = &ids[ids.len().saturating_sub(10)..];
+                    player.send(format!("Recent games: {}\nWatch one with !replay <id> [speed].\n",
+                                        recent.join(", ")));
+                }
+                return;
+            },
+            (Some("stop"), None, _) => {
+                if self.playbacks.contains_key(&id) {
+                    player.send_static("Replay stopped.\n");
+                } else {
+                    player.send_static("You are not watching a replay.\n");
+                }
+                self.stop_replay(id);
+                return;
+            },
+            (Some(replay_id), speed, None) => match speed.map_or(Ok(10), |speed| speed.parse::<u64>()) {
+                Ok(speed) if speed > 0 => (replay_id, speed),
+                _ => {
+                    player.send_static("Speed must be a positive number.\n");
+                    return;
                 },
+            },
+            _ => {
+                player.send_static("Usage: !replay [<id> [speed] | stop]\n");
+                return;
+            },
+        };
+        let contents = match recorder.load(replay_id) {
+            Ok(contents) => contents,
+            Err(_) => {
+                player.send_static("There is no such replay. Type !replay to see them.\n");
+                return;
+            },
+        };
+        let player = player.clone();
+        self.stop_replay(id);
+        let stop = Arc::new(AtomicBool::new(false));
+        self.playbacks.insert(id, stop.clone());
+        player.send(format!("Playing back game {} at {}x speed. Type !replay stop to stop.\n",
+                            replay_id, speed));
+        replay::play(contents, speed, player, stop);
+    }
+
+    fn stop_replay(&mut self, id: PlayerId) {
+        if let Some(stop) = self.playbacks.remove(&id) {
+            stop.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+impl GameService {
+    /// `!addbot [strategy]` connects a bot that plays in the next game.
+    fn handle_add_bot(&mut self, id: PlayerId, args: &str) {
+        let player = match self.stage.players().get(&id) {
+            Some(info) => &info.player,
+            None => return,
+        };
+        let user_handler = match &self.user_handler {
+            Some(user_handler) => user_handler,
+            None => {
+                player.send_static("Bots are not available on this server.\n");
+                return;
+            },
+        };
+        if !self.stage.in_lobby() {
+            player.send_static("Bots can only be added in the lobby.\n");
+            return;
+        }
+        if self.bots.len() >= MAX_BOTS {
+            player.send(format!("There can be at most {} bots.\n", MAX_BOTS));
+            return;
+        }
+        let name = if args.is_empty() { "follow-the-crowd" } else { args };
+        let strategy = match bot::load_strategy(name) {
+            Some(strategy) => strategy,
+            None => {
+                player.send(format!("Unknown strategy. Use one of: {}.\n",
+                                    bot::STRATEGIES.join(", ")));
+                return;
+            },
+        };
+        let number = (1..).find(|&number| !self.bots.contains_key(&bot::bot_id(number)))
+            .expect("GameService ran out of bot numbers");
+        let bot_id = bot::bot_id(number);
+        let login = format!("bot{}", number);
+        player.send(format!("Adding {}, who plays {}.\n", login, name));
+        self.bots.insert(bot_id, Bot::new(strategy));
+        let user = User::detached(bot_id, login.into());
+        for event in vec![UserEvent::NewUser(user), UserEvent::NewMessage(bot_id, "!play".into())] {
+            user_handler.unbounded_send(event).expect("GameService user_handler failed");
+        }
+    }
+
+    /// `!removebot <login>` disconnects a bot from the lobby.
+    fn handle_remove_bot(&mut self, id: PlayerId, login: &str) {
+        let player = match self.stage.players().get(&id) {
+            Some(info) => &info.player,
+            None => return,
+        };
+        if !self.stage.in_lobby() {
+            player.send_static("Bots can only be removed in the lobby.\n");
+            return;
+        }
+        let bot_id = self.stage.players().values()
+            .map(|info| &info.player)
+            .find(|other| bot::is_bot(other.get_id()) && other.get_login() == login)
+            .map(|other| other.get_id());
+        match (bot_id, &self.user_handler) {
+            (Some(bot_id), Some(user_handler)) =>
+                user_handler.unbounded_send(UserEvent::DropUser(bot_id))
+                    .expect("GameService user_handler failed"),
+            _ => player.send_static("Usage: !removebot <login> of a bot in the lobby\n"),
+        }
+    }
+
+    /// Passes the game's new events on to the bots that know about them.
+    fn inform_bots(&mut self) {
+        let game = match self.stage.game() {
+            Some(game) => game,
+            None => {
+                self.bots_informed = 0;
+                return;
+            },
+        };
+        let events = game.log.events();
+        for event in &events[self.bots_informed.min(events.len())..] {
+            for (&id, bot) in self.bots.iter_mut() {
+                if game.knows(id, event) {
+                    bot.on_event(event);
+                }
+            }
+        }
+        self.bots_informed = events.len();
+    }
+
+    /// Gives every bot a turn when the game reaches a new point. Pausing the
+    /// game forgets the point, so bots take their turn again once it resumes.
+    fn wake_bots(&mut self) {
+        let turn = self.stage.turn();
+        if turn == self.bot_turn {
+            return;
+        }
+        self.bot_turn = turn;
+        if let Some(turn) = turn {
+            for (&id, bot) in self.bots.iter_mut() {
+                self.timer.add_alarm(bot.delay_ms(), TimerEvent::Bot(id, turn));
             }
         }
     }
+
+    /// Answers the admin console.
+    fn handle_query(&self, query: Query) {
+        let answer = match query.subject {
+            Subject::Game => {
+                let mut answer = match &self.stage {
+                    GameStage::Lobby(stage) => {
+                        let starting = if stage.can_start { "" } else { ", starting" };
+                        format!("In the lobby: {} players, {} playing{}.\n",
+                                stage.players.len(), stage.active_count(), starting)
+                    },
+                    stage => stage.snapshot().unwrap_or_default(),
+                };
+                answer.push_str(&format!("{} bots; {} replays being watched.\n",
+                                         self.bots.len(), self.playbacks.len()));
+                answer
+            },
+            Subject::Player(login) => self.describe_player(&login),
+            Subject::Ping => "pong\n".to_string(),
+            _ => "GameService can't answer that.\n".to_string(),
+        };
+        let _ = query.reply.send(answer);
+    }
+
+    /// Everything the game knows about a player, roles and all.
+    fn describe_player(&self, login: &str) -> String {
+        let info = match self.stage.players().values()
+            .find(|info| info.player.get_login().eq_ignore_ascii_case(login)) {
+            Some(info) => info,
+            None => return format!("{} isn't in the game or the lobby.\n", login),
+        };
+        let state = match info.state {
+            PlayerState::Active => "playing",
+            PlayerState::Observer => "observing",
+            PlayerState::Dead => "dead",
+        };
+        let mut answer = format!("{} {}: {}", info.player.get_id(), info.player.get_login(), state);
+        if !self.stage.in_lobby() && info.state != PlayerState::Observer {
+            answer.push_str(&format!(", {}", info.role.name()));
+        }
+        if let Some(away) = info.away {
+            let now = Instant::now();
+            let left = if away > now { (away - now).as_secs() } else { 0 };
+            answer.push_str(&format!(", away with {}s to come back", left));
+        }
+        if let Some(lover) = info.lover.and_then(|lover| self.stage.players().get(&lover)) {
+            answer.push_str(&format!(", in love with {}", lover.player.get_login()));
+        }
+        if !info.used.is_empty() {
+            let used: Vec<&str> = info.used.iter().map(|ability| ability.keyword()).collect();
+            answer.push_str(&format!(", used {}", used.join(", ")));
+        }
+        if self.bots.contains_key(&info.player.get_id()) {
+            answer.push_str(", bot");
+        }
+        answer.push_str(&format!(", last active {}s ago\n", info.last_active.elapsed().as_secs()));
+        answer
+    }
+
+    /// Types the bot's lines for its turn into the chat, unless the game has moved on.
+    fn handle_bot(&mut self, id: PlayerId, turn: Turn) {
+        if self.stage.turn() != Some(turn) {
+            return;
+        }
+        let (bot, user_handler) = match (self.bots.get_mut(&id), &self.user_handler) {
+            (Some(bot), Some(user_handler)) => (bot, user_handler),
+            _ => return,
+        };
+        for line in self.stage.bot_lines(id, bot) {
+            user_handler.unbounded_send(UserEvent::NewMessage(id, line.into()))
+                .expect("GameService user_handler failed");
+        }
+    }
+}
+
+impl GameStage {
+    fn handle_game_event(mut self, event: GameEvent, timer: &mut Timer<TimerEvent>) -> Self {
+        if let Some(info) = self.players_mut().get_mut(&event.player_id()) {
+            info.last_active = Instant::now();
+        }
+        match event {
+            GameEvent::Activity(_) => return self,
+            GameEvent::Chat(id, text) => {
+                let login = self.players().get(&id).map(|info| info.player.get_login().to_string());
+                if let (Some(login), Some(game)) = (login, self.game_mut()) {
+                    game.log.record_chat(&login, &text);
+                }
+                return self;
+            },
+            GameEvent::Connected(player) => {
+                let id = player.get_id();
+                self.handle_connected(player);
+                return self.end_wait(id, timer);
+            },
+            GameEvent::Disconnected(id) => {
+                self.handle_disconnected(id, timer);
+                return self.wait_for(id, timer);
+            },
+            GameEvent::CommandList(id) => {
+                self.handle_list(id);
+                return self;
+            },
+            GameEvent::CommandPause(id) => return self.handle_pause(id, timer),
+            GameEvent::CommandStats(id, login) => {
+                self.handle_stats(id, &login);
+                return self;
+            },
+            GameEvent::CommandTop(id) => {
+                self.handle_top(id);
+                return self;
+            },
+            GameEvent::CommandRules(id) => {
+                let rules = match &self {
+                    GameStage::Lobby(stage) => stage.rules,
+                    stage => stage.game().expect("GameStage has no game").rules,
+                };
+                if let Some(info) = self.players().get(&id) {
+                    info.player.send(rules.describe());
+                }
+                return self;
+            },
+            GameEvent::CommandReveal(id) => {
+                self.handle_reveal(id);
+                return self;
+            },
+            GameEvent::CommandPredict(id, args) => {
+                match self.game_mut() {
+                    Some(game) => game.predict(id, &args),
+                    None => if let Some(info) = self.players().get(&id) {
+                        info.player.send_static("There is no game in progress.\n");
+                    },
+                }
+                return self;
+            },
+            GameEvent::CommandTournament(id, args) => {
+                self.handle_tournament(id, &args, timer);
+                return self;
+            },
+            GameEvent::CommandOdds(id) => {
+                self.handle_odds(id);
+                return self;
+            },
+            GameEvent::CommandPlay(id) | GameEvent::CommandObserve(id) if !self.in_lobby() => {
+                if let Some(info) = self.players().get(&id) {
+                    info.player.send_static("A game is in progress; wait for it to end.\n");
+                }
+                return self;
+            },
+            GameEvent::CommandForceStart(id) if !self.in_lobby() => {
+                if let Some(info) = self.players().get(&id) {
+                    info.player.send_static("A game is already in progress.\n");
+                }
+                return self;
+            },
+            GameEvent::CommandAbort(id) => return self.handle_abort(id, timer),
+            GameEvent::CommandLeave(id) if !self.in_lobby() => return self.handle_leave(id, timer),
+            GameEvent::Action(id, _) | GameEvent::CommandVote(id, _) if !self.admit(id) =>
+                return self,
+            GameEvent::CommandSetRule(id, _) if !self.in_lobby() => {
+                if let Some(info) = self.players().get(&id) {
+                    info.player.send_static("Rules can only be changed in the lobby.\n");
+                }
+                return self;
+            },
+            _ => (),
+        }
+        match self {
+            GameStage::Lobby(stage) => stage.handle_game_event(event, timer),
+            GameStage::Day(stage) => stage.handle_game_event(event, timer),
+            GameStage::Night(stage) => stage.handle_game_event(event, timer),
+            GameStage::Shot(stage) => stage.handle_game_event(event, timer),
+            GameStage::Paused(stage) => stage.handle_game_event(event),
+        }
+    }
+
+    fn handle_timer_event(self, event: TimerEvent, timer: &mut Timer<TimerEvent>) -> Self {
+        let epoch = match event {
+            TimerEvent::Phase(epoch) => epoch,
+            TimerEvent::AwayCheck => return self.check_away(timer),
+            TimerEvent::Reconnect(epoch) => return self.stop_waiting(epoch, timer),
+            // Bots' turns are handled by `GameService`.
+            TimerEvent::Bot(..) => return self,
+            TimerEvent::IdleCheck => {
+                timer.add_alarm(IDLE_CHECK_SECONDS * 1000, TimerEvent::IdleCheck);
+                return match self {
+                    GameStage::Lobby(mut stage) => {
+                        stage.check_idle();
+                        GameStage::Lobby(stage)
+                    },
+                    stage => stage,
+                };
+            },
+        };
+        match self {
+            GameStage::Lobby(stage) => stage.handle_timer_event(epoch, timer),
+            GameStage::Day(stage) => stage.handle_timer_event(epoch, timer),
+            GameStage::Night(stage) => stage.handle_timer_event(epoch, timer),
+            GameStage::Shot(stage) => stage.handle_timer_event(epoch, timer),
+            stage @ GameStage::Paused(_) => stage,
+        }
+    }
 }
 
 impl GameStage {
-    fn handle_game_event(self, event: GameEvent, timer: &mut Timer<u64>) -> Self {
-        self
+    /// The one gate for actions and votes during a game: only the living take
+    /// part, and a hunter who just died takes their shot. Everyone else is
+    /// told why not.
+    fn admit(&self, id: PlayerId) -> bool {
+        let game = match self.game() {
+            Some(game) => game,
+            None => return true,
+        };
+        let info = match game.players.get(&id) {
+            Some(info) => info,
+            None => return false,
+        };
+        let error = match info.state {
+            PlayerState::Active => return true,
+            PlayerState::Dead if self.shooter() == Some(id) => return true,
+            PlayerState::Dead => ActionError::Dead,
+            PlayerState::Observer => ActionError::Observer,
+        };
+        let locale = info.player.locale().unwrap_or(game.locale);
+        info.player.send(locale.action_error(error));
+        false
+    }
+
+    /// The hunter taking their shot, if that's where the game is at.
+    fn shooter(&self) -> Option<PlayerId> {
+        match self {
+            GameStage::Shot(stage) => Some(stage.hunter),
+            GameStage::Paused(stage) => stage.stage.shooter(),
+            _ => None,
+        }
+    }
+
+    /// Where the game is at for the bots; `None` in the lobby and while paused.
+    fn turn(&self) -> Option<Turn> {
+        match self {
+            GameStage::Day(stage) => Some(match stage.phase {
+                DayPhase::Discussion => Turn::Discussion(stage.game.day),
+                DayPhase::Voting => Turn::Voting(stage.game.day),
+                DayPhase::LastWords(_) => Turn::LastWords(stage.game.day),
+            }),
+            GameStage::Night(stage) => Some(match stage.step {
+                NightStep::Early => Turn::Night(stage.game.day),
+                NightStep::Late(_) => Turn::LateNight(stage.game.day),
+            }),
+            GameStage::Shot(stage) => Some(Turn::Shot(stage.hunter)),
+            GameStage::Lobby(_) | GameStage::Paused(_) => None,
+        }
+    }
+
+    /// What the bot says or does at this point of the game, as chat lines.
+    fn bot_lines(&self, id: PlayerId, bot: &mut Bot) -> Vec<String> {
+        let alive = match self.game().and_then(|game| game.players.get(&id)) {
+            Some(info) => info.state == PlayerState::Active,
+            None => return Vec::new(),
+        };
+        let line = match self {
+            GameStage::Day(stage) => match stage.phase {
+                DayPhase::Discussion if alive => bot.chat().map(str::to_string),
+                DayPhase::Voting if alive => {
+                    let login = |id: &PlayerId| stage.game.players[id].player.get_login();
+                    let votes = if stage.game.rules.anonymous_votes {
+                        Vec::new()
+                    } else {
+                        stage.votes.iter().map(|(voter, suspect)| (login(voter), login(suspect))).collect()
+                    };
+                    Some(bot.vote(&stage.game.bot_view(id, votes, None)))
+                },
+                DayPhase::LastWords(lynched) if lynched == id => Some(bot.last_words().to_string()),
+                _ => None,
+            },
+            GameStage::Night(stage) if alive => return stage.bot_actions(id, bot),
+            GameStage::Shot(stage) if stage.hunter == id =>
+                Some(bot.shoot(&stage.game.bot_view(id, Vec::new(), None))),
+            _ => None,
+        };
+        line.into_iter().collect()
+    }
+
+    fn in_lobby(&self) -> bool {
+        if let GameStage::Lobby(_) = self {
+            true
+        } else {
+            false
+        }
+    }
+
+    fn players(&self) -> &HashMap<PlayerId, PlayerInfo> {
+        match self {
+            GameStage::Lobby(stage) => &stage.players,
+            GameStage::Day(stage) => &stage.game.players,
+            GameStage::Night(stage) => &stage.game.players,
+            GameStage::Shot(stage) => &stage.game.players,
+            GameStage::Paused(stage) => stage.stage.players(),
+        }
+    }
+
+    fn players_mut(&mut self) -> &mut HashMap<PlayerId, PlayerInfo> {
+        match self {
+            GameStage::Lobby(stage) => &mut stage.players,
+            GameStage::Day(stage) => &mut stage.game.players,
+            GameStage::Night(stage) => &mut stage.game.players,
+            GameStage::Shot(stage) => &mut stage.game.players,
+            GameStage::Paused(stage) => stage.stage.players_mut(),
+        }
+    }
+
+    fn handle_pause(mut self, id: PlayerId, timer: &mut Timer<TimerEvent>) -> Self {
+        let login = match self.players().get(&id) {
+            Some(info) if info.state == PlayerState::Active && !self.in_lobby() =>
+                info.player.get_login().to_string(),
+            Some(info) => {
+                info.player.send_static("Only living players can pause or resume a game.\n");
+                return self;
+            },
+            None => return self,
+        };
+        if let GameStage::Paused(_) = self {
+            return self.resume(&login, timer);
+        }
+        let remaining = match self.game_mut() {
+            Some(game) => {
+                game.broadcast(format!("{} paused the game. Type !pause again to resume.\n",
+                                       login).into());
+                game.pause()
+            },
+            None => return self,
+        };
+        GameStage::Paused(PausedStage {
+            stage: Box::new(self),
+            remaining,
+            reconnecting: None,
+        })
+    }
+
+    fn resume(self, login: &str, timer: &Timer<TimerEvent>) -> Self {
+        self.restart_clock(&format!("{} resumed the game.", login), timer)
+    }
+
+    /// Starts a paused game's clock again with the time that was left on it.
+    fn restart_clock(self, news: &str, timer: &Timer<TimerEvent>) -> Self {
+        let (mut stage, remaining) = match self {
+            GameStage::Paused(paused) => (*paused.stage, paused.remaining),
+            stage => return stage,
+        };
+        if let Some(game) = stage.game_mut() {
+            game.schedule_phase(remaining, timer);
+            game.broadcast(format!("{} {} seconds remaining.\n",
+                                   news, remaining.as_secs()).into());
+        }
+        // Grace periods that ran out while the game was paused are enforced now.
+        stage.check_away(timer)
+    }
+
+    /// Stops the clock for a living player who just dropped out, if the rules
+    /// say to, so the phase doesn't end while they're reconnecting.
+    fn wait_for(mut self, id: PlayerId, timer: &Timer<TimerEvent>) -> Self {
+        if let GameStage::Paused(_) = self {
+            return self;
+        }
+        let seconds = self.game().map_or(0, |game| game.rules.reconnect_seconds);
+        let login = match self.players().get(&id) {
+            Some(info) if seconds > 0 && info.state == PlayerState::Active
+                && info.away.is_some() => info.player.get_login().to_string(),
+            _ => return self,
+        };
+        let game = self.game_mut().expect("GameStage has no game");
+        let remaining = game.pause();
+        timer.add_alarm(seconds * 1000, TimerEvent::Reconnect(game.epoch));
+        game.broadcast(format!("The clock is stopped for up to {} seconds while {} \
+                                reconnects.\n", seconds, login).into());
+        GameStage::Paused(PausedStage {
+            stage: Box::new(self),
+            remaining,
+            reconnecting: Some(id),
+        })
+    }
+
+    /// Starts the clock again once the player it stopped for is back.
+    fn end_wait(self, id: PlayerId, timer: &Timer<TimerEvent>) -> Self {
+        let back = match &self {
+            GameStage::Paused(paused) => paused.reconnecting == Some(id)
+                && self.players().get(&id).map_or(false, |info| info.away.is_none()),
+            _ => false,
+        };
+        if back {
+            self.restart_clock("The game goes on.", timer)
+        } else {
+            self
+        }
+    }
+
+    /// Starts the clock again when a dropped player has taken too long to come
+    /// back. They still have the rest of their grace period.
+    fn stop_waiting(self, epoch: u64, timer: &Timer<TimerEvent>) -> Self {
+        let login = match &self {
+            GameStage::Paused(paused) => match (paused.reconnecting, paused.stage.game()) {
+                (Some(id), Some(game)) if game.epoch == epoch =>
+                    game.players.get(&id).map(|info| info.player.get_login().to_string()),
+                _ => None,
+            },
+            _ => None,
+        };
+        match login {
+            Some(login) => self.restart_clock(&format!("{} has not reconnected.", login), timer),
+            None => self,
+        }
+    }
+
+    /// `!odds`: rough chances of each faction from the living roles. Only
+    /// observers and admins who are out of the game may see them, so they
+    /// can't sway it.
+    fn handle_odds(&self, id: PlayerId) {
+        let info = match self.players().get(&id) {
+            Some(info) => info,
+            None => return,
+        };
+        let (stage, game) = match self {
+            GameStage::Paused(paused) => (&*paused.stage, paused.stage.game()),
+            stage => (stage, stage.game()),
+        };
+        let game = match game {
+            Some(game) => game,
+            None => {
+                info.player.send_static("There is no game in progress.\n");
+                return;
+            },
+        };
+        let allowed = match info.state {
+            PlayerState::Observer => true,
+            PlayerState::Dead => info.player.is_admin(),
+            PlayerState::Active => false,
+        };
+        if !allowed {
+            info.player.send_static("Only observers can see the odds.\n");
+            return;
+        }
+        let alive: Vec<Faction> = game.players.values()
+            .filter(|info| info.state == PlayerState::Active)
+            .map(|info| info.role.faction())
+            .collect();
+        let night = if let GameStage::Night(_) = stage { true } else { false };
+        let chances: Vec<String> = odds::estimate(&alive, night, &mut Rng::from_time()).iter()
+            .map(|&(faction, percent)| format!("{} {}%", faction.name(), percent))
+            .collect();
+        info.player.send(format!("Odds over {} random games from the living roles: {}.\n",
+                                 odds::SIMULATIONS, chances.join(", ")));
+    }
+
+    /// `!tournament` shows the standings, `!tournament start <games>` starts a
+    /// series from the lobby and `!tournament stop` calls it off.
+    fn handle_tournament(&mut self, id: PlayerId, args: &str, timer: &Timer<TimerEvent>) {
+        let player = match self.players().get(&id) {
+            Some(info) => info.player.clone(),
+            None => return,
+        };
+        let mut words = args.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (None, _, _) => match self.tournament_mut() {
+                Some(tournament) => player.send(tournament.standings()),
+                None => player.send_static("There is no tournament in progress.\n"),
+            },
+            (Some("start"), Some(games), None) => match self {
+                GameStage::Lobby(stage) => stage.start_tournament(&player, games, timer),
+                _ => player.send_static("Tournaments can only be started in the lobby.\n"),
+            },
+            (Some("stop"), None, _) => {
+                let is_member = self.tournament_mut().as_ref()
+                    .map(|tournament| tournament.is_member(player.get_login()));
+                match is_member {
+                    Some(true) => *self.tournament_mut() = None,
+                    Some(false) => {
+                        player.send_static("Only the tournament's players can stop it.\n");
+                        return;
+                    },
+                    None => {
+                        player.send_static("There is no tournament in progress.\n");
+                        return;
+                    },
+                }
+                let message: Arc<str> = format!("{} stopped the tournament.\n",
+                                                player.get_login()).into();
+                for info in self.players().values() {
+                    info.player.send_arc(message.clone());
+                }
+            },
+            _ => player.send_static("Usage: !tournament [start <games> | stop]\n"),
+        }
+    }
+
+    fn tournament_mut(&mut self) -> &mut Option<Tournament> {
+        match self {
+            GameStage::Lobby(stage) => &mut stage.tournament,
+            stage => &mut stage.game_mut().expect("GameStage has no game").tournament,
+        }
+    }
+
+    fn handle_stats(&self, id: PlayerId, login: &str) {
+        let requester = match self.players().get(&id) {
+            Some(info) => &info.player,
+            None => return,
+        };
+        let login = if login.is_empty() { requester.get_login() } else { login };
+        requester.send(self.stats().get(login).unwrap_or_default().describe(login));
+    }
+
+    fn handle_top(&self, id: PlayerId) {
+        let requester = match self.players().get(&id) {
+            Some(info) => &info.player,
+            None => return,
+        };
+        let top = self.stats().top(10);
+        if top.is_empty() {
+            requester.send_static("Nobody has finished a game yet.\n");
+            return;
+        }
+        let mut message = String::from("Top players:\n");
+        for (place, (login, stats)) in top.iter().enumerate() {
+            message.push_str(&format!("  {}. {} - {} ({} games, {} wins)\n",
+                                      place + 1, login, stats.rating, stats.games, stats.wins()));
+        }
+        requester.send(message);
+    }
+
+    fn stats(&self) -> &dyn StatsStore {
+        match self {
+            GameStage::Lobby(stage) => &*stage.stats,
+            stage => &*stage.game().expect("GameStage has no game").stats,
+        }
+    }
+
+    /// Ends the game in progress with no winner and no stats.
+    fn handle_abort(self, id: PlayerId, timer: &Timer<TimerEvent>) -> Self {
+        let login = match self.players().get(&id) {
+            Some(info) if self.in_lobby() => {
+                info.player.send_static("There is no game in progress.\n");
+                return self;
+            },
+            Some(info) => info.player.get_login().to_string(),
+            None => return self,
+        };
+        match self.into_game() {
+            Some(game) => GameStage::Lobby(game.abort(&login, timer)),
+            None => unreachable!("GameStage outside the lobby has no game"),
+        }
+    }
+
+    fn into_game(self) -> Option<Game> {
+        match self {
+            GameStage::Day(stage) => Some(stage.game),
+            GameStage::Night(stage) => Some(stage.game),
+            GameStage::Shot(stage) => Some(stage.game),
+            GameStage::Paused(stage) => stage.stage.into_game(),
+            GameStage::Lobby(_) => None,
+        }
+    }
+
+    fn handle_reveal(&mut self, id: PlayerId) {
+        let reply = match self {
+            GameStage::Lobby(_) => Some("There is no game in progress.\n"),
+            GameStage::Paused(_) => Some("The game is paused.\n"),
+            _ => None,
+        };
+        if let Some(reply) = reply {
+            if let Some(info) = self.players().get(&id) {
+                info.player.send_static(reply);
+            }
+            return;
+        }
+        if let Some(game) = self.game_mut() {
+            game.reveal(id);
+        }
+    }
+
+    fn game(&self) -> Option<&Game> {
+        match self {
+            GameStage::Day(stage) => Some(&stage.game),
+            GameStage::Night(stage) => Some(&stage.game),
+            GameStage::Shot(stage) => Some(&stage.game),
+            GameStage::Paused(stage) => stage.stage.game(),
+            GameStage::Lobby(_) => None,
+        }
+    }
+
+    fn game_mut(&mut self) -> Option<&mut Game> {
+        match self {
+            GameStage::Day(stage) => Some(&mut stage.game),
+            GameStage::Night(stage) => Some(&mut stage.game),
+            GameStage::Shot(stage) => Some(&mut stage.game),
+            GameStage::Paused(stage) => stage.stage.game_mut(),
+            GameStage::Lobby(_) => None,
+        }
+    }
+
+    fn handle_connected(&mut self, player: Player) {
+        if let Some(game) = self.game_mut() {
+            game.log.record(&LogEvent::Join(player.get_login().into()));
+        }
+        if self.in_lobby() {
+            player.mute(MuteLevel::AllowAll);
+        }
+        // A login keeps its id across connections, so returning players come
+        // back with the id of their seat.
+        let returning = self.players().get(&player.get_id())
+            .filter(|info| info.away.is_some() && info.state != PlayerState::Observer)
+            .map(|info| info.player.get_id());
+        if let Some(old_id) = returning {
+            self.rejoin(old_id, player);
+            return;
+        }
+        if let Some(snapshot) = self.snapshot() {
+            player.send(snapshot);
+        }
+        let info = PlayerInfo {
+            player,
+            state: PlayerState::Observer,
+            role: Role::Civilian,
+            away: None,
+            lover: None,
+            used: Vec::new(),
+            last_active: Instant::now(),
+        };
+        self.players_mut().insert(info.player.get_id(), info);
+    }
+
+    /// Public view of the game in progress, for observers who join mid-game.
+    fn snapshot(&self) -> Option<String> {
+        let (stage, remaining, paused) = match self {
+            GameStage::Paused(paused) => (&*paused.stage, paused.remaining, true),
+            stage => (stage, stage.game()?.time_left(), false),
+        };
+        let game = stage.game()?;
+        let mut message = match stage {
+            GameStage::Day(_) => format!("A game is in progress: day {}", game.day),
+            GameStage::Shot(_) => "A game is in progress: the hunter is taking aim".to_string(),
+            _ => format!("A game is in progress: night {}", game.day + 1),
+        };
+        if paused {
+            message.push_str(&format!(", paused with {} seconds left.\n", remaining.as_secs()));
+        } else {
+            message.push_str(&format!(", {} seconds left.\n", remaining.as_secs()));
+        }
+        let mut alive: Vec<&str> = game.players.values()
+            .filter(|info| info.state == PlayerState::Active)
+            .map(|info| info.player.get_login())
+            .collect();
+        alive.sort();
+        message.push_str(&format!("Alive ({}): {}\n", alive.len(), alive.join(", ")));
+        if let GameStage::Day(day) = stage {
+            if day.is_voting() {
+                message.push_str(&day.describe_votes());
+            }
+        }
+        Some(message)
+    }
+
+    fn handle_disconnected(&mut self, id: PlayerId, timer: &Timer<TimerEvent>) {
+        let login: Option<Box<str>> = self.players().get(&id)
+            .map(|info| info.player.get_login().into());
+        if let (Some(login), Some(game)) = (login, self.game_mut()) {
+            game.log.record(&LogEvent::Leave(login));
+        }
+        let in_lobby = self.in_lobby();
+        let players = self.players_mut();
+        let is_observer = players.get(&id).map(|info| info.state == PlayerState::Observer);
+        if in_lobby || is_observer == Some(true) {
+            players.remove(&id);
+        }
+        if let GameStage::Lobby(stage) = self {
+            stage.check_countdown();
+            return;
+        }
+        let game = match self.game_mut() {
+            Some(game) => game,
+            None => return,
+        };
+        let grace = game.rules.away_grace_seconds;
+        let login = match game.players.get_mut(&id) {
+            Some(info) => {
+                info.away = Some(Instant::now() + Duration::from_secs(grace));
+                if info.state != PlayerState::Active {
+                    return;
+                }
+                info.player.get_login().to_string()
+            },
+            None => return,
+        };
+        game.broadcast(format!("{} lost connection and has {} seconds to come back.\n",
+                               login, grace).into());
+        timer.add_alarm(grace * 1000, TimerEvent::AwayCheck);
+    }
+
+    /// Eliminates living players whose grace period has run out.
+    fn check_away(mut self, timer: &Timer<TimerEvent>) -> Self {
+        if let GameStage::Paused(_) = self {
+            return self;
+        }
+        let now = Instant::now() + Duration::from_millis(500);
+        let expired: Vec<PlayerId> = match self.game() {
+            Some(game) => game.players.iter()
+                .filter(|&(_, info)| info.state == PlayerState::Active
+                                     && info.away.map_or(false, |deadline| deadline <= now))
+                .map(|(&id, _)| id)
+                .collect(),
+            None => return self,
+        };
+        for id in expired {
+            self.abandon(id, "did not come back and is out of the game");
+        }
+        self.check_winner(timer)
+    }
+
+    /// `!leave` during a game: a living player is out at once, as if they had
+    /// dropped and not come back, but stays connected and in the graveyard.
+    fn handle_leave(mut self, id: PlayerId, timer: &Timer<TimerEvent>) -> Self {
+        match self.players().get(&id) {
+            Some(info) if info.state == PlayerState::Active => (),
+            Some(info) => {
+                info.player.send_static("You are not playing in this game.\n");
+                return self;
+            },
+            None => return self,
+        }
+        self.abandon(id, "left the game");
+        // Unlike a hunter who is killed, one who leaves doesn't get to shoot.
+        let game = self.game_mut().expect("GameStage has no game");
+        game.pending_shots.retain(|&hunter| hunter != id);
+        self.check_winner(timer)
+    }
+
+    /// Takes a living player out of the game for good, saying why.
+    fn abandon(&mut self, id: PlayerId, news: &str) {
+        self.forget(id);
+        let game = self.game_mut().expect("GameStage has no game");
+        let login = game.kill(id);
+        game.log.push(LogEvent::Abandon(login.clone()));
+        game.broadcast(format!("{} {}.\n", login, news).into());
+    }
+
+    /// Ends the game if someone has won outside the usual phase changes.
+    fn check_winner(self, timer: &Timer<TimerEvent>) -> Self {
+        let winner = self.game().and_then(|game| game.winner());
+        match (self, winner) {
+            (GameStage::Day(stage), Some(winner)) =>
+                GameStage::Lobby(stage.game.end(winner, timer)),
+            (GameStage::Night(stage), Some(winner)) =>
+                GameStage::Lobby(stage.game.end(winner, timer)),
+            (stage, _) => stage,
+        }
+    }
+
+    /// Drops the player's pending votes and night actions, and any aimed at them.
+    fn forget(&mut self, id: PlayerId) {
+        match self {
+            GameStage::Day(stage) => {
+                stage.votes.retain(|&voter, &mut suspect| voter != id && suspect != id);
+                stage.skips.remove(&id);
+            },
+            GameStage::Night(stage) =>
+                stage.actions.retain(|&(actor, _), target| actor != id && *target != Some(id)),
+            GameStage::Paused(stage) => stage.stage.forget(id),
+            GameStage::Lobby(_) | GameStage::Shot(_) => (),
+        }
+    }
+
+    /// Moves a returning player's seat over to their new connection.
+    fn rejoin(&mut self, old_id: PlayerId, player: Player) {
+        let new_id = player.get_id();
+        self.rekey(old_id, new_id);
+        let game = self.game_mut().expect("GameStage has no game");
+        let mut info = game.players.remove(&old_id).expect("GameStage returning player is missing");
+        info.player = player;
+        info.away = None;
+        info.last_active = Instant::now();
+        rekey(&mut game.last_protected, old_id, new_id);
+        rekey_values(&mut game.last_protected, old_id, new_id);
+        for other in game.players.values_mut() {
+            if other.lover == Some(old_id) {
+                other.lover = Some(new_id);
+            }
+        }
+        if info.state == PlayerState::Dead {
+            info.player.set_status(PlayerStatus::Dead);
+            info.player.mute(MuteLevel::DenyAll(DEAD_MUTE));
+            info.player.send(format!("Welcome back! You were the {} and are dead now. \
+                                      You can still talk in the graveyard with #<message>.\n",
+                                     info.role.name()));
+        } else {
+            info.player.set_status(PlayerStatus::Alive);
+            info.player.send(format!("Welcome back! You are still in the game as {}.\n",
+                                     info.role.name()));
+            game.broadcast(format!("{} is back.\n", info.player.get_login()).into());
+        }
+        game.players.insert(new_id, info);
+        self.restore_chat(new_id);
+    }
+
+    fn rekey(&mut self, old_id: PlayerId, new_id: PlayerId) {
+        match self {
+            GameStage::Day(stage) => {
+                rekey(&mut stage.votes, old_id, new_id);
+                rekey_values(&mut stage.votes, old_id, new_id);
+                if stage.skips.remove(&old_id) {
+                    stage.skips.insert(new_id);
+                }
+            },
+            GameStage::Night(stage) => {
+                let swap = |id: PlayerId| if id == old_id { new_id } else { id };
+                let actions = stage.actions.drain()
+                    .map(|((actor, ability), target)| ((swap(actor), ability), target.map(swap)))
+                    .collect();
+                stage.actions = actions;
+            },
+            GameStage::Shot(stage) => {
+                if stage.hunter == old_id {
+                    stage.hunter = new_id;
+                }
+            },
+            GameStage::Paused(stage) => {
+                if stage.reconnecting == Some(old_id) {
+                    stage.reconnecting = Some(new_id);
+                }
+                stage.stage.rekey(old_id, new_id);
+            },
+            GameStage::Lobby(_) => (),
+        }
+    }
+
+    /// Sets a living player's mute level and team channel to match the current phase.
+    fn restore_chat(&self, id: PlayerId) {
+        let info = match self.players().get(&id) {
+            Some(info) if info.state == PlayerState::Active => info,
+            _ => return,
+        };
+        match self {
+            GameStage::Day(stage) => match stage.phase {
+                DayPhase::Discussion | DayPhase::Voting => info.player.mute(MuteLevel::AllowAll),
+                DayPhase::LastWords(_) =>
+                    info.player.mute(MuteLevel::DenyPublic("Let the condemned speak.\n")),
+            },
+            GameStage::Night(_) => {
+                info.player.mute(MuteLevel::DenyPublic("The town is asleep.\n"));
+                if info.role.is_mafia() {
+                    info.player.set_team(Some(Team::Mafia));
+                }
+            },
+            GameStage::Shot(_) => info.player.mute(MuteLevel::AllowAll),
+            GameStage::Paused(stage) => stage.stage.restore_chat(id),
+            GameStage::Lobby(_) => (),
+        }
+    }
+
+    fn handle_list(&self, id: PlayerId) {
+        let players = self.players();
+        let requester = match players.get(&id) {
+            Some(info) => &info.player,
+            None => return,
+        };
+        let (last_roles, active_title) = match self {
+            GameStage::Lobby(stage) => (Some(&stage.last_roles), "Players"),
+            _ => (None, "Alive"),
+        };
+        let describe = |info: &PlayerInfo| {
+            let role = last_roles.and_then(|roles| roles.get(&info.player.get_id()));
+            let mut name = match role {
+                Some(role) => format!("{} ({})", info.player.get_login(), role.name()),
+                None => info.player.get_login().to_string(),
+            };
+            if info.away.is_some() {
+                name.push_str(" (away)");
+            }
+            name
+        };
+        let mut message = String::new();
+        for &(state, title) in &[(PlayerState::Active, active_title),
+                                 (PlayerState::Dead, "Dead"),
+                                 (PlayerState::Observer, "Observers")] {
+            let mut names: Vec<String> = players.values()
+                .filter(|info| info.state == state)
+                .map(|info| describe(info))
+                .collect();
+            if names.is_empty() && state == PlayerState::Dead {
+                continue;
+            }
+            names.sort();
+            message.push_str(&format!("{} ({}): {}\n", title, names.len(), names.join(", ")));
+        }
+        requester.send(message);
+    }
+}
+
+fn rekey<V>(map: &mut HashMap<PlayerId, V>, old_id: PlayerId, new_id: PlayerId) {
+    if let Some(value) = map.remove(&old_id) {
+        map.insert(new_id, value);
+    }
+}
+
+fn rekey_values(map: &mut HashMap<PlayerId, PlayerId>, old_id: PlayerId, new_id: PlayerId) {
+    for value in map.values_mut() {
+        if *value == old_id {
+            *value = new_id;
+        }
+    }
+}
+
+fn has_won(info: &PlayerInfo, winner: Faction) -> bool {
+    match winner {
+        Faction::Lovers => info.lover.is_some(),
+        faction => info.role.faction() == faction,
+    }
+}
+
+impl PausedStage {
+    fn handle_game_event(self, event: GameEvent) -> GameStage {
+        match event {
+            GameEvent::Action(id, _) => {
+                if let Some(info) = self.stage.players().get(&id) {
+                    let locale = self.stage.game().expect("PausedStage has no game").locale;
+                    let locale = info.player.locale().unwrap_or(locale);
+                    info.player.send(locale.action_error(ActionError::Paused));
+                }
+            },
+            GameEvent::CommandVote(id, _) | GameEvent::CommandVotes(id) => {
+                if let Some(info) = self.stage.players().get(&id) {
+                    info.player.send_static("The game is paused.\n");
+                }
+            },
+            _ => (),
+        }
+        GameStage::Paused(self)
+    }
+}
+
+impl LobbyStage {
+    const COUNTDOWN_SECONDS: u64 = 10;
+    const AUTO_START_SECONDS: u64 = 30;
+    /// Time to look over a finished tournament game before the next one starts.
+    const TOURNAMENT_BREAK_SECONDS: u64 = 30;
+    const MAX_TOURNAMENT_GAMES: u32 = 20;
+
+    fn handle_game_event(mut self, event: GameEvent, timer: &mut Timer<TimerEvent>) -> GameStage {
+        match event {
+            GameEvent::CommandStart(id) => self.handle_start(id, timer),
+            GameEvent::CommandForceStart(id) => return self.force_start(id, timer),
+            GameEvent::CommandPlay(id) if !self.may_play(id) => {
+                if let Some(info) = self.players.get(&id) {
+                    info.player.send_static("A tournament is in progress; only its players can join.\n");
+                }
+            },
+            GameEvent::CommandPlay(id) if self.address_taken(id) => {
+                if let Some(info) = self.players.get(&id) {
+                    info.player.send_static("Someone from your address is already playing; \
+                                             you can observe this game.\n");
+                }
+            },
+            GameEvent::CommandPlay(id) => {
+                self.set_state(id, PlayerState::Active);
+                self.check_auto_start(timer);
+            },
+            GameEvent::CommandObserve(id) | GameEvent::CommandLeave(id) => {
+                self.set_state(id, PlayerState::Observer);
+                self.check_countdown();
+            },
+            GameEvent::CommandSetRule(id, args) => {
+                self.handle_set_rule(id, &args);
+                self.check_countdown();
+                self.check_auto_start(timer);
+            },
+            GameEvent::Action(id, _) => {
+                if let Some(info) = self.players.get(&id) {
+                    let locale = info.player.locale().unwrap_or(self.locale);
+                    info.player.send(locale.action_error(ActionError::NoGame));
+                }
+            },
+            GameEvent::CommandVote(id, _) | GameEvent::CommandVotes(id) => {
+                if let Some(info) = self.players.get(&id) {
+                    info.player.send_static("There is no vote in progress.\n");
+                }
+            },
+            _ => (),
+        }
+        GameStage::Lobby(self)
+    }
+
+    fn handle_timer_event(self, epoch: u64, timer: &mut Timer<TimerEvent>) -> GameStage {
+        if epoch != self.epoch || self.can_start {
+            return GameStage::Lobby(self);
+        }
+        let now = Instant::now();
+        if now + Duration::from_millis(500) >= self.deadline {
+            self.start(timer)
+        } else {
+            let seconds = ((self.deadline - now).as_millis() as u64 + 500) / 1000;
+            self.broadcast(format!("{}...\n", seconds).into());
+            GameStage::Lobby(self)
+        }
+    }
+
+    fn handle_start(&mut self, id: PlayerId, timer: &Timer<TimerEvent>) {
+        let player = match self.players.get(&id) {
+            Some(info) if info.state == PlayerState::Active => &info.player,
+            Some(info) => {
+                info.player.send_static("Only players can start the game. Use !play to join.\n");
+                return;
+            },
+            None => return,
+        };
+        if !self.can_start {
+            player.send_static("The game is already starting.\n");
+            return;
+        }
+        let count = self.active_count();
+        if count < self.rules.min_players {
+            player.send(format!("At least {} players are needed to start, but only {} {} \
+                                 playing. Others can join with !play.\n",
+                                self.rules.min_players, count,
+                                if count == 1 { "is" } else { "are" }));
+            return;
+        }
+        if let Some(message) = self.instant_win() {
+            player.send(message);
+            return;
+        }
+        let login = player.get_login().to_string();
+        self.begin_countdown(Self::COUNTDOWN_SECONDS, false, timer);
+        self.broadcast(format!("{} is starting the game. It begins in {} seconds.\n",
+                               login, Self::COUNTDOWN_SECONDS).into());
+    }
+
+    /// Starts the game at once, for an admin who doesn't want to wait for
+    /// the countdown.
+    fn force_start(self, id: PlayerId, timer: &mut Timer<TimerEvent>) -> GameStage {
+        let player = match self.players.get(&id) {
+            Some(info) => &info.player,
+            None => return GameStage::Lobby(self),
+        };
+        let count = self.active_count();
+        if count < self.rules.min_players {
+            player.send(format!("At least {} players are needed to start, but only {} {} \
+                                 playing.\n",
+                                self.rules.min_players, count,
+                                if count == 1 { "is" } else { "are" }));
+            return GameStage::Lobby(self);
+        }
+        if let Some(message) = self.instant_win() {
+            player.send(message);
+            return GameStage::Lobby(self);
+        }
+        let login = player.get_login().to_string();
+        self.broadcast(format!("{} started the game.\n", login).into());
+        self.start(timer)
+    }
+
+    /// Starts a tournament between the players in the lobby.
+    fn start_tournament(&mut self, player: &Player, games: &str, timer: &Timer<TimerEvent>) {
+        if self.tournament.is_some() {
+            player.send_static("A tournament is already in progress.\n");
+            return;
+        }
+        if !self.can_start {
+            player.send_static("The game is already starting.\n");
+            return;
+        }
+        if self.players.get(&player.get_id()).map(|info| info.state) != Some(PlayerState::Active) {
+            player.send_static("Only players can start a tournament. Use !play to join.\n");
+            return;
+        }
+        let games = match games.parse::<u32>() {
+            Ok(games) if games > 0 && games <= Self::MAX_TOURNAMENT_GAMES => games,
+            _ => {
+                player.send(format!("A tournament lasts from 1 to {} games.\n",
+                                    Self::MAX_TOURNAMENT_GAMES));
+                return;
+            },
+        };
+        let mut logins: Vec<&str> = self.players.values()
+            .filter(|info| info.state == PlayerState::Active)
+            .map(|info| info.player.get_login())
+            .collect();
+        if logins.len() < self.rules.min_players {
+            player.send(format!("At least {} players are needed for a tournament. \
+                                 Others can join with !play.\n", self.rules.min_players));
+            return;
+        }
+        logins.sort();
+        let message = format!("{} started a tournament of {} games between {}.\n",
+                              player.get_login(), games, logins.join(", "));
+        self.tournament = Some(Tournament::new(games, &logins));
+        self.broadcast(message.into());
+        self.continue_tournament(Self::COUNTDOWN_SECONDS, timer);
+    }
+
+    /// Counts down to the tournament's next game, if there is a tournament.
+    fn continue_tournament(&mut self, seconds: u64, timer: &Timer<TimerEvent>) {
+        let (game, games) = match &self.tournament {
+            Some(tournament) => (tournament.next_game(), tournament.games()),
+            None => return,
+        };
+        if self.active_count() < self.rules.min_players {
+            self.broadcast(format!("Game {} of {} of the tournament needs at least {} players; \
+                                    type !start once they are here.\n",
+                                   game, games, self.rules.min_players).into());
+            return;
+        }
+        self.begin_countdown(seconds, false, timer);
+        self.broadcast(format!("Game {} of {} of the tournament starts in {} seconds.\n",
+                               game, games, seconds).into());
+    }
+
+    /// Anyone can join a game, except during a tournament, which only its players can.
+    fn may_play(&self, id: PlayerId) -> bool {
+        match (&self.tournament, self.players.get(&id)) {
+            (Some(tournament), Some(info)) => tournament.is_member(info.player.get_login()),
+            _ => true,
+        }
+    }
+
+    /// Whether someone else from the player's address is playing, if only one
+    /// player per address may.
+    fn address_taken(&self, id: PlayerId) -> bool {
+        let address = match self.players.get(&id).and_then(|info| info.player.address()) {
+            Some(address) if self.rules.one_player_per_ip => address,
+            _ => return false,
+        };
+        self.players.values().any(|info| info.player.get_id() != id
+                                         && info.state == PlayerState::Active
+                                         && info.player.address() == Some(address))
+    }
+
+    /// Starts the countdown by itself once the `autostart` player count is reached.
+    fn check_auto_start(&mut self, timer: &Timer<TimerEvent>) {
+        let target = match self.rules.auto_start {
+            Some(target) => target.max(self.rules.min_players),
+            None => return,
+        };
+        let count = self.active_count();
+        if !self.can_start || count < target {
+            return;
+        }
+        self.begin_countdown(Self::AUTO_START_SECONDS, true, timer);
+        self.broadcast(format!("{} players are ready. The game starts automatically \
+                                in {} seconds.\n", count, Self::AUTO_START_SECONDS).into());
     }
 
-    fn handle_timer_event(self, timer: &mut Timer<u64>) -> Self {
-        self
+    fn begin_countdown(&mut self, seconds: u64, auto_start: bool, timer: &Timer<TimerEvent>) {
+        self.can_start = false;
+        self.auto_start = auto_start;
+        self.epoch += 1;
+        self.deadline = Instant::now() + Duration::from_secs(seconds);
+        for &remaining in &[10, 3, 2, 1] {
+            if remaining < seconds {
+                timer.add_alarm((seconds - remaining) * 1000, TimerEvent::Phase(self.epoch));
+            }
+        }
+        timer.add_alarm(seconds * 1000, TimerEvent::Phase(self.epoch));
+    }
+
+    fn handle_set_rule(&mut self, id: PlayerId, args: &str) {
+        let player = match self.players.get(&id) {
+            Some(info) => info.player.clone(),
+            None => return,
+        };
+        let mut words = args.split_whitespace();
+        let (key, value) = match (words.next(), words.next(), words.next()) {
+            (Some(key), Some(value), None) => (key, value),
+            _ => {
+                player.send_static("Usage: !setrule <rule> <value>\n");
+                return;
+            },
+        };
+        match self.rules.set(key, value) {
+            Ok(()) => self.broadcast(format!("{} set {} to {}.\n",
+                                             player.get_login(), key, value).into()),
+            Err(message) => player.send_static(message),
+        }
+    }
+
+    /// Moves players who have been idle for too long to the observers.
+    fn check_idle(&mut self) {
+        let limit = match self.rules.idle_seconds {
+            Some(seconds) => Duration::from_secs(seconds),
+            None => return,
+        };
+        let now = Instant::now();
+        let mut idle = Vec::new();
+        for info in self.players.values_mut() {
+            if info.state == PlayerState::Active && now.duration_since(info.last_active) >= limit
+                && !bot::is_bot(info.player.get_id())
+            {
+                info.state = PlayerState::Observer;
+                info.player.send(format!("You have been idle for {} minutes and are now \
+                                          observing. Type !play to join again.\n",
+                                         limit.as_secs() / 60));
+                idle.push(info.player.get_login().to_string());
+            }
+        }
+        if idle.is_empty() {
+            return;
+        }
+        idle.sort();
+        self.broadcast(format!("Idle players moved to observers: {}.\n", idle.join(", ")).into());
+        self.check_countdown();
+    }
+
+    /// Cancels a running start countdown if there are no longer enough players.
+    fn check_countdown(&mut self) {
+        let needed = match self.rules.auto_start {
+            Some(target) if self.auto_start => target.max(self.rules.min_players),
+            _ => self.rules.min_players,
+        };
+        if !self.can_start && self.active_count() < needed {
+            self.can_start = true;
+            self.epoch += 1;
+            self.broadcast("Start cancelled: not enough players.\n".into());
+        }
+    }
+
+    fn active_count(&self) -> usize {
+        self.players.values().filter(|info| info.state == PlayerState::Active).count()
+    }
+
+    /// Explains why the game can't start if the roles it would deal have
+    /// already decided it, like a mafia as large as the town.
+    fn instant_win(&self) -> Option<String> {
+        let count = self.active_count();
+        let dealt = self.rules.distribution.roles(count);
+        let winner = roles::winning_faction(dealt.iter().map(|role| role.faction()), 0)?;
+        let mut names: Vec<&str> = dealt.iter().map(|role| role.name()).collect();
+        names.sort();
+        Some(format!("With {} players the roles would be {}, and the {} would win before the \
+                      game begins. Change the rules with !setrule.\n",
+                     count, names.join(", "), winner.name()))
+    }
+
+    fn set_state(&mut self, id: PlayerId, state: PlayerState) {
+        let login = match self.players.get_mut(&id) {
+            Some(info) if info.state == state => {
+                info.player.send_static(match state {
+                    PlayerState::Active => "You are already playing.\n",
+                    _ => "You are already observing.\n",
+                });
+                return;
+            },
+            Some(info) => {
+                info.state = state;
+                info.player.get_login().to_string()
+            },
+            None => return,
+        };
+        let message = match state {
+            PlayerState::Active => format!("{} will play in the next game.\n", login),
+            _ => format!("{} is now observing.\n", login),
+        };
+        self.broadcast(message.into());
+    }
+
+    fn broadcast(&self, message: Arc<str>) {
+        for info in self.players.values() {
+            info.player.send_arc(message.clone());
+        }
+    }
+
+    fn start(mut self, timer: &mut Timer<TimerEvent>) -> GameStage {
+        self.can_start = true;
+        // The rules may have changed during the countdown.
+        if let Some(message) = self.instant_win() {
+            self.broadcast(message.into());
+            return GameStage::Lobby(self);
+        }
+        let mut ids: Vec<PlayerId> = self.players.iter()
+            .filter(|&(_, info)| info.state == PlayerState::Active)
+            .map(|(&id, _)| id)
+            .collect();
+        ids.sort_by_key(|id| self.players[id].player.get_login().to_string());
+        let seed = match self.rules.seed {
+            Some(seed) => seed,
+            None => self.rng.next_u64(),
+        };
+        let mut rng = Rng::new(seed);
+        let mut roles = self.rules.distribution.assign(ids.len(), &mut rng);
+        if let Some(tournament) = &mut self.tournament {
+            let logins: Vec<&str> = ids.iter().map(|id| self.players[id].player.get_login()).collect();
+            roles = tournament.rotate(&logins, roles, &mut rng);
+        }
+        let replay = self.recorder.as_ref().and_then(|recorder| recorder.open(seed));
+        let mut log = GameLog::new(replay);
+        self.last_roles.clear();
+        for (id, role) in ids.iter().zip(roles) {
+            let info = self.players.get_mut(id).expect("LobbyStage player is missing");
+            info.role = role;
+            log.record(&LogEvent::Deal {
+                login: info.player.get_login().into(),
+                role: role.name(),
+            });
+            info.player.set_status(PlayerStatus::Alive);
+            let locale = info.player.locale().unwrap_or(self.locale);
+            info.player.send_typed(MessageKind::Role, locale.role_assigned(role).into());
+        }
+        let mafia: Vec<&Player> = ids.iter()
+            .map(|id| &self.players[id])
+            .filter(|info| info.role.is_mafia())
+            .map(|info| &info.player)
+            .collect();
+        for player in &mafia {
+            let teammates: Vec<&str> = mafia.iter()
+                .filter(|other| other.get_id() != player.get_id())
+                .map(|other| other.get_login())
+                .collect();
+            player.send(player.locale().unwrap_or(self.locale).mafia_team(&teammates));
+        }
+        for info in self.players.values() {
+            if info.state == PlayerState::Observer {
+                info.player.mute(MuteLevel::DenyAll(OBSERVER_MUTE));
+                info.player.send_static("The game has started. Talk to other spectators \
+                                         with #<message>.\n");
+            }
+        }
+        let game = Game {
+            locale: self.locale,
+            players: self.players,
+            epoch: self.epoch,
+            deadline: Instant::now(),
+            day: 0,
+            rules: self.rules,
+            rng,
+            seed,
+            stats: self.stats,
+            recorder: self.recorder,
+            tournament: self.tournament,
+            last_protected: HashMap::new(),
+            revealed: false,
+            pending_shots: Vec::new(),
+            predictions: HashMap::new(),
+            log,
+        };
+        GameStage::Night(NightStage::new(game, timer))
+    }
+}
+
+impl DayStage {
+    fn new(mut game: Game, timer: &mut Timer<TimerEvent>) -> Self {
+        game.log.push(LogEvent::DayStart(game.day));
+        for info in game.players.values() {
+            if info.state == PlayerState::Active {
+                info.player.mute(MuteLevel::AllowAll);
+                info.player.set_team(None);
+            }
+        }
+        let mut stage = DayStage {
+            game,
+            votes: HashMap::new(),
+            skips: HashSet::new(),
+            phase: DayPhase::Discussion,
+        };
+        let seconds = stage.game.rules.discussion_seconds;
+        if seconds == 0 {
+            stage.open_voting(timer);
+        } else {
+            stage.game.schedule_phase(Duration::from_secs(seconds), timer);
+            stage.game.broadcast_typed(MessageKind::PhaseChange,
+                                       format!("Day {} begins. Discuss what happened; voting \
+                                                opens in {} seconds.\n",
+                                               stage.game.day, seconds).into());
+        }
+        stage
+    }
+
+    fn open_voting(&mut self, timer: &Timer<TimerEvent>) {
+        let seconds = self.game.rules.voting_seconds;
+        self.phase = DayPhase::Voting;
+        self.game.schedule_phase(Duration::from_secs(seconds), timer);
+        self.game.broadcast_typed(MessageKind::PhaseChange,
+                                  format!("Voting on day {} is open. Vote against a suspect \
+                                           with !vote <login> or to spare everyone with !vote \
+                                           skip, and see the tally with !votes; the day ends \
+                                           when a majority agrees, when every living player \
+                                           has voted or in {} seconds.\n",
+                                          self.game.day, seconds).into());
+    }
+
+    fn handle_game_event(self, event: GameEvent, timer: &mut Timer<TimerEvent>) -> GameStage {
+        match event {
+            GameEvent::Action(id, _) | GameEvent::CommandVote(id, _) | GameEvent::CommandVotes(id)
+                if !self.is_voting() =>
+            {
+                let error = match self.phase {
+                    DayPhase::Discussion => ActionError::Discussion,
+                    _ => ActionError::VotingOver,
+                };
+                if let Some(info) = self.game.players.get(&id) {
+                    let locale = info.player.locale().unwrap_or(self.game.locale);
+                    info.player.send(locale.action_error(error));
+                }
+                GameStage::Day(self)
+            },
+            GameEvent::Action(id, target) | GameEvent::CommandVote(id, target) =>
+                self.handle_vote(id, &target, timer),
+            GameEvent::CommandVotes(id) => {
+                if let Some(info) = self.game.players.get(&id) {
+                    info.player.send(self.describe_votes());
+                }
+                GameStage::Day(self)
+            },
+            _ => GameStage::Day(self),
+        }
+    }
+
+    fn handle_timer_event(mut self, epoch: u64, timer: &mut Timer<TimerEvent>) -> GameStage {
+        match self.game.check_alarm(epoch) {
+            Alarm::Stale => GameStage::Day(self),
+            Alarm::Warning(seconds) => {
+                self.game.broadcast_countdown(seconds);
+                GameStage::Day(self)
+            },
+            Alarm::Expired => match self.phase {
+                DayPhase::Discussion => {
+                    self.open_voting(timer);
+                    GameStage::Day(self)
+                },
+                DayPhase::Voting => self.finish(timer),
+                DayPhase::LastWords(id) => self.end_last_words(id, timer),
+            },
+        }
+    }
+
+    fn is_voting(&self) -> bool {
+        if let DayPhase::Voting = self.phase {
+            true
+        } else {
+            false
+        }
+    }
+
+    fn handle_vote(mut self, id: PlayerId, target: &str, timer: &mut Timer<TimerEvent>) -> GameStage {
+        let voter = match self.game.players.get(&id) {
+            Some(info) => info.player.clone(),
+            None => return GameStage::Day(self),
+        };
+        if target == "skip" {
+            self.handle_skip(&voter);
+            return self.check_votes(timer);
+        }
+        let suspect = match self.game.find_alive(target) {
+            Some(info) => info.player.clone(),
+            None => {
+                let locale = voter.locale().unwrap_or(self.game.locale);
+                voter.send(locale.action_error(ActionError::UnknownPlayer(target)));
+                return GameStage::Day(self);
+            },
+        };
+        let previous = self.votes.insert(id, suspect.get_id());
+        if previous == Some(suspect.get_id()) {
+            voter.send(format!("You are already voting against {}.\n", suspect.get_login()));
+            return GameStage::Day(self);
+        }
+        let was_skipping = self.skips.remove(&id);
+        self.game.log.push(LogEvent::Vote {
+            voter: voter.get_login().into(),
+            suspect: suspect.get_login().into(),
+        });
+        if self.game.rules.anonymous_votes {
+            voter.send(format!("You secretly vote against {}.\n", suspect.get_login()));
+            if previous.is_none() && !was_skipping {
+                self.broadcast_turnout();
+            }
+        } else if was_skipping {
+            self.game.broadcast_typed(MessageKind::VoteUpdate,
+                                      format!("{} changes their vote from skipping to {}.\n",
+                                              voter.get_login(), suspect.get_login()).into());
+        } else {
+            self.announce_vote(&voter, &suspect, previous);
+        }
+        self.check_votes(timer)
+    }
+
+    fn handle_skip(&mut self, voter: &Player) {
+        let id = voter.get_id();
+        if !self.skips.insert(id) {
+            voter.send_static("You are already voting to skip the lynch.\n");
+            return;
+        }
+        let previous = self.votes.remove(&id);
+        self.game.log.push(LogEvent::Skip(voter.get_login().into()));
+        if self.game.rules.anonymous_votes {
+            voter.send_static("You secretly vote to skip the lynch.\n");
+            if previous.is_none() {
+                self.broadcast_turnout();
+            }
+        } else {
+            self.game.broadcast_typed(MessageKind::VoteUpdate,
+                                      format!("{} votes to skip the lynch.\n",
+                                              voter.get_login()).into());
+        }
+    }
+
+    fn broadcast_turnout(&self) {
+        self.game.broadcast_typed(MessageKind::VoteUpdate,
+                                  format!("{} of {} living players have voted.\n",
+                                          self.votes.len() + self.skips.len(),
+                                          self.game.alive_count()).into());
+    }
+
+    /// Ends the day early once everyone has voted, or once a majority has
+    /// voted either for skipping or against the same suspect.
+    fn check_votes(self, timer: &mut Timer<TimerEvent>) -> GameStage {
+        let alive = self.game.alive_count();
+        let top = self.tally().first().map(|(_, voters)| voters.len()).unwrap_or(0);
+        if self.votes.len() + self.skips.len() >= alive
+            || self.skips.len() * 2 > alive
+            || top * 2 > alive
+        {
+            self.finish(timer)
+        } else {
+            GameStage::Day(self)
+        }
+    }
+
+    fn announce_vote(&self, voter: &Player, suspect: &Player, previous: Option<PlayerId>) {
+        let message = match previous {
+            Some(previous) => format!("{} changes their vote from {} to {}.\n",
+                                      voter.get_login(),
+                                      self.game.players[&previous].player.get_login(),
+                                      suspect.get_login()),
+            None => format!("{} votes against {}.\n", voter.get_login(), suspect.get_login()),
+        };
+        self.game.broadcast_typed(MessageKind::VoteUpdate, message.into());
+    }
+
+    /// Returns each suspect with their voters, most votes first.
+    fn tally(&self) -> Vec<(PlayerId, Vec<PlayerId>)> {
+        let mut tally: HashMap<PlayerId, Vec<PlayerId>> = HashMap::new();
+        for (&voter, &suspect) in self.votes.iter() {
+            tally.entry(suspect).or_insert_with(Vec::new).push(voter);
+        }
+        let mut tally: Vec<(PlayerId, Vec<PlayerId>)> = tally.into_iter().collect();
+        tally.sort_by_key(|(_, voters)| std::cmp::Reverse(voters.len()));
+        tally
+    }
+
+    fn describe_votes(&self) -> String {
+        if self.game.rules.anonymous_votes {
+            return format!("Votes are secret. {} of {} living players have voted.\n",
+                           self.votes.len() + self.skips.len(), self.game.alive_count());
+        }
+        let login = |id: &PlayerId| self.game.players[id].player.get_login();
+        let mut message = String::from("Votes:\n");
+        for (suspect, voters) in self.tally() {
+            let voters: Vec<&str> = voters.iter().map(login).collect();
+            message.push_str(&format!("  {} - {} ({})\n",
+                                      login(&suspect), voters.len(), voters.join(", ")));
+        }
+        if !self.skips.is_empty() {
+            let mut skippers: Vec<&str> = self.skips.iter().map(login).collect();
+            skippers.sort();
+            message.push_str(&format!("  skip - {} ({})\n", skippers.len(), skippers.join(", ")));
+        }
+        let mut silent: Vec<&str> = self.game.players.iter()
+            .filter(|&(id, info)| info.state == PlayerState::Active
+                                  && !self.votes.contains_key(id)
+                                  && !self.skips.contains(id))
+            .map(|(_, info)| info.player.get_login())
+            .collect();
+        if !silent.is_empty() {
+            silent.sort();
+            message.push_str(&format!("  not voted: {}\n", silent.join(", ")));
+        }
+        message
+    }
+
+    fn finish(mut self, timer: &mut Timer<TimerEvent>) -> GameStage {
+        let tally = self.tally();
+        let top = tally.first().map(|(_, voters)| voters.len()).unwrap_or(0);
+        let leaders: Vec<PlayerId> = tally.iter()
+            .filter(|(_, voters)| voters.len() == top)
+            .map(|&(id, _)| id)
+            .collect();
+        let alive = self.game.alive_count();
+        let skips = self.skips.len();
+        if self.game.rules.anonymous_votes && (top > 0 || skips > 0) {
+            let mut message = String::from("The votes are in:\n");
+            for (suspect, voters) in &tally {
+                message.push_str(&format!("  {} - {}\n",
+                                          self.game.players[suspect].player.get_login(),
+                                          voters.len()));
+            }
+            if skips > 0 {
+                message.push_str(&format!("  skip - {}\n", skips));
+            }
+            self.game.broadcast(message.into());
+        }
+        if top == 0 && skips == 0 {
+            self.game.broadcast("Nobody voted; nobody is lynched today.\n".into());
+        } else if skips >= top {
+            self.game.broadcast("The town votes to spare everyone; nobody is lynched today.\n".into());
+        } else if leaders.len() > 1 {
+            self.game.broadcast("The vote is tied; nobody is lynched today.\n".into());
+        } else if self.game.rules.majority && top * 2 <= alive {
+            self.game.broadcast(format!("{} needed votes from more than half of the living \
+                                         players; nobody is lynched today.\n",
+                                        self.game.players[&leaders[0]].player.get_login()).into());
+        } else {
+            return self.lynch(leaders[0], timer);
+        }
+        self.game.log.push(LogEvent::NoLynch);
+        self.game.advance(Phase::Night, timer)
+    }
+
+    fn lynch(mut self, id: PlayerId, timer: &mut Timer<TimerEvent>) -> GameStage {
+        let login = self.game.kill(id);
+        self.game.log.push(LogEvent::Lynch(login.clone()));
+        let seconds = self.game.rules.last_words_seconds;
+        let lynched = narration::lynched(self.game.locale, &mut self.game.rng, &login);
+        if seconds == 0 {
+            self.game.broadcast(format!("{}\n", lynched).into());
+            return self.end_last_words(id, timer);
+        }
+        self.game.broadcast(format!("{} They have {} seconds for their last words.\n",
+                                    lynched, seconds).into());
+        for info in self.game.players.values() {
+            if info.state == PlayerState::Active {
+                info.player.mute(MuteLevel::DenyPublic("Let the condemned speak.\n"));
+            }
+        }
+        self.game.players[&id].player.mute(MuteLevel::AllowAll);
+        self.phase = DayPhase::LastWords(id);
+        self.game.schedule_phase(Duration::from_secs(seconds), timer);
+        GameStage::Day(self)
+    }
+
+    fn end_last_words(self, id: PlayerId, timer: &mut Timer<TimerEvent>) -> GameStage {
+        self.game.players[&id].player.mute(MuteLevel::DenyAll(DEAD_MUTE));
+        self.game.advance(Phase::Night, timer)
+    }
+}
+
+impl NightStage {
+    fn new(mut game: Game, timer: &mut Timer<TimerEvent>) -> Self {
+        let seconds = game.rules.night_seconds;
+        game.schedule_phase(Duration::from_secs(seconds), timer);
+        game.log.push(LogEvent::NightStart(game.day + 1));
+        let nightfall = narration::nightfall(game.locale, &mut game.rng);
+        game.broadcast_typed(MessageKind::PhaseChange, format!("{}\n", nightfall).into());
+        for info in game.players.values() {
+            if info.state == PlayerState::Active {
+                info.player.mute(MuteLevel::DenyPublic("The town is asleep.\n"));
+                let (late, early): (Vec<Ability>, Vec<Ability>) = game.abilities(info)
+                    .into_iter()
+                    .partition(|ability| ability.is_late());
+                let mut prompt = night_prompt(&early);
+                if !late.is_empty() {
+                    prompt.push_str("You will be told the mafia's victim once they have chosen.\n");
+                }
+                if info.role.is_mafia() {
+                    info.player.set_team(Some(Team::Mafia));
+                    prompt.push_str("Talk to your team with *<message>.\n");
+                }
+                if !prompt.is_empty() {
+                    info.player.send(prompt);
+                }
+            }
+        }
+        NightStage {
+            game,
+            actions: HashMap::new(),
+            step: NightStep::Early,
+        }
+    }
+
+    fn handle_game_event(self, event: GameEvent, timer: &mut Timer<TimerEvent>) -> GameStage {
+        match event {
+            GameEvent::Action(id, target) => self.handle_action(id, &target, timer),
+            GameEvent::CommandVote(id, _) | GameEvent::CommandVotes(id) => {
+                if let Some(info) = self.game.players.get(&id) {
+                    info.player.send_static("There is no vote at night.\n");
+                }
+                GameStage::Night(self)
+            },
+            _ => GameStage::Night(self),
+        }
+    }
+
+    fn handle_timer_event(mut self, epoch: u64, timer: &mut Timer<TimerEvent>) -> GameStage {
+        match self.game.check_alarm(epoch) {
+            Alarm::Stale => GameStage::Night(self),
+            Alarm::Warning(seconds) => {
+                self.game.broadcast_countdown(seconds);
+                GameStage::Night(self)
+            },
+            Alarm::Expired => {
+                self.skip_idle();
+                self.end_step(timer)
+            },
+        }
+    }
+
+    /// Moves on once everyone has acted or time is up: from the early step to
+    /// the late one if anyone has something to do in it, and from there to dawn.
+    fn end_step(mut self, timer: &mut Timer<TimerEvent>) -> GameStage {
+        if let NightStep::Late(victim) = self.step {
+            return self.finish(victim, timer);
+        }
+        let victim = self.mafia_target();
+        self.step = NightStep::Late(victim);
+        let news = match victim {
+            Some(victim) => format!("The mafia attack {} tonight.\n",
+                                    self.game.players[&victim].player.get_login()),
+            None => "The mafia attack nobody tonight.\n".to_string(),
+        };
+        let mut waiting = false;
+        for info in self.game.players.values() {
+            let abilities = self.step_abilities(info);
+            if info.state == PlayerState::Active && !abilities.is_empty() {
+                info.player.send(format!("{}{}", news, night_prompt(&abilities)));
+                waiting = true;
+            }
+        }
+        if !waiting {
+            return self.finish(victim, timer);
+        }
+        let seconds = self.game.rules.late_seconds;
+        self.game.schedule_phase(Duration::from_secs(seconds), timer);
+        GameStage::Night(self)
+    }
+
+    /// The player's abilities that can be used in the current step.
+    fn step_abilities(&self, info: &PlayerInfo) -> Vec<Ability> {
+        let late = if let NightStep::Late(_) = self.step { true } else { false };
+        self.game.abilities(info).into_iter()
+            .filter(|ability| ability.is_late() == late)
+            .collect()
+    }
+
+    fn handle_action(mut self, id: PlayerId, target: &str, timer: &mut Timer<TimerEvent>) -> GameStage {
+        let (actor, abilities) = match self.game.players.get(&id) {
+            Some(info) => match self.check_actor(info) {
+                Ok(abilities) => (info.player.clone(), abilities),
+                Err(error) => {
+                    let locale = info.player.locale().unwrap_or(self.game.locale);
+                    info.player.send(locale.action_error(error));
+                    return GameStage::Night(self);
+                },
+            },
+            None => return GameStage::Night(self),
+        };
+        if target == "pass" {
+            self.handle_pass(&actor, &abilities);
+        } else {
+            let (ability, target) = parse_action(&abilities, target);
+            self.handle_ability(&actor, ability, target);
+        }
+        if self.all_acted() {
+            self.end_step(timer)
+        } else {
+            GameStage::Night(self)
+        }
+    }
+
+    /// Checks that the player has something to do tonight, before their target
+    /// is looked at. Returns what they can do right now. Only the living get
+    /// this far; see `GameStage::admit`.
+    fn check_actor(&self, info: &PlayerInfo) -> Result<Vec<Ability>, ActionError<'static>> {
+        let abilities = self.step_abilities(info);
+        if abilities.is_empty() {
+            Err(ActionError::NothingToDo)
+        } else {
+            Ok(abilities)
+        }
+    }
+
+    fn handle_pass(&mut self, actor: &Player, abilities: &[Ability]) {
+        for &ability in abilities {
+            self.actions.entry((actor.get_id(), ability)).or_insert(None);
+        }
+        self.game.log.record(&LogEvent::Action {
+            actor: actor.get_login().into(),
+            ability: "pass",
+            target: None,
+        });
+        actor.send_static("You do nothing tonight.\n");
+    }
+
+    fn handle_ability(&mut self, actor: &Player, ability: Ability, target: &str) {
+        if ability == Ability::Matchmake {
+            self.handle_matchmaking(actor, target);
+            return;
+        }
+        let target = match self.game.find_alive(target) {
+            Some(info) => info.player.clone(),
+            None => {
+                let locale = actor.locale().unwrap_or(self.game.locale);
+                actor.send(locale.action_error(ActionError::UnknownPlayer(target)));
+                return;
+            },
+        };
+        if actor.get_id() == target.get_id() && !ability.allows_self() {
+            actor.send_static("You can't choose yourself for that.\n");
+            return;
+        }
+        if ability == Ability::Protect
+            && self.game.last_protected.get(&actor.get_id()) == Some(&target.get_id())
+        {
+            actor.send(format!("You protected {} last night; choose someone else.\n",
+                               target.get_login()));
+            return;
+        }
+        self.actions.insert((actor.get_id(), ability), Some(target.get_id()));
+        self.game.log.record(&LogEvent::Action {
+            actor: actor.get_login().into(),
+            ability: ability.keyword(),
+            target: Some(target.get_login().into()),
+        });
+        let login = target.get_login();
+        match ability {
+            Ability::Kill => {
+                self.game.send_to_mafia(format!("{} wants to kill {}.\n{}",
+                                                actor.get_login(), login,
+                                                self.describe_proposals()).into());
+            },
+            Ability::FindDetective =>
+                actor.send(format!("You will check {} tonight and learn the result at dawn.\n",
+                                   login)),
+            Ability::Investigate =>
+                actor.send(format!("You will investigate {} tonight and learn the result \
+                                    at dawn.\n", login)),
+            Ability::Divine =>
+                actor.send(format!("You will learn {}'s role at dawn.\n", login)),
+            Ability::Protect => actor.send(format!("You will protect {} tonight.\n", login)),
+            Ability::Heal => actor.send(format!("You will heal {} tonight.\n", login)),
+            Ability::Poison => actor.send(format!("You will poison {} tonight.\n", login)),
+            Ability::SoloKill => actor.send(format!("You will kill {} tonight.\n", login)),
+            Ability::Matchmake => (),
+        }
+    }
+
+    fn handle_matchmaking(&mut self, cupid: &Player, targets: &str) {
+        let mut logins = targets.split_whitespace();
+        let (first, second) = match (logins.next(), logins.next(), logins.next()) {
+            (Some(first), Some(second), None) if first != second => (first, second),
+            _ => {
+                cupid.send_static("Choose two different players with !!<login> <login>.\n");
+                return;
+            },
+        };
+        let mut lovers = Vec::new();
+        for &login in &[first, second] {
+            match self.game.find_alive(login) {
+                Some(info) => lovers.push(info.player.clone()),
+                None => {
+                    let locale = cupid.locale().unwrap_or(self.game.locale);
+                    cupid.send(locale.action_error(ActionError::UnknownPlayer(login)));
+                    return;
+                },
+            }
+        }
+        for (lover, other) in lovers.iter().zip(lovers.iter().rev()) {
+            self.game.players.get_mut(&lover.get_id())
+                .expect("GameService lover is missing")
+                .lover = Some(other.get_id());
+            lover.send(format!("You have fallen in love with {}. If one of you dies, so does \
+                                the other; if you are the last two standing, you win together.\n",
+                               other.get_login()));
+        }
+        self.actions.insert((cupid.get_id(), Ability::Matchmake), Some(lovers[0].get_id()));
+        self.game.log.push(LogEvent::Lovers(first.into(), second.into()));
+        cupid.send(format!("{} and {} are now lovers.\n", first, second));
+    }
+
+    /// A bot's picks for the current step of the night.
+    fn bot_actions(&self, id: PlayerId, bot: &mut Bot) -> Vec<String> {
+        let info = &self.game.players[&id];
+        let abilities: Vec<(Ability, Vec<&str>)> = self.step_abilities(info).into_iter()
+            .map(|ability| (ability, self.allowed_targets(id, ability)))
+            .collect();
+        if abilities.is_empty() {
+            return Vec::new();
+        }
+        let login = |id: &PlayerId| self.game.players[id].player.get_login();
+        let picks = if info.role.is_mafia() {
+            self.targets(Ability::Kill).map(|(killer, victim)| (login(&killer), login(&victim))).collect()
+        } else {
+            Vec::new()
+        };
+        let victim = match self.step {
+            NightStep::Late(victim) => victim.map(|victim| login(&victim)),
+            NightStep::Early => None,
+        };
+        bot.act(&self.game.bot_view(id, picks, victim), &abilities)
+    }
+
+    /// Who the player may pick with the ability tonight.
+    fn allowed_targets(&self, id: PlayerId, ability: Ability) -> Vec<&str> {
+        let last_protected = self.game.last_protected.get(&id);
+        self.game.players.iter()
+            .filter(|&(target, info)| info.state == PlayerState::Active
+                                      && (*target != id || ability.allows_self())
+                                      && (ability != Ability::Protect || last_protected != Some(target)))
+            .map(|(_, info)| info.player.get_login())
+            .collect()
+    }
+
+    /// Tonight's picks for one ability as (actor, target) pairs, leaving out passes.
+    fn targets<'a>(&'a self, ability: Ability) -> impl Iterator<Item = (PlayerId, PlayerId)> + 'a {
+        self.actions.iter().filter_map(move |(&(actor, used), &target)| {
+            if used == ability {
+                target.map(|target| (actor, target))
+            } else {
+                None
+            }
+        })
+    }
+
+    fn describe_proposals(&self) -> String {
+        let login = |id: &PlayerId| self.game.players[id].player.get_login();
+        let mut proposals: HashMap<PlayerId, Vec<&str>> = HashMap::new();
+        for (killer, victim) in self.targets(Ability::Kill) {
+            proposals.entry(victim).or_insert_with(Vec::new).push(login(&killer));
+        }
+        let mut lines: Vec<String> = proposals.into_iter()
+            .map(|(victim, mut killers)| {
+                killers.sort();
+                format!("  {} - {}\n", login(&victim), killers.join(", "))
+            })
+            .collect();
+        lines.sort();
+        let rule = if self.game.rules.unanimous_kill {
+            "every mafioso must agree"
+        } else {
+            "most proposals win, the don breaks ties"
+        };
+        format!("Proposals ({}):\n{}", rule, lines.concat())
+    }
+
+    /// The mafia's victim for tonight under the `killvote` rule, if they agreed on one.
+    fn mafia_target(&self) -> Option<PlayerId> {
+        if self.game.rules.unanimous_kill {
+            let pick = |id: &PlayerId| self.actions.get(&(*id, Ability::Kill)).and_then(|&target| target);
+            let mut mafia = self.game.players.iter()
+                .filter(|&(_, info)| info.state == PlayerState::Active && info.role.is_mafia());
+            let first = pick(mafia.next()?.0)?;
+            return if mafia.all(|(id, _)| pick(id) == Some(first)) {
+                Some(first)
+            } else {
+                None
+            };
+        }
+        let mut tally: HashMap<PlayerId, usize> = HashMap::new();
+        for (_, victim) in self.targets(Ability::Kill) {
+            *tally.entry(victim).or_insert(0) += 1;
+        }
+        let top = tally.values().cloned().max().unwrap_or(0);
+        let leaders: Vec<PlayerId> = tally.iter()
+            .filter(|&(_, &count)| count == top)
+            .map(|(&id, _)| id)
+            .collect();
+        if leaders.len() == 1 {
+            return Some(leaders[0]);
+        }
+        let players = &self.game.players;
+        self.targets(Ability::Kill)
+            .find(|(killer, _)| players.get(killer).map_or(false, |info| info.role == Role::Don))
+            .map(|(_, victim)| victim)
+            .filter(|pick| leaders.contains(pick))
+    }
+
+    /// Whether every living player has used or passed on every ability they have in this step.
+    fn all_acted(&self) -> bool {
+        self.game.players.iter()
+            .filter(|&(_, info)| info.state == PlayerState::Active)
+            .all(|(&id, info)| self.has_acted(id, info))
+    }
+
+    fn has_acted(&self, id: PlayerId, info: &PlayerInfo) -> bool {
+        self.step_abilities(info).into_iter()
+            .all(|ability| self.actions.contains_key(&(id, ability)))
+    }
+
+    /// Called when a step of the night runs out: tells everyone who didn't act that
+    /// their turn was skipped and, if the rules say so, picks the mafia's victim at random.
+    fn skip_idle(&mut self) {
+        for (&id, info) in &self.game.players {
+            if info.state == PlayerState::Active && !self.has_acted(id, info) {
+                info.player.send_static("Time is up; your night action was skipped.\n");
+            }
+        }
+        if let NightStep::Late(_) = self.step {
+            return;
+        }
+        if !self.game.rules.random_mafia_kill || self.targets(Ability::Kill).next().is_some() {
+            return;
+        }
+        let mut mafia: Vec<&PlayerInfo> = Vec::new();
+        let mut victims: Vec<&PlayerInfo> = Vec::new();
+        for info in self.game.players.values() {
+            if info.state == PlayerState::Active {
+                if info.role.is_mafia() {
+                    mafia.push(info);
+                } else {
+                    victims.push(info);
+                }
+            }
+        }
+        if mafia.is_empty() || victims.is_empty() {
+            return;
+        }
+        victims.sort_by_key(|info| info.player.get_login());
+        let victim = victims[self.game.rng.below(victims.len())].player.clone();
+        for killer in mafia {
+            self.actions.insert((killer.player.get_id(), Ability::Kill), Some(victim.get_id()));
+        }
+        self.game.send_to_mafia(format!("The mafia didn't choose in time; {} was picked at random.\n",
+                                        victim.get_login()).into());
+    }
+
+    /// Resolves the night by running every step of `RESOLUTION_ORDER` in turn;
+    /// within a step, actions are applied in login order of the players who chose
+    /// them. The mafia's victim, settled at the end of the early step, is
+    /// attacked at the start of the kill step.
+    fn finish(mut self, mafia_target: Option<PlayerId>, timer: &mut Timer<TimerEvent>) -> GameStage {
+        let protected: HashMap<PlayerId, PlayerId> = self.targets(Ability::Protect).collect();
+        for (&(actor, ability), target) in &self.actions {
+            if ability.once_per_game() && target.is_some() {
+                if let Some(info) = self.game.players.get_mut(&actor) {
+                    info.used.push(ability);
+                }
+            }
+        }
+        self.game.day += 1;
+        self.game.last_protected = protected;
+        let mut outcome = Outcome {
+            saved: HashSet::new(),
+            news: Vec::new(),
+        };
+        for &priority in &RESOLUTION_ORDER {
+            if let (Priority::Kill, Some(victim)) = (priority, mafia_target) {
+                outcome.news.push(self.game.attack(victim, &outcome.saved));
+            }
+            for (actor, ability, target) in self.scheduled(priority) {
+                self.apply(actor, ability, target, &mut outcome);
+            }
+        }
+        let mut news = outcome.news;
+        if news.is_empty() {
+            news.push("Nobody died tonight.".to_string());
+        }
+        let dawn = narration::dawn(self.game.locale, &mut self.game.rng);
+        self.game.broadcast_typed(MessageKind::PhaseChange,
+                                  format!("{} {}\n", dawn, news.join(" ")).into());
+        self.game.advance(Phase::Day, timer)
+    }
+
+    /// Tonight's picks that take effect in the given step, in login order of
+    /// the players who made them.
+    fn scheduled(&self, priority: Priority) -> Vec<(PlayerId, Ability, PlayerId)> {
+        let mut scheduled: Vec<(PlayerId, Ability, PlayerId)> = self.actions.iter()
+            .filter(|&(&(_, ability), _)| ability.priority() == Some(priority))
+            .filter_map(|(&(actor, ability), &target)| target.map(|target| (actor, ability, target)))
+            .collect();
+        scheduled.sort_by_key(|&(actor, _, _)| self.game.players[&actor].player.get_login());
+        scheduled
+    }
+
+    /// Applies one player's pick to the night's outcome.
+    fn apply(&mut self, actor: PlayerId, ability: Ability, target: PlayerId, outcome: &mut Outcome) {
+        let player = self.game.players[&actor].player.clone();
+        let suspect = &self.game.players[&target];
+        let login = suspect.player.get_login();
+        match ability {
+            Ability::Protect | Ability::Heal => {
+                outcome.saved.insert(target);
+            },
+            // Killers who die earlier in the night don't get to strike.
+            Ability::SoloKill | Ability::Poison => {
+                if self.game.is_alive(actor) && self.game.is_alive(target) {
+                    outcome.news.push(self.game.attack(target, &outcome.saved));
+                }
+            },
+            Ability::FindDetective => {
+                let is_detective = suspect.role == Role::Detective;
+                if is_detective {
+                    player.send(format!("{} is the detective.\n", login));
+                } else {
+                    player.send(format!("{} is not the detective.\n", login));
+                }
+                self.game.log.push(LogEvent::DonCheck {
+                    don: player.get_login().into(),
+                    suspect: login.into(),
+                    is_detective,
+                });
+            },
+            Ability::Investigate => {
+                let is_mafia = suspect.role.is_mafia();
+                if is_mafia {
+                    player.send(format!("{} is a member of the mafia.\n", login));
+                } else {
+                    player.send(format!("{} is not a member of the mafia.\n", login));
+                }
+                self.game.log.push(LogEvent::Investigation {
+                    detective: player.get_login().into(),
+                    suspect: login.into(),
+                    is_mafia,
+                });
+            },
+            Ability::Divine => {
+                let role = suspect.role.name();
+                player.send(format!("{} is the {}.\n", login, role));
+                self.game.log.push(LogEvent::Divination {
+                    seer: player.get_login().into(),
+                    suspect: login.into(),
+                    role,
+                });
+            },
+            // The mafia's picks are settled together by `mafia_target`.
+            Ability::Kill | Ability::Matchmake => (),
+        }
+    }
+}
+
+/// Explains how to use each of the abilities at night, or nothing if there are none.
+fn night_prompt(abilities: &[Ability]) -> String {
+    let mut prompt = String::new();
+    for (index, &ability) in abilities.iter().enumerate() {
+        let syntax = match (index, ability) {
+            (_, Ability::Matchmake) => "!!<login> <login>".to_string(),
+            (0, _) => "!!<login>".to_string(),
+            (_, ability) => format!("!!{} <login>", ability.keyword()),
+        };
+        prompt.push_str(&format!("{} with {}.\n", ability.describe(), syntax));
+    }
+    if !abilities.is_empty() {
+        prompt.push_str("Type !!pass to do nothing tonight.\n");
+    }
+    prompt
+}
+
+/// Splits `<keyword> <login>` into the ability and its target; anything else
+/// targets the first of the abilities.
+fn parse_action<'a>(abilities: &[Ability], target: &'a str) -> (Ability, &'a str) {
+    let mut words = target.splitn(2, char::is_whitespace);
+    if let (Some(keyword), Some(rest)) = (words.next(), words.next()) {
+        if let Some(&ability) = abilities.iter().find(|ability| ability.keyword() == keyword) {
+            return (ability, rest.trim());
+        }
+    }
+    (abilities[0], target)
+}
+
+impl ShotStage {
+    fn new(mut game: Game, hunter: PlayerId, next: Phase, timer: &mut Timer<TimerEvent>) -> Self {
+        let seconds = game.rules.shot_seconds;
+        game.schedule_phase(Duration::from_secs(seconds), timer);
+        for info in game.players.values() {
+            if info.state == PlayerState::Active {
+                info.player.mute(MuteLevel::AllowAll);
+                info.player.set_team(None);
+            }
+        }
+        let player = game.players[&hunter].player.clone();
+        game.broadcast(format!("{} was the hunter and has {} seconds to take someone down \
+                                with them.\n", player.get_login(), seconds).into());
+        player.send_static("Shoot a player with !!<login>, or hold your fire with !!pass.\n");
+        ShotStage {
+            game,
+            hunter,
+            next,
+        }
+    }
+
+    fn handle_game_event(self, event: GameEvent, timer: &mut Timer<TimerEvent>) -> GameStage {
+        match event {
+            GameEvent::Action(id, target) if id == self.hunter => self.handle_shot(&target, timer),
+            GameEvent::Action(id, _) | GameEvent::CommandVote(id, _) | GameEvent::CommandVotes(id) => {
+                if let Some(info) = self.game.players.get(&id) {
+                    info.player.send_static("Wait for the hunter to shoot.\n");
+                }
+                GameStage::Shot(self)
+            },
+            _ => GameStage::Shot(self),
+        }
+    }
+
+    fn handle_timer_event(mut self, epoch: u64, timer: &mut Timer<TimerEvent>) -> GameStage {
+        match self.game.check_alarm(epoch) {
+            Alarm::Stale => GameStage::Shot(self),
+            Alarm::Warning(seconds) => {
+                self.game.broadcast_countdown(seconds);
+                GameStage::Shot(self)
+            },
+            Alarm::Expired => {
+                self.game.broadcast("The hunter did not shoot in time.\n".into());
+                self.game.advance(self.next, timer)
+            },
+        }
+    }
+
+    fn handle_shot(mut self, target: &str, timer: &mut Timer<TimerEvent>) -> GameStage {
+        let hunter = self.game.players[&self.hunter].player.clone();
+        if target == "pass" {
+            self.game.broadcast(format!("The hunter {} holds their fire.\n",
+                                        hunter.get_login()).into());
+            return self.game.advance(self.next, timer);
+        }
+        let target = match self.game.find_alive(target) {
+            Some(info) => info.player.get_id(),
+            None => {
+                let locale = hunter.locale().unwrap_or(self.game.locale);
+                hunter.send(locale.action_error(ActionError::UnknownPlayer(target)));
+                return GameStage::Shot(self);
+            },
+        };
+        let login = self.game.kill(target);
+        self.game.broadcast(format!("With their last breath, the hunter {} shoots {}.\n",
+                                    hunter.get_login(), login).into());
+        self.game.log.push(LogEvent::Shot {
+            hunter: hunter.get_login().into(),
+            target: login,
+        });
+        self.game.advance(self.next, timer)
+    }
+}
+
+impl Game {
+    /// Starts the phase clock. Bumping the epoch makes any alarms left over
+    /// from the previous phase stale.
+    fn schedule_phase(&mut self, length: Duration, timer: &Timer<TimerEvent>) {
+        self.epoch += 1;
+        self.deadline = Instant::now() + length;
+        let length_ms = length.as_millis() as u64;
+        for &warning in &[60_000, 10_000] {
+            if length_ms > warning {
+                timer.add_alarm(length_ms - warning, TimerEvent::Phase(self.epoch));
+            }
+        }
+        timer.add_alarm(length_ms, TimerEvent::Phase(self.epoch));
+    }
+
+    /// Stops the phase clock and returns the time that was left on it.
+    fn pause(&mut self) -> Duration {
+        self.epoch += 1;
+        self.time_left()
+    }
+
+    fn time_left(&self) -> Duration {
+        let now = Instant::now();
+        if self.deadline > now {
+            self.deadline - now
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+
+    fn check_alarm(&self, epoch: u64) -> Alarm {
+        if epoch != self.epoch {
+            return Alarm::Stale;
+        }
+        let now = Instant::now();
+        if now + Duration::from_millis(500) >= self.deadline {
+            Alarm::Expired
+        } else {
+            Alarm::Warning(((self.deadline - now).as_millis() as u64 + 500) / 1000)
+        }
+    }
+
+    fn broadcast_countdown(&self, seconds: u64) {
+        let message: Arc<str> = format!("{} seconds remaining.\n", seconds).into();
+        for info in self.players.values() {
+            info.player.send_countdown(message.clone());
+        }
+    }
+
+    fn broadcast(&self, message: Arc<str>) {
+        self.broadcast_typed(MessageKind::System, message);
+    }
+
+    fn broadcast_typed(&self, kind: MessageKind, message: Arc<str>) {
+        for info in self.players.values() {
+            info.player.send_typed(kind, message.clone());
+        }
+    }
+
+    fn find_alive(&self, login: &str) -> Option<&PlayerInfo> {
+        self.players.values()
+            .find(|info| info.state == PlayerState::Active && info.player.get_login() == login)
+    }
+
+    fn send_to_mafia(&self, message: Arc<str>) {
+        for info in self.players.values() {
+            if info.state == PlayerState::Active && info.role.is_mafia() {
+                info.player.send_arc(message.clone());
+            }
+        }
+    }
+
+    /// Whether the player knows about the event: what is announced to everyone,
+    /// and what they found out themselves.
+    fn knows(&self, id: PlayerId, event: &LogEvent) -> bool {
+        let info = match self.players.get(&id) {
+            Some(info) => info,
+            None => return false,
+        };
+        let login = info.player.get_login();
+        match event {
+            LogEvent::Investigation{detective, ..} => &**detective == login,
+            LogEvent::DonCheck{don, ..} => &**don == login,
+            LogEvent::Divination{seer, ..} => &**seer == login,
+            LogEvent::Lovers(first, second) =>
+                info.role == Role::Cupid || &**first == login || &**second == login,
+            LogEvent::Vote{..} | LogEvent::Skip(_) => !self.rules.anonymous_votes,
+            LogEvent::Save(_) => false,
+            _ => true,
+        }
+    }
+
+    /// What a bot sees of the game, given the votes or picks it gets to know about.
+    fn bot_view<'a>(&'a self, id: PlayerId, votes: Vec<(&'a str, &'a str)>,
+                    victim: Option<&'a str>) -> View<'a> {
+        let is_mafia = self.players[&id].role.is_mafia();
+        let mut alive = Vec::new();
+        let mut team = Vec::new();
+        for (&other, info) in &self.players {
+            if other != id && info.state == PlayerState::Active {
+                alive.push(info.player.get_login());
+                if is_mafia && info.role.is_mafia() {
+                    team.push(info.player.get_login());
+                }
+            }
+        }
+        alive.sort();
+        View {
+            alive,
+            team,
+            votes,
+            victim,
+        }
+    }
+
+    fn is_alive(&self, id: PlayerId) -> bool {
+        self.players.get(&id).map_or(false, |info| info.state == PlayerState::Active)
+    }
+
+    /// What the player's role can still do tonight.
+    fn abilities(&self, info: &PlayerInfo) -> Vec<Ability> {
+        let lovers_chosen = self.players.values().any(|other| other.lover.is_some());
+        info.role.abilities().iter()
+            .cloned()
+            .filter(|&ability| ability != Ability::Matchmake || (self.day == 0 && !lovers_chosen))
+            .filter(|ability| !info.used.contains(ability))
+            .collect()
+    }
+
+    fn alive_count(&self) -> usize {
+        self.players.values().filter(|info| info.state == PlayerState::Active).count()
+    }
+
+    /// Returns the winning faction if the game is over: the lovers win once they
+    /// are the last two standing, the town wins once the mafia and the maniac
+    /// are dead, the maniac wins once nobody else is left, the mafia wins once
+    /// the maniac is dead and the mafia is at least as large as the town.
+    fn winner(&self) -> Option<Faction> {
+        let alive: Vec<&PlayerInfo> = self.players.values()
+            .filter(|info| info.state == PlayerState::Active)
+            .collect();
+        let lovers = alive.iter().filter(|info| info.lover.is_some()).count();
+        roles::winning_faction(alive.iter().map(|info| info.role.faction()), lovers)
+    }
+
+    fn record_stats(&mut self, winner: Faction) {
+        let lynched_day_one = self.log.lynched_on(1);
+        let participants: Vec<(&PlayerInfo, bool)> = self.players.values()
+            .filter(|info| info.state != PlayerState::Observer)
+            .map(|info| (info, has_won(info, winner)))
+            .collect();
+        let ratings: Vec<(i32, bool)> = participants.iter()
+            .map(|&(info, won)| {
+                let stats = self.stats.get(info.player.get_login());
+                (stats.map_or(INITIAL_RATING, |stats| stats.rating), won)
+            })
+            .collect();
+        let changes = rating::adjust(&ratings);
+        for (&(info, won), rating_change) in participants.iter().zip(changes) {
+            if bot::is_bot(info.player.get_id()) || info.player.is_guest() {
+                continue;
+            }
+            let login = info.player.get_login();
+            self.stats.record(login, &GameResult {
+                win: if won { Some(winner) } else { None },
+                lynched_day_one: lynched_day_one == Some(login),
+                survived: info.state == PlayerState::Active,
+                rating_change,
+            });
+        }
+    }
+
+    /// Scores the game for the tournament, and announces the series' winner
+    /// once it's over.
+    fn record_tournament(&mut self, winner: Faction) {
+        let mut tournament = match self.tournament.take() {
+            Some(tournament) => tournament,
+            None => return,
+        };
+        let results: Vec<(&str, bool)> = self.players.values()
+            .filter(|info| info.state != PlayerState::Observer)
+            .map(|info| (info.player.get_login(), has_won(info, winner)))
+            .collect();
+        tournament.record_game(&results);
+        let mut message = tournament.standings();
+        if tournament.is_over() {
+            message.push_str(&tournament.describe_winner());
+        } else {
+            self.tournament = Some(tournament);
+        }
+        self.broadcast(message.into());
+    }
+
+    /// Notes an observer's guess at who the mafia are. Guesses can be changed
+    /// until the first day's vote is over.
+    fn predict(&mut self, id: PlayerId, args: &str) {
+        let observer = match self.players.get(&id) {
+            Some(info) if info.state == PlayerState::Observer => info.player.clone(),
+            Some(info) => {
+                info.player.send_static("Only observers can make predictions.\n");
+                return;
+            },
+            None => return,
+        };
+        let voted = self.log.events().iter().any(|event| match event {
+            LogEvent::Lynch(_) | LogEvent::NoLynch => true,
+            _ => false,
+        });
+        if voted {
+            observer.send_static("Predictions closed when the first day's vote ended.\n");
+            return;
+        }
+        let mut suspects: Vec<&str> = args.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|login| !login.is_empty())
+            .collect();
+        suspects.sort();
+        suspects.dedup();
+        if suspects.is_empty() {
+            observer.send_static("Usage: !predict <login>,<login>,...\n");
+            return;
+        }
+        for &suspect in &suspects {
+            let playing = self.players.values()
+                .any(|info| info.state != PlayerState::Observer && info.player.get_login() == suspect);
+            if !playing {
+                observer.send(format!("{} is not playing in this game.\n", suspect));
+                return;
+            }
+        }
+        observer.send(format!("Your prediction is noted: {}. It stays secret until the game \
+                               ends.\n", suspects.join(", ")));
+        let suspects: Vec<Box<str>> = suspects.into_iter().map(|suspect| suspect.into()).collect();
+        self.predictions.insert(observer.get_login().into(), suspects);
+    }
+
+    /// Scores the observers' predictions, a point for every mafioso found and
+    /// a point off for every wrong guess, and announces the best spectator.
+    fn score_predictions(&self) {
+        if self.predictions.is_empty() {
+            return;
+        }
+        let is_mafia = |login: &str| self.players.values()
+            .any(|info| info.player.get_login() == login && info.role.is_mafia());
+        let mafia = self.players.values()
+            .filter(|info| info.state != PlayerState::Observer && info.role.is_mafia())
+            .count();
+        let mut scores: Vec<(&str, usize, usize)> = self.predictions.iter()
+            .map(|(observer, suspects)| {
+                let found = suspects.iter().filter(|&suspect| is_mafia(suspect)).count();
+                (&**observer, found, suspects.len() - found)
+            })
+            .collect();
+        let score = |found: usize, wrong: usize| found as i64 - wrong as i64;
+        scores.sort_by_key(|&(observer, found, wrong)|
+                           (std::cmp::Reverse(score(found, wrong)), observer));
+        let mut message = String::from("Spectator predictions:\n");
+        for &(observer, found, wrong) in &scores {
+            message.push_str(&format!("  {} - found {} of {} mafiosi, {} wrong\n",
+                                      observer, found, mafia, wrong));
+        }
+        let (_, top_found, top_wrong) = scores[0];
+        let top = score(top_found, top_wrong);
+        if top > 0 {
+            let best: Vec<&str> = scores.iter()
+                .filter(|&&(_, found, wrong)| score(found, wrong) == top)
+                .map(|&(observer, _, _)| observer)
+                .collect();
+            message.push_str(&format!("Best spectator: {}!\n", best.join(", ")));
+        }
+        self.broadcast(message.into());
+    }
+
+    /// Kills the player unless they are among the `saved`, and returns the
+    /// morning news about it.
+    fn attack(&mut self, victim: PlayerId, saved: &HashSet<PlayerId>) -> String {
+        if saved.contains(&victim) {
+            let login = self.players[&victim].player.get_login().into();
+            self.log.push(LogEvent::Save(login));
+            "Someone was attacked last night, but survived.".to_string()
+        } else {
+            let login = self.kill(victim);
+            self.log.push(LogEvent::Kill(login.clone()));
+            narration::found_dead(self.locale, &mut self.rng, &login)
+        }
+    }
+
+    /// Moves on after a phase: lets every hunter who died take their shot, then
+    /// ends the game if someone has won, or starts the `next` phase.
+    fn advance(mut self, next: Phase, timer: &mut Timer<TimerEvent>) -> GameStage {
+        if !self.pending_shots.is_empty() {
+            let hunter = self.pending_shots.remove(0);
+            return GameStage::Shot(ShotStage::new(self, hunter, next, timer));
+        }
+        if let Some(winner) = self.winner() {
+            return GameStage::Lobby(self.end(winner, timer));
+        }
+        match next {
+            Phase::Day => GameStage::Day(DayStage::new(self, timer)),
+            Phase::Night => GameStage::Night(NightStage::new(self, timer)),
+        }
+    }
+
+    /// Announces the winner with a full role reveal and puts everyone who took
+    /// part back into a fresh lobby, which counts down to the tournament's next
+    /// game if there is one.
+    fn end(mut self, winner: Faction, timer: &Timer<TimerEvent>) -> LobbyStage {
+        let mut reveal: Vec<(&str, Role)> = self.players.values()
+            .filter(|info| info.state != PlayerState::Observer)
+            .map(|info| (info.player.get_login(), info.role))
+            .collect();
+        reveal.sort_by_key(|&(login, _)| login);
+        let verb = if winner == Faction::Lovers { "win" } else { "wins" };
+        let mut message = format!("Game over: the {} {}!\nRoles:\n", winner.name(), verb);
+        for (login, role) in reveal {
+            message.push_str(&format!("  {} - {}\n", login, role.name()));
+        }
+        message.push_str(&self.log.summary());
+        message.push_str(&format!("Seed: {}\n", self.seed));
+        self.broadcast_typed(MessageKind::Role, message.into());
+        self.record_stats(winner);
+        self.record_tournament(winner);
+        self.score_predictions();
+        self.into_lobby(timer)
+    }
+
+    /// Ends the game with no winner, at an admin's word. Nothing is recorded,
+    /// and a tournament in progress is called off.
+    fn abort(mut self, admin: &str, timer: &Timer<TimerEvent>) -> LobbyStage {
+        let mut reveal: Vec<(&str, Role)> = self.players.values()
+            .filter(|info| info.state != PlayerState::Observer)
+            .map(|info| (info.player.get_login(), info.role))
+            .collect();
+        reveal.sort_by_key(|&(login, _)| login);
+        let mut message = format!("{} aborted the game.\nRoles:\n", admin);
+        for (login, role) in reveal {
+            message.push_str(&format!("  {} - {}\n", login, role.name()));
+        }
+        if self.tournament.take().is_some() {
+            message.push_str("The tournament is called off.\n");
+        }
+        self.broadcast_typed(MessageKind::Role, message.into());
+        self.into_lobby(timer)
+    }
+
+    /// Puts everyone back in the lobby once the game is over.
+    fn into_lobby(mut self, timer: &Timer<TimerEvent>) -> LobbyStage {
+        // Players who left during the game and never came back have no seat in the lobby.
+        self.players.retain(|_, info| info.away.is_none());
+        let mut last_roles = HashMap::new();
+        let now = Instant::now();
+        for info in self.players.values_mut() {
+            info.last_active = now;
+            if info.state != PlayerState::Observer {
+                last_roles.insert(info.player.get_id(), info.role);
+                info.state = PlayerState::Active;
+                info.role = Role::Civilian;
+                info.lover = None;
+                info.used.clear();
+                info.player.set_status(PlayerStatus::Spectator);
+                info.player.set_team(None);
+            }
+            info.player.mute(MuteLevel::AllowAll);
+        }
+        let mut lobby = LobbyStage {
+            locale: self.locale,
+            players: self.players,
+            epoch: self.epoch + 1,
+            can_start: true,
+            auto_start: false,
+            deadline: Instant::now(),
+            rules: self.rules,
+            rng: Rng::from_time(),
+            stats: self.stats,
+            recorder: self.recorder,
+            tournament: self.tournament,
+            last_roles,
+        };
+        lobby.continue_tournament(LobbyStage::TOURNAMENT_BREAK_SECONDS, timer);
+        lobby
+    }
+
+    /// Announces the detective's role on their behalf, so that the claim can't be faked.
+    fn reveal(&mut self, id: PlayerId) {
+        let info = match self.players.get(&id) {
+            Some(info) => info,
+            None => return,
+        };
+        let reply = if !self.rules.detective_reveal {
+            Some("Revealing roles is disabled in this game.\n")
+        } else if info.state != PlayerState::Active || info.role != Role::Detective {
+            Some("Only the living detective can reveal themselves.\n")
+        } else if self.revealed {
+            Some("You have already revealed yourself.\n")
+        } else {
+            None
+        };
+        if let Some(reply) = reply {
+            info.player.send_static(reply);
+            return;
+        }
+        let login: Box<str> = info.player.get_login().into();
+        self.revealed = true;
+        self.broadcast_typed(MessageKind::Role,
+            format!("The game master confirms: {} is the detective.\n", login).into());
+        self.log.push(LogEvent::Reveal(login));
+    }
+
+    /// Marks the player dead and moves them to the graveyard, taking their lover
+    /// along. Returns their login.
+    fn kill(&mut self, id: PlayerId) -> Box<str> {
+        let login = self.bury(id);
+        let lover = self.players[&id].lover;
+        if let Some(lover) = lover.filter(|&lover| self.is_alive(lover)) {
+            let lover_login = self.bury(lover);
+            self.broadcast(format!("{} dies of a broken heart.\n", lover_login).into());
+            self.log.push(LogEvent::Heartbreak(lover_login));
+        }
+        login
+    }
+
+    /// Marks a single player dead. A hunter who is still connected gets their shot
+    /// at the next phase change.
+    fn bury(&mut self, id: PlayerId) -> Box<str> {
+        let info = self.players.get_mut(&id).expect("GameService killed a missing player");
+        if info.role == Role::Hunter && info.away.is_none() {
+            self.pending_shots.push(id);
+        }
+        info.state = PlayerState::Dead;
+        info.player.mute(MuteLevel::DenyAll(DEAD_MUTE));
+        info.player.set_status(PlayerStatus::Dead);
+        info.player.set_team(None);
+        info.player.send_static("You are dead. You can still talk in the graveyard \
+                                 with #<message>.\n");
+        info.player.get_login().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::login_service::UserId;
+    use crate::socket_service::Outbox;
+    use crate::stats::MemoryStats;
+
+    use std::net::SocketAddr;
+
+    /// A lobby in which the players are ready to play, with ids counting up
+    /// from 1 in the order given.
+    fn lobby(logins: &[&str]) -> LobbyStage {
+        let mut players = HashMap::new();
+        for (number, &login) in logins.iter().enumerate() {
+            let player = Player::detached(UserId(number as u64 + 1), login);
+            players.insert(player.get_id(), PlayerInfo {
+                player,
+                state: PlayerState::Active,
+                role: Role::Civilian,
+                away: None,
+                lover: None,
+                used: Vec::new(),
+                last_active: Instant::now(),
+            });
+        }
+        LobbyStage {
+            locale: Locale::En,
+            players,
+            epoch: 0,
+            can_start: true,
+            auto_start: false,
+            deadline: Instant::now(),
+            rules: GameRules::default(),
+            rng: Rng::new(1),
+            stats: Box::new(MemoryStats::new()),
+            recorder: None,
+            tournament: None,
+            last_roles: HashMap::new(),
+        }
+    }
+
+    /// The first night of a game in which the players have the given roles.
+    fn night(cast: &[(&str, Role)], timer: &mut Timer<TimerEvent>) -> NightStage {
+        let logins: Vec<&str> = cast.iter().map(|&(login, _)| login).collect();
+        let mut stage = match lobby(&logins).start(timer) {
+            GameStage::Night(stage) => stage,
+            _ => panic!("the game didn't start with a night"),
+        };
+        for info in stage.game.players.values_mut() {
+            let login = info.player.get_login();
+            info.role = cast.iter()
+                .find(|&&(name, _)| name == login)
+                .map(|&(_, role)| role)
+                .expect("the player has no role");
+        }
+        stage
+    }
+
+    fn id(game: &Game, login: &str) -> PlayerId {
+        game.players.values()
+            .find(|info| info.player.get_login() == login)
+            .map(|info| info.player.get_id())
+            .expect("no such player")
+    }
+
+    /// Has a connected spectator watch the game.
+    fn add_observer(game: &mut Game, id: PlayerId, login: &str) -> Outbox {
+        let (player, outbox) = Player::connected(id, login);
+        game.players.insert(id, PlayerInfo {
+            player,
+            state: PlayerState::Observer,
+            role: Role::Civilian,
+            away: None,
+            lover: None,
+            used: Vec::new(),
+            last_active: Instant::now(),
+        });
+        outbox
+    }
+
+    const TOWN: &[(&str, Role)] = &[
+        ("ann", Role::Mafia),
+        ("bob", Role::Civilian),
+        ("cat", Role::Civilian),
+        ("dan", Role::Civilian),
+        ("eve", Role::Civilian),
+    ];
+
+    /// Seven players, so that nobody has won after a night with two deaths.
+    const CAST: &[(&str, Role)] = &[
+        ("ann", Role::Mafia),
+        ("bob", Role::Doctor),
+        ("cat", Role::Maniac),
+        ("dan", Role::Detective),
+        ("eve", Role::Civilian),
+        ("fay", Role::Civilian),
+        ("gus", Role::Civilian),
+    ];
+
+    /// Resolves the night with the given picks, as (actor, ability, target)
+    /// logins, and the mafia's victim.
+    fn resolve(picks: &[(&str, Ability, &str)], victim: Option<&str>) -> Game {
+        let mut timer = Timer::new();
+        let mut night = night(CAST, &mut timer);
+        for &(actor, ability, target) in picks {
+            let (actor, target) = (id(&night.game, actor), id(&night.game, target));
+            night.actions.insert((actor, ability), Some(target));
+        }
+        let victim = victim.map(|login| id(&night.game, login));
+        match night.finish(victim, &mut timer) {
+            GameStage::Day(day) => day.game,
+            _ => panic!("the game didn't go on to the day"),
+        }
+    }
+
+    fn is_alive(game: &Game, login: &str) -> bool {
+        game.is_alive(id(game, login))
+    }
+
+    #[test]
+    fn doctor_saves_the_maniacs_victim() {
+        let game = resolve(&[("cat", Ability::SoloKill, "eve"),
+                             ("bob", Ability::Protect, "eve")], None);
+        assert!(is_alive(&game, "eve"));
+        assert_eq!(game.last_protected.get(&id(&game, "bob")), Some(&id(&game, "eve")));
+    }
+
+    #[test]
+    fn doctor_saves_the_mafias_victim_only() {
+        let game = resolve(&[("cat", Ability::SoloKill, "fay"),
+                             ("bob", Ability::Protect, "eve")], Some("eve"));
+        assert!(is_alive(&game, "eve"));
+        assert!(!is_alive(&game, "fay"));
+    }
+
+    #[test]
+    fn maniac_killed_by_the_mafia_does_not_strike() {
+        let game = resolve(&[("cat", Ability::SoloKill, "eve")], Some("cat"));
+        assert!(!is_alive(&game, "cat"));
+        assert!(is_alive(&game, "eve"));
+    }
+
+    #[test]
+    fn unprotected_victims_die() {
+        let game = resolve(&[("cat", Ability::SoloKill, "fay"),
+                             ("bob", Ability::Protect, "gus")], Some("eve"));
+        assert!(!is_alive(&game, "eve"));
+        assert!(!is_alive(&game, "fay"));
+        assert!(is_alive(&game, "gus"));
+    }
+
+    #[test]
+    fn stale_countdown_alarm_does_not_start_the_game() {
+        let mut timer = Timer::new();
+        let mut lobby = lobby(&["ann", "bob", "cat", "dan"]);
+        lobby.handle_start(UserId(1), &timer);
+        let stale = lobby.epoch;
+        // Cancelled and started again, so the first countdown's alarms are out of date.
+        lobby.set_state(UserId(2), PlayerState::Observer);
+        lobby.check_countdown();
+        lobby.set_state(UserId(2), PlayerState::Active);
+        lobby.handle_start(UserId(1), &timer);
+        lobby.deadline = Instant::now();
+        let lobby = match lobby.handle_timer_event(stale, &mut timer) {
+            GameStage::Lobby(lobby) => lobby,
+            _ => panic!("a stale alarm started the game"),
+        };
+        let current = lobby.epoch;
+        match lobby.handle_timer_event(current, &mut timer) {
+            GameStage::Night(_) => (),
+            _ => panic!("the countdown's own alarm didn't start the game"),
+        }
+    }
+
+    #[test]
+    fn start_with_the_mafia_at_parity_is_refused() {
+        let timer = Timer::new();
+        let mut lobby = lobby(&["ann", "bob", "cat", "dan"]);
+        lobby.rules.distribution.players_per_mafia = 2;
+        assert!(lobby.instant_win().is_some());
+        lobby.handle_start(UserId(1), &timer);
+        assert!(lobby.can_start, "the countdown began");
+    }
+
+    #[test]
+    fn start_with_only_the_maniac_against_the_town_is_allowed() {
+        let timer = Timer::new();
+        let mut lobby = lobby(&["ann", "bob", "cat", "dan"]);
+        lobby.rules.distribution.maniac_from = 4;
+        assert!(lobby.instant_win().is_none());
+        lobby.handle_start(UserId(1), &timer);
+        assert!(!lobby.can_start, "the countdown didn't begin");
+    }
+
+    #[test]
+    fn rules_changed_during_the_countdown_are_checked_again() {
+        let mut timer = Timer::new();
+        let mut lobby = lobby(&["ann", "bob", "cat", "dan"]);
+        lobby.handle_start(UserId(1), &timer);
+        lobby.rules.distribution.players_per_mafia = 2;
+        match lobby.start(&mut timer) {
+            GameStage::Lobby(lobby) => assert!(lobby.can_start),
+            _ => panic!("a game the mafia had already won started"),
+        }
+    }
+
+    #[test]
+    fn spectators_vote_is_refused() {
+        let mut timer = Timer::new();
+        let mut day = match night(TOWN, &mut timer).finish(None, &mut timer) {
+            GameStage::Day(day) => day,
+            _ => panic!("the night didn't end"),
+        };
+        day.open_voting(&timer);
+        let mut outbox = add_observer(&mut day.game, UserId(9), "zoe");
+        let vote = GameEvent::CommandVote(UserId(9), "bob".into());
+        match GameStage::Day(day).handle_game_event(vote, &mut timer) {
+            GameStage::Day(day) => assert!(day.votes.is_empty() && day.skips.is_empty()),
+            _ => panic!("the day ended"),
+        }
+        assert_eq!(outbox.text(), "You are observing this game and cannot act or vote.\n");
+    }
+
+    #[test]
+    fn dead_players_night_action_is_refused() {
+        let mut timer = Timer::new();
+        let mut night = night(TOWN, &mut timer);
+        let ann = id(&night.game, "ann");
+        let (player, mut outbox) = Player::connected(ann, "ann");
+        let info = night.game.players.get_mut(&ann).expect("ann is missing");
+        info.player = player;
+        info.state = PlayerState::Dead;
+        let action = GameEvent::Action(ann, "bob".into());
+        match GameStage::Night(night).handle_game_event(action, &mut timer) {
+            GameStage::Night(night) => assert!(night.actions.is_empty()),
+            _ => panic!("the night ended"),
+        }
+        assert_eq!(outbox.text(), "You are dead; only the living can act or vote.\n");
+    }
+
+    #[test]
+    fn alarm_from_an_earlier_phase_is_ignored() {
+        let mut timer = Timer::new();
+        let mut night = night(TOWN, &mut timer);
+        let night_epoch = night.game.epoch;
+        night.game.deadline = Instant::now();
+        let mut day = match night.handle_timer_event(night_epoch, &mut timer) {
+            GameStage::Day(day) => day,
+            _ => panic!("the night didn't end"),
+        };
+        day.game.deadline = Instant::now();
+        let day = match day.handle_timer_event(night_epoch, &mut timer) {
+            GameStage::Day(day) => day,
+            _ => panic!("the day ended"),
+        };
+        assert!(!day.is_voting());
+        let day_epoch = day.game.epoch;
+        match day.handle_timer_event(day_epoch, &mut timer) {
+            GameStage::Day(ref day) if day.is_voting() => (),
+            _ => panic!("the discussion's own alarm didn't open voting"),
+        }
+    }
+
+    /// A night with the clock stopped for bob, who just dropped out.
+    fn night_waiting_for_bob(timer: &mut Timer<TimerEvent>) -> (GameStage, PlayerId, u64) {
+        let mut night = night(TOWN, timer);
+        night.game.rules.reconnect_seconds = 30;
+        let bob = id(&night.game, "bob");
+        let stage = GameStage::Night(night).handle_game_event(GameEvent::Disconnected(bob), timer);
+        let epoch = match &stage {
+            GameStage::Paused(paused) => paused.stage.game().expect("no game").epoch,
+            _ => panic!("the clock kept running"),
+        };
+        (stage, bob, epoch)
+    }
+
+    #[test]
+    fn reconnect_resumes_the_night() {
+        let mut timer = Timer::new();
+        let (stage, bob, epoch) = night_waiting_for_bob(&mut timer);
+        let back = GameEvent::Connected(Player::detached(bob, "bob"));
+        match stage.handle_game_event(back, &mut timer) {
+            GameStage::Night(night) => {
+                assert!(night.game.players[&bob].away.is_none());
+                assert!(night.game.epoch != epoch, "the clock wasn't started again");
+            },
+            _ => panic!("the night didn't resume"),
+        }
+    }
+
+    #[test]
+    fn night_resumes_without_a_player_who_is_slow_to_reconnect() {
+        let mut timer = Timer::new();
+        let (stage, bob, epoch) = night_waiting_for_bob(&mut timer);
+        match stage.handle_timer_event(TimerEvent::Reconnect(epoch), &mut timer) {
+            GameStage::Night(night) => {
+                assert!(night.game.players[&bob].away.is_some());
+                assert!(night.game.is_alive(bob), "bob lost the rest of the grace period");
+            },
+            _ => panic!("the night didn't resume"),
+        }
+    }
+
+    #[test]
+    fn no_reconnect_wait_by_default() {
+        let mut timer = Timer::new();
+        let night = night(TOWN, &mut timer);
+        let bob = id(&night.game, "bob");
+        match GameStage::Night(night).handle_game_event(GameEvent::Disconnected(bob), &mut timer) {
+            GameStage::Night(_) => (),
+            _ => panic!("the clock stopped"),
+        }
+    }
+
+    #[test]
+    fn leave_takes_a_player_out_of_the_game_but_keeps_them_connected() {
+        let mut timer = Timer::new();
+        let mut night = night(CAST, &mut timer);
+        let eve = id(&night.game, "eve");
+        let (player, mut outbox) = Player::connected(eve, "eve");
+        night.game.players.get_mut(&eve).expect("eve is missing").player = player;
+        match GameStage::Night(night).handle_game_event(GameEvent::CommandLeave(eve), &mut timer) {
+            GameStage::Night(night) => {
+                assert!(!night.game.is_alive(eve));
+                assert!(night.game.players.contains_key(&eve));
+            },
+            _ => panic!("the night ended"),
+        }
+        assert!(outbox.text().contains("eve left the game."));
+        assert!(!outbox.closed, "the socket was closed");
+    }
+
+    #[test]
+    fn leave_in_the_lobby_observes() {
+        let lobby = lobby(&["ann", "bob", "cat", "dan"]);
+        let stage = lobby.handle_game_event(GameEvent::CommandLeave(UserId(2)), &mut Timer::new());
+        match stage {
+            GameStage::Lobby(lobby) =>
+                assert!(lobby.players[&UserId(2)].state == PlayerState::Observer),
+            _ => panic!("the game started"),
+        }
+    }
+
+    /// A lobby in which ann plays and bob, from the same address, observes;
+    /// with their outboxes.
+    fn lobby_sharing_an_address(one_player_per_ip: bool) -> (LobbyStage, Outbox, Outbox) {
+        let address = SocketAddr::from(([10, 0, 0, 1], 4000));
+        let mut lobby = lobby(&["ann", "bob"]);
+        lobby.rules.one_player_per_ip = one_player_per_ip;
+        let (ann, ann_outbox) = Player::remote(UserId(1), "ann", address);
+        let (bob, bob_outbox) = Player::remote(UserId(2), "bob", address);
+        for player in vec![ann, bob] {
+            lobby.players.get_mut(&player.get_id()).expect("player is missing").player = player;
+        }
+        lobby.players.get_mut(&UserId(2)).expect("bob is missing").state = PlayerState::Observer;
+        (lobby, ann_outbox, bob_outbox)
+    }
+
+    #[test]
+    fn second_login_from_an_address_observes() {
+        let (lobby, _ann, mut bob) = lobby_sharing_an_address(true);
+        match lobby.handle_game_event(GameEvent::CommandPlay(UserId(2)), &mut Timer::new()) {
+            GameStage::Lobby(lobby) =>
+                assert!(lobby.players[&UserId(2)].state == PlayerState::Observer),
+            _ => panic!("the game started"),
+        }
+        assert!(bob.text().contains("Someone from your address is already playing"));
+    }
+
+    #[test]
+    fn shared_address_may_play_by_default() {
+        let (lobby, _ann, _bob) = lobby_sharing_an_address(false);
+        match lobby.handle_game_event(GameEvent::CommandPlay(UserId(2)), &mut Timer::new()) {
+            GameStage::Lobby(lobby) =>
+                assert!(lobby.players[&UserId(2)].state == PlayerState::Active),
+            _ => panic!("the game started"),
+        }
+    }
+
+    #[test]
+    fn only_observers_see_the_odds() {
+        let mut timer = Timer::new();
+        let mut night = night(TOWN, &mut timer);
+        let bob = id(&night.game, "bob");
+        let (player, mut bob_outbox) = Player::connected(bob, "bob");
+        night.game.players.get_mut(&bob).expect("bob is missing").player = player;
+        let mut zoe_outbox = add_observer(&mut night.game, UserId(9), "zoe");
+        let stage = GameStage::Night(night);
+        stage.handle_odds(bob);
+        stage.handle_odds(UserId(9));
+        assert_eq!(bob_outbox.text(), "Only observers can see the odds.\n");
+        assert!(zoe_outbox.text().starts_with("Odds over 1000 random games"));
+    }
+
+    #[test]
+    fn alarm_from_before_a_pause_is_ignored() {
+        let mut timer = Timer::new();
+        let night = night(TOWN, &mut timer);
+        let epoch = night.game.epoch;
+        let ann = id(&night.game, "ann");
+        let paused = GameStage::Night(night).handle_pause(ann, &mut timer);
+        let mut stage = paused.handle_pause(ann, &mut timer);
+        if let Some(game) = stage.game_mut() {
+            game.deadline = Instant::now();
+        }
+        match stage.handle_timer_event(TimerEvent::Phase(epoch), &mut timer) {
+            GameStage::Night(_) => (),
+            _ => panic!("an alarm from before the pause ended the night"),
+        }
     }
 }
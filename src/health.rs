@@ -0,0 +1,137 @@
+//! `/healthz` and `/readyz` over plain HTTP, on a port of their own, for
+//! Kubernetes probes and systemd watchdogs. The server is healthy while all
+//! four services answer a ping in time, and ready once it is also listening.
+
+use crate::admin_console::{Query, Subject};
+use crate::metrics::SocketMetrics;
+
+use futures::{
+    prelude::*,
+    select,
+    channel::{
+        mpsc::UnboundedSender,
+        oneshot,
+    },
+};
+
+use runtime::{
+    net::{TcpListener, TcpStream},
+    time::Delay,
+};
+
+use std::{
+    io,
+    sync::Arc,
+    time::Duration,
+};
+
+/// How long a service may take to answer a ping.
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+/// Bytes of a request read at most; only the request line matters.
+const MAX_REQUEST: usize = 4096;
+
+/// What the checks look at.
+#[derive(Clone)]
+pub struct Probes {
+    /// Query handlers of the services, by name.
+    pub services: Vec<(&'static str, UnboundedSender<Query>)>,
+    pub metrics: Arc<SocketMetrics>,
+}
+
+pub struct HealthServer {
+    listener: TcpListener,
+    probes: Probes,
+}
+
+impl HealthServer {
+    pub fn new(listener: TcpListener, probes: Probes) -> Self {
+        HealthServer {
+            listener,
+            probes,
+        }
+    }
+
+    pub async fn run(mut self) {
+        let mut connections = self.listener.incoming();
+        while let Some(stream) = connections.next().await {
+            match stream {
+                Ok(stream) => {
+                    let probes = self.probes.clone();
+                    #[allow(unused)] {
+                        runtime::spawn(async move {
+                            if let Err(err) = serve(stream, probes).await {
+                                debug!("Health check connection failed: {}", err);
+                            }
+                        });
+                    }
+                },
+                Err(err) => error!("Health check server failed to accept a connection: {}", err),
+            }
+        }
+    }
+}
+
+async fn serve(stream: TcpStream, probes: Probes) -> io::Result<()> {
+    let (mut reader, mut writer) = stream.split();
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|end| end == b"\r\n\r\n") && request.len() < MAX_REQUEST {
+        let count = reader.read(&mut buffer).await?;
+        if count == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..count]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut words = request.lines().next().unwrap_or("").split_whitespace();
+    let method = words.next().unwrap_or("");
+    let path = words.next().unwrap_or("");
+    let (status, body) = match (method, path) {
+        ("GET", "/healthz") | ("HEAD", "/healthz") => check(&probes, false).await,
+        ("GET", "/readyz") | ("HEAD", "/readyz") => check(&probes, true).await,
+        ("GET", _) | ("HEAD", _) => ("404 Not Found", "Not found.\n".to_string()),
+        _ => ("405 Method Not Allowed", "Only GET and HEAD are allowed.\n".to_string()),
+    };
+    let mut response = format!("HTTP/1.1 {}\r\n\
+                                Content-Type: text/plain; charset=utf-8\r\n\
+                                Content-Length: {}\r\n\
+                                Connection: close\r\n\r\n",
+                               status, body.len());
+    if method != "HEAD" {
+        response.push_str(&body);
+    }
+    writer.write_all(response.as_bytes()).await
+}
+
+/// `200 OK` if all is well, and `503` with what isn't otherwise. Readiness
+/// also needs the listeners bound.
+async fn check(probes: &Probes, readiness: bool) -> (&'static str, String) {
+    let mut failures = String::new();
+    if readiness && !probes.metrics.is_listening() {
+        failures.push_str("Not listening yet.\n");
+    }
+    for (name, handler) in &probes.services {
+        if !ping(handler).await {
+            failures.push_str(&format!("{} is not answering.\n", name));
+        }
+    }
+    if failures.is_empty() {
+        ("200 OK", "ok\n".to_string())
+    } else {
+        warn!("Health check failed: {}", failures.trim_end().replace('\n', " "));
+        ("503 Service Unavailable", failures)
+    }
+}
+
+async fn ping(handler: &UnboundedSender<Query>) -> bool {
+    let (reply, answer) = oneshot::channel();
+    if handler.unbounded_send(Query{subject: Subject::Ping, reply}).is_err() {
+        return false;
+    }
+    let mut answer = answer.fuse();
+    let mut timeout = Delay::new(PING_TIMEOUT).fuse();
+    select! {
+        answer = answer => answer.is_ok(),
+        _ = timeout => false,
+    }
+}
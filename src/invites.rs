@@ -0,0 +1,52 @@
+//! One-time invite tokens, for servers where not just anybody may register.
+//! The tokens are kept in a file, one per line, and each is struck off it as
+//! soon as somebody uses it.
+
+use std::{
+    collections::HashSet,
+    fs,
+    io,
+    path::PathBuf,
+};
+
+pub struct Invites {
+    path: PathBuf,
+    tokens: HashSet<Box<str>>,
+}
+
+impl Invites {
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let tokens = fs::read_to_string(&path)?
+            .lines()
+            .map(str::trim)
+            .filter(|token| !token.is_empty())
+            .map(Into::into)
+            .collect();
+        Ok(Invites{path, tokens})
+    }
+
+    /// Takes the token, so that nobody else can, if it's valid.
+    pub fn take(&mut self, token: &str) -> bool {
+        let taken = self.tokens.remove(token);
+        if taken {
+            self.save();
+        }
+        taken
+    }
+
+    /// Returns a token that was taken, but not used after all.
+    pub fn put_back(&mut self, token: Box<str>) {
+        self.tokens.insert(token);
+        self.save();
+    }
+
+    fn save(&self) {
+        let mut tokens: Vec<&str> = self.tokens.iter().map(|token| &**token).collect();
+        tokens.sort();
+        let mut contents = tokens.join("\n");
+        contents.push('\n');
+        if let Err(err) = fs::write(&self.path, contents) {
+            error!("Failed to save invite tokens to {}: {}", self.path.display(), err);
+        }
+    }
+}
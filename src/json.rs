@@ -0,0 +1,153 @@
+//! Just enough JSON for replay files and the JSON wire protocol: flat objects
+//! of strings, numbers and booleans.
+
+use std::{
+    iter::Peekable,
+    str::Chars,
+};
+
+/// Parses a flat JSON object into its keys and values. Strings are unescaped;
+/// other values are kept as written. Nested objects and arrays aren't supported.
+pub fn parse_object(line: &str) -> Option<Vec<(String, String)>> {
+    let mut chars = line.trim().chars().peekable();
+    if chars.next()? != '{' {
+        return None;
+    }
+    let mut fields = Vec::new();
+    skip_whitespace(&mut chars);
+    if chars.peek() == Some(&'}') {
+        chars.next();
+        return Some(fields);
+    }
+    loop {
+        skip_whitespace(&mut chars);
+        if chars.next()? != '"' {
+            return None;
+        }
+        let key = parse_string(&mut chars)?;
+        skip_whitespace(&mut chars);
+        if chars.next()? != ':' {
+            return None;
+        }
+        skip_whitespace(&mut chars);
+        let value = if chars.peek() == Some(&'"') {
+            chars.next();
+            parse_string(&mut chars)?
+        } else {
+            let mut value = String::new();
+            while let Some(&c) = chars.peek() {
+                if c == ',' || c == '}' {
+                    break;
+                }
+                value.push(c);
+                chars.next();
+            }
+            value.trim_end().to_string()
+        };
+        fields.push((key, value));
+        skip_whitespace(&mut chars);
+        match chars.next()? {
+            ',' => continue,
+            '}' => return Some(fields),
+            _ => return None,
+        }
+    }
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.peek().map_or(false, |c| c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+/// Reads the rest of a string whose opening quote has been consumed.
+fn parse_string(chars: &mut impl Iterator<Item = char>) -> Option<String> {
+    let mut value = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(value),
+            '\\' => match chars.next()? {
+                'n' => value.push('\n'),
+                'r' => value.push('\r'),
+                't' => value.push('\t'),
+                'u' => {
+                    let mut code = parse_hex(chars)?;
+                    // Characters outside the BMP come as a surrogate pair.
+                    if code >= 0xd800 && code < 0xdc00 {
+                        if chars.next()? != '\\' || chars.next()? != 'u' {
+                            return None;
+                        }
+                        let low = parse_hex(chars)?;
+                        if low < 0xdc00 || low >= 0xe000 {
+                            return None;
+                        }
+                        code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                    }
+                    value.push(std::char::from_u32(code)?);
+                },
+                c => value.push(c),
+            },
+            c => value.push(c),
+        }
+    }
+}
+
+/// Reads the four hex digits of a `\u` escape.
+fn parse_hex(chars: &mut impl Iterator<Item = char>) -> Option<u32> {
+    let code: String = chars.by_ref().take(4).collect();
+    if code.len() != 4 {
+        return None;
+    }
+    u32::from_str_radix(&code, 16).ok()
+}
+
+/// Quotes and escapes a JSON string.
+pub fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(pairs: &[(&str, &str)]) -> Option<Vec<(String, String)>> {
+        Some(pairs.iter().map(|&(key, value)| (key.to_string(), value.to_string())).collect())
+    }
+
+    #[test]
+    fn spaces_around_the_punctuation_are_skipped() {
+        assert_eq!(parse_object(r#"{"type": "line", "text": "hi"}"#),
+                   fields(&[("type", "line"), ("text", "hi")]));
+        assert_eq!(parse_object(r#"{ "type":"line" }"#), fields(&[("type", "line")]));
+        assert_eq!(parse_object("{ \"read\" : true ,\n \"n\": 3 }"),
+                   fields(&[("read", "true"), ("n", "3")]));
+        assert_eq!(parse_object("{ }"), fields(&[]));
+    }
+
+    #[test]
+    fn escaped_emoji_is_decoded() {
+        assert_eq!(parse_object(r#"{"text": "hi \ud83d\ude00"}"#),
+                   fields(&[("text", "hi \u{1f600}")]));
+        assert_eq!(parse_object(r#"{"text": "\u00e9"}"#), fields(&[("text", "\u{e9}")]));
+    }
+
+    #[test]
+    fn lone_surrogate_is_rejected() {
+        assert_eq!(parse_object(r#"{"text": "\ud83d"}"#), None);
+        assert_eq!(parse_object(r#"{"text": "\ude00"}"#), None);
+    }
+}
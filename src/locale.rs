@@ -1,3 +1,5 @@
+use crate::roles::Role;
+
 #[derive(Clone, Copy)]
 pub enum Locale {
     En,
@@ -5,3 +7,212 @@ pub enum Locale {
 }
 
 pub const HELP_EN: &'static str = "TODO: write help\n";
+
+impl Locale {
+    /// The language's ISO 639-1 code, as users pick it with `!set language`.
+    pub fn code(self) -> &'static str {
+        match self {
+            Locale::En => "en",
+            Locale::Ru => "ru",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Locale> {
+        match code {
+            "en" => Some(Locale::En),
+            "ru" => Some(Locale::Ru),
+            _ => None,
+        }
+    }
+
+    /// Private message telling a player which role they were dealt and how to play it.
+    pub fn role_assigned(self, role: Role) -> String {
+        match self {
+            Locale::En => format!("Your role: {}.\n{}", role.name(), self.role_briefing(role)),
+            Locale::Ru => format!("Ваша роль: {}.\n{}", role.name(), self.role_briefing(role)),
+        }
+    }
+
+    fn role_briefing(self, role: Role) -> &'static str {
+        match (self, role) {
+            (Locale::En, Role::Civilian) =>
+                "You are an ordinary citizen. Find the mafia by talking during the day \
+                 and vote to lynch them with !vote <login>.\n",
+            (Locale::En, Role::Mafia) =>
+                "You are a member of the mafia. Each night your team picks a victim; \
+                 during the day, blend in with the town.\n",
+            (Locale::En, Role::Don) =>
+                "You lead the mafia. Each night you vote on the victim with your team \
+                 and may check one player to learn whether they are the detective.\n",
+            (Locale::En, Role::Detective) =>
+                "You are the detective. Each night you may investigate one player to \
+                 learn whether they are a member of the mafia.\n",
+            (Locale::En, Role::Doctor) =>
+                "You are the doctor. Each night you may protect one player from being \
+                 killed, but not the same player two nights in a row.\n",
+            (Locale::En, Role::Maniac) =>
+                "You are the maniac. You play for yourself: kill a player every night \
+                 and be the last one standing.\n",
+            (Locale::En, Role::Cupid) =>
+                "You are the cupid. On the first night, pick two players to fall in love; \
+                 if one of them dies, so does the other.\n",
+            (Locale::En, Role::Werewolf) =>
+                "You are a werewolf. Each night your pack picks a victim; during the day, \
+                 pass for a villager.\n",
+            (Locale::En, Role::Seer) =>
+                "You are the seer. Each night you may learn the exact role of one player.\n",
+            (Locale::En, Role::Witch) =>
+                "You are the witch. Each night, once the werewolves have chosen, you learn \
+                 their victim. You have one healing potion that saves a player from tonight's \
+                 attacks and one poison that kills; each works once per game.\n",
+            (Locale::En, Role::Hunter) =>
+                "You are the hunter. Whenever you die, you get a few seconds to shoot \
+                 any player you like and take them down with you.\n",
+            (Locale::En, Role::Villager) =>
+                "You are a villager. Find the werewolves by talking during the day \
+                 and vote to lynch them with !vote <login>.\n",
+            (Locale::Ru, Role::Civilian) =>
+                "Вы мирный житель. Днём вычисляйте мафию в разговоре и голосуйте \
+                 за казнь командой !vote <логин>.\n",
+            (Locale::Ru, Role::Mafia) =>
+                "Вы член мафии. Каждую ночь ваша команда выбирает жертву; днём \
+                 притворяйтесь мирным жителем.\n",
+            (Locale::Ru, Role::Don) =>
+                "Вы дон мафии. Каждую ночь вы выбираете жертву вместе с командой и \
+                 можете проверить одного игрока, не комиссар ли он.\n",
+            (Locale::Ru, Role::Detective) =>
+                "Вы комиссар. Каждую ночь вы можете проверить одного игрока и узнать, \
+                 состоит ли он в мафии.\n",
+            (Locale::Ru, Role::Doctor) =>
+                "Вы доктор. Каждую ночь вы можете спасти одного игрока от смерти, \
+                 но не одного и того же две ночи подряд.\n",
+            (Locale::Ru, Role::Maniac) =>
+                "Вы маньяк. Вы играете сам за себя: убивайте по игроку каждую ночь \
+                 и останьтесь последним в живых.\n",
+            (Locale::Ru, Role::Cupid) =>
+                "Вы купидон. В первую ночь выберите двух влюблённых; если один из них \
+                 умрёт, умрёт и другой.\n",
+            (Locale::Ru, Role::Werewolf) =>
+                "Вы оборотень. Каждую ночь ваша стая выбирает жертву; днём \
+                 притворяйтесь жителем деревни.\n",
+            (Locale::Ru, Role::Seer) =>
+                "Вы провидица. Каждую ночь вы можете узнать роль одного игрока.\n",
+            (Locale::Ru, Role::Witch) =>
+                "Вы ведьма. Каждую ночь, когда оборотни выберут жертву, вы узнаете её. \
+                 У вас есть лечебное зелье, спасающее игрока от ночных нападений, и яд, \
+                 убивающий его; каждое можно использовать один раз за игру.\n",
+            (Locale::Ru, Role::Hunter) =>
+                "Вы охотник. Когда бы вы ни погибли, у вас будет несколько секунд, \
+                 чтобы застрелить любого игрока и забрать его с собой.\n",
+            (Locale::Ru, Role::Villager) =>
+                "Вы житель деревни. Днём вычисляйте оборотней в разговоре и голосуйте \
+                 за казнь командой !vote <логин>.\n",
+        }
+    }
+
+    /// Private message introducing a mafioso to the rest of their team.
+    pub fn mafia_team(self, teammates: &[&str]) -> String {
+        match (self, teammates.is_empty()) {
+            (Locale::En, true) => "You are the only member of the mafia.\n".to_string(),
+            (Locale::En, false) => format!("Your fellow mafiosi: {}.\n", teammates.join(", ")),
+            (Locale::Ru, true) => "Вы единственный член мафии.\n".to_string(),
+            (Locale::Ru, false) => format!("Ваши сообщники: {}.\n", teammates.join(", ")),
+        }
+    }
+}
+
+/// Why a login was refused at the nickname prompt.
+#[derive(Clone, Copy)]
+pub enum LoginError {
+    TooShort,
+    TooLong,
+    /// Logins are letters, digits, `_` and `-`, starting with a letter.
+    BadCharacter,
+    Reserved,
+    BotName,
+}
+
+/// Why a `!!<login>` action was refused.
+pub enum ActionError<'a> {
+    NoGame,
+    Paused,
+    Observer,
+    Dead,
+    NothingToDo,
+    Discussion,
+    VotingOver,
+    UnknownPlayer(&'a str),
+}
+
+impl Locale {
+    pub fn action_error(self, error: ActionError) -> String {
+        match self {
+            Locale::En => match error {
+                ActionError::NoGame =>
+                    "There is no game in progress.\n".to_string(),
+                ActionError::Paused =>
+                    "The game is paused.\n".to_string(),
+                ActionError::Observer =>
+                    "You are observing this game and cannot act or vote.\n".to_string(),
+                ActionError::Dead =>
+                    "You are dead; only the living can act or vote.\n".to_string(),
+                ActionError::NothingToDo =>
+                    "Your role has nothing to do at night.\n".to_string(),
+                ActionError::Discussion =>
+                    "Voting opens once the discussion is over.\n".to_string(),
+                ActionError::VotingOver =>
+                    "Voting is over for today.\n".to_string(),
+                ActionError::UnknownPlayer(login) =>
+                    format!("There is no living player named \"{}\".\n", login),
+            },
+            Locale::Ru => match error {
+                ActionError::NoGame =>
+                    "Игра ещё не началась.\n".to_string(),
+                ActionError::Paused =>
+                    "Игра приостановлена.\n".to_string(),
+                ActionError::Observer =>
+                    "Вы наблюдаете за этой игрой и не можете действовать или голосовать.\n"
+                        .to_string(),
+                ActionError::Dead =>
+                    "Вы мертвы; действовать и голосовать могут только живые.\n".to_string(),
+                ActionError::NothingToDo =>
+                    "Вашей роли нечего делать ночью.\n".to_string(),
+                ActionError::Discussion =>
+                    "Голосование начнётся после обсуждения.\n".to_string(),
+                ActionError::VotingOver =>
+                    "Голосование на сегодня окончено.\n".to_string(),
+                ActionError::UnknownPlayer(login) =>
+                    format!("Нет живого игрока с именем \"{}\".\n", login),
+            },
+        }
+    }
+}
+
+impl Locale {
+    pub fn login_error(self, error: LoginError) -> &'static str {
+        match (self, error) {
+            (Locale::En, LoginError::TooShort) =>
+                "Nicknames must be at least 3 characters long.\n",
+            (Locale::En, LoginError::TooLong) =>
+                "Nicknames can be at most 16 characters long.\n",
+            (Locale::En, LoginError::BadCharacter) =>
+                "Nicknames must start with a letter and contain only latin letters, \
+                 digits, \"_\" and \"-\".\n",
+            (Locale::En, LoginError::Reserved) =>
+                "That nickname is reserved.\n",
+            (Locale::En, LoginError::BotName) =>
+                "Names like that are kept for bots.\n",
+            (Locale::Ru, LoginError::TooShort) =>
+                "Ник должен быть не короче 3 символов.\n",
+            (Locale::Ru, LoginError::TooLong) =>
+                "Ник должен быть не длиннее 16 символов.\n",
+            (Locale::Ru, LoginError::BadCharacter) =>
+                "Ник должен начинаться с буквы и состоять только из латинских букв, \
+                 цифр, \"_\" и \"-\".\n",
+            (Locale::Ru, LoginError::Reserved) =>
+                "Этот ник зарезервирован.\n",
+            (Locale::Ru, LoginError::BotName) =>
+                "Такие имена оставлены для ботов.\n",
+        }
+    }
+}
@@ -0,0 +1,269 @@
+//! Logging. A record has a level, the module it comes from, a message, and
+//! fields naming what it is about, such as the connection (`conn`) and the
+//! account (`login`). Records go to stderr or to a file that is rotated when it
+//! grows too big, as text or as JSON lines. Log with `error!`, `warn!`,
+//! `info!` and `debug!`, fields first:
+//!
+//!     info!(conn = id, login = login; "Logged in as {}", role);
+
+use crate::json;
+
+use chrono::prelude::*;
+
+use lazy_static::lazy_static;
+
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+};
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+/// How logging is set up; the `[log]` table of the configuration.
+#[derive(Clone)]
+pub struct LogConfig {
+    /// Records below this level are dropped, unless a module has its own.
+    pub level: Level,
+    /// Levels for single modules, like `login_service`, and their submodules.
+    pub modules: Vec<(String, Level)>,
+    pub format: Format,
+    /// File to log to; stderr if not set.
+    pub file: Option<PathBuf>,
+    /// Size in bytes past which the file is moved to `<file>.1`, the old
+    /// `<file>.1` to `<file>.2`, and so on.
+    pub max_size: u64,
+    /// How many of the moved files to keep.
+    pub keep: u32,
+}
+
+struct Logger {
+    config: RwLock<LogConfig>,
+    output: Mutex<Output>,
+}
+
+enum Output {
+    Stderr,
+    File {
+        path: PathBuf,
+        file: File,
+        /// Bytes in the file so far.
+        size: u64,
+    },
+}
+
+lazy_static! {
+    static ref LOGGER: Logger = Logger {
+        config: RwLock::new(LogConfig::default()),
+        output: Mutex::new(Output::Stderr),
+    };
+}
+
+macro_rules! log_at {
+    ($level:expr, $($key:ident = $value:expr),+; $($arg:tt)+) => {{
+        let level = $level;
+        if $crate::logging::enabled(level, module_path!()) {
+            $crate::logging::write(level, module_path!(),
+                                   &[$((stringify!($key), $value.to_string())),+],
+                                   format_args!($($arg)+));
+        }
+    }};
+    ($level:expr, $($arg:tt)+) => {{
+        let level = $level;
+        if $crate::logging::enabled(level, module_path!()) {
+            $crate::logging::write(level, module_path!(), &[], format_args!($($arg)+));
+        }
+    }};
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => { log_at!($crate::logging::Level::Error, $($arg)+) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => { log_at!($crate::logging::Level::Warn, $($arg)+) };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => { log_at!($crate::logging::Level::Info, $($arg)+) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => { log_at!($crate::logging::Level::Debug, $($arg)+) };
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: Level::Info,
+            modules: Vec::new(),
+            format: Format::Text,
+            file: None,
+            max_size: 10 * 1024 * 1024,
+            keep: 5,
+        }
+    }
+}
+
+impl Level {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+/// Sets logging up, at start and again on reload. The file is only reopened
+/// if it changed; if it can't be opened, records go to stderr.
+pub fn configure(config: &LogConfig) {
+    let mut failure = None;
+    {
+        let mut output = LOGGER.output.lock().expect("Logger lock poisoned");
+        let reopen = match (&*output, &config.file) {
+            (Output::File{path, ..}, Some(file)) => path != file,
+            (Output::Stderr, None) => false,
+            _ => true,
+        };
+        if reopen {
+            *output = match &config.file {
+                Some(path) => open(path).unwrap_or_else(|err| {
+                    failure = Some(format!("Failed to open log file {}: {}", path.display(), err));
+                    Output::Stderr
+                }),
+                None => Output::Stderr,
+            };
+        }
+        *LOGGER.config.write().expect("Logger lock poisoned") = config.clone();
+    }
+    if let Some(failure) = failure {
+        error!("{}", failure);
+    }
+}
+
+/// Whether a record at `level` from `module` would be written.
+pub fn enabled(level: Level, module: &str) -> bool {
+    let config = LOGGER.config.read().expect("Logger lock poisoned");
+    let module = short_module(module);
+    let threshold = config.modules.iter()
+        .filter(|(name, _)| module.starts_with(&**name)
+                && (module.len() == name.len() || module[name.len()..].starts_with("::")))
+        .max_by_key(|(name, _)| name.len())
+        .map_or(config.level, |&(_, level)| level);
+    level <= threshold
+}
+
+/// Writes a record. Use the macros, which check `enabled` first.
+pub fn write(level: Level, module: &str, fields: &[(&str, String)], message: fmt::Arguments) {
+    let (format, max_size, keep) = {
+        let config = LOGGER.config.read().expect("Logger lock poisoned");
+        (config.format, config.max_size, config.keep)
+    };
+    let time = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    let module = short_module(module);
+    let mut line = match format {
+        Format::Text => format!("{} {:5} {}: {}",
+                                time, level.name().to_uppercase(), module, message),
+        Format::Json => format!("{{\"time\":{},\"level\":{},\"module\":{},\"message\":{}",
+                                json::quote(&time), json::quote(level.name()),
+                                json::quote(module), json::quote(&message.to_string())),
+    };
+    for (key, value) in fields {
+        match format {
+            Format::Text if value.contains(char::is_whitespace) =>
+                line.push_str(&format!(" {}={:?}", key, value)),
+            Format::Text => line.push_str(&format!(" {}={}", key, value)),
+            Format::Json => line.push_str(&format!(",{}:{}", json::quote(key), json::quote(value))),
+        }
+    }
+    if format == Format::Json {
+        line.push('}');
+    }
+    line.push('\n');
+    let mut output = LOGGER.output.lock().expect("Logger lock poisoned");
+    if let Err(err) = output.write(line.as_bytes(), max_size, keep) {
+        eprintln!("Failed to write to the log: {}", err);
+        eprint!("{}", line);
+    }
+}
+
+impl Output {
+    fn write(&mut self, line: &[u8], max_size: u64, keep: u32) -> io::Result<()> {
+        match self {
+            Output::Stderr => io::stderr().write_all(line),
+            Output::File{path, file, size} => {
+                if *size > 0 && *size + line.len() as u64 > max_size {
+                    rotate(path, keep)?;
+                    *file = OpenOptions::new().create(true).append(true).open(&*path)?;
+                    *size = 0;
+                }
+                file.write_all(line)?;
+                *size += line.len() as u64;
+                Ok(())
+            },
+        }
+    }
+}
+
+fn open(path: &Path) -> io::Result<Output> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok(Output::File{path: path.to_path_buf(), file, size})
+}
+
+/// Moves `<path>.1` to `<path>.2` and so on, dropping the oldest, and `<path>`
+/// to `<path>.1`.
+fn rotate(path: &Path, keep: u32) -> io::Result<()> {
+    if keep == 0 {
+        return fs::remove_file(path);
+    }
+    for number in (1..keep).rev() {
+        let from = numbered(path, number);
+        if from.exists() {
+            fs::rename(from, numbered(path, number + 1))?;
+        }
+    }
+    fs::rename(path, numbered(path, 1))
+}
+
+fn numbered(path: &Path, number: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", number));
+    PathBuf::from(name)
+}
+
+/// Module paths without the crate's name, as they are written in the
+/// configuration.
+fn short_module(module: &str) -> &str {
+    match module.find("::") {
+        Some(split) => &module[split + 2..],
+        None => module,
+    }
+}
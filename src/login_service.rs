@@ -1,23 +1,78 @@
 use crate::socket_service::{ SocketId, SocketEvent, SocketProxy };
-use crate::locale::Locale;
+use crate::protocol::MessageKind;
+use crate::locale::{Locale, LoginError};
+use crate::motd::Motd;
+use crate::invites::Invites;
+use crate::bans::BanList;
+use crate::admin_console::{Query, Subject};
+use crate::auth::{Authenticator, AuthResult};
+use crate::settings::UserSettings;
+use crate::mail::MailStore;
+use crate::config::LiveConfig;
+use crate::bot;
+use crate::util::{Rng, Timer};
 
 use futures::{
     prelude::*,
+    select,
+    future::BoxFuture,
     channel::mpsc::{UnboundedSender, UnboundedReceiver, unbounded}
 };
 
 use std::{
-    sync::Arc,
-    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{Arc, RwLock},
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
 };
 
-pub type UserId = SocketId;
+/// Identifies a user while the server runs. Unlike the address of their
+/// socket, it stays the same when they reconnect.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub struct UserId(pub u64);
+
+const MIN_LOGIN: usize = 3;
+const MAX_LOGIN: usize = 16;
+/// Logins nobody may take, since players would take them for the server's own.
+const RESERVED_LOGINS: &[&str] = &[
+    "admin", "administrator", "all", "everyone", "game", "guest", "mafia", "moderator",
+    "root", "server", "system",
+];
+
+const MIN_PASSWORD: usize = 8;
+/// Wrong passwords a connection may give before it's closed.
+const MAX_FAILURES_PER_CONNECTION: u32 = 3;
+/// Wrong passwords for one login, from any connection, before it's locked.
+const MAX_FAILURES_PER_LOGIN: u32 = 10;
+const LOCKOUT: Duration = Duration::from_secs(15 * 60);
+/// A connection that hasn't typed anything this long after connecting is shown
+/// the prompt again, in case it got lost.
+const REMINDER_SECONDS: u64 = 60;
+/// Connections that haven't logged in this long after connecting are closed,
+/// however busy they are.
+const LOGIN_DEADLINE_SECONDS: u64 = 5 * 60;
+/// Invitations to play waiting for one offline login.
+const MAX_INVITATIONS: usize = 10;
+/// Invitations one user may have waiting at once.
+const MAX_INVITATIONS_SENT: usize = 20;
 
 #[derive(Clone)]
 pub struct User {
     id: UserId,
     login: Box<str>,
     socket: SocketProxy,
+    /// Logged in with a made-up nickname and no account.
+    guest: bool,
+    /// May use the admin commands.
+    admin: bool,
+    /// Shared by the user's clones, so that a change shows everywhere.
+    settings: Arc<RwLock<UserSettings>>,
+    /// Where changed settings go to be saved, unless the user has no account.
+    settings_saver: Option<UnboundedSender<SettingsUpdate>>,
+    /// Where mail for offline users and requests for the user's own go,
+    /// unless the user has no account.
+    mailer: Option<UnboundedSender<MailRequest>>,
 }
 
 pub enum UserEvent {
@@ -26,36 +81,180 @@ pub enum UserEvent {
     DropUser(UserId),
 }
 
+/// Settings a user changed, by login, to be saved with their account.
+struct SettingsUpdate(Box<str>, UserSettings);
+
+/// What a user wants done with mail, or with invitations to play for users
+/// who are offline. Answers go to the user.
+enum MailRequest {
+    /// Leave a message for these logins, who weren't online.
+    Leave(User, Vec<Box<str>>, Box<str>),
+    /// The same, once the logins have been looked up: the accounts' spellings,
+    /// or the logins nobody has.
+    Post(User, Vec<Result<Box<str>, Box<str>>>, Box<str>),
+    Review(User),
+    Clear(User),
+    Invite(User, Box<str>),
+    /// The same, once the login has been looked up.
+    PostInvite(User, Result<Box<str>, Box<str>>),
+}
+
 pub struct LoginService {
     event_handler: UnboundedSender<UserEvent>,
     socket_sender: UnboundedSender<SocketEvent>,
     socket_receiver: UnboundedReceiver<SocketEvent>,
+    settings_sender: UnboundedSender<SettingsUpdate>,
+    settings_receiver: UnboundedReceiver<SettingsUpdate>,
+    mail_sender: UnboundedSender<MailRequest>,
+    mail_receiver: UnboundedReceiver<MailRequest>,
+    query_sender: UnboundedSender<Query>,
+    query_receiver: UnboundedReceiver<Query>,
+    auth_sender: UnboundedSender<AuthReply>,
+    auth_receiver: UnboundedReceiver<AuthReply>,
     auth_state: HashMap<SocketId, AuthState>,
-    login_state: HashMap<Box<str>, LoginState>,
+    /// Lowercased logins of the users online.
+    online: HashSet<Box<str>>,
+    /// Ids handed out so far, by lowercased login.
+    user_ids: HashMap<Box<str>, UserId>,
+    next_user_id: u64,
+    /// Wrong passwords given so far, by connection.
+    connection_failures: HashMap<SocketId, u32>,
+    /// Wrong passwords given so far, by lowercased login.
+    login_failures: HashMap<Box<str>, u32>,
+    /// Lowercased logins that may not log in until the given time.
+    locked: HashMap<Box<str>, Instant>,
+    /// Connections that haven't logged in yet.
+    logging_in: HashMap<SocketId, LoginAttempt>,
+    /// Numbers login attempts, so that alarms for a closed connection don't go
+    /// off for a new one from the same address.
+    next_attempt: u64,
+    timer: Timer<LoginAlarm>,
+    authenticator: Box<dyn Authenticator>,
     locale: Locale,
+    /// Idle timeouts, whether guests may log in, and who the admins are.
+    config: LiveConfig,
+    motd: Motd,
+    /// New accounts need an invite token, if there are invites.
+    invites: Option<Invites>,
+    /// Tokens taken by connections that haven't made their account yet.
+    held_invites: HashMap<SocketId, Box<str>>,
+    bans: BanList,
+    mail: MailStore,
+    /// Who invited each offline login to play, by the lowercased login. They
+    /// are told when they log in; invitations don't outlive the server.
+    invitations: HashMap<Box<str>, Vec<Box<str>>>,
+    /// For guest nicknames.
+    rng: Rng,
+}
+
+/// How long connections may stay silent before they are closed.
+#[derive(Clone, Copy)]
+pub struct IdleTimeouts {
+    /// Before logging in.
+    pub login: Duration,
+    /// After logging in.
+    pub session: Duration,
+}
+
+struct LoginAttempt {
+    number: u64,
+    /// Whether the connection has sent anything yet.
+    typed: bool,
+}
+
+enum LoginAlarm {
+    Reminder(SocketId, u64),
+    Deadline(SocketId, u64),
+}
+
+/// An answer of the authenticator, for the connection that asked.
+enum AuthReply {
+    /// The login as typed, and its account's spelling if it has one.
+    Lookup(SocketId, Box<str>, Option<Box<str>>),
+    Verify(SocketId, AuthResult),
+    /// Whether the new password was stored.
+    SetPassword(SocketId, bool),
 }
 
 enum AuthState {
     Initial(SocketProxy),
-    GotLogin(SocketProxy, Box<str>),
+    /// Waiting for the authenticator to look up the nickname.
+    LookingUp(SocketProxy),
+    /// A new login, waiting for an invite token.
+    GotNewLogin(SocketProxy, Box<str>),
+    /// Waiting for the password; true if the login is new and the password
+    /// is being chosen.
+    GotLogin(SocketProxy, Box<str>, bool),
+    /// Waiting for the authenticator to check the password.
+    Verifying(SocketProxy, Box<str>),
     Ok(User),
+    /// Logged in, and answering the prompts of `!passwd` or `!delete-account`,
+    /// which aren't passed on to the chat.
+    Managing(User, Management),
+    /// Waiting for the authenticator to check the password given at one of
+    /// those prompts.
+    Checking(User, Management),
+    /// Waiting for the authenticator to store the new password of `!passwd`.
+    SettingPassword(User),
 }
 
-enum LoginState {
-    Online(Box<str>),
-    Offline(Box<str>),
+#[derive(Clone, Copy, PartialEq)]
+enum Management {
+    /// `!passwd`, asking for the current password.
+    CurrentPassword,
+    NewPassword,
+    /// `!delete-account`, asking for the password.
+    DeletePassword,
+    /// Asking the user to confirm the deletion.
+    ConfirmDelete,
 }
 
 impl LoginService {
-    pub fn new(event_handler: UnboundedSender<UserEvent>, locale: Locale) -> Self {
+    pub fn new(event_handler: UnboundedSender<UserEvent>,
+               authenticator: Box<dyn Authenticator>,
+               locale: Locale,
+               config: LiveConfig,
+               motd: Motd,
+               invites: Option<Invites>,
+               bans: BanList,
+               mail: MailStore) -> Self {
         let (socket_sender, socket_receiver) = unbounded();
+        let (settings_sender, settings_receiver) = unbounded();
+        let (mail_sender, mail_receiver) = unbounded();
+        let (query_sender, query_receiver) = unbounded();
+        let (auth_sender, auth_receiver) = unbounded();
         LoginService {
             event_handler,
             socket_sender,
             socket_receiver,
+            settings_sender,
+            settings_receiver,
+            mail_sender,
+            mail_receiver,
+            query_sender,
+            query_receiver,
+            auth_sender,
+            auth_receiver,
+            authenticator,
             locale,
+            config,
+            motd,
+            invites,
+            held_invites: HashMap::new(),
+            bans,
+            mail,
+            invitations: HashMap::new(),
+            rng: Rng::from_time(),
             auth_state: HashMap::new(),
-            login_state: HashMap::new(),
+            online: HashSet::new(),
+            user_ids: HashMap::new(),
+            next_user_id: 1,
+            connection_failures: HashMap::new(),
+            login_failures: HashMap::new(),
+            locked: HashMap::new(),
+            logging_in: HashMap::new(),
+            next_attempt: 0,
+            timer: Timer::new(),
         }
     }
 
@@ -63,97 +262,674 @@ impl LoginService {
         self.socket_sender.clone()
     }
 
+    pub fn make_query_handler(&self) -> UnboundedSender<Query> {
+        self.query_sender.clone()
+    }
+
     pub async fn run(mut self) {
         loop {
-            match self.socket_receiver.next().await {
-                Some(SocketEvent::NewSocket(proxy)) => self.handle_new_socket(proxy),
-                Some(SocketEvent::NewMessage(id, data)) => self.handle_new_message(id, data),
-                Some(SocketEvent::ClosedSocket(id)) => self.handle_closed_socket(id),
-                None => panic!("LoginService socket_receiver terminated"),
+            select! {
+                event = self.socket_receiver.next().fuse() => match event {
+                    Some(SocketEvent::NewSocket(proxy)) => self.handle_new_socket(proxy),
+                    Some(SocketEvent::NewMessage(id, data)) => self.handle_new_message(id, data),
+                    Some(SocketEvent::ClosedSocket(id)) => self.handle_closed_socket(id),
+                    None => panic!("LoginService socket_receiver terminated"),
+                },
+                alarm = self.timer.next().fuse() => match alarm {
+                    Some(LoginAlarm::Reminder(id, number)) => self.remind(id, number),
+                    Some(LoginAlarm::Deadline(id, number)) => self.check_deadline(id, number),
+                    None => panic!("LoginService timer terminated"),
+                },
+                reply = self.auth_receiver.next().fuse() => match reply {
+                    Some(reply) => self.handle_auth_reply(reply),
+                    None => panic!("LoginService auth_receiver terminated"),
+                },
+                update = self.settings_receiver.next().fuse() => match update {
+                    Some(SettingsUpdate(login, settings)) =>
+                        self.authenticator.set_settings(&login, settings.to_map()),
+                    None => panic!("LoginService settings_receiver terminated"),
+                },
+                request = self.mail_receiver.next().fuse() => match request {
+                    Some(request) => self.handle_mail_request(request),
+                    None => panic!("LoginService mail_receiver terminated"),
+                },
+                query = self.query_receiver.next().fuse() => match query {
+                    Some(query) => self.handle_query(query),
+                    None => panic!("LoginService query_receiver terminated"),
+                },
             }
         }
     }
 
     fn handle_new_socket(&mut self, proxy: SocketProxy) {
-        proxy.send_static("Welcome to the Mafia server!\nPlease enter your nickname: ");
-        self.auth_state.insert(proxy.get_id(), AuthState::Initial(proxy));
+        proxy.send_static("Welcome to the Mafia server!\n");
+        if let Some(motd) = self.motd.get() {
+            proxy.send_arc(motd);
+        }
+        proxy.send_static("Please enter your nickname: ");
+        proxy.set_idle_timeout(self.config.idle_timeouts().login);
+        let id = proxy.get_id();
+        let number = self.next_attempt;
+        self.next_attempt += 1;
+        self.logging_in.insert(id, LoginAttempt{number, typed: false});
+        self.timer.add_alarm(REMINDER_SECONDS * 1000, LoginAlarm::Reminder(id, number));
+        self.timer.add_alarm(LOGIN_DEADLINE_SECONDS * 1000, LoginAlarm::Deadline(id, number));
+        self.auth_state.insert(id, AuthState::Initial(proxy));
+    }
+
+    /// Answers the admin console.
+    fn handle_query(&self, query: Query) {
+        let answer = match query.subject {
+            Subject::Users => {
+                let mut users: Vec<&User> = self.auth_state.values()
+                    .filter_map(AuthState::user)
+                    .collect();
+                users.sort_by_key(|user| user.id);
+                let mut answer = format!("{} logged in, {} logging in\n",
+                                         users.len(), self.logging_in.len());
+                for user in users {
+                    answer.push_str(&format!("  {} {} from {}{}{}\n",
+                                             user.id, user.login, user.socket.get_id(),
+                                             if user.guest { ", guest" } else { "" },
+                                             if user.admin { ", admin" } else { "" }));
+                }
+                answer
+            },
+            Subject::Ping => "pong\n".to_string(),
+            _ => "LoginService can't answer that.\n".to_string(),
+        };
+        let _ = query.reply.send(answer);
+    }
+
+    /// Shows the nickname prompt again to a connection that hasn't answered it.
+    fn remind(&self, id: SocketId, number: u64) {
+        match (self.logging_in.get(&id), self.auth_state.get(&id)) {
+            (Some(attempt), Some(AuthState::Initial(proxy)))
+                if attempt.number == number && !attempt.typed =>
+                proxy.send_static("\nStill there? Please enter your nickname: "),
+            _ => (),
+        }
+    }
+
+    /// Closes a connection that still hasn't logged in.
+    fn check_deadline(&mut self, id: SocketId, number: u64) {
+        if self.logging_in.get(&id).map_or(true, |attempt| attempt.number != number) {
+            return;
+        }
+        self.logging_in.remove(&id);
+        let proxy = match self.auth_state.get(&id) {
+            Some(AuthState::Initial(proxy))
+            | Some(AuthState::LookingUp(proxy))
+            | Some(AuthState::GotNewLogin(proxy, _))
+            | Some(AuthState::GotLogin(proxy, _, _))
+            | Some(AuthState::Verifying(proxy, _)) => proxy.clone(),
+            _ => return,
+        };
+        self.auth_state.remove(&id);
+        self.connection_failures.remove(&id);
+        info!(conn = id; "Closing connection: login timed out");
+        proxy.set_echo(true);
+        proxy.send_static("\nYou took too long to log in. Goodbye!\n");
+        proxy.close();
     }
 
     fn handle_new_message(&mut self, id: SocketId, data: Box<str>) {
+        if let Some(attempt) = self.logging_in.get_mut(&id) {
+            attempt.typed = true;
+        }
         let state = self.auth_state.remove(&id);
         let new_state = match state {
             Some(AuthState::Initial(proxy)) => {
                 let login = data;
-                match self.login_state.get(&login) {
-                    Some(LoginState::Online(_)) => {
-                        proxy.send(format!("Player \"{}\" is already online.\n\
-                                            Please enter your nickname: ", login));
-                        AuthState::Initial(proxy)
-                    },
-                    Some(LoginState::Offline(_)) => {
-                        proxy.send(format!("Password for \"{}\": ", login));
-                        AuthState::GotLogin(proxy, login)
-                    },
-                    None => {
-                        proxy.send(format!("Creating player \"{}\". Enter password: ", login));
-                        AuthState::GotLogin(proxy, login)
-                    }
+                if self.config.guests() && login.eq_ignore_ascii_case("guest") {
+                    let login = self.guest_login();
+                    proxy.send(format!("Welcome, {}! As a guest, your games won't count \
+                                        towards any stats.\n", login));
+                    self.login_user(proxy, login, true)
+                } else if let Err(error) = check_login(&login) {
+                    proxy.send(format!("{}Please enter your nickname: ",
+                                       self.locale.login_error(error)));
+                    AuthState::Initial(proxy)
+                } else if let Some(until) = self.bans.login_ban(&login) {
+                    let until = match until {
+                        Some(until) => format!(" until {}", until.format("%Y-%m-%d %H:%M UTC")),
+                        None => String::new(),
+                    };
+                    proxy.send(format!("Player \"{}\" is banned{}.\n\
+                                        Please enter your nickname: ", login, until));
+                    AuthState::Initial(proxy)
+                } else if let Some(minutes) = self.lockout_minutes(&login) {
+                    proxy.send(format!("Player \"{}\" is locked after too many wrong passwords; \
+                                        try again in {} minutes.\n\
+                                        Please enter your nickname: ", login, minutes));
+                    AuthState::Initial(proxy)
+                } else {
+                    let lookup = self.authenticator.find_login(&login);
+                    self.await_reply(lookup, move |found| AuthReply::Lookup(id, login, found));
+                    AuthState::LookingUp(proxy)
                 }
             },
-            Some(AuthState::GotLogin(proxy, login)) => {
+            Some(AuthState::GotNewLogin(proxy, login)) => {
+                let token = data;
+                let valid = self.invites.as_mut().map_or(false, |invites| invites.take(&token));
+                if valid {
+                    info!(conn = id, login = login; "Invite token used");
+                    self.held_invites.insert(id, token);
+                    proxy.send_static("Invite accepted. Enter password: ");
+                    proxy.set_echo(false);
+                    AuthState::GotLogin(proxy, login, true)
+                } else {
+                    proxy.send_static("That invite token isn't valid.\n\
+                                       Please enter your nickname: ");
+                    AuthState::Initial(proxy)
+                }
+            },
+            Some(AuthState::GotLogin(proxy, login, new)) => {
                 let password = data;
-                let login_state = self.login_state.remove(&login);
-                let (new_login_state, new_auth_state) = match login_state {
-                    Some(LoginState::Online(password)) => {
-                        proxy.send(format!("Player \"{}\" is already online.\n\
-                                            Please enter your nickname: ", login));
-                        (LoginState::Online(password), AuthState::Initial(proxy))
-                    },
-                    Some(LoginState::Offline(real_password)) => {
-                        if password == real_password {
-                            proxy.send(format!("Welcome back, {}!\n", login));
-                            let user = User {
-                                id: proxy.get_id(),
-                                login: login.clone(),
-                                socket: proxy,
-                            };
-                            self.event_handler.unbounded_send(UserEvent::NewUser(user.clone()))
-                                .expect("LoginService event_handler stream error");
-                            (LoginState::Online(real_password), AuthState::Ok(user))
-                        } else {
-                            proxy.send_static("Incorrect password.\nPlease enter your nickname: ");
-                            (LoginState::Offline(real_password), AuthState::Initial(proxy))
-                        }
-                    },
-                    None => {
-                        proxy.send(format!("Password created. Welcome, {}!\n", login));
-                        let user = User {
-                            id: proxy.get_id(),
-                            login: login.clone(),
-                            socket: proxy,
-                        };
-                        self.event_handler.unbounded_send(UserEvent::NewUser(user.clone()))
-                            .expect("LoginService event_handler stream error");
-                        (LoginState::Online(password), AuthState::Ok(user))
-                    }
+                proxy.set_echo(true);
+                let weakness = if new {
+                    check_password(&login, &password)
+                } else {
+                    None
                 };
-                self.login_state.insert(login, new_login_state);
-                new_auth_state
+                if password.is_empty() {
+                    proxy.send_static("Please enter your nickname: ");
+                    AuthState::Initial(proxy)
+                } else if let Some(weakness) = weakness {
+                    proxy.send(format!("{} Enter password: ", weakness));
+                    proxy.set_echo(false);
+                    AuthState::GotLogin(proxy, login, new)
+                } else if self.lockout_minutes(&login).is_some() {
+                    // Locked while this connection was at the password prompt.
+                    proxy.send_static("Too many wrong passwords; try again later.\n\
+                                       Please enter your nickname: ");
+                    AuthState::Initial(proxy)
+                } else {
+                    let check = self.authenticator.verify(&login, &password);
+                    self.await_reply(check, move |result| AuthReply::Verify(id, result));
+                    AuthState::Verifying(proxy, login)
+                }
             },
-            Some(AuthState::Ok(user)) => {
-                self.event_handler.unbounded_send(UserEvent::NewMessage(user.id, data))
-                    .expect("LoginService event_handler stream error");
-                AuthState::Ok(user)
+            Some(AuthState::Ok(user)) => match &*data {
+                "!passwd" | "!delete-account" if user.guest => {
+                    user.send_static("Guests have no account to manage.\n");
+                    AuthState::Ok(user)
+                },
+                "!passwd" => {
+                    user.send_static("Current password: ");
+                    user.socket.set_echo(false);
+                    AuthState::Managing(user, Management::CurrentPassword)
+                },
+                "!delete-account" => {
+                    user.send_static("Password: ");
+                    user.socket.set_echo(false);
+                    AuthState::Managing(user, Management::DeletePassword)
+                },
+                _ => {
+                    self.event_handler.unbounded_send(UserEvent::NewMessage(user.id, data))
+                        .expect("LoginService event_handler stream error");
+                    AuthState::Ok(user)
+                },
             },
+            Some(AuthState::Managing(user, step)) => self.handle_management(user, step, data),
+            // Typed ahead of the authenticator's answer, so at a prompt that
+            // hasn't been shown yet.
+            Some(state @ AuthState::LookingUp(_))
+            | Some(state @ AuthState::Verifying(..))
+            | Some(state @ AuthState::Checking(..))
+            | Some(state @ AuthState::SettingPassword(_)) => state,
             None => return,
         };
         self.auth_state.insert(id, new_state);
     }
 
+    /// Awaits an answer of the authenticator off the service's loop, so that a
+    /// slow backend holds up nobody but the connection waiting for it.
+    fn await_reply<T, F>(&self, answer: BoxFuture<'static, T>, reply: F)
+        where T: Send + 'static,
+              F: FnOnce(T) -> AuthReply + Send + 'static
+    {
+        let sender = self.auth_sender.clone();
+        #[allow(unused)] {
+            runtime::spawn(async move {
+                let answer = answer.await;
+                // The service only goes away with the server.
+                let _ = sender.unbounded_send(reply(answer));
+            });
+        }
+    }
+
+    /// Picks up where the connection was left waiting for the authenticator.
+    /// Connections closed in the meantime have no state left to pick up.
+    fn handle_auth_reply(&mut self, reply: AuthReply) {
+        let id = match &reply {
+            AuthReply::Lookup(id, _, _)
+            | AuthReply::Verify(id, _)
+            | AuthReply::SetPassword(id, _) => *id,
+        };
+        let new_state = match (self.auth_state.remove(&id), reply) {
+            (Some(AuthState::LookingUp(proxy)), AuthReply::Lookup(_, login, found)) =>
+                self.handle_lookup(proxy, login, found),
+            (Some(AuthState::Verifying(proxy, login)), AuthReply::Verify(_, result)) =>
+                self.handle_verify(proxy, login, result),
+            (Some(AuthState::Checking(user, step)), AuthReply::Verify(_, result)) =>
+                self.handle_check(user, step, result),
+            (Some(AuthState::SettingPassword(user)), AuthReply::SetPassword(_, changed)) => {
+                if changed {
+                    user.send_static("Password changed.\n");
+                } else {
+                    user.send_static("Passwords can't be changed on this server.\n");
+                }
+                AuthState::Ok(user)
+            },
+            (Some(state), _) => state,
+            (None, _) => return,
+        };
+        self.auth_state.insert(id, new_state);
+    }
+
+    /// Asks for the password, or for an invite first if the login is new.
+    fn handle_lookup(&mut self, proxy: SocketProxy, login: Box<str>, found: Option<Box<str>>)
+        -> AuthState
+    {
+        let new = found.is_none();
+        let login = found.unwrap_or(login);
+        if self.is_online(&login) {
+            // Most likely the player's own connection, dead without
+            // their server knowing yet.
+            proxy.send(format!("Player \"{}\" is already online. Enter the password to \
+                                take over that session, or nothing to pick another \
+                                nickname: ", login));
+            proxy.set_echo(false);
+            AuthState::GotLogin(proxy, login, new)
+        } else if !new {
+            proxy.send(format!("Password for \"{}\": ", login));
+            proxy.set_echo(false);
+            AuthState::GotLogin(proxy, login, false)
+        } else if self.invites.is_some() && !self.held_invites.contains_key(&proxy.get_id()) {
+            proxy.send(format!("Creating player \"{}\". New players need an invite; \
+                                enter your invite token: ", login));
+            AuthState::GotNewLogin(proxy, login)
+        } else {
+            proxy.send(format!("Creating player \"{}\". Enter password: ", login));
+            proxy.set_echo(false);
+            AuthState::GotLogin(proxy, login, true)
+        }
+    }
+
+    fn handle_verify(&mut self, proxy: SocketProxy, login: Box<str>, result: AuthResult)
+        -> AuthState
+    {
+        match result {
+            AuthResult::Accepted => {
+                self.login_failures.remove(&*login.to_lowercase());
+                if self.is_online(&login) {
+                    self.take_over(&login);
+                }
+                proxy.send(format!("Welcome back, {}!\n", login));
+                self.login_user(proxy, login, false)
+            },
+            AuthResult::Created => {
+                // The invite is used up.
+                self.held_invites.remove(&proxy.get_id());
+                proxy.send(format!("Password created. Welcome, {}!\n", login));
+                self.login_user(proxy, login, false)
+            },
+            AuthResult::Rejected => {
+                self.handle_failure(&proxy, &login);
+                AuthState::Initial(proxy)
+            },
+        }
+    }
+
+    /// Takes the user's answer to an account command's prompt. An empty answer
+    /// cancels the command.
+    fn handle_management(&mut self, user: User, step: Management, data: Box<str>)
+        -> AuthState
+    {
+        if step != Management::ConfirmDelete {
+            user.socket.set_echo(true);
+        }
+        if data.is_empty() {
+            user.send_static("Cancelled.\n");
+            return AuthState::Ok(user);
+        }
+        match step {
+            Management::CurrentPassword | Management::DeletePassword => {
+                // Guesses made here count against the login as at the prompt,
+                // or an unattended terminal would allow any number of them.
+                if self.lockout_minutes(&user.login).is_some() {
+                    user.send_static("Too many wrong passwords; try again later.\n");
+                    return AuthState::Ok(user);
+                }
+                let id = user.socket.get_id();
+                let check = self.authenticator.verify(&user.login, &data);
+                self.await_reply(check, move |result| AuthReply::Verify(id, result));
+                AuthState::Checking(user, step)
+            },
+            Management::NewPassword => {
+                if let Some(weakness) = check_password(&user.login, &data) {
+                    user.send(format!("{} New password: ", weakness));
+                    user.socket.set_echo(false);
+                    AuthState::Managing(user, Management::NewPassword)
+                } else {
+                    let id = user.socket.get_id();
+                    let change = self.authenticator.set_password(&user.login, &data);
+                    self.await_reply(change, move |changed| AuthReply::SetPassword(id, changed));
+                    AuthState::SettingPassword(user)
+                }
+            },
+            Management::ConfirmDelete => {
+                if *data != *format!("delete {}", user.login) {
+                    user.send_static("Cancelled.\n");
+                    AuthState::Ok(user)
+                } else if self.authenticator.delete(&user.login) {
+                    self.user_ids.remove(&*user.login.to_lowercase());
+                    self.mail.clear(&user.login);
+                    self.invitations.remove(&*user.login.to_lowercase());
+                    info!(conn = user.socket.get_id(), login = user.login; "Account deleted");
+                    user.send_static("Your account has been deleted. Goodbye!\n");
+                    user.drop();
+                    AuthState::Ok(user)
+                } else {
+                    user.send_static("Accounts can't be deleted on this server.\n");
+                    AuthState::Ok(user)
+                }
+            },
+        }
+    }
+
+    /// Goes on to the next prompt of an account command once the password
+    /// given at its first one has been checked.
+    fn handle_check(&mut self, user: User, step: Management, result: AuthResult) -> AuthState {
+        match result {
+            AuthResult::Accepted => {
+                self.login_failures.remove(&*user.login.to_lowercase());
+            },
+            _ => {
+                if self.count_failure(user.socket.get_id(), &user.login) {
+                    user.send_static("Incorrect password. Too many failed attempts; goodbye!\n");
+                    user.drop();
+                } else {
+                    user.send_static("Incorrect password.\n");
+                }
+                return AuthState::Ok(user);
+            },
+        }
+        if step == Management::CurrentPassword {
+            user.send_static("New password: ");
+            user.socket.set_echo(false);
+            AuthState::Managing(user, Management::NewPassword)
+        } else {
+            user.send(format!("Type \"delete {}\" to delete your account for good: ",
+                              user.login));
+            AuthState::Managing(user, Management::ConfirmDelete)
+        }
+    }
+
+    fn login_user(&mut self, proxy: SocketProxy, login: Box<str>, guest: bool) -> AuthState {
+        self.logging_in.remove(&proxy.get_id());
+        proxy.set_idle_timeout(self.config.idle_timeouts().session);
+        let key: Box<str> = login.to_lowercase().into();
+        let id = match self.user_ids.get(&key) {
+            Some(&id) => id,
+            None => {
+                let id = UserId(self.next_user_id);
+                self.next_user_id += 1;
+                // Guests' nicknames are made up for the one session.
+                if !guest {
+                    self.user_ids.insert(key, id);
+                }
+                id
+            },
+        };
+        let settings = if guest {
+            UserSettings::new()
+        } else {
+            UserSettings::from_map(&self.authenticator.settings(&login))
+        };
+        if let Some(color) = settings.color {
+            proxy.set_color(color);
+        }
+        let user = User {
+            id,
+            login: login.clone(),
+            socket: proxy,
+            guest,
+            admin: !guest && self.config.is_admin(&login),
+            settings: Arc::new(RwLock::new(settings)),
+            settings_saver: if guest { None } else { Some(self.settings_sender.clone()) },
+            mailer: if guest { None } else { Some(self.mail_sender.clone()) },
+        };
+        self.online.insert(login.to_lowercase().into());
+        self.event_handler.unbounded_send(UserEvent::NewUser(user.clone()))
+            .expect("LoginService event_handler stream error");
+        if !guest {
+            self.deliver_mail(&user);
+            self.deliver_invitations(&user);
+        }
+        AuthState::Ok(user)
+    }
+
+    /// Shows the user the mail left for them since they were last here.
+    fn deliver_mail(&mut self, user: &User) {
+        let unread = self.mail.take_unread(&user.login);
+        if unread.is_empty() {
+            return;
+        }
+        user.send(format!("You got {} message(s) while you were away:\n", unread.len()));
+        for mail in &unread {
+            user.send_typed(MessageKind::Private, mail.render().into());
+        }
+        user.send_static("!mail shows them again, !mail clear deletes them.\n");
+    }
+
+    /// Tells the user who invited them to play while they were away.
+    fn deliver_invitations(&mut self, user: &User) {
+        if let Some(inviters) = self.invitations.remove(&*user.login.to_lowercase()) {
+            for inviter in inviters {
+                user.send(format!("{} invited you to play.\n", inviter));
+            }
+        }
+    }
+
+    /// Keeps the invitation for the login, which wasn't online, until it logs in.
+    fn invite(&mut self, user: &User, login: Box<str>) {
+        if let Some(other) = self.online_user(&login) {
+            // Logged in while the login was being looked up.
+            other.send(format!("{} invited you to play.\n", user.login));
+            user.send(format!("Invitation sent to {}.\n", login));
+            return;
+        }
+        let sent = self.invitations.values()
+            .filter(|inviters| inviters.contains(&user.login))
+            .count();
+        let inviters = self.invitations.entry(login.to_lowercase().into()).or_default();
+        if inviters.contains(&user.login) {
+            user.send(format!("You have already invited {}.\n", login));
+        } else if inviters.len() >= MAX_INVITATIONS {
+            user.send(format!("{} has too many pending invitations.\n", login));
+        } else if sent >= MAX_INVITATIONS_SENT {
+            user.send(format!("You have {} invitations waiting already; \
+                               wait for some of them to be seen.\n", sent));
+        } else {
+            inviters.push(user.login.clone());
+            user.send(format!("{} is offline and will get your invitation on their next \
+                               login.\n", login));
+        }
+    }
+
+    fn handle_mail_request(&mut self, request: MailRequest) {
+        match request {
+            MailRequest::Leave(user, recipients, text) => {
+                let lookups: Vec<_> = recipients.into_iter()
+                    .map(|login| {
+                        let lookup = self.authenticator.find_login(&login);
+                        (login, lookup)
+                    })
+                    .collect();
+                let mailer = self.mail_sender.clone();
+                #[allow(unused)] {
+                    runtime::spawn(async move {
+                        let mut found = Vec::new();
+                        for (login, lookup) in lookups {
+                            found.push(lookup.await.ok_or(login));
+                        }
+                        let _ = mailer.unbounded_send(MailRequest::Post(user, found, text));
+                    });
+                }
+            },
+            MailRequest::Post(user, recipients, text) => {
+                let mut unknown_logins = vec![];
+                for login in recipients {
+                    let login = match login {
+                        Ok(login) => login,
+                        Err(login) => {
+                            unknown_logins.push(login);
+                            continue;
+                        },
+                    };
+                    // The sender isn't told, so that ignoring someone stays private.
+                    let settings = UserSettings::from_map(&self.authenticator.settings(&login));
+                    let result = if settings.ignores(&user.login) {
+                        Ok(())
+                    } else {
+                        self.mail.leave(&user.login, &login, &text)
+                    };
+                    match result {
+                        Ok(()) => user.send(format!("{} is offline and will get your message \
+                                                     on logging in.\n", login)),
+                        Err(reason) => user.send(format!("Can't leave mail for {}: {}.\n",
+                                                         login, reason)),
+                    }
+                }
+                if !unknown_logins.is_empty() {
+                    user.send(format!("Unknown user(s): {}\n", unknown_logins.join(", ")));
+                }
+            },
+            MailRequest::Review(user) => {
+                let mailbox: String = self.mail.mailbox(&user.login).iter()
+                    .map(|mail| mail.render())
+                    .collect();
+                if mailbox.is_empty() {
+                    user.send_static("You have no mail.\n");
+                } else {
+                    user.send_typed(MessageKind::Private, mailbox.into());
+                    self.mail.take_unread(&user.login);
+                }
+            },
+            MailRequest::Clear(user) => match self.mail.clear(&user.login) {
+                0 => user.send_static("You have no mail.\n"),
+                count => user.send(format!("Deleted {} message(s).\n", count)),
+            },
+            MailRequest::Invite(user, login) => {
+                let lookup = self.authenticator.find_login(&login);
+                let mailer = self.mail_sender.clone();
+                #[allow(unused)] {
+                    runtime::spawn(async move {
+                        let found = lookup.await.ok_or(login);
+                        let _ = mailer.unbounded_send(MailRequest::PostInvite(user, found));
+                    });
+                }
+            },
+            MailRequest::PostInvite(user, Ok(login)) => self.invite(&user, login),
+            MailRequest::PostInvite(user, Err(login)) =>
+                user.send(format!("There is no player called {}.\n", login)),
+        }
+    }
+
+    /// A `Guest-<number>` nickname nobody online has.
+    fn guest_login(&mut self) -> Box<str> {
+        loop {
+            let login = format!("Guest-{}", 1000 + self.rng.below(9000));
+            if !self.is_online(&login) {
+                return login.into();
+            }
+        }
+    }
+
+    /// The user online with the login, if there is one.
+    fn online_user(&self, login: &str) -> Option<&User> {
+        let key = login.to_lowercase();
+        self.auth_state.values()
+            .filter_map(AuthState::user)
+            .find(|user| user.login.to_lowercase() == key)
+    }
+
+    /// Logs out the session the login is online with, so that a new
+    /// connection can log in with it.
+    fn take_over(&mut self, login: &str) {
+        let key = login.to_lowercase();
+        let id = self.auth_state.iter()
+            .filter_map(|(&id, state)| match state.user() {
+                Some(user) if user.login.to_lowercase() == key => Some(id),
+                _ => None,
+            })
+            .next();
+        let user = id.and_then(|id| self.auth_state.remove(&id)).and_then(AuthState::into_user);
+        if let Some(user) = user {
+            info!(conn = user.socket.get_id(), login = login; "Session taken over");
+            self.online.remove(&*key);
+            self.event_handler.unbounded_send(UserEvent::DropUser(user.id))
+                .expect("LoginService event_handler stream error");
+            user.send_static("Your session has been taken over from another connection.\n");
+            user.drop();
+        }
+    }
+
+    /// Counts a wrong password against both the connection and the login.
+    /// Connections that keep guessing are closed; logins that keep being
+    /// guessed at are locked for a while.
+    fn handle_failure(&mut self, proxy: &SocketProxy, login: &str) {
+        if self.count_failure(proxy.get_id(), login) {
+            proxy.send_static("Incorrect password. Too many failed attempts; goodbye!\n");
+            proxy.close();
+        } else {
+            proxy.send_static("Incorrect password.\nPlease enter your nickname: ");
+        }
+    }
+
+    /// Counts a wrong password against the login, which is locked once it has
+    /// had too many, and against the connection. Returns whether the
+    /// connection has used up its tries and should be closed.
+    fn count_failure(&mut self, id: SocketId, login: &str) -> bool {
+        let key: Box<str> = login.to_lowercase().into();
+        let login_failures = self.login_failures.entry(key.clone()).or_insert(0);
+        *login_failures += 1;
+        if *login_failures >= MAX_FAILURES_PER_LOGIN {
+            self.login_failures.remove(&key);
+            warn!(login = login; "Locking after {} wrong passwords", MAX_FAILURES_PER_LOGIN);
+            self.locked.insert(key, Instant::now() + LOCKOUT);
+        }
+        let connection_failures = self.connection_failures.entry(id).or_insert(0);
+        *connection_failures += 1;
+        *connection_failures >= MAX_FAILURES_PER_CONNECTION
+    }
+
+    /// How many more minutes the login is locked for, if it is.
+    fn lockout_minutes(&mut self, login: &str) -> Option<u64> {
+        let key = login.to_lowercase();
+        let until = *self.locked.get(&*key)?;
+        let now = Instant::now();
+        if until <= now {
+            self.locked.remove(&*key);
+            return None;
+        }
+        Some((until - now).as_secs() / 60 + 1)
+    }
+
+    fn is_online(&self, login: &str) -> bool {
+        self.online.contains(&*login.to_lowercase())
+    }
+
     fn handle_closed_socket(&mut self, id: SocketId) {
-        if let Some(AuthState::Ok(user)) = self.auth_state.remove(&id) {
-            if let Some(LoginState::Online(password)) = self.login_state.remove(&user.login) {
-                self.login_state.insert(user.login, LoginState::Offline(password));
+        self.connection_failures.remove(&id);
+        self.logging_in.remove(&id);
+        if let (Some(token), Some(invites)) = (self.held_invites.remove(&id), &mut self.invites) {
+            invites.put_back(token);
+        }
+        if let Some(user) = self.auth_state.remove(&id).and_then(AuthState::into_user) {
+            if self.online.remove(&*user.login.to_lowercase()) {
                 self.event_handler.unbounded_send(UserEvent::DropUser(user.id))
                     .expect("LoginService event_handler stream error");
             } else {
@@ -163,7 +939,93 @@ impl LoginService {
     }
 }
 
+/// Checks that a login is fit for chat: `+login` and `!!login` must be able to
+/// name it, and nobody should pass for the server or a bot.
+fn check_login(login: &str) -> Result<(), LoginError> {
+    let lowercase = login.to_lowercase();
+    if login.chars().count() < MIN_LOGIN {
+        Err(LoginError::TooShort)
+    } else if login.chars().count() > MAX_LOGIN {
+        Err(LoginError::TooLong)
+    } else if !login.starts_with(|c: char| c.is_ascii_alphabetic())
+        || !login.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        Err(LoginError::BadCharacter)
+    } else if bot::is_bot_login(&lowercase) {
+        Err(LoginError::BotName)
+    } else if RESERVED_LOGINS.contains(&lowercase.as_str()) || is_guest_login(&lowercase) {
+        Err(LoginError::Reserved)
+    } else {
+        Ok(())
+    }
+}
+
+/// What's wrong with a new account's password, if anything.
+fn check_password(login: &str, password: &str) -> Option<&'static str> {
+    if password.chars().count() < MIN_PASSWORD {
+        Some("Passwords must be at least 8 characters long.")
+    } else if password.to_lowercase().contains(&*login.to_lowercase()) {
+        Some("Passwords can't contain the nickname.")
+    } else if password.chars().all(char::is_alphabetic)
+        || !password.chars().any(char::is_alphabetic)
+    {
+        Some("Passwords must mix letters with digits or symbols.")
+    } else {
+        None
+    }
+}
+
+impl AuthState {
+    fn user(&self) -> Option<&User> {
+        match self {
+            AuthState::Ok(user)
+            | AuthState::Managing(user, _)
+            | AuthState::Checking(user, _)
+            | AuthState::SettingPassword(user) => Some(user),
+            _ => None,
+        }
+    }
+
+    fn into_user(self) -> Option<User> {
+        match self {
+            AuthState::Ok(user)
+            | AuthState::Managing(user, _)
+            | AuthState::Checking(user, _)
+            | AuthState::SettingPassword(user) => Some(user),
+            _ => None,
+        }
+    }
+}
+
+/// Guests get logins of the form `guest-<number>`, in any case.
+fn is_guest_login(lowercase: &str) -> bool {
+    lowercase.starts_with("guest-") && lowercase[6..].chars().all(|c| c.is_ascii_digit())
+}
+
 impl User {
+    /// A user with no connection behind it, for bots.
+    pub fn detached(id: UserId, login: Box<str>) -> Self {
+        User {
+            id,
+            login,
+            socket: SocketProxy::detached(),
+            guest: false,
+            admin: false,
+            settings: Arc::new(RwLock::new(UserSettings::new())),
+            settings_saver: None,
+            mailer: None,
+        }
+    }
+
+    /// A user with an account, logged in on the socket, for tests.
+    #[cfg(test)]
+    pub fn connected(id: UserId, login: Box<str>, socket: SocketProxy) -> Self {
+        User {
+            socket,
+            ..User::detached(id, login)
+        }
+    }
+
     pub fn get_id(&self) -> UserId {
         self.id
     }
@@ -172,6 +1034,19 @@ impl User {
         &self.login
     }
 
+    pub fn is_guest(&self) -> bool {
+        self.guest
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.admin
+    }
+
+    /// The address the user is connected from, unless they are local.
+    pub fn address(&self) -> Option<IpAddr> {
+        self.socket.get_id().ip()
+    }
+
     pub fn send(&self, message: String) {
         self.socket.send(message)
     }
@@ -188,7 +1063,274 @@ impl User {
         self.socket.send_static(message)
     }
 
+    pub fn send_typed(&self, kind: MessageKind, message: Arc<str>) {
+        self.socket.send_typed(kind, message)
+    }
+
+    pub fn set_color(&self, color: bool) {
+        self.socket.set_color(color)
+    }
+
+    pub fn settings(&self) -> UserSettings {
+        self.settings.read().expect("User settings lock poisoned").clone()
+    }
+
+    /// Whether the user has `login` on their ignore list.
+    pub fn ignores(&self, login: &str) -> bool {
+        self.settings.read().expect("User settings lock poisoned").ignores(login)
+    }
+
+    /// Changes the user's settings and saves them with their account, if
+    /// they have one.
+    pub fn update_settings<F: FnOnce(&mut UserSettings)>(&self, update: F) {
+        let mut settings = self.settings.write().expect("User settings lock poisoned");
+        update(&mut settings);
+        if let Some(saver) = &self.settings_saver {
+            saver.unbounded_send(SettingsUpdate(self.login.clone(), settings.clone()))
+                .expect("User settings_saver failed");
+        }
+    }
+
+    /// Leaves a message for users who aren't online. Those without an
+    /// account are reported to the user.
+    pub fn leave_mail(&self, recipients: Vec<Box<str>>, text: &str) {
+        self.request_mail(MailRequest::Leave(self.clone(), recipients, text.into()));
+    }
+
+    /// Shows the user their mail.
+    pub fn review_mail(&self) {
+        self.request_mail(MailRequest::Review(self.clone()));
+    }
+
+    /// Deletes the user's mail.
+    pub fn clear_mail(&self) {
+        self.request_mail(MailRequest::Clear(self.clone()));
+    }
+
+    /// Invites a login that isn't online to play, once it logs in. Logins
+    /// without an account are reported to the user.
+    pub fn invite(&self, login: Box<str>) {
+        match &self.mailer {
+            Some(mailer) => mailer.unbounded_send(MailRequest::Invite(self.clone(), login))
+                .expect("User mailer failed"),
+            None => self.send_static("Guests can only invite players who are online.\n"),
+        }
+    }
+
+    fn request_mail(&self, request: MailRequest) {
+        match &self.mailer {
+            Some(mailer) => mailer.unbounded_send(request).expect("User mailer failed"),
+            None => self.send_static("Only users with an account have mail.\n"),
+        }
+    }
+
     pub fn drop(&self) {
         self.socket.close()
     }
 }
+
+impl fmt::Display for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::socket_service::Outbox;
+
+    use futures::{
+        prelude::*,
+        channel::oneshot,
+        executor::block_on,
+        future,
+    };
+
+    const ACCOUNTS: &[&str] = &["Alice", "Bob"];
+
+    type Check = (Box<str>, oneshot::Sender<AuthResult>);
+
+    /// Knows the `ACCOUNTS`, and leaves it to the test when each password
+    /// check finishes and how.
+    struct MockAuthenticator {
+        checks: UnboundedSender<Check>,
+    }
+
+    impl Authenticator for MockAuthenticator {
+        fn is_registered(&self, login: &str) -> BoxFuture<'static, bool> {
+            let registered = ACCOUNTS.iter().any(|account| account.eq_ignore_ascii_case(login));
+            future::ready(registered).boxed()
+        }
+
+        fn find_login(&self, login: &str) -> BoxFuture<'static, Option<Box<str>>> {
+            let found = ACCOUNTS.iter()
+                .find(|account| account.eq_ignore_ascii_case(login))
+                .map(|&account| account.into());
+            future::ready(found).boxed()
+        }
+
+        fn verify(&mut self, login: &str, _credential: &str) -> BoxFuture<'static, AuthResult> {
+            let (sender, receiver) = oneshot::channel();
+            let _ = self.checks.unbounded_send((login.into(), sender));
+            receiver.map(|result| result.unwrap_or(AuthResult::Rejected)).boxed()
+        }
+    }
+
+    /// A `LoginService` whose loop is run by hand.
+    struct Harness {
+        service: LoginService,
+        events: UnboundedReceiver<UserEvent>,
+        checks: UnboundedReceiver<Check>,
+    }
+
+    impl Harness {
+        fn new() -> Self {
+            let (event_sender, events) = unbounded();
+            let (check_sender, checks) = unbounded();
+            let config = Config::default();
+            let service = LoginService::new(event_sender,
+                                            Box::new(MockAuthenticator{checks: check_sender}),
+                                            Locale::En, config.live_config(None, &[]),
+                                            Motd::new(None), None, BanList::load(None).unwrap(),
+                                            MailStore::load(None).unwrap());
+            Harness{service, events, checks}
+        }
+
+        fn connect(&mut self, number: u64) -> (SocketId, Outbox) {
+            let (proxy, outbox) = SocketProxy::numbered(number);
+            let id = proxy.get_id();
+            self.service.handle_new_socket(proxy);
+            (id, outbox)
+        }
+
+        /// Types the nickname and the password. Returns the password check,
+        /// which is left to the test.
+        fn enter(&mut self, id: SocketId, login: &str) -> Check {
+            self.service.handle_new_message(id, login.into());
+            self.next_reply();
+            self.service.handle_new_message(id, "secret-123".into());
+            block_on(self.checks.next()).unwrap()
+        }
+
+        /// Hands the service the authenticator's next answer, as its loop would.
+        fn next_reply(&mut self) {
+            let reply = block_on(self.service.auth_receiver.next()).unwrap();
+            self.service.handle_auth_reply(reply);
+        }
+
+        /// Hands the service the next mail request, as its loop would.
+        fn next_mail_request(&mut self) {
+            let request = block_on(self.service.mail_receiver.next()).unwrap();
+            self.service.handle_mail_request(request);
+        }
+
+        fn logged_in(&mut self) -> User {
+            match block_on(self.events.next()) {
+                Some(UserEvent::NewUser(user)) => user,
+                _ => panic!("expected a new user"),
+            }
+        }
+
+        fn log_in(&mut self, number: u64, login: &str) -> (User, Outbox) {
+            let (id, outbox) = self.connect(number);
+            let (_, check) = self.enter(id, login);
+            assert!(check.send(AuthResult::Accepted).is_ok());
+            self.next_reply();
+            (self.logged_in(), outbox)
+        }
+
+        fn is_verifying(&self, id: SocketId) -> bool {
+            match self.service.auth_state.get(&id) {
+                Some(AuthState::Verifying(..)) => true,
+                _ => false,
+            }
+        }
+    }
+
+    #[test]
+    fn slow_check_holds_up_only_its_connection() {
+        let mut harness = Harness::new();
+        let (alice, _alice_outbox) = harness.connect(1);
+        let (bob, _bob_outbox) = harness.connect(2);
+
+        let (login, alice_check) = harness.enter(alice, "alice");
+        assert_eq!(&*login, "Alice");
+        assert!(harness.is_verifying(alice));
+
+        let (login, bob_check) = harness.enter(bob, "bob");
+        assert_eq!(&*login, "Bob");
+        assert!(bob_check.send(AuthResult::Accepted).is_ok());
+        harness.next_reply();
+        assert_eq!(harness.logged_in().get_login(), "Bob");
+        assert!(harness.is_verifying(alice));
+
+        assert!(alice_check.send(AuthResult::Accepted).is_ok());
+        harness.next_reply();
+        assert_eq!(harness.logged_in().get_login(), "Alice");
+    }
+
+    #[test]
+    fn input_while_verifying_is_ignored() {
+        let mut harness = Harness::new();
+        let (id, _outbox) = harness.connect(1);
+        let (_, check) = harness.enter(id, "alice");
+        harness.service.handle_new_message(id, "hello?".into());
+        assert!(harness.is_verifying(id));
+
+        assert!(check.send(AuthResult::Accepted).is_ok());
+        harness.next_reply();
+        assert_eq!(harness.logged_in().get_login(), "Alice");
+    }
+
+    #[test]
+    fn new_password_is_stored_off_the_loop() {
+        let mut harness = Harness::new();
+        let (user, mut outbox) = harness.log_in(1, "alice");
+        let id = user.socket.get_id();
+        harness.service.handle_new_message(id, "!passwd".into());
+        harness.service.handle_new_message(id, "secret-123".into());
+        let (_, check) = block_on(harness.checks.next()).unwrap();
+        assert!(check.send(AuthResult::Accepted).is_ok());
+        harness.next_reply();
+        assert!(outbox.text().ends_with("New password: "));
+
+        harness.service.handle_new_message(id, "better-456".into());
+        match harness.service.auth_state.get(&id) {
+            Some(AuthState::SettingPassword(_)) => (),
+            _ => panic!("expected the password to be being set"),
+        }
+        harness.next_reply();
+        assert!(outbox.text().contains("Passwords can't be changed"));
+    }
+
+    #[test]
+    fn offline_invitation_is_delivered_on_login() {
+        let mut harness = Harness::new();
+        let (alice, mut alice_outbox) = harness.log_in(1, "alice");
+        alice.invite("bob".into());
+        harness.next_mail_request();
+        harness.next_mail_request();
+        assert!(alice_outbox.text().contains("Bob is offline and will get your invitation"));
+
+        alice.invite("BOB".into());
+        harness.next_mail_request();
+        harness.next_mail_request();
+        assert!(alice_outbox.text().contains("You have already invited Bob."));
+
+        let (_, mut bob_outbox) = harness.log_in(2, "bob");
+        assert!(bob_outbox.text().contains("Alice invited you to play."));
+    }
+
+    #[test]
+    fn invitation_for_unknown_login_is_refused() {
+        let mut harness = Harness::new();
+        let (alice, mut alice_outbox) = harness.log_in(1, "alice");
+        alice.invite("nobody".into());
+        harness.next_mail_request();
+        harness.next_mail_request();
+        assert!(alice_outbox.text().contains("There is no player called nobody."));
+        assert!(harness.service.invitations.is_empty());
+    }
+}
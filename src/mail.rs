@@ -0,0 +1,158 @@
+//! Private messages left for users who were offline, kept by account until the
+//! user clears them. They are kept in a file, one JSON object per line, which
+//! is written again on every change.
+
+use crate::json::{self, quote};
+
+use chrono::prelude::*;
+
+use std::{
+    collections::HashMap,
+    fs,
+    io,
+    path::PathBuf,
+};
+
+/// Messages a mailbox holds at most; more are turned away until it's cleared.
+const MAILBOX_SIZE: usize = 50;
+
+#[derive(Clone)]
+pub struct Mail {
+    pub from: Box<str>,
+    /// The recipient's login, as their account spells it.
+    pub to: Box<str>,
+    pub sent: DateTime<Utc>,
+    pub text: Box<str>,
+    /// Shown to the recipient already.
+    pub read: bool,
+}
+
+pub struct MailStore {
+    path: Option<PathBuf>,
+    /// By lowercased login, oldest first.
+    mailboxes: HashMap<Box<str>, Vec<Mail>>,
+}
+
+impl MailStore {
+    /// Reads the mail from `path`, if there is one; a missing file means there
+    /// is none yet. Without a path, mail only lasts until the server stops.
+    pub fn load(path: Option<PathBuf>) -> io::Result<Self> {
+        let mut mailboxes: HashMap<Box<str>, Vec<Mail>> = HashMap::new();
+        if let Some(path) = &path {
+            for mail in read_mail(path)? {
+                mailboxes.entry(mail.to.to_lowercase().into()).or_default().push(mail);
+            }
+        }
+        Ok(MailStore {
+            path,
+            mailboxes,
+        })
+    }
+
+    pub fn leave(&mut self, from: &str, to: &str, text: &str) -> Result<(), &'static str> {
+        let mailbox = self.mailboxes.entry(to.to_lowercase().into()).or_default();
+        if mailbox.len() >= MAILBOX_SIZE {
+            return Err("mailbox is full");
+        }
+        mailbox.push(Mail {
+            from: from.into(),
+            to: to.into(),
+            sent: Utc::now(),
+            text: text.into(),
+            read: false,
+        });
+        self.save();
+        Ok(())
+    }
+
+    /// All of the user's mail, read or not.
+    pub fn mailbox(&self, login: &str) -> &[Mail] {
+        self.mailboxes.get(&*login.to_lowercase()).map(|mailbox| &mailbox[..]).unwrap_or(&[])
+    }
+
+    /// The mail the user hasn't seen yet, which from now on counts as read.
+    pub fn take_unread(&mut self, login: &str) -> Vec<Mail> {
+        let unread: Vec<Mail> = match self.mailboxes.get_mut(&*login.to_lowercase()) {
+            Some(mailbox) => mailbox.iter_mut()
+                .filter(|mail| !mail.read)
+                .map(|mail| {
+                    mail.read = true;
+                    mail.clone()
+                })
+                .collect(),
+            None => return Vec::new(),
+        };
+        if !unread.is_empty() {
+            self.save();
+        }
+        unread
+    }
+
+    /// Throws away the user's mail. Returns how much there was.
+    pub fn clear(&mut self, login: &str) -> usize {
+        let count = self.mailboxes.remove(&*login.to_lowercase())
+            .map_or(0, |mailbox| mailbox.len());
+        if count > 0 {
+            self.save();
+        }
+        count
+    }
+
+    fn save(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let mut mail: Vec<&Mail> = self.mailboxes.values().flatten().collect();
+        mail.sort_by_key(|mail| mail.sent);
+        let contents: String = mail.iter()
+            .map(|mail| format!("{{\"from\":{},\"to\":{},\"sent\":{},\"read\":{},\"text\":{}}}\n",
+                                quote(&mail.from), quote(&mail.to), quote(&mail.sent.to_rfc3339()),
+                                mail.read, quote(&mail.text)))
+            .collect();
+        if let Err(err) = fs::write(path, contents) {
+            error!("Failed to save mail to {}: {}", path.display(), err);
+        }
+    }
+}
+
+impl Mail {
+    /// The message as the recipient sees it, with the date it was sent.
+    pub fn render(&self) -> String {
+        format!("[{}] [{}]->[{}] {}\n",
+                self.sent.format("%Y-%m-%d %H:%M UTC"), self.from, self.to, self.text)
+    }
+}
+
+fn read_mail(path: &PathBuf) -> io::Result<Vec<Mail>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let mut mail = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_mail(line) {
+            Some(parsed) => mail.push(parsed),
+            None => warn!("Skipping malformed mail on line {} of {}", number + 1, path.display()),
+        }
+    }
+    Ok(mail)
+}
+
+fn parse_mail(line: &str) -> Option<Mail> {
+    let fields = json::parse_object(line)?;
+    let field = |name: &str| fields.iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str());
+    Some(Mail {
+        from: field("from")?.into(),
+        to: field("to")?.into(),
+        sent: DateTime::parse_from_rfc3339(field("sent")?).ok()?.with_timezone(&Utc),
+        text: field("text")?.into(),
+        read: field("read")? == "true",
+    })
+}
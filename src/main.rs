@@ -3,18 +3,60 @@
 #![feature(slice_partition_dedup)]
 #![recursion_limit="128"]
 
+#[macro_use]
+mod logging;
 mod game_service;
 mod chat_service;
+mod chat_log;
 mod login_service;
 mod socket_service;
 mod locale;
+mod roles;
+mod rules;
+mod game_log;
+mod narration;
+mod auth;
+mod accounts;
+mod settings;
+mod stats;
+mod rating;
+mod odds;
+mod replay;
+mod json;
+mod protocol;
+mod ansi;
+mod websocket;
+mod tls;
+mod telnet;
+mod proxy_protocol;
+mod motd;
+mod invites;
+mod bans;
+mod mail;
+mod admin_console;
+mod health;
+mod config;
+mod metrics;
+mod bot;
+mod tournament;
 mod util;
 
 use game_service::GameService;
 use chat_service::ChatService;
+use chat_log::ChatLog;
 use login_service::LoginService;
-use socket_service::SocketService;
-use locale::Locale;
+use socket_service::{SocketService, ListenConfig};
+use config::Config;
+use motd::Motd;
+use invites::Invites;
+use bans::BanList;
+use mail::MailStore;
+use admin_console::{AdminConsole, Control, Handlers};
+use health::{HealthServer, Probes};
+use auth::PasswordDb;
+use accounts::{AccountStore, MemoryAccounts, SqliteAccounts};
+use stats::MemoryStats;
+use replay::ReplayRecorder;
 
 use futures::{
     select,
@@ -22,35 +64,221 @@ use futures::{
     channel::mpsc::unbounded,
 };
 
+use runtime::net::TcpListener;
+
 use std::{
     net::IpAddr,
+    path::PathBuf,
     process::exit,
+    thread,
 };
 
+use signal_hook::{SIGHUP, SIGUSR1, iterator::Signals};
+
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+#[structopt(name = "mafia", about = "A server for playing Mafia over telnet.")]
 struct Args {
-    address: IpAddr,
-    port: u16,
-    locale: Locale,
+    /// TOML file to read the configuration from.
+    #[structopt(short = "c", long = "config", parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Address to listen on, in place of the configured ones.
+    #[structopt(long = "address")]
+    address: Option<IpAddr>,
+    /// Port to listen on, in place of the configured ones.
+    #[structopt(long = "port")]
+    port: Option<u16>,
+    /// Language of the server's messages.
+    #[structopt(long = "locale", raw(possible_values = r#"&["en", "ru"]"#))]
+    locale: Option<String>,
+    /// How much to log.
+    #[structopt(long = "log-level",
+                raw(possible_values = r#"&["error", "warn", "info", "debug"]"#))]
+    log_level: Option<String>,
+    /// File to log to, in place of stderr.
+    #[structopt(long = "log-file", parse(from_os_str))]
+    log_file: Option<PathBuf>,
+    /// Log as text, or as JSON lines.
+    #[structopt(long = "log-format", raw(possible_values = r#"&["text", "json"]"#))]
+    log_format: Option<String>,
+    /// Directory that relative paths in the configuration are taken from.
+    #[structopt(long = "data-dir", parse(from_os_str))]
+    data_dir: Option<PathBuf>,
+    /// Sets any configuration key, as in the file: -o limits.total=100.
+    #[structopt(short = "o", number_of_values = 1)]
+    overrides: Vec<String>,
 }
 
 impl Args {
-    fn parse() -> Self {
-        Args {
-            address: [127, 0, 0, 1].into(),
-            port: 8080,
-            locale: Locale::En,
+    /// The options that stand for configuration keys, as `-o` lines, so that
+    /// they still hold when the configuration is reloaded.
+    fn overrides(&self) -> Vec<String> {
+        let mut overrides = self.overrides.clone();
+        let options = [("locale", self.locale.clone()),
+                       ("log.level", self.log_level.clone()),
+                       ("log.file", self.log_file.as_ref().map(|file| file.display().to_string())),
+                       ("log.format", self.log_format.clone()),
+                       ("data_dir", self.data_dir.as_ref().map(|dir| dir.display().to_string()))];
+        for (key, value) in options.iter() {
+            if let Some(value) = value {
+                overrides.push(format!("{}={}", key, toml::Value::String(value.clone())));
+            }
         }
+        overrides
     }
 }
 
 #[runtime::main]
 async fn main() -> std::io::Result<()> {
-    let args = Args::parse();
-    let game_service = GameService::new(args.locale);
-    let chat_service = ChatService::new(game_service.make_event_handler(), args.locale);
-    let login_service = LoginService::new(chat_service.make_user_handler(), args.locale);
+    let args = Args::from_args();
+    let config_path = args.config.as_ref().map(PathBuf::as_path);
+    let overrides = args.overrides();
+    let mut config = match Config::load(config_path, &overrides) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("Failed to load the configuration: {}.", err);
+            exit(1);
+        },
+    };
+    config.listen_on(args.address, args.port);
+    logging::configure(&config.log);
+    let live_config = config.live_config(config_path, &overrides);
+    let replay_chat = config.replay_chat;
+    let recorder = config.replay_dir.map(|dir| ReplayRecorder::new(dir, replay_chat));
+    let mut game_service = GameService::new(config.locale, config.rules,
+                                            Box::new(MemoryStats::new()), recorder);
+    let motd = Motd::new(config.motd);
+    let bans = match BanList::load(config.ban_list) {
+        Ok(bans) => bans,
+        Err(err) => {
+            error!("Failed to load bans: {}.", err);
+            exit(1);
+        },
+    };
+    let chat_log_private = config.chat_log_private;
+    let chat_log = config.chat_log_dir.map(|dir| ChatLog::new(dir, chat_log_private));
+    let chat_service = ChatService::new(game_service.make_event_handler(),
+                                        config.graveyard_policy, config.locale, motd.clone(),
+                                        bans.clone(), chat_log);
+    game_service.connect_bots(chat_service.make_user_handler());
+    let store: Box<dyn AccountStore> = match &config.accounts_db {
+        Some(path) => match SqliteAccounts::open(path) {
+            Ok(store) => Box::new(store),
+            Err(err) => {
+                error!("Failed to open the account database: {}.", err);
+                exit(1);
+            },
+        },
+        None => Box::new(MemoryAccounts),
+    };
+    let password_db = match PasswordDb::new(store) {
+        Ok(db) => db,
+        Err(err) => {
+            error!("Failed to load accounts: {}.", err);
+            exit(1);
+        },
+    };
+    let invites = match config.invite_tokens {
+        Some(path) => match Invites::load(path) {
+            Ok(invites) => Some(invites),
+            Err(err) => {
+                error!("Failed to load invite tokens: {}.", err);
+                exit(1);
+            },
+        },
+        None => None,
+    };
+    let mail = match MailStore::load(config.mail) {
+        Ok(mail) => mail,
+        Err(err) => {
+            error!("Failed to load mail: {}.", err);
+            exit(1);
+        },
+    };
+    let login_service = LoginService::new(chat_service.make_user_handler(),
+                                         Box::new(password_db), config.locale,
+                                         live_config.clone(), motd.clone(), invites,
+                                         bans.clone(), mail);
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => match tls::load_acceptor(cert, key) {
+            Ok(acceptor) => Some(acceptor),
+            Err(err) => {
+                error!("Failed to set up TLS: {}.", err);
+                exit(1);
+            },
+        },
+        (None, None) => None,
+        _ => {
+            error!("TLS needs both tls_cert and tls_key.");
+            exit(1);
+        },
+    };
+    if config.proxy_protocol && config.trusted_proxies.is_empty() {
+        error!("The PROXY protocol needs trusted_proxies, the addresses of the proxies.");
+        exit(1);
+    }
+    let listen_config = ListenConfig {
+        listen: config.listen,
+        json_port: config.json_port,
+        ws_port: config.ws_port,
+        unix_socket: config.unix_socket,
+        proxy_protocol: config.proxy_protocol,
+        trusted_proxies: config.trusted_proxies,
+    };
     let socket_service = SocketService::new(login_service.make_socket_handler(),
-                                            args.address, args.port);
+                                            listen_config, tls, live_config.clone(),
+                                            bans.clone());
+    let socket_metrics = socket_service.metrics();
+
+    let (control_sender, mut control_receiver) = unbounded();
+    if let Some(address) = config.admin_console {
+        let token = match &config.admin_token {
+            Some(token) if !token.is_empty() => token,
+            _ => {
+                error!("The admin console needs an admin token.");
+                exit(1);
+            },
+        };
+        let listener = match TcpListener::bind(address) {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("Failed to start the admin console: {}.", err);
+                exit(1);
+            },
+        };
+        info!("Admin console listening on {}", listener.local_addr()?);
+        let handlers = Handlers {
+            sockets: socket_service.make_query_handler(),
+            logins: login_service.make_query_handler(),
+            game: game_service.make_query_handler(),
+            control: control_sender.clone(),
+        };
+        #[allow(unused)] {
+            runtime::spawn(AdminConsole::new(listener, token, handlers).run());
+        }
+    }
+
+    if let Some(address) = config.health {
+        let listener = match TcpListener::bind(address) {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("Failed to start the health checks: {}.", err);
+                exit(1);
+            },
+        };
+        info!("Health checks listening on {}", listener.local_addr()?);
+        let probes = Probes {
+            services: vec![("socket service", socket_service.make_query_handler()),
+                           ("login service", login_service.make_query_handler()),
+                           ("chat service", chat_service.make_query_handler()),
+                           ("game service", game_service.make_query_handler())],
+            metrics: socket_metrics.clone(),
+        };
+        #[allow(unused)] {
+            runtime::spawn(HealthServer::new(listener, probes).run());
+        }
+    }
 
     let mut socket_task = runtime::spawn(socket_service.run()).fuse();
     let mut login_task = runtime::spawn(login_service.run()).fuse();
@@ -62,30 +290,61 @@ async fn main() -> std::io::Result<()> {
         ctrlc_sender.unbounded_send(()).expect("Error sending Ctrl-C event");
     }).expect("Error setting Ctrl-C handler");
 
-    select! {
-        res = socket_task => {
-            if let Err(err) = res {
-                eprintln!("Socket service failed: {}.", err);
-            } else {
-                eprintln!("Socket service exited unexpectedly.");
-            }
-            exit(1)
-        },
-        _ = login_task => {
-            eprintln!("Login service exited unexpectedly.");
-            exit(1);
-        },
-        _ = chat_task => {
-            eprintln!("Chat service exited unexpectedly.");
-            exit(1);
-        },
-        _ = game_task => {
-            eprintln!("Game service exited unexpectedly.");
-            exit(1);
-        },
-        _ = ctrlc_receiver.next().fuse() => {
-            eprintln!("User-requested shutdown.");
-            exit(0);
-        },
+    let (signal_sender, mut signal_receiver) = unbounded();
+    let signals = Signals::new(&[SIGHUP, SIGUSR1])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            signal_sender.unbounded_send(signal).expect("Error sending signal event");
+        }
+    });
+
+    loop {
+        select! {
+            res = socket_task => {
+                if let Err(err) = res {
+                    error!("Socket service failed: {}.", err);
+                } else {
+                    error!("Socket service exited unexpectedly.");
+                }
+                exit(1)
+            },
+            _ = login_task => {
+                error!("Login service exited unexpectedly.");
+                exit(1);
+            },
+            _ = chat_task => {
+                error!("Chat service exited unexpectedly.");
+                exit(1);
+            },
+            _ = game_task => {
+                error!("Game service exited unexpectedly.");
+                exit(1);
+            },
+            _ = ctrlc_receiver.next().fuse() => {
+                info!("User-requested shutdown.");
+                exit(0);
+            },
+            control = control_receiver.next().fuse() => match control {
+                Some(Control::Reload) => {
+                    live_config.reload();
+                    motd.reload();
+                    bans.reload();
+                },
+                Some(Control::Shutdown) => {
+                    info!("Shutdown requested from the admin console.");
+                    exit(0);
+                },
+                None => (),
+            },
+            signal = signal_receiver.next().fuse() => match signal {
+                Some(SIGHUP) => {
+                    live_config.reload();
+                    motd.reload();
+                    bans.reload();
+                },
+                Some(SIGUSR1) => info!("{}", socket_metrics.report()),
+                _ => (),
+            },
+        }
     }
 }
@@ -0,0 +1,59 @@
+//! Counters for keeping an eye on the server while it runs. Operators get a
+//! report in the log by sending the server SIGUSR1.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn add(&self, amount: u64) {
+        self.0.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    pub fn increment(&self) {
+        self.add(1);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Network health, as `SocketService` sees it.
+#[derive(Default)]
+pub struct SocketMetrics {
+    /// Set once every listener is bound.
+    listening: AtomicBool,
+    pub accepted: Counter,
+    /// Turned away for going over the connection limits.
+    pub rejected: Counter,
+    pub closed: Counter,
+    /// Closed by the server: idle, unresponsive, too slow to read, or sending garbage.
+    pub forced_closes: Counter,
+    pub bytes_read: Counter,
+    pub bytes_written: Counter,
+    pub read_errors: Counter,
+}
+
+impl SocketMetrics {
+    pub fn set_listening(&self) {
+        self.listening.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_listening(&self) -> bool {
+        self.listening.load(Ordering::Relaxed)
+    }
+
+    pub fn active(&self) -> u64 {
+        self.accepted.get().saturating_sub(self.closed.get())
+    }
+
+    pub fn report(&self) -> String {
+        format!("Connections: {} active, {} accepted, {} rejected, {} forced closes; \
+                 {} bytes read, {} bytes written; {} read errors",
+                self.active(), self.accepted.get(), self.rejected.get(),
+                self.forced_closes.get(), self.bytes_read.get(), self.bytes_written.get(),
+                self.read_errors.get())
+    }
+}
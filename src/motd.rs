@@ -0,0 +1,51 @@
+//! The message of the day, shown to everyone who connects before they log in.
+//! It's read from a file, and read again whenever the server gets SIGHUP, so
+//! it can be changed without a restart.
+
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+/// Handle to the current message; clones share it.
+#[derive(Clone)]
+pub struct Motd {
+    path: Option<PathBuf>,
+    text: Arc<RwLock<Option<Arc<str>>>>,
+}
+
+impl Motd {
+    /// Reads the message from `path`, or has none if there's no path.
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let motd = Motd {
+            path,
+            text: Arc::new(RwLock::new(None)),
+        };
+        motd.reload();
+        motd
+    }
+
+    /// Reads the file again. If it can't be read, the old message stays.
+    pub fn reload(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) => {
+                error!("Failed to read MOTD from {}: {}", path.display(), err);
+                return;
+            },
+        };
+        let text = text.trim_end();
+        let text = if text.is_empty() { None } else { Some(format!("{}\n", text).into()) };
+        *self.text.write().expect("Motd lock poisoned") = text;
+        info!("Loaded MOTD from {}", path.display());
+    }
+
+    pub fn get(&self) -> Option<Arc<str>> {
+        self.text.read().expect("Motd lock poisoned").clone()
+    }
+}
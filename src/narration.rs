@@ -0,0 +1,91 @@
+//! Flavor text for phase changes. Every event has a few templates per locale
+//! and one is picked at random, so that games don't all read the same.
+
+use crate::locale::Locale;
+use crate::util::Rng;
+
+const NIGHTFALL_EN: &[&str] = &[
+    "Night falls on the town.",
+    "The town falls asleep.",
+    "The streetlights flicker on and the town goes quiet.",
+    "Darkness settles over the rooftops; doors are locked and shutters closed.",
+];
+
+const NIGHTFALL_RU: &[&str] = &[
+    "Над городом опускается ночь.",
+    "Город засыпает.",
+    "Зажигаются фонари, и город затихает.",
+    "Темнота накрывает крыши; двери заперты, ставни закрыты.",
+];
+
+const DAWN_EN: &[&str] = &[
+    "The sun rises.",
+    "Dawn breaks over the town.",
+    "The roosters crow and the town wakes up.",
+    "Morning comes, grey and cold.",
+];
+
+const DAWN_RU: &[&str] = &[
+    "Восходит солнце.",
+    "Над городом рассвет.",
+    "Кричат петухи, и город просыпается.",
+    "Наступает серое холодное утро.",
+];
+
+const FOUND_DEAD_EN: &[&str] = &[
+    "{login} was found dead this morning.",
+    "{login} didn't come down for breakfast; they were found dead in their bed.",
+    "A scream rings out: {login} lies dead in the street.",
+    "The milkman found {login} dead on their doorstep.",
+];
+
+const FOUND_DEAD_RU: &[&str] = &[
+    "Утром {login} был найден мёртвым.",
+    "{login} не вышел к завтраку: его нашли мёртвым в постели.",
+    "Раздаётся крик: {login} лежит мёртвым посреди улицы.",
+    "Молочник нашёл {login} мёртвым на пороге.",
+];
+
+const LYNCHED_EN: &[&str] = &[
+    "The town has lynched {login}.",
+    "The crowd has spoken: {login} is led to the gallows.",
+    "With a heavy heart, the town hangs {login}.",
+];
+
+const LYNCHED_RU: &[&str] = &[
+    "Город казнил игрока {login}.",
+    "Толпа решила: {login} отправляется на виселицу.",
+    "С тяжёлым сердцем город вешает игрока {login}.",
+];
+
+pub fn nightfall(locale: Locale, rng: &mut Rng) -> &'static str {
+    pick(rng, match locale {
+        Locale::En => NIGHTFALL_EN,
+        Locale::Ru => NIGHTFALL_RU,
+    })
+}
+
+pub fn dawn(locale: Locale, rng: &mut Rng) -> &'static str {
+    pick(rng, match locale {
+        Locale::En => DAWN_EN,
+        Locale::Ru => DAWN_RU,
+    })
+}
+
+pub fn found_dead(locale: Locale, rng: &mut Rng, login: &str) -> String {
+    pick(rng, match locale {
+        Locale::En => FOUND_DEAD_EN,
+        Locale::Ru => FOUND_DEAD_RU,
+    }).replace("{login}", login)
+}
+
+pub fn lynched(locale: Locale, rng: &mut Rng, login: &str) -> String {
+    pick(rng, match locale {
+        Locale::En => LYNCHED_EN,
+        Locale::Ru => LYNCHED_RU,
+    }).replace("{login}", login)
+}
+
+fn pick(rng: &mut Rng, templates: &[&'static str]) -> &'static str {
+    templates[rng.below(templates.len())]
+}
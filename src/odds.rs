@@ -0,0 +1,76 @@
+//! Rough chances of each faction winning, for observers following a game. The
+//! game is played out at random many times from the living roles: every night
+//! the mafia and the maniac each kill somebody else, every day the town lynches
+//! anybody. Nobody protects, investigates or reasons about anyone.
+
+use crate::roles::{self, Faction};
+use crate::util::Rng;
+
+/// Games played out per estimate, which keeps it cheap: each one takes at most
+/// a round per living player.
+pub const SIMULATIONS: u32 = 1000;
+
+/// Percentages of the simulated games each faction won, most likely first.
+/// Factions that won none are left out.
+pub fn estimate(alive: &[Faction], night: bool, rng: &mut Rng) -> Vec<(Faction, u32)> {
+    let mut wins: Vec<(Faction, u32)> = Vec::new();
+    for _ in 0..SIMULATIONS {
+        let winner = play_out(alive.to_vec(), night, rng);
+        match wins.iter_mut().find(|(faction, _)| *faction == winner) {
+            Some((_, count)) => *count += 1,
+            None => wins.push((winner, 1)),
+        }
+    }
+    wins.sort_by(|a, b| b.1.cmp(&a.1));
+    wins.into_iter()
+        .map(|(faction, count)| (faction, count * 100 / SIMULATIONS))
+        .collect()
+}
+
+fn play_out(mut alive: Vec<Faction>, mut night: bool, rng: &mut Rng) -> Faction {
+    loop {
+        if let Some(winner) = roles::winning_faction(alive.iter().cloned(), 0) {
+            return winner;
+        }
+        if night {
+            kill_for(Faction::Mafia, &mut alive, rng);
+            kill_for(Faction::Maniac, &mut alive, rng);
+        } else {
+            let victim = rng.below(alive.len());
+            alive.swap_remove(victim);
+        }
+        night = !night;
+    }
+}
+
+/// If anyone of the faction is left, they kill somebody outside of it.
+fn kill_for(killer: Faction, alive: &mut Vec<Faction>, rng: &mut Rng) {
+    if !alive.contains(&killer) {
+        return;
+    }
+    let targets: Vec<usize> = (0..alive.len()).filter(|&index| alive[index] != killer).collect();
+    if !targets.is_empty() {
+        alive.swap_remove(targets[rng.below(targets.len())]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decided_game_goes_to_its_winner() {
+        let alive = [Faction::Mafia, Faction::Mafia, Faction::Town];
+        let odds = estimate(&alive, false, &mut Rng::new(1));
+        assert!(odds == vec![(Faction::Mafia, 100)]);
+    }
+
+    #[test]
+    fn odds_add_up() {
+        let alive = [Faction::Mafia, Faction::Maniac, Faction::Town, Faction::Town, Faction::Town];
+        let odds = estimate(&alive, true, &mut Rng::new(1));
+        let total: u32 = odds.iter().map(|&(_, percent)| percent).sum();
+        assert!(total > 97 && total <= 100);
+        assert!(odds.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+    }
+}
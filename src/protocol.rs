@@ -0,0 +1,91 @@
+//! The JSON wire protocol, spoken on the JSON port for clients that would rather
+//! not parse the text one. Every message is a JSON object on its own line.
+//!
+//! The server sends `{"type":<kind>,"text":<message>}`, where the kind is one of
+//! `chat`, `system`, `phase_change` and `vote_update`, and the text is what a
+//! text client would have been sent.
+//!
+//! Clients send one of:
+//!   `{"type":"line","text":...}`, a line exactly as a text client would type it
+//!   (used for the login and password, for instance);
+//!   `{"type":"chat","text":...}`, a public chat message;
+//!   `{"type":"command","name":...,"args":...}`, for `!name args`;
+//!   `{"type":"action","name":...,"args":...}`, for `!!name args`;
+//!   `{"type":"pong"}`, in answer to the server's `{"type":"ping"}`.
+//! `args` may be left out.
+
+use crate::json;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum MessageKind {
+    /// What players say to each other, in any channel.
+    Chat,
+    /// Everything else the server has to say.
+    System,
+    /// A new day, night or voting round.
+    PhaseChange,
+    /// Somebody voted, or the turnout changed.
+    VoteUpdate,
+    /// A private message between players.
+    Private,
+    /// Who plays which role, told to a player or everyone.
+    Role,
+    /// A player acting something out: `* login shrugs`.
+    Emote,
+}
+
+impl MessageKind {
+    fn name(self) -> &'static str {
+        match self {
+            MessageKind::Chat => "chat",
+            MessageKind::System => "system",
+            MessageKind::PhaseChange => "phase_change",
+            MessageKind::VoteUpdate => "vote_update",
+            // These only differ from the others in colour.
+            MessageKind::Private | MessageKind::Emote => "chat",
+            MessageKind::Role => "system",
+        }
+    }
+}
+
+/// Wraps a message for a JSON client, with its line break.
+pub fn encode(kind: MessageKind, message: &str) -> String {
+    let text = if message.ends_with('\n') { &message[..message.len() - 1] } else { message };
+    format!("{{\"type\":\"{}\",\"text\":{}}}\n", kind.name(), json::quote(text))
+}
+
+/// A keepalive ping, which clients should answer with a pong.
+pub fn ping() -> &'static str {
+    "{\"type\":\"ping\"}\n"
+}
+
+/// Turns a line from a JSON client into the line a text client would have
+/// typed, or `None` if it was a pong.
+pub fn decode(line: &str) -> Result<Option<String>, &'static str> {
+    let fields = json::parse_object(line).ok_or("Messages must be flat JSON objects.")?;
+    let field = |name: &str| fields.iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str());
+    let text = field("text").unwrap_or("");
+    let args = field("args").unwrap_or("");
+    match field("type") {
+        Some("line") => Ok(Some(text.to_string())),
+        Some("chat") if text.starts_with(|c: char| "+#*!".contains(c)) =>
+            Err("Chat text can't start with a special character; send a line instead."),
+        Some("chat") => Ok(Some(text.to_string())),
+        Some(kind @ "command") | Some(kind @ "action") => {
+            let name = field("name").ok_or("Commands and actions need a name.")?;
+            let prefix = if kind == "command" { "!" } else { "!!" };
+            Ok(Some(format!("{}{} {}", prefix, name, args).trim_end().to_string()))
+        },
+        Some("pong") => Ok(None),
+        Some(_) => Err("Unknown message type; use line, chat, command, action or pong."),
+        None => Err("Messages need a type."),
+    }
+}
@@ -0,0 +1,89 @@
+//! The PROXY protocol, versions 1 and 2, which haproxy and nginx use to pass
+//! on the address of the client they are forwarding a connection for.
+
+use futures::prelude::*;
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// The longest a version 1 header can be, line break included.
+const V1_MAX: usize = 107;
+
+/// Reads the header the proxy sends ahead of the client's data, and returns the
+/// client's address, or `None` if the proxy didn't give one, as it doesn't for
+/// its own health checks.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    // Both versions' headers are at least this long, so this never reads too far.
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        let mut fixed = [0; 4];
+        stream.read_exact(&mut fixed).await?;
+        let mut addresses = vec![0; u16::from_be_bytes([fixed[2], fixed[3]]) as usize];
+        stream.read_exact(&mut addresses).await?;
+        parse_v2(fixed[0], fixed[1], &addresses)
+    } else if start.starts_with(b"PROXY ") {
+        let mut line = start.to_vec();
+        let mut byte = [0; 1];
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX {
+                return Err(invalid("PROXY header too long"));
+            }
+            stream.read_exact(&mut byte).await?;
+            line.push(byte[0]);
+        }
+        let line = std::str::from_utf8(&line).map_err(|_| invalid("PROXY header isn't text"))?;
+        parse_v1(line.trim_end())
+    } else {
+        Err(invalid("no PROXY header"))
+    }
+}
+
+/// Parses a header like `PROXY TCP4 <source> <destination> <source port> <destination port>`.
+fn parse_v1(line: &str) -> io::Result<Option<SocketAddr>> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.get(1) {
+        Some(&"TCP4") | Some(&"TCP6") if fields.len() == 6 => {
+            let ip: IpAddr = fields[2].parse().map_err(|_| invalid("bad PROXY source address"))?;
+            let port: u16 = fields[4].parse().map_err(|_| invalid("bad PROXY source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        },
+        Some(&"UNKNOWN") => Ok(None),
+        _ => Err(invalid("malformed PROXY header")),
+    }
+}
+
+/// Parses the binary header, given its version and command byte, its family
+/// byte and the address block that follows them.
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY version"));
+    }
+    match version_command & 0x0f {
+        // The proxy's own connection.
+        0 => return Ok(None),
+        1 => (),
+        _ => return Err(invalid("unsupported PROXY command")),
+    }
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    match family {
+        0x11 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            Ok(Some(SocketAddr::new(ip.into(), port(8))))
+        },
+        0x21 if addresses.len() >= 36 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port(32))))
+        },
+        // Unix sockets and unknown families carry no address worth having.
+        _ => Ok(None),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
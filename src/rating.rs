@@ -0,0 +1,35 @@
+//! ELO-style rating. Winners are rated against the average of the losers and
+//! vice versa, so a game counts as one match between two sides.
+
+pub const INITIAL_RATING: i32 = 1000;
+
+/// How far a single game can move a rating.
+const K_FACTOR: f64 = 32.0;
+
+/// Takes each participant's rating and whether they won; returns the rating
+/// changes in the same order.
+pub fn adjust(players: &[(i32, bool)]) -> Vec<i32> {
+    let average = |won: bool| {
+        let side: Vec<i32> = players.iter()
+            .filter(|&&(_, player_won)| player_won == won)
+            .map(|&(rating, _)| rating)
+            .collect();
+        if side.is_empty() {
+            None
+        } else {
+            Some(side.iter().sum::<i32>() as f64 / side.len() as f64)
+        }
+    };
+    let (winners, losers) = match (average(true), average(false)) {
+        (Some(winners), Some(losers)) => (winners, losers),
+        _ => return vec![0; players.len()],
+    };
+    players.iter()
+        .map(|&(rating, won)| {
+            let opponents = if won { losers } else { winners };
+            let expected = 1.0 / (1.0 + 10f64.powf((opponents - rating as f64) / 400.0));
+            let score = if won { 1.0 } else { 0.0 };
+            (K_FACTOR * (score - expected)).round() as i32
+        })
+        .collect()
+}
@@ -0,0 +1,203 @@
+use crate::chat_service::Player;
+use crate::game_log::LogEvent;
+use crate::json::{self, quote};
+
+use runtime::time::Delay;
+
+use std::{
+    fs::{self, File},
+    io::{self, LineWriter, Write},
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Records every game to its own JSON-lines file in a directory, one event per
+/// line, so that games can be reviewed and disputes settled.
+#[derive(Clone)]
+pub struct ReplayRecorder {
+    dir: PathBuf,
+    /// Public chat messages are recorded too.
+    chat: bool,
+}
+
+/// The replay file of a single game.
+pub struct Replay {
+    file: LineWriter<File>,
+    chat: bool,
+    started: Instant,
+}
+
+impl ReplayRecorder {
+    pub fn new(dir: PathBuf, chat: bool) -> Self {
+        ReplayRecorder {
+            dir,
+            chat,
+        }
+    }
+
+    /// Creates the file for a new game. If that fails, the game goes unrecorded.
+    pub fn open(&self, seed: u64) -> Option<Replay> {
+        let stamp = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+        let path = self.dir.join(format!("{}-{}.jsonl", stamp, seed));
+        match File::create(&path) {
+            Ok(file) => Some(Replay {
+                file: LineWriter::new(file),
+                chat: self.chat,
+                started: Instant::now(),
+            }),
+            Err(err) => {
+                error!("Failed to create replay file {}: {}.", path.display(), err);
+                None
+            },
+        }
+    }
+
+    /// Ids of the recorded games, oldest first.
+    pub fn list(&self) -> Vec<String> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        let mut ids: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                if name.ends_with(".jsonl") {
+                    Some(name[..name.len() - ".jsonl".len()].to_string())
+                } else {
+                    None
+                }
+            })
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    pub fn load(&self, id: &str) -> io::Result<String> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_digit() || c == '-') {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no such replay"));
+        }
+        fs::read_to_string(self.dir.join(format!("{}.jsonl", id)))
+    }
+}
+
+/// Plays a recorded game back to a single player, on a virtual clock running
+/// `speed` times faster than the game did, until it's over or `stop` is set.
+pub fn play(replay: String, speed: u64, player: Player, stop: Arc<AtomicBool>) {
+    #[allow(unused)] {
+        runtime::spawn(async move {
+            let mut clock = 0;
+            for line in replay.lines() {
+                let (time, text) = match render(line) {
+                    Some(event) => event,
+                    None => continue,
+                };
+                if time > clock {
+                    Delay::new(Duration::from_millis((time - clock) / speed.max(1))).await;
+                    clock = time;
+                }
+                if stop.load(Ordering::Relaxed) {
+                    return;
+                }
+                player.send(format!("[replay {:02}:{:02}] {}\n",
+                                    time / 60_000, time / 1000 % 60, text));
+            }
+            player.send_static("The replay is over.\n");
+        });
+    }
+}
+
+impl Replay {
+    pub fn record(&mut self, event: &LogEvent) {
+        let fields = encode(event);
+        self.write(&fields);
+    }
+
+    pub fn record_chat(&mut self, login: &str, text: &str) {
+        if self.chat {
+            self.write(&format!("\"event\":\"chat\",\"login\":{},\"text\":{}",
+                                quote(login), quote(text)));
+        }
+    }
+
+    fn write(&mut self, fields: &str) {
+        let elapsed = self.started.elapsed();
+        let result = writeln!(self.file, "{{\"time\":{}.{:03},{}}}",
+                              elapsed.as_secs(), elapsed.subsec_millis(), fields);
+        if let Err(err) = result {
+            error!("Failed to write replay: {}.", err);
+        }
+    }
+}
+
+/// The event's fields as the inside of a JSON object.
+fn encode(event: &LogEvent) -> String {
+    match event {
+        LogEvent::NightStart(night) => format!("\"event\":\"night\",\"number\":{}", night),
+        LogEvent::DayStart(day) => format!("\"event\":\"day\",\"number\":{}", day),
+        LogEvent::Deal{login, role} =>
+            format!("\"event\":\"deal\",\"login\":{},\"role\":{}", quote(login), quote(role)),
+        LogEvent::Join(login) => format!("\"event\":\"join\",\"login\":{}", quote(login)),
+        LogEvent::Leave(login) => format!("\"event\":\"leave\",\"login\":{}", quote(login)),
+        LogEvent::Vote{voter, suspect} =>
+            format!("\"event\":\"vote\",\"voter\":{},\"suspect\":{}",
+                    quote(voter), quote(suspect)),
+        LogEvent::Skip(voter) => format!("\"event\":\"skip\",\"voter\":{}", quote(voter)),
+        LogEvent::Lynch(login) => format!("\"event\":\"lynch\",\"login\":{}", quote(login)),
+        LogEvent::NoLynch => "\"event\":\"no_lynch\"".to_string(),
+        LogEvent::Action{actor, ability, target} =>
+            format!("\"event\":\"action\",\"actor\":{},\"ability\":{},\"target\":{}",
+                    quote(actor), quote(ability),
+                    target.as_ref().map_or("null".to_string(), |target| quote(target))),
+        LogEvent::Kill(login) => format!("\"event\":\"kill\",\"login\":{}", quote(login)),
+        LogEvent::Abandon(login) => format!("\"event\":\"abandon\",\"login\":{}", quote(login)),
+        LogEvent::Save(login) => format!("\"event\":\"save\",\"login\":{}", quote(login)),
+        LogEvent::Investigation{detective, suspect, is_mafia} =>
+            format!("\"event\":\"investigation\",\"detective\":{},\"suspect\":{},\"is_mafia\":{}",
+                    quote(detective), quote(suspect), is_mafia),
+        LogEvent::DonCheck{don, suspect, is_detective} =>
+            format!("\"event\":\"don_check\",\"don\":{},\"suspect\":{},\"is_detective\":{}",
+                    quote(don), quote(suspect), is_detective),
+        LogEvent::Divination{seer, suspect, role} =>
+            format!("\"event\":\"divination\",\"seer\":{},\"suspect\":{},\"role\":{}",
+                    quote(seer), quote(suspect), quote(role)),
+        LogEvent::Reveal(login) => format!("\"event\":\"reveal\",\"login\":{}", quote(login)),
+        LogEvent::Lovers(first, second) =>
+            format!("\"event\":\"lovers\",\"first\":{},\"second\":{}",
+                    quote(first), quote(second)),
+        LogEvent::Heartbreak(login) =>
+            format!("\"event\":\"heartbreak\",\"login\":{}", quote(login)),
+        LogEvent::Shot{hunter, target} =>
+            format!("\"event\":\"shot\",\"hunter\":{},\"target\":{}",
+                    quote(hunter), quote(target)),
+    }
+}
+
+/// Turns a replay line back into its time in milliseconds and a readable description.
+fn render(line: &str) -> Option<(u64, String)> {
+    let fields = json::parse_object(line)?;
+    let field = |name: &str| fields.iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str());
+    let time = field("time")?;
+    let mut parts = time.splitn(2, '.');
+    let seconds: u64 = parts.next()?.parse().ok()?;
+    let millis: u64 = parts.next().unwrap_or("0").parse().ok()?;
+    let event = field("event")?;
+    let text = if event == "chat" {
+        format!("{}: {}", field("login")?, field("text")?)
+    } else {
+        let details: Vec<String> = fields.iter()
+            .filter(|(key, _)| key != "time" && key != "event")
+            .map(|(key, value)| format!("{} {}", key, value))
+            .collect();
+        format!("{} ({})", event, details.join(", "))
+    };
+    Some((seconds * 1000 + millis, text))
+}
@@ -0,0 +1,323 @@
+use crate::util::Rng;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Role {
+    Civilian,
+    Mafia,
+    /// Head of the mafia: votes on the kill and checks players for the detective.
+    Don,
+    Detective,
+    Doctor,
+    /// Plays for themselves and kills a player every night.
+    Maniac,
+    /// Links two players as lovers on the first night.
+    Cupid,
+    /// The werewolf preset's mafioso.
+    Werewolf,
+    /// Learns a player's exact role every night.
+    Seer,
+    /// Has one healing and one poison potion for the whole game.
+    Witch,
+    /// Gets to shoot a player when they die.
+    Hunter,
+    /// The werewolf preset's civilian.
+    Villager,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Faction {
+    Town,
+    Mafia,
+    Maniac,
+    /// The two lovers, when they are the last ones standing. No role belongs to it.
+    Lovers,
+}
+
+/// Something a role can do at night. `!!<login>` uses the role's first
+/// available ability, `!!<keyword> <login>` picks one by its keyword.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Ability {
+    /// Vote on the mafia's victim.
+    Kill,
+    /// Learn whether a player is the detective.
+    FindDetective,
+    /// Learn whether a player is in the mafia.
+    Investigate,
+    /// Learn a player's exact role.
+    Divine,
+    /// Save a player from tonight's attacks, but not the same one twice in a row.
+    Protect,
+    /// Save a player from tonight's attacks, once per game.
+    Heal,
+    /// Kill a player, once per game.
+    Poison,
+    /// Kill a player on one's own.
+    SoloKill,
+    /// Make two players lovers, on the first night only.
+    Matchmake,
+}
+
+/// Steps of night resolution. Every ability belongs to one of them, so a new
+/// role only has to say where its ability slots in.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Priority {
+    /// Stops a player's action from happening.
+    Block,
+    /// Moves a player's action onto someone else.
+    Redirect,
+    Protect,
+    Kill,
+    Investigate,
+}
+
+/// The order in which night actions are resolved at dawn.
+pub const RESOLUTION_ORDER: [Priority; 5] = [
+    Priority::Block,
+    Priority::Redirect,
+    Priority::Protect,
+    Priority::Kill,
+    Priority::Investigate,
+];
+
+/// The role set a game is dealt from.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Preset {
+    /// Mafia, don, detective, doctor, maniac, cupid and civilians.
+    Classic,
+    /// Werewolves, seer, witch, hunter and villagers.
+    Werewolf,
+}
+
+/// How many special roles a game of a given size gets. Everyone who doesn't
+/// get a special role is a civilian (a villager in the werewolf preset).
+#[derive(Clone, Copy)]
+pub struct RoleDistribution {
+    pub preset: Preset,
+    /// One mafioso (werewolf) per this many players (always at least one).
+    pub players_per_mafia: usize,
+    /// Smallest game in which one of the mafiosi is the don.
+    pub don_from: usize,
+    /// Smallest game that gets a detective (seer).
+    pub detective_from: usize,
+    /// Smallest game that gets a doctor (witch).
+    pub doctor_from: usize,
+    /// Smallest game that gets a maniac.
+    pub maniac_from: usize,
+    /// Smallest game that gets a cupid.
+    pub cupid_from: usize,
+    /// Smallest game that gets a hunter.
+    pub hunter_from: usize,
+}
+
+impl Role {
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Civilian => "Civilian",
+            Role::Mafia => "Mafia",
+            Role::Don => "Don",
+            Role::Detective => "Detective",
+            Role::Doctor => "Doctor",
+            Role::Maniac => "Maniac",
+            Role::Cupid => "Cupid",
+            Role::Werewolf => "Werewolf",
+            Role::Seer => "Seer",
+            Role::Witch => "Witch",
+            Role::Hunter => "Hunter",
+            Role::Villager => "Villager",
+        }
+    }
+
+    pub fn faction(self) -> Faction {
+        match self {
+            Role::Mafia | Role::Don | Role::Werewolf => Faction::Mafia,
+            Role::Civilian | Role::Detective | Role::Doctor | Role::Cupid
+                | Role::Seer | Role::Witch | Role::Hunter | Role::Villager => Faction::Town,
+            Role::Maniac => Faction::Maniac,
+        }
+    }
+
+    pub fn is_mafia(self) -> bool {
+        self.faction() == Faction::Mafia
+    }
+
+    /// What the role can do at night, in order of preference for `!!<login>`.
+    pub fn abilities(self) -> &'static [Ability] {
+        match self {
+            Role::Mafia | Role::Werewolf => &[Ability::Kill],
+            Role::Don => &[Ability::Kill, Ability::FindDetective],
+            Role::Detective => &[Ability::Investigate],
+            Role::Doctor => &[Ability::Protect],
+            Role::Maniac => &[Ability::SoloKill],
+            Role::Cupid => &[Ability::Matchmake],
+            Role::Seer => &[Ability::Divine],
+            Role::Witch => &[Ability::Heal, Ability::Poison],
+            Role::Civilian | Role::Villager | Role::Hunter => &[],
+        }
+    }
+}
+
+impl Faction {
+    pub fn name(self) -> &'static str {
+        match self {
+            Faction::Town => "town",
+            Faction::Mafia => "mafia",
+            Faction::Maniac => "maniac",
+            Faction::Lovers => "lovers",
+        }
+    }
+}
+
+impl Ability {
+    pub fn keyword(self) -> &'static str {
+        match self {
+            Ability::Kill | Ability::SoloKill => "kill",
+            Ability::FindDetective => "check",
+            Ability::Investigate => "investigate",
+            Ability::Divine => "divine",
+            Ability::Protect => "protect",
+            Ability::Heal => "heal",
+            Ability::Poison => "poison",
+            Ability::Matchmake => "love",
+        }
+    }
+
+    /// How the ability is explained in the night prompt.
+    pub fn describe(self) -> &'static str {
+        match self {
+            Ability::Kill => "Choose tonight's victim",
+            Ability::FindDetective => "Check whether a player is the detective",
+            Ability::Investigate => "Investigate a player",
+            Ability::Divine => "Learn a player's role",
+            Ability::Protect => "Protect a player",
+            Ability::Heal => "Use your healing potion on a player",
+            Ability::Poison => "Use your poison on a player",
+            Ability::SoloKill => "Kill a player",
+            Ability::Matchmake => "Choose two lovers",
+        }
+    }
+
+    pub fn once_per_game(self) -> bool {
+        self == Ability::Heal || self == Ability::Poison
+    }
+
+    /// Abilities used after the mafia have chosen, by players who are told the victim.
+    pub fn is_late(self) -> bool {
+        self == Ability::Heal || self == Ability::Poison
+    }
+
+    /// The resolution step the ability takes effect in, or `None` for matchmaking,
+    /// which takes effect as soon as it's used.
+    pub fn priority(self) -> Option<Priority> {
+        match self {
+            Ability::Protect | Ability::Heal => Some(Priority::Protect),
+            Ability::Kill | Ability::SoloKill | Ability::Poison => Some(Priority::Kill),
+            Ability::FindDetective | Ability::Investigate | Ability::Divine =>
+                Some(Priority::Investigate),
+            Ability::Matchmake => None,
+        }
+    }
+
+    pub fn allows_self(self) -> bool {
+        self == Ability::Protect || self == Ability::Heal
+    }
+}
+
+impl RoleDistribution {
+    /// Returns a shuffled list of `count` roles.
+    pub fn assign(&self, count: usize, rng: &mut Rng) -> Vec<Role> {
+        let mut roles = self.roles(count);
+        rng.shuffle(&mut roles);
+        roles
+    }
+
+    /// The roles a game of `count` players is dealt, in no particular order.
+    pub fn roles(&self, count: usize) -> Vec<Role> {
+        match self.preset {
+            Preset::Classic => self.classic(count),
+            Preset::Werewolf => self.werewolf(count),
+        }
+    }
+
+    fn classic(&self, count: usize) -> Vec<Role> {
+        let mut roles = self.mafia(count, Role::Mafia);
+        if count >= self.don_from && !roles.is_empty() {
+            roles[0] = Role::Don;
+        }
+        add_role(&mut roles, count, self.detective_from, Role::Detective);
+        add_role(&mut roles, count, self.doctor_from, Role::Doctor);
+        add_role(&mut roles, count, self.maniac_from, Role::Maniac);
+        add_role(&mut roles, count, self.cupid_from, Role::Cupid);
+        while roles.len() < count {
+            roles.push(Role::Civilian);
+        }
+        roles
+    }
+
+    fn werewolf(&self, count: usize) -> Vec<Role> {
+        let mut roles = self.mafia(count, Role::Werewolf);
+        add_role(&mut roles, count, self.detective_from, Role::Seer);
+        add_role(&mut roles, count, self.doctor_from, Role::Witch);
+        add_role(&mut roles, count, self.hunter_from, Role::Hunter);
+        while roles.len() < count {
+            roles.push(Role::Villager);
+        }
+        roles
+    }
+
+    fn mafia(&self, count: usize, role: Role) -> Vec<Role> {
+        let mut roles = Vec::with_capacity(count);
+        let mafia = (count / self.players_per_mafia.max(1)).max(1).min(count);
+        for _ in 0..mafia {
+            roles.push(role);
+        }
+        roles
+    }
+}
+
+/// The faction that has won among the living, given their factions and how many
+/// of them are lovers. See `Game::winner`.
+pub fn winning_faction<I>(factions: I, lovers: usize) -> Option<Faction>
+    where I: IntoIterator<Item = Faction>
+{
+    let (mut mafia, mut town, mut maniac) = (0, 0, 0);
+    for faction in factions {
+        match faction {
+            Faction::Mafia => mafia += 1,
+            Faction::Town => town += 1,
+            Faction::Maniac => maniac += 1,
+            Faction::Lovers => (),
+        }
+    }
+    if lovers == 2 && mafia + town + maniac == 2 {
+        Some(Faction::Lovers)
+    } else if mafia == 0 && maniac == 0 {
+        Some(Faction::Town)
+    } else if mafia == 0 && town == 0 {
+        Some(Faction::Maniac)
+    } else if maniac == 0 && mafia >= town {
+        Some(Faction::Mafia)
+    } else {
+        None
+    }
+}
+
+fn add_role(roles: &mut Vec<Role>, count: usize, from: usize, role: Role) {
+    if count >= from && roles.len() < count {
+        roles.push(role);
+    }
+}
+
+impl Default for RoleDistribution {
+    fn default() -> Self {
+        RoleDistribution {
+            preset: Preset::Classic,
+            players_per_mafia: 4,
+            don_from: 8,
+            detective_from: 5,
+            doctor_from: 6,
+            maniac_from: 9,
+            cupid_from: 7,
+            hunter_from: 7,
+        }
+    }
+}
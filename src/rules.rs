@@ -0,0 +1,217 @@
+use crate::roles::{RoleDistribution, Preset};
+
+/// Game parameters, set up before the game starts and carried through every stage.
+#[derive(Clone, Copy)]
+pub struct GameRules {
+    pub min_players: usize,
+    /// Length of the day's discussion, before voting opens; zero skips it.
+    pub discussion_seconds: u64,
+    pub voting_seconds: u64,
+    pub night_seconds: u64,
+    /// How long the witch has to act once the mafia's victim is known.
+    pub late_seconds: u64,
+    /// How long a lynched player may speak before being muted; zero disables last words.
+    pub last_words_seconds: u64,
+    /// How long a hunter who just died has to pick who to shoot.
+    pub shot_seconds: u64,
+    /// How long a player who drops out of a game in progress has to reconnect
+    /// before they are eliminated.
+    pub away_grace_seconds: u64,
+    /// How long the phase clock stops when a living player drops out, to give
+    /// them a chance to reconnect; zero keeps it running.
+    pub reconnect_seconds: u64,
+    /// Lynching requires votes from more than half of the living players,
+    /// rather than just the most votes.
+    pub majority: bool,
+    /// Votes are cast in secret; only the final tally is announced.
+    pub anonymous_votes: bool,
+    /// The detective may reveal their role once per game with `!reveal`.
+    pub detective_reveal: bool,
+    /// If the mafia haven't agreed on a victim by the end of the night, one is
+    /// picked at random instead of nobody dying.
+    pub random_mafia_kill: bool,
+    /// The mafia only kill if every mafioso picked the same victim, rather than
+    /// going with the most picked one.
+    pub unanimous_kill: bool,
+    /// Seed for role assignment and every random choice in the game, so that a
+    /// game can be replayed exactly. A fresh one is drawn when unset.
+    pub seed: Option<u64>,
+    /// Start the game by itself once this many players are ready.
+    pub auto_start: Option<usize>,
+    /// Players who do nothing in the lobby for this long are moved to observers.
+    pub idle_seconds: Option<u64>,
+    /// Only one player per address may play in a game; the others observe.
+    /// Set in the server's configuration rather than with `!setrule`, as it
+    /// is there against multi-accounting.
+    pub one_player_per_ip: bool,
+    pub distribution: RoleDistribution,
+}
+
+impl Default for GameRules {
+    fn default() -> Self {
+        GameRules {
+            min_players: 4,
+            discussion_seconds: 120,
+            voting_seconds: 180,
+            night_seconds: 60,
+            late_seconds: 20,
+            last_words_seconds: 30,
+            shot_seconds: 20,
+            away_grace_seconds: 120,
+            reconnect_seconds: 0,
+            majority: true,
+            anonymous_votes: false,
+            detective_reveal: true,
+            random_mafia_kill: false,
+            unanimous_kill: false,
+            seed: None,
+            auto_start: None,
+            idle_seconds: Some(600),
+            one_player_per_ip: false,
+            distribution: RoleDistribution::default(),
+        }
+    }
+}
+
+impl GameRules {
+    /// Changes the rule named `key`. On failure returns a message for the user.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), &'static str> {
+        let distribution = &mut self.distribution;
+        match key {
+            "minplayers" => self.min_players = parse_number(value, 2)? as usize,
+            "discussion" => self.discussion_seconds = parse_number(value, 0)?,
+            "voting" => self.voting_seconds = parse_number(value, 10)?,
+            "night" => self.night_seconds = parse_number(value, 10)?,
+            "late" => self.late_seconds = parse_number(value, 5)?,
+            "lastwords" => self.last_words_seconds = parse_number(value, 0)?,
+            "shot" => self.shot_seconds = parse_number(value, 5)?,
+            "awaygrace" => self.away_grace_seconds = parse_number(value, 10)?,
+            "reconnect" => self.reconnect_seconds = parse_number(value, 0)?,
+            "majority" => self.majority = parse_switch(value)?,
+            "anonvote" => self.anonymous_votes = parse_switch(value)?,
+            "reveal" => self.detective_reveal = parse_switch(value)?,
+            "randomkill" => self.random_mafia_kill = parse_switch(value)?,
+            "killvote" => self.unanimous_kill = match value {
+                "unanimous" => true,
+                "majority" => false,
+                _ => return Err("Use unanimous or majority.\n"),
+            },
+            "preset" => distribution.preset = match value {
+                "classic" => Preset::Classic,
+                "werewolf" => Preset::Werewolf,
+                _ => return Err("Use classic or werewolf.\n"),
+            },
+            "mafia" => distribution.players_per_mafia = parse_number(value, 2)? as usize,
+            "don" => distribution.don_from = parse_threshold(value)?,
+            "detective" => distribution.detective_from = parse_threshold(value)?,
+            "doctor" => distribution.doctor_from = parse_threshold(value)?,
+            "maniac" => distribution.maniac_from = parse_threshold(value)?,
+            "cupid" => distribution.cupid_from = parse_threshold(value)?,
+            "hunter" => distribution.hunter_from = parse_threshold(value)?,
+            "autostart" if value == "off" => self.auto_start = None,
+            "autostart" => self.auto_start = Some(parse_number(value, 2)? as usize),
+            "idle" if value == "off" => self.idle_seconds = None,
+            "idle" => self.idle_seconds = Some(parse_number(value, 60)?),
+            "seed" if value == "random" => self.seed = None,
+            "seed" => self.seed = Some(parse_number(value, 0)?),
+            _ => return Err("Unknown rule. Type !rules to see them all.\n"),
+        }
+        Ok(())
+    }
+
+    /// Lists every rule with its current value, in the form `!setrule` accepts.
+    pub fn describe(&self) -> String {
+        let switch = |value: bool| if value { "on" } else { "off" };
+        let threshold = |value: usize| if value == usize::max_value() {
+            "off".to_string()
+        } else {
+            value.to_string()
+        };
+        let distribution = &self.distribution;
+        let mut message = String::from("Rules:\n");
+        message.push_str(&format!("  minplayers {} - players needed to start\n", self.min_players));
+        message.push_str(&format!("  discussion {} - seconds of discussion each day\n",
+                                  self.discussion_seconds));
+        message.push_str(&format!("  voting {} - seconds of voting each day\n",
+                                  self.voting_seconds));
+        message.push_str(&format!("  night {} - seconds in a night\n", self.night_seconds));
+        message.push_str(&format!("  late {} - seconds the witch gets after the mafia choose\n",
+                                  self.late_seconds));
+        message.push_str(&format!("  lastwords {} - seconds of last words\n", self.last_words_seconds));
+        message.push_str(&format!("  shot {} - seconds a dead hunter has to shoot\n",
+                                  self.shot_seconds));
+        message.push_str(&format!("  awaygrace {} - seconds a dropped player has to come back\n",
+                                  self.away_grace_seconds));
+        message.push_str(&format!("  reconnect {} - seconds the clock stops for a dropped player\n",
+                                  self.reconnect_seconds));
+        message.push_str(&format!("  majority {} - lynching needs over half of the votes\n",
+                                  switch(self.majority)));
+        let auto_start = match self.auto_start {
+            Some(count) => count.to_string(),
+            None => "off".to_string(),
+        };
+        message.push_str(&format!("  autostart {} - players needed to start automatically\n",
+                                  auto_start));
+        let idle = match self.idle_seconds {
+            Some(seconds) => seconds.to_string(),
+            None => "off".to_string(),
+        };
+        message.push_str(&format!("  idle {} - seconds before idle lobby players observe\n",
+                                  idle));
+        message.push_str(&format!("  anonvote {} - votes are secret\n",
+                                  switch(self.anonymous_votes)));
+        message.push_str(&format!("  reveal {} - the detective may !reveal\n",
+                                  switch(self.detective_reveal)));
+        message.push_str(&format!("  randomkill {} - idle mafia get a random victim\n",
+                                  switch(self.random_mafia_kill)));
+        let seed = match self.seed {
+            Some(seed) => seed.to_string(),
+            None => "random".to_string(),
+        };
+        message.push_str(&format!("  seed {} - seed for roles and random choices\n", seed));
+        message.push_str(&format!("  killvote {} - how the mafia agree on a victim\n",
+                                  if self.unanimous_kill { "unanimous" } else { "majority" }));
+        message.push_str(&format!("  preset {} - role set, classic or werewolf\n",
+                                  match distribution.preset {
+                                      Preset::Classic => "classic",
+                                      Preset::Werewolf => "werewolf",
+                                  }));
+        message.push_str(&format!("  mafia {} - players per mafioso\n",
+                                  distribution.players_per_mafia));
+        for &(name, value) in &[("don", distribution.don_from),
+                                ("detective", distribution.detective_from),
+                                ("doctor", distribution.doctor_from),
+                                ("maniac", distribution.maniac_from),
+                                ("cupid", distribution.cupid_from),
+                                ("hunter", distribution.hunter_from)] {
+            message.push_str(&format!("  {} {} - smallest game with a {}\n",
+                                      name, threshold(value), name));
+        }
+        message
+    }
+}
+
+fn parse_switch(value: &str) -> Result<bool, &'static str> {
+    match value {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err("Use on or off.\n"),
+    }
+}
+
+fn parse_number(value: &str, min: u64) -> Result<u64, &'static str> {
+    match value.parse() {
+        Ok(number) if number >= min => Ok(number),
+        Ok(_) => Err("That value is too small.\n"),
+        Err(_) => Err("Expected a number.\n"),
+    }
+}
+
+/// A game size from which a role is dealt, or `off` to never deal it.
+fn parse_threshold(value: &str) -> Result<usize, &'static str> {
+    if value == "off" {
+        Ok(usize::max_value())
+    } else {
+        parse_number(value, 0).map(|number| number as usize)
+    }
+}
@@ -0,0 +1,325 @@
+//! Per-account settings, chosen with `!set` and kept with the account, so that
+//! they are back the next time the user logs in.
+
+use crate::locale::Locale;
+
+use chrono::prelude::*;
+
+use std::collections::HashMap;
+
+#[derive(Clone)]
+pub struct UserSettings {
+    /// Language of the game's messages; the server's own if not set.
+    pub locale: Option<Locale>,
+    /// Whether messages are coloured; guessed from the terminal if not set.
+    pub color: Option<bool>,
+    pub timestamps: Timestamps,
+    /// Offset from UTC that timestamps are given in; the server's local time
+    /// if not set.
+    pub timezone: Option<FixedOffset>,
+    pub notifications: Preferences,
+    /// Lowercased logins whose public and private messages the user doesn't
+    /// get; `!ignore` and `!unignore` change it.
+    pub ignored: Vec<Box<str>>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Timestamps {
+    Off,
+    /// Hours and minutes.
+    Short,
+    /// Hours, minutes and seconds.
+    Long,
+}
+
+/// Per-user notification flags. `!verbosity <preset>` sets them all at once,
+/// `!verbosity <flag> on|off` overrides a single one:
+///
+///   preset    joins  countdown  echo  deafen
+///   verbose   on     on         on    off      (default)
+///   normal    off    on         on    off
+///   quiet     off    off        off   off
+///
+/// Private messages are always delivered regardless of these flags.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Preferences {
+    /// Show connect/disconnect notices.
+    pub joins: bool,
+    /// Show phase timer warnings.
+    pub countdown: bool,
+    /// Show copies of your own messages.
+    pub echo: bool,
+    /// Hide public chat.
+    pub deafen: bool,
+}
+
+impl UserSettings {
+    pub fn new() -> Self {
+        UserSettings {
+            locale: None,
+            color: None,
+            timestamps: Timestamps::Short,
+            timezone: None,
+            notifications: Preferences::VERBOSE,
+            ignored: Vec::new(),
+        }
+    }
+
+    /// Reads settings as they are kept with the account. Unknown keys and
+    /// values that don't parse are left at their defaults.
+    pub fn from_map(map: &HashMap<String, String>) -> Self {
+        let mut settings = UserSettings::new();
+        for (key, value) in map {
+            if key == "ignore" {
+                settings.ignored = value.split(',')
+                    .filter(|login| !login.is_empty())
+                    .map(|login| login.to_lowercase().into())
+                    .collect();
+            } else {
+                let _ = settings.set(key, value);
+            }
+        }
+        settings
+    }
+
+    /// The settings as they are kept with the account, leaving out the ones
+    /// that aren't set.
+    pub fn to_map(&self) -> HashMap<String, String> {
+        let mut map = HashMap::new();
+        if let Some(locale) = self.locale {
+            map.insert("language".to_string(), locale.code().to_string());
+        }
+        if let Some(color) = self.color {
+            map.insert("color".to_string(), on_off(color).to_string());
+        }
+        map.insert("timestamps".to_string(), self.timestamps.name().to_string());
+        if let Some(timezone) = self.timezone {
+            map.insert("timezone".to_string(), format_offset(timezone));
+        }
+        let prefs = self.notifications;
+        for &(key, value) in &[("joins", prefs.joins), ("countdown", prefs.countdown),
+                               ("echo", prefs.echo), ("deafen", prefs.deafen)] {
+            map.insert(key.to_string(), on_off(value).to_string());
+        }
+        if !self.ignored.is_empty() {
+            map.insert("ignore".to_string(), self.ignored.join(","));
+        }
+        map
+    }
+
+    /// Changes one setting, as `!set <key> <value>` does.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), &'static str> {
+        match key {
+            "language" => self.locale = match value {
+                "default" => None,
+                _ => Some(Locale::from_code(value)
+                          .ok_or("Unknown language. Use en, ru or default.\n")?),
+            },
+            "color" => self.color = match value {
+                "auto" => None,
+                _ => Some(parse_on_off(value).ok_or("Usage: !set color on|off|auto\n")?),
+            },
+            "timestamps" => self.timestamps = Timestamps::from_name(value)
+                .ok_or("Usage: !set timestamps off|short|long\n")?,
+            "timezone" => self.timezone = match value {
+                "local" => None,
+                _ => Some(parse_offset(value)
+                          .ok_or("Give the timezone as local, UTC or an offset like +03:00.\n")?),
+            },
+            "joins" | "countdown" | "echo" | "deafen" => {
+                let value = parse_on_off(value).ok_or("Notifications are either on or off.\n")?;
+                let prefs = &mut self.notifications;
+                match key {
+                    "joins" => prefs.joins = value,
+                    "countdown" => prefs.countdown = value,
+                    "echo" => prefs.echo = value,
+                    _ => prefs.deafen = value,
+                }
+            },
+            _ => return Err("Unknown setting. See !settings for the list.\n"),
+        }
+        Ok(())
+    }
+
+    pub fn ignores(&self, login: &str) -> bool {
+        self.ignored.iter().any(|ignored| ignored.eq_ignore_ascii_case(login))
+    }
+
+    /// What `!settings` shows.
+    pub fn describe(&self) -> String {
+        let prefs = self.notifications;
+        format!("language {}\ncolor {}\ntimestamps {}\ntimezone {}\n\
+                 joins {}\ncountdown {}\necho {}\ndeafen {}\n",
+                self.locale.map_or("default", Locale::code),
+                self.color.map_or("auto", on_off),
+                self.timestamps.name(),
+                self.timezone.map_or("local".to_string(), format_offset),
+                on_off(prefs.joins), on_off(prefs.countdown),
+                on_off(prefs.echo), on_off(prefs.deafen))
+    }
+
+    /// The current time, to put before a chat message with a space after it,
+    /// or nothing if the user doesn't want timestamps.
+    pub fn timestamp(&self) -> String {
+        self.timestamp_at(Utc::now())
+    }
+
+    /// Like `timestamp`, for a message sent at `time`.
+    pub fn timestamp_at(&self, time: DateTime<Utc>) -> String {
+        let format = match self.timestamps {
+            Timestamps::Off => return String::new(),
+            Timestamps::Short => "%H:%M ",
+            Timestamps::Long => "%H:%M:%S ",
+        };
+        match self.timezone {
+            Some(offset) => time.with_timezone(&offset).format(format).to_string(),
+            None => time.with_timezone(&Local).format(format).to_string(),
+        }
+    }
+}
+
+impl Timestamps {
+    fn name(self) -> &'static str {
+        match self {
+            Timestamps::Off => "off",
+            Timestamps::Short => "short",
+            Timestamps::Long => "long",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(Timestamps::Off),
+            "short" => Some(Timestamps::Short),
+            "long" => Some(Timestamps::Long),
+            _ => None,
+        }
+    }
+}
+
+impl Preferences {
+    pub const VERBOSE: Preferences = Preferences{joins: true, countdown: true, echo: true, deafen: false};
+    pub const NORMAL: Preferences = Preferences{joins: false, countdown: true, echo: true, deafen: false};
+    pub const QUIET: Preferences = Preferences{joins: false, countdown: false, echo: false, deafen: false};
+
+    pub fn preset(name: &str) -> Option<Preferences> {
+        match name {
+            "verbose" => Some(Preferences::VERBOSE),
+            "normal" => Some(Preferences::NORMAL),
+            "quiet" => Some(Preferences::QUIET),
+            _ => None,
+        }
+    }
+
+    /// Turns a single flag on or off, leaving the others as they are. On
+    /// failure returns a message for the user.
+    pub fn set_flag(&mut self, flag: &str, value: bool) -> Result<(), &'static str> {
+        match flag {
+            "joins" => self.joins = value,
+            "countdown" => self.countdown = value,
+            "echo" => self.echo = value,
+            "deafen" => self.deafen = value,
+            _ => return Err("Unknown flag. Use joins, countdown, echo or deafen.\n"),
+        }
+        Ok(())
+    }
+
+    pub fn describe(&self) -> String {
+        let preset = if *self == Preferences::VERBOSE {
+            "verbose"
+        } else if *self == Preferences::NORMAL {
+            "normal"
+        } else if *self == Preferences::QUIET {
+            "quiet"
+        } else {
+            "custom"
+        };
+        format!("{} (joins {}, countdown {}, echo {}, deafen {})",
+                preset, on_off(self.joins), on_off(self.countdown), on_off(self.echo),
+                on_off(self.deafen))
+    }
+}
+
+fn on_off(value: bool) -> &'static str {
+    if value { "on" } else { "off" }
+}
+
+fn parse_on_off(value: &str) -> Option<bool> {
+    match value {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parses `UTC`, or an offset like `+3`, `-05:30` or `+0200`.
+fn parse_offset(value: &str) -> Option<FixedOffset> {
+    if value.eq_ignore_ascii_case("utc") {
+        return FixedOffset::east_opt(0);
+    }
+    let sign = match value.chars().next()? {
+        '+' => 1,
+        '-' => -1,
+        _ => return None,
+    };
+    let digits: String = value[1..].chars().filter(|&c| c != ':').collect();
+    if digits.is_empty() || digits.len() > 4 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let (hours, minutes) = if digits.len() <= 2 {
+        (digits.parse::<i32>().ok()?, 0)
+    } else {
+        let split = digits.len() - 2;
+        (digits[..split].parse::<i32>().ok()?, digits[split..].parse::<i32>().ok()?)
+    };
+    if hours > 14 || minutes >= 60 {
+        return None;
+    }
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+fn format_offset(offset: FixedOffset) -> String {
+    let seconds = offset.local_minus_utc();
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let minutes = seconds.abs() / 60;
+    format!("{}{:02}:{:02}", sign, minutes / 60, minutes % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags(prefs: Preferences) -> (bool, bool, bool, bool) {
+        (prefs.joins, prefs.countdown, prefs.echo, prefs.deafen)
+    }
+
+    #[test]
+    fn presets_match_the_table() {
+        assert_eq!(flags(Preferences::preset("verbose").unwrap()), (true, true, true, false));
+        assert_eq!(flags(Preferences::preset("normal").unwrap()), (false, true, true, false));
+        assert_eq!(flags(Preferences::preset("quiet").unwrap()), (false, false, false, false));
+        assert!(Preferences::preset("loud").is_none());
+        assert!(UserSettings::new().notifications == Preferences::VERBOSE);
+    }
+
+    #[test]
+    fn override_keeps_the_other_flags() {
+        let mut prefs = Preferences::NORMAL;
+        prefs.set_flag("countdown", false).unwrap();
+        assert_eq!(flags(prefs), (false, false, true, false));
+        prefs.set_flag("deafen", true).unwrap();
+        assert_eq!(flags(prefs), (false, false, true, true));
+        assert!(prefs.set_flag("volume", true).is_err());
+        assert_eq!(flags(prefs), (false, false, true, true));
+    }
+
+    #[test]
+    fn describe_names_the_preset() {
+        assert!(Preferences::QUIET.describe().starts_with("quiet "));
+        let mut prefs = Preferences::QUIET;
+        prefs.set_flag("echo", true).unwrap();
+        assert!(prefs.describe().starts_with("custom "));
+        prefs.set_flag("echo", false).unwrap();
+        assert!(prefs.describe().starts_with("quiet "));
+    }
+}
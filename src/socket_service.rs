@@ -1,23 +1,73 @@
-use crate::util::{monitor, Monitored, FlatlineFuture};
+use crate::util::{monitor, with_timeout, Monitored, FlatlineFuture, Timer};
+use crate::protocol::{self, Protocol, MessageKind};
+use crate::websocket::{self, Frame};
+use crate::telnet::{self, Decoded};
+use crate::proxy_protocol;
+use crate::ansi;
+use crate::metrics::SocketMetrics;
+use crate::bans::BanList;
+use crate::config::LiveConfig;
+use crate::admin_console::{Query, Subject};
 
 use futures::{
     prelude::*,
-    future::Fuse,
+    future::{self, Fuse},
     select,
-    channel::mpsc::{UnboundedSender, UnboundedReceiver, unbounded},
+    channel::mpsc::{self, UnboundedSender, UnboundedReceiver, unbounded},
     io::{ReadHalf, WriteHalf},
 };
 
 use runtime::net::{TcpListener, TcpStream};
 
+use romio::uds::UnixListener;
+
+use async_tls::TlsAcceptor;
+
 use std::{
+    fmt,
     io,
     sync::Arc,
+    fs,
     net::{IpAddr, SocketAddr},
+    path::PathBuf,
     collections::HashMap,
+    time::{Duration, Instant},
 };
 
-pub type SocketId = SocketAddr;
+/// Identifies a connection while the server runs. The number is handed out by
+/// `SocketService` and never reused, so a client connected to two of our ports
+/// from the same address gets two ids; the address only comes along as data.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub struct SocketId {
+    number: u64,
+    peer: Peer,
+}
+
+/// Where a connection comes from.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub enum Peer {
+    Remote(SocketAddr),
+    /// The Unix socket, or no connection at all, for bots.
+    Local,
+}
+
+/// How often connections are pinged.
+const KEEPALIVE_SECONDS: u64 = 30;
+/// Pings in a row a client may leave unanswered before it's taken for gone.
+const MAX_MISSED_PONGS: u32 = 3;
+
+/// Messages a connection may have waiting to be written before the client is
+/// considered too slow to keep and gets disconnected.
+const WRITE_QUEUE: usize = 256;
+/// Most bytes gathered from the queue into a single write.
+const WRITE_BATCH: usize = 65536;
+/// How long a trusted proxy may take to send the PROXY header.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a new connection may take over all of its handshakes.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Lines with invalid UTF-8 in them that may come in a row before the client
+/// is taken to be sending garbage, rather than the odd stray byte.
+const MAX_GARBLED_LINES: u32 = 5;
 
 #[derive(Clone)]
 pub struct SocketProxy {
@@ -31,19 +81,132 @@ pub enum SocketEvent {
     ClosedSocket(SocketId),
 }
 
+/// How many connections the server takes at once, in all and from one address.
+#[derive(Clone, Copy)]
+pub struct ConnectionLimits {
+    pub total: usize,
+    pub per_ip: usize,
+}
+
+/// Where and how to take connections.
+pub struct ListenConfig {
+    /// Addresses to take text clients on; the other ports are opened on each
+    /// of their IPs too.
+    pub listen: Vec<SocketAddr>,
+    /// Port for clients speaking the JSON protocol, if any.
+    pub json_port: Option<u16>,
+    /// Port for browsers to connect to over WebSocket, if any.
+    pub ws_port: Option<u16>,
+    /// Path of a Unix socket for local clients, like admin tools, if any.
+    pub unix_socket: Option<PathBuf>,
+    /// TCP connections come through a proxy, which starts them with a PROXY
+    /// protocol header giving the client's real address.
+    pub proxy_protocol: bool,
+    /// The proxies whose headers are believed; connections from anywhere
+    /// else are taken at their word and not expected to send one.
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
 pub struct SocketService {
     event_handler: UnboundedSender<SocketEvent>,
-    address: IpAddr,
-    port: u16,
-    socket_writer: HashMap<SocketId, Monitored<WriteHalf<TcpStream>>>,
+    config: ListenConfig,
+    /// Wraps every connection, on every port, in TLS.
+    tls: Option<TlsAcceptor>,
+    /// Holds the connection limits, which may be reloaded.
+    live_config: LiveConfig,
+    bans: BanList,
+    connections: HashMap<SocketId, Connection>,
+    /// Connections still doing their handshakes, by the number their id will
+    /// have, with the address they count against, if it's known yet.
+    handshakes: HashMap<u64, Option<IpAddr>>,
+    /// Number of the next connection's id; 0 is left for detached proxies.
+    next_socket: u64,
+    connection_receiver: UnboundedReceiver<io::Result<NewConnection>>,
+    connection_sender: UnboundedSender<io::Result<NewConnection>>,
     request_receiver: UnboundedReceiver<SocketRequest>,
     request_sender: UnboundedSender<SocketRequest>,
     read_receiver: UnboundedReceiver<ReadResult>,
     read_sender: UnboundedSender<ReadResult>,
+    query_receiver: UnboundedReceiver<Query>,
+    query_sender: UnboundedSender<Query>,
+    timer: Timer<Alarm>,
+    metrics: Arc<SocketMetrics>,
+}
+
+enum Alarm {
+    /// Time to check if the connection is idle, as of the given idle epoch.
+    Idle(SocketId, u64),
+    Keepalive,
+}
+
+/// What a connection runs over: a plain TCP stream, or a TLS one.
+pub trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Stream for T {}
+
+enum NewConnection {
+    /// Just accepted, with the handshakes still to do.
+    Tcp(TcpStream, Transport, Protocol),
+    /// A client on the Unix socket, which has no handshakes.
+    Local(Box<dyn Stream>),
+    /// The handshakes of the connection with this number are over.
+    Opened(u64, io::Result<(SocketAddr, Box<dyn Stream>)>, Transport, Protocol),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Transport {
+    Tcp,
+    WebSocket,
+}
+
+struct Connection {
+    /// Feeds the connection's writer task.
+    queue: mpsc::Sender<Vec<u8>>,
+    /// How long the client may go without sending anything; forever if `None`.
+    idle_timeout: Option<Duration>,
+    last_read: Instant,
+    /// Bumped whenever the idle timeout changes, to make the old checks stale.
+    idle_epoch: u64,
+    missed_pongs: u32,
+    /// The client has negotiated telnet options, so it can be pinged in band.
+    /// Plain text clients, like netcat, are left to the idle timeout instead.
+    telnet: bool,
+    /// Messages are coloured with ANSI escapes.
+    color: bool,
+    transport: Transport,
+    protocol: Protocol,
+}
+
+/// What a keepalive round does with a connection.
+#[derive(PartialEq, Debug)]
+enum Keepalive {
+    /// The client can't be pinged without garbling its stream.
+    Skip,
+    Ping,
+    /// The client missed too many pings in a row.
+    Drop,
+}
+
+impl Connection {
+    fn keepalive(&mut self) -> Keepalive {
+        if self.transport == Transport::Tcp && self.protocol == Protocol::Text && !self.telnet {
+            Keepalive::Skip
+        } else if self.missed_pongs >= MAX_MISSED_PONGS {
+            Keepalive::Drop
+        } else {
+            self.missed_pongs += 1;
+            Keepalive::Ping
+        }
+    }
 }
 
 enum SocketRequest {
-    SendMessage(SocketId, SocketMessage),
+    SendMessage(SocketId, MessageKind, SocketMessage),
+    /// Bytes to write exactly as they are, like telnet negotiation.
+    SendBytes(SocketId, Vec<u8>),
+    SetEcho(SocketId, bool),
+    SetColor(SocketId, bool),
+    SetIdleTimeout(SocketId, Duration),
     CloseSocket(SocketId),
 }
 
@@ -53,48 +216,161 @@ enum SocketMessage {
     Arc(Arc<str>),
 }
 
+/// What was sent to a test's proxy.
+#[cfg(test)]
+pub struct Outbox {
+    receiver: UnboundedReceiver<SocketRequest>,
+    /// The proxy was told to close the socket, as of the last look.
+    pub closed: bool,
+}
+
+#[cfg(test)]
+impl Outbox {
+    /// The text sent since the last look.
+    pub fn text(&mut self) -> String {
+        let mut text = String::new();
+        while let Ok(Some(request)) = self.receiver.try_next() {
+            match request {
+                SocketRequest::SendMessage(_, _, message) => text.push_str(match &message {
+                    SocketMessage::Static(message) => *message,
+                    SocketMessage::Boxed(message) => &**message,
+                    SocketMessage::Arc(message) => &**message,
+                }),
+                SocketRequest::CloseSocket(_) => self.closed = true,
+                _ => (),
+            }
+        }
+        text
+    }
+}
+
 struct SocketReader {
     id: SocketId,
-    reader: ReadHalf<TcpStream>,
+    reader: ReadHalf<Box<dyn Stream>>,
     flatline: Fuse<FlatlineFuture>,
     sender: UnboundedSender<ReadResult>,
+    framing: Framing,
+    metrics: Arc<SocketMetrics>,
+    /// Lines in a row that weren't valid UTF-8.
+    garbled_lines: u32,
     keep_running: bool,
 }
 
+/// How the lines are cut out of the data read.
+enum Framing {
+    Telnet(telnet::Decoder),
+    WebSocket(websocket::Decoder),
+}
+
 enum ReadResult {
     Ok(SocketId, Box<str>),
     IoError(SocketId, io::Error),
-    Utf8Error(SocketId, std::str::Utf8Error),
+    Utf8Error(SocketId),
+    FrameError(SocketId, &'static str),
+    /// Something to write back to the client without its asking.
+    Reply(SocketId, Vec<u8>),
+    Pong(SocketId),
+    /// The client showed it speaks telnet.
+    Telnet(SocketId),
+    /// The telnet client named its terminal.
+    Terminal(SocketId, String),
+    LineTooLong(SocketId),
     Closed(SocketId),
 }
 
 impl SocketService {
-    pub fn new(event_handler: UnboundedSender<SocketEvent>, address: IpAddr, port: u16) -> Self {
+    pub fn new(event_handler: UnboundedSender<SocketEvent>, config: ListenConfig,
+               tls: Option<TlsAcceptor>, live_config: LiveConfig,
+               bans: BanList) -> Self {
+        let (connection_sender, connection_receiver) = unbounded();
         let (request_sender, request_receiver) = unbounded();
         let (read_sender, read_receiver) = unbounded();
+        let (query_sender, query_receiver) = unbounded();
         SocketService {
             event_handler,
-            address,
-            port,
-            socket_writer: HashMap::new(),
+            config,
+            tls,
+            live_config,
+            bans,
+            connections: HashMap::new(),
+            handshakes: HashMap::new(),
+            next_socket: 1,
+            connection_receiver,
+            connection_sender,
             request_receiver,
             request_sender,
             read_receiver,
             read_sender,
+            query_receiver,
+            query_sender,
+            timer: Timer::new(),
+            metrics: Arc::new(SocketMetrics::default()),
         }
     }
 
-    pub async fn run(mut self) -> std::io::Result<()> {
-        let mut listener = TcpListener::bind((self.address, self.port))?;
-        println!("Listening on {}", listener.local_addr()?);
+    pub fn metrics(&self) -> Arc<SocketMetrics> {
+        self.metrics.clone()
+    }
 
-        let mut connections = listener.incoming();
+    pub fn make_query_handler(&self) -> UnboundedSender<Query> {
+        self.query_sender.clone()
+    }
 
+    pub async fn run(mut self) -> std::io::Result<()> {
+        let mut ips: Vec<IpAddr> = Vec::new();
+        for &address in &self.config.listen {
+            let listener = TcpListener::bind(address)?;
+            info!("Listening on {}", listener.local_addr()?);
+            self.accept(listener, Transport::Tcp, Protocol::Text);
+            if !ips.contains(&address.ip()) {
+                ips.push(address.ip());
+            }
+        }
+        for &ip in &ips {
+            if let Some(port) = self.config.json_port {
+                let listener = TcpListener::bind((ip, port))?;
+                info!("Listening for JSON clients on {}", listener.local_addr()?);
+                self.accept(listener, Transport::Tcp, Protocol::Json);
+            }
+            if let Some(port) = self.config.ws_port {
+                let listener = TcpListener::bind((ip, port))?;
+                info!("Listening for WebSocket clients on {}", listener.local_addr()?);
+                self.accept(listener, Transport::WebSocket, Protocol::Text);
+            }
+        }
+        if let Some(path) = &self.config.unix_socket {
+            // A socket file left over from an earlier run would be in the way.
+            let _ = fs::remove_file(path);
+            let listener = UnixListener::bind(path)?;
+            info!("Listening on {}", path.display());
+            self.accept_unix(listener);
+        }
+        self.metrics.set_listening();
+
+        self.timer.add_alarm(KEEPALIVE_SECONDS * 1000, Alarm::Keepalive);
         loop {
             select! {
-                maybe_stream = connections.next().fuse() => {
-                    self.handle_connection(maybe_stream
-                                           .expect("SocketService connections stream terminated")?);
+                maybe_connection = self.connection_receiver.next().fuse() => {
+                    match maybe_connection.expect("SocketService connections stream terminated")? {
+                        NewConnection::Tcp(stream, transport, protocol) =>
+                            self.handle_accepted(stream, transport, protocol),
+                        NewConnection::Local(stream) => {
+                            let number = self.next_socket;
+                            self.next_socket += 1;
+                            let id = SocketId{ number, peer: Peer::Local };
+                            self.handle_connection(id, stream, Transport::Tcp, Protocol::Text);
+                        },
+                        NewConnection::Opened(number, result, transport, protocol) => {
+                            self.handshakes.remove(&number);
+                            match result {
+                                Ok((address, stream)) => {
+                                    let id = SocketId{ number, peer: Peer::Remote(address) };
+                                    self.handle_connection(id, stream, transport, protocol);
+                                },
+                                Err(err) => warn!("Failed to open a connection: {}", err),
+                            }
+                        },
+                    }
                 },
                 maybe_read = self.read_receiver.next().fuse() => {
                     if let Some(result) = maybe_read {
@@ -105,82 +381,494 @@ impl SocketService {
                 },
                 maybe_request = self.request_receiver.next().fuse() => {
                     self.handle_request(maybe_request
-                                        .expect("SocketService request stream terminated")).await;
+                                        .expect("SocketService request stream terminated"));
+                },
+                maybe_query = self.query_receiver.next().fuse() => {
+                    self.handle_query(maybe_query.expect("SocketService query stream terminated"));
+                },
+                maybe_alarm = self.timer.next().fuse() => {
+                    match maybe_alarm.expect("SocketService timer terminated") {
+                        Alarm::Idle(id, epoch) => self.check_idle(id, epoch),
+                        Alarm::Keepalive => {
+                            self.keepalive();
+                            self.timer.add_alarm(KEEPALIVE_SECONDS * 1000, Alarm::Keepalive);
+                        },
+                    }
                 },
             }
         }
     }
 
-    fn handle_connection(&mut self, stream: TcpStream) {
-        if let Ok(id) = stream.peer_addr() {
-            eprintln!("New connection from {}", id);
-            let proxy = SocketProxy{ id, channel: self.request_sender.clone() };
-            let (reader, writer) = stream.split();
-            let (monitored, flatline) = monitor(writer);
-            self.socket_writer.insert(id, monitored);
+    /// Accepts connections on the listener for as long as the service runs,
+    /// and hands them to the service to be let in or turned away.
+    fn accept(&self, mut listener: TcpListener, transport: Transport, protocol: Protocol) {
+        let sender = self.connection_sender.clone();
+        #[allow(unused)] {
+            runtime::spawn(async move {
+                let mut connections = listener.incoming();
+                while let Some(stream) = connections.next().await {
+                    let connection = stream.map(|stream| {
+                        NewConnection::Tcp(stream, transport, protocol)
+                    });
+                    if sender.unbounded_send(connection).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    }
+
+    /// Accepts local connections, which speak the text protocol without TLS.
+    fn accept_unix(&self, mut listener: UnixListener) {
+        let sender = self.connection_sender.clone();
+        #[allow(unused)] {
+            runtime::spawn(async move {
+                let mut connections = listener.incoming();
+                while let Some(stream) = connections.next().await {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(err) => {
+                            let _ = sender.unbounded_send(Err(err));
+                            return;
+                        },
+                    };
+                    if sender.unbounded_send(Ok(NewConnection::Local(Box::new(stream)))).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    }
 
-            #[allow(unused)] {
-                runtime::spawn(SocketReader::run(id, reader, flatline, self.read_sender.clone()));
-            }
+    /// Lets a connection that was just accepted go on to its handshakes, each
+    /// in its own task so that a slow client can't hold the others up, unless
+    /// it's over the limits. It counts against them from now on.
+    fn handle_accepted(&mut self, stream: TcpStream, transport: Transport, protocol: Protocol) {
+        let address = match stream.peer_addr() {
+            Ok(address) => address,
+            Err(err) => {
+                warn!("Failed to open a connection: {}", err);
+                return;
+            },
+        };
+        // The clients behind a proxy are only known once it sends the header.
+        let proxied = self.config.proxy_protocol
+            && self.config.trusted_proxies.contains(&address.ip());
+        let ip = if proxied { None } else { Some(address.ip()) };
+        if let Some(rejection) = self.check_limits(ip) {
+            info!(conn = address; "Rejecting connection: {}", rejection.trim_end());
+            self.metrics.rejected.increment();
+            // Before the handshakes, only plain TCP clients can be told why.
+            let data = if self.tls.is_none() && transport == Transport::Tcp {
+                encode(transport, protocol, MessageKind::System, rejection, false)
+            } else {
+                Vec::new()
+            };
+            refuse(Box::new(stream), data);
+            return;
+        }
+        let number = self.next_socket;
+        self.next_socket += 1;
+        self.handshakes.insert(number, ip);
+        let sender = self.connection_sender.clone();
+        let tls = self.tls.clone();
+        #[allow(unused)] {
+            runtime::spawn(async move {
+                let opening = open(stream, proxied, tls, transport);
+                let result = with_timeout(HANDSHAKE_TIMEOUT, opening).await;
+                let _ = sender.unbounded_send(Ok(NewConnection::Opened(number, result,
+                                                                        transport, protocol)));
+            });
+        }
+    }
 
-            self.event_handler.unbounded_send(SocketEvent::NewSocket(proxy))
-                .expect("SocketService event_handler stream error");
+    /// Why a connection from `ip` can't be let in, if it can't. Connections
+    /// still shaking hands count too. Local clients are all the same to us, so
+    /// they aren't limited by address.
+    fn check_limits(&self, ip: Option<IpAddr>) -> Option<&'static str> {
+        let limits = self.live_config.limits();
+        let from_ip = match ip {
+            Some(ip) => self.connections.keys().filter(|other| other.ip() == Some(ip)).count()
+                + self.handshakes.values().filter(|&&other| other == Some(ip)).count(),
+            None => 0,
+        };
+        if ip.map_or(false, |ip| self.bans.is_ip_banned(ip)) {
+            Some("You are banned from this server.\n")
+        } else if self.connections.len() + self.handshakes.len() >= limits.total {
+            Some("The server is full. Please try again later.\n")
+        } else if from_ip >= limits.per_ip {
+            Some("Too many connections from your address.\n")
+        } else {
+            None
+        }
+    }
+
+    /// Takes on a connection whose handshakes are done. The limits are checked
+    /// again, since only now is the address of a proxied client known.
+    fn handle_connection(&mut self, id: SocketId, stream: Box<dyn Stream>,
+                         transport: Transport, protocol: Protocol) {
+        if let Some(rejection) = self.check_limits(id.ip()) {
+            info!(conn = id; "Rejecting connection: {}", rejection.trim_end());
+            self.metrics.rejected.increment();
+            refuse(stream, encode(transport, protocol, MessageKind::System, rejection, false));
+            return;
         }
+        info!(conn = id; "New connection");
+        self.metrics.accepted.increment();
+        let proxy = SocketProxy{ id, channel: self.request_sender.clone() };
+        let (reader, writer) = stream.split();
+        let (monitored, flatline) = monitor(writer);
+        let (queue, pending) = mpsc::channel(WRITE_QUEUE);
+        self.connections.insert(id, Connection {
+            queue,
+            transport,
+            protocol,
+            idle_timeout: None,
+            last_read: Instant::now(),
+            idle_epoch: 0,
+            missed_pongs: 0,
+            telnet: false,
+            color: false,
+        });
+        if transport == Transport::Tcp && protocol == Protocol::Text {
+            // Terminals that say what they are get colour from the start.
+            self.enqueue(id, telnet::request_terminal_type());
+        }
+
+        #[allow(unused)] {
+            runtime::spawn(SocketReader::run(id, reader, flatline, self.read_sender.clone(),
+                                             transport, self.metrics.clone()));
+            runtime::spawn(write_forever(id, monitored, pending, self.read_sender.clone(),
+                                         self.metrics.clone()));
+        }
+
+        self.event_handler.unbounded_send(SocketEvent::NewSocket(proxy))
+            .expect("SocketService event_handler stream error");
     }
 
     fn handle_read(&mut self, result: ReadResult) {
         match result {
             ReadResult::Ok(id, data) => {
-                eprintln!("Received {} bytes from {}", data.len(), id);
+                debug!(conn = id; "Received {} bytes", data.len());
+                let is_json = match self.connections.get_mut(&id) {
+                    Some(connection) => {
+                        connection.last_read = Instant::now();
+                        connection.protocol == Protocol::Json
+                    },
+                    None => false,
+                };
+                let data = if is_json {
+                    match protocol::decode(&data) {
+                        Ok(Some(line)) => line.into_boxed_str(),
+                        Ok(None) => {
+                            self.handle_pong(id);
+                            return;
+                        },
+                        Err(err) => {
+                            self.handle_request(SocketRequest::SendMessage(
+                                id, MessageKind::System, SocketMessage::Static(err)));
+                            return;
+                        },
+                    }
+                } else {
+                    data
+                };
                 self.event_handler.unbounded_send(SocketEvent::NewMessage(id, data))
                     .expect("SocketService event_handler stream error");
             },
             ReadResult::Closed(id) => {
-                eprintln!("Remote closed connection: {}", id);
+                info!(conn = id; "Remote closed connection");
                 self.close_connection(id);
             },
-            ReadResult::Utf8Error(id, _) => {
-                eprintln!("Closing connection to {}: invalid utf-8", id);
-                self.close_connection(id);
+            ReadResult::Utf8Error(id) => {
+                warn!(conn = id; "Closing connection: too much invalid utf-8");
+                self.force_close(id);
             },
             ReadResult::IoError(id, err) => {
-                eprintln!("Closing connection to {}: write error {}", id, err);
+                warn!(conn = id; "Closing connection: write error {}", err);
                 self.close_connection(id);
             },
+            ReadResult::FrameError(id, err) => {
+                warn!(conn = id; "Closing connection: {}", err);
+                self.force_close(id);
+            },
+            ReadResult::Reply(id, data) => self.enqueue(id, data),
+            ReadResult::Pong(id) => self.handle_pong(id),
+            ReadResult::Telnet(id) => {
+                if let Some(connection) = self.connections.get_mut(&id) {
+                    connection.telnet = true;
+                }
+            },
+            ReadResult::Terminal(id, name) => {
+                let name = name.to_ascii_uppercase();
+                if let Some(connection) = self.connections.get_mut(&id) {
+                    connection.color = name != "DUMB" && name != "UNKNOWN";
+                }
+            },
+            ReadResult::LineTooLong(id) => {
+                let message =
+                    SocketMessage::Static("Your line was too long and has been dropped.\n");
+                self.handle_request(SocketRequest::SendMessage(id, MessageKind::System, message));
+            },
         }
     }
 
     fn close_connection(&mut self, id: SocketId) {
-        if let Some(mut writer) = self.socket_writer.remove(&id) {
-            writer.close();
+        // Dropping the queue lets the writer task finish what's queued and
+        // close the socket, which stops the reader.
+        if self.connections.remove(&id).is_some() {
+            self.metrics.closed.increment();
             self.event_handler.unbounded_send(SocketEvent::ClosedSocket(id))
                 .expect("SocketService event_handler stream error");
         }
     }
 
-    async fn handle_request(&mut self, request: SocketRequest) {
+    /// Answers the admin console.
+    fn handle_query(&self, query: Query) {
+        let answer = match query.subject {
+            Subject::Connections => {
+                let now = Instant::now();
+                let mut ids: Vec<&SocketId> = self.connections.keys().collect();
+                ids.sort();
+                let mut answer = format!("{}\n", self.metrics.report());
+                for id in ids {
+                    let connection = &self.connections[id];
+                    answer.push_str(&format!("  {} {}{}, idle {}s{}\n",
+                                             id,
+                                             match connection.transport {
+                                                 Transport::Tcp => "tcp",
+                                                 Transport::WebSocket => "websocket",
+                                             },
+                                             match connection.protocol {
+                                                 Protocol::Text => "",
+                                                 Protocol::Json => "/json",
+                                             },
+                                             (now - connection.last_read).as_secs(),
+                                             if connection.color { ", color" } else { "" }));
+                }
+                answer
+            },
+            Subject::Ping => "pong\n".to_string(),
+            _ => "SocketService can't answer that.\n".to_string(),
+        };
+        let _ = query.reply.send(answer);
+    }
+
+    fn handle_request(&mut self, request: SocketRequest) {
         match request {
-            SocketRequest::SendMessage(id, message) => {
-                if let Some(writer) = self.socket_writer.get_mut(&id) {
-                    let data = match &message {
-                        SocketMessage::Static(string) => string.as_bytes(),
-                        SocketMessage::Boxed(string) => string.as_bytes(),
-                        SocketMessage::Arc(string) => string.as_bytes(),
-                    };
-                    if let Err(err) = writer.write_all(&data).await {
-                        eprintln!("Closing connection to {}: write error {}", id, err);
-                        self.close_connection(id);
-                    }
+            SocketRequest::SendMessage(id, kind, message) => {
+                let connection = match self.connections.get(&id) {
+                    Some(connection) => connection,
+                    None => return,
+                };
+                let text: &str = match &message {
+                    SocketMessage::Static(string) => string,
+                    SocketMessage::Boxed(string) => string,
+                    SocketMessage::Arc(string) => string,
+                };
+                let data = encode(connection.transport, connection.protocol, kind, text,
+                                  connection.color);
+                self.enqueue(id, data);
+            },
+            SocketRequest::SendBytes(id, data) => self.enqueue(id, data),
+            SocketRequest::SetEcho(id, echo) => {
+                // Only telnet clients know what to do with this.
+                let is_telnet = self.connections.get(&id).map_or(false, |connection| {
+                    connection.transport == Transport::Tcp && connection.protocol == Protocol::Text
+                });
+                if is_telnet {
+                    self.enqueue(id, telnet::set_echo(echo));
+                }
+            },
+            SocketRequest::SetColor(id, color) => {
+                // Browsers and JSON clients would show the escapes as they are.
+                if let Some(connection) = self.connections.get_mut(&id) {
+                    connection.color = color && connection.transport == Transport::Tcp
+                        && connection.protocol == Protocol::Text;
+                }
+            },
+            SocketRequest::SetIdleTimeout(id, timeout) => {
+                if let Some(connection) = self.connections.get_mut(&id) {
+                    connection.idle_timeout = Some(timeout);
+                    connection.last_read = Instant::now();
+                    connection.idle_epoch += 1;
+                    self.timer.add_alarm(timeout.as_millis() as u64,
+                                         Alarm::Idle(id, connection.idle_epoch));
                 }
             },
             SocketRequest::CloseSocket(id) => {
-                if let Some(_) = self.socket_writer.get_mut(&id) {
-                    eprintln!("Closing connection to {}", id);
+                if let Some(_) = self.connections.get_mut(&id) {
+                    info!(conn = id; "Closing connection");
                     self.close_connection(id);
                 }
             },
         }
     }
+
+    /// Pings every connection that can be, and drops the ones that stopped
+    /// answering.
+    fn keepalive(&mut self) {
+        let mut gone = Vec::new();
+        let mut pings = Vec::new();
+        for (&id, connection) in self.connections.iter_mut() {
+            match connection.keepalive() {
+                Keepalive::Skip => {},
+                Keepalive::Ping => {
+                    pings.push((id, ping(connection.transport, connection.protocol)));
+                },
+                Keepalive::Drop => gone.push(id),
+            }
+        }
+        for id in gone {
+            info!(conn = id; "Closing connection: no answer to {} pings", MAX_MISSED_PONGS);
+            self.force_close(id);
+        }
+        for (id, data) in pings {
+            self.enqueue(id, data);
+        }
+    }
+
+    fn handle_pong(&mut self, id: SocketId) {
+        if let Some(connection) = self.connections.get_mut(&id) {
+            connection.missed_pongs = 0;
+        }
+    }
+
+    /// Closes the connection if the client has sent nothing for the whole idle
+    /// timeout, and checks again once it would have otherwise.
+    fn check_idle(&mut self, id: SocketId, epoch: u64) {
+        let (timeout, idle) = match self.connections.get(&id) {
+            Some(connection) if connection.idle_epoch == epoch => match connection.idle_timeout {
+                Some(timeout) => (timeout, connection.last_read.elapsed()),
+                None => return,
+            },
+            _ => return,
+        };
+        if idle < timeout {
+            self.timer.add_alarm((timeout - idle).as_millis() as u64 + 1, Alarm::Idle(id, epoch));
+            return;
+        }
+        info!(conn = id; "Closing connection: idle for {} seconds", idle.as_secs());
+        let message = SocketMessage::Static("You have been idle for too long. Goodbye!\n");
+        self.handle_request(SocketRequest::SendMessage(id, MessageKind::System, message));
+        self.force_close(id);
+    }
+
+    /// Hands the data to the connection's writer task. A client that lets its
+    /// queue fill up is disconnected rather than left to hold up the others or
+    /// use up memory.
+    fn enqueue(&mut self, id: SocketId, data: Vec<u8>) {
+        let result = match self.connections.get_mut(&id) {
+            Some(connection) => connection.queue.try_send(data),
+            None => return,
+        };
+        if let Err(err) = result {
+            if err.is_full() {
+                warn!(conn = id; "Closing connection: too many unsent messages");
+                self.force_close(id);
+            } else {
+                self.close_connection(id);
+            }
+        }
+    }
+
+    /// Closes a connection the client would rather have kept.
+    fn force_close(&mut self, id: SocketId) {
+        if self.connections.contains_key(&id) {
+            self.metrics.forced_closes.increment();
+            self.close_connection(id);
+        }
+    }
+}
+
+/// Puts a message into the form the connection's client expects.
+fn encode(transport: Transport, wire: Protocol, kind: MessageKind, text: &str,
+          color: bool) -> Vec<u8> {
+    let encoded;
+    let text = match wire {
+        Protocol::Text if color => {
+            encoded = ansi::paint(kind, text);
+            &encoded
+        },
+        Protocol::Text => text,
+        Protocol::Json => {
+            encoded = protocol::encode(kind, text);
+            &encoded
+        },
+    };
+    match transport {
+        Transport::Tcp => text.as_bytes().to_vec(),
+        Transport::WebSocket => websocket::encode_text(text),
+    }
+}
+
+fn ping(transport: Transport, wire: Protocol) -> Vec<u8> {
+    match (transport, wire) {
+        (Transport::WebSocket, _) => websocket::ping(),
+        (Transport::Tcp, Protocol::Json) => protocol::ping().as_bytes().to_vec(),
+        (Transport::Tcp, Protocol::Text) => telnet::ping(),
+    }
+}
+
+/// Writes out the connection's queue until the queue is dropped or a write fails.
+/// Whatever has piled up in the queue by the time the writer wakes up goes out
+/// in one write, so a broadcast costs a syscall per client rather than one per
+/// message.
+async fn write_forever(
+    id: SocketId,
+    mut writer: Monitored<WriteHalf<Box<dyn Stream>>>,
+    mut pending: mpsc::Receiver<Vec<u8>>,
+    sender: UnboundedSender<ReadResult>,
+    metrics: Arc<SocketMetrics>,
+) {
+    while let Some(mut batch) = pending.next().await {
+        while batch.len() < WRITE_BATCH {
+            match pending.try_next() {
+                Ok(Some(data)) => batch.extend_from_slice(&data),
+                _ => break,
+            }
+        }
+        if let Err(err) = writer.write_all(&batch).await {
+            let _ = sender.unbounded_send(ReadResult::IoError(id, err));
+            return;
+        }
+        metrics.bytes_written.add(batch.len() as u64);
+    }
+    let _ = writer.close().await;
+}
+
+/// Writes the rejection, if there is one, and closes the connection.
+fn refuse(mut stream: Box<dyn Stream>, data: Vec<u8>) {
+    #[allow(unused)] {
+        runtime::spawn(async move {
+            if data.is_empty() || stream.write_all(&data).await.is_ok() {
+                let _ = stream.close().await;
+            }
+        });
+    }
+}
+
+/// Does the handshakes a new connection needs before it can be used. Only the
+/// trusted proxies, whose connections are `proxied`, get to say the connection
+/// is from somewhere else.
+async fn open(mut stream: TcpStream, proxied: bool, tls: Option<TlsAcceptor>,
+              transport: Transport) -> io::Result<(SocketAddr, Box<dyn Stream>)>
+{
+    let mut address = stream.peer_addr()?;
+    if proxied {
+        let header = proxy_protocol::read_header(&mut stream);
+        if let Some(client) = with_timeout(PROXY_HEADER_TIMEOUT, header).await? {
+            address = client;
+        }
+    }
+    let mut stream: Box<dyn Stream> = match tls {
+        Some(acceptor) => Box::new(acceptor.accept(stream).await?),
+        None => Box::new(stream),
+    };
+    if transport == Transport::WebSocket {
+        websocket::handshake(&mut stream).await?;
+    }
+    Ok((address, stream))
 }
 
 impl SocketReader {
@@ -188,12 +876,27 @@ impl SocketReader {
     
     async fn run(
         id: SocketId,
-        reader: ReadHalf<TcpStream>,
+        reader: ReadHalf<Box<dyn Stream>>,
         flatline: FlatlineFuture,
-        sender: UnboundedSender<ReadResult>
+        sender: UnboundedSender<ReadResult>,
+        transport: Transport,
+        metrics: Arc<SocketMetrics>,
     ) {
         let flatline = flatline.fuse();
-        let socket_reader = SocketReader{id, reader, flatline, sender, keep_running: true};
+        let framing = match transport {
+            Transport::Tcp => Framing::Telnet(telnet::Decoder::new()),
+            Transport::WebSocket => Framing::WebSocket(websocket::Decoder::new()),
+        };
+        let socket_reader = SocketReader {
+            id,
+            reader,
+            flatline,
+            sender,
+            framing,
+            metrics,
+            garbled_lines: 0,
+            keep_running: true,
+        };
         socket_reader.read_forever().await
     }
 
@@ -203,8 +906,12 @@ impl SocketReader {
             select! {
                 result = self.reader.read(&mut buffer).fuse() => {
                     match result {
-                        Ok(len) => self.handle_data(&buffer[..len]),
+                        Ok(len) => {
+                            self.metrics.bytes_read.add(len as u64);
+                            self.handle_data(&buffer[..len]);
+                        },
                         Err(err) => {
+                            self.metrics.read_errors.increment();
                             self.sender.unbounded_send(ReadResult::IoError(self.id, err))
                                 .expect(Self::ERROR);
                             return;
@@ -223,31 +930,160 @@ impl SocketReader {
             self.keep_running = false;
             return;
         }
+        match &mut self.framing {
+            Framing::Telnet(decoder) => {
+                let decoded = decoder.decode(data);
+                self.handle_lines(decoded);
+            },
+            Framing::WebSocket(decoder) => {
+                let decoded = decoder.decode(data);
+                self.handle_frames(decoded);
+            },
+        }
+    }
 
-        let mut start = 0;
-        for i in 0..data.len() {
-            if data[i] == b'\n' {
-                match std::str::from_utf8(&data[start..i]) {
-                    Ok(line) => {
-                        self.sender.unbounded_send(ReadResult::Ok(self.id, line.trim().into()))
-                            .expect(Self::ERROR);
-                    },
-                    Err(err) => {
-                        self.sender.unbounded_send(ReadResult::Utf8Error(self.id, err))
-                            .expect(Self::ERROR);
-                        self.keep_running = false;
-                        return;
-                    }
+    /// Passes on the lines typed, and has the negotiation answered.
+    fn handle_lines(&mut self, decoded: Decoded) {
+        if !decoded.reply.is_empty() {
+            self.sender.unbounded_send(ReadResult::Reply(self.id, decoded.reply))
+                .expect(Self::ERROR);
+        }
+        if decoded.negotiated {
+            self.sender.unbounded_send(ReadResult::Telnet(self.id)).expect(Self::ERROR);
+        }
+        if decoded.pong {
+            self.sender.unbounded_send(ReadResult::Pong(self.id)).expect(Self::ERROR);
+        }
+        if let Some(terminal) = decoded.terminal {
+            self.sender.unbounded_send(ReadResult::Terminal(self.id, terminal))
+                .expect(Self::ERROR);
+        }
+        for _ in 0..decoded.dropped {
+            self.sender.unbounded_send(ReadResult::LineTooLong(self.id)).expect(Self::ERROR);
+        }
+        for line in decoded.lines {
+            if !self.handle_line(&line) {
+                return;
+            }
+        }
+    }
+
+    /// Passes on every line of every complete text frame.
+    fn handle_frames(&mut self, decoded: Result<Vec<Frame>, &'static str>) {
+        let frames = match decoded {
+            Ok(frames) => frames,
+            Err(err) => {
+                self.sender.unbounded_send(ReadResult::FrameError(self.id, err))
+                    .expect(Self::ERROR);
+                self.keep_running = false;
+                return;
+            },
+        };
+        for frame in frames {
+            let text = match frame {
+                Frame::Text(text) => text,
+                Frame::Pong => {
+                    self.sender.unbounded_send(ReadResult::Pong(self.id)).expect(Self::ERROR);
+                    continue;
+                },
+                Frame::Ping(payload) => {
+                    let pong = websocket::pong(&payload);
+                    self.sender.unbounded_send(ReadResult::Reply(self.id, pong))
+                        .expect(Self::ERROR);
+                    continue;
+                },
+                Frame::Close(status) => {
+                    // The echo goes out ahead of the close, since the writer
+                    // finishes its queue first.
+                    let echo = websocket::close(&status);
+                    self.sender.unbounded_send(ReadResult::Reply(self.id, echo))
+                        .expect(Self::ERROR);
+                    self.sender.unbounded_send(ReadResult::Closed(self.id))
+                        .expect(Self::ERROR);
+                    self.keep_running = false;
+                    return;
+                },
+            };
+            let text = if text.ends_with(b"\n") { &text[..text.len() - 1] } else { &text[..] };
+            for line in text.split(|&byte| byte == b'\n') {
+                if !self.handle_line(line) {
+                    return;
                 }
-                start = i+1;
             }
         }
     }
+
+    /// Passes on a line, with any invalid UTF-8 in it replaced, since telnet
+    /// clients are prone to sending stray bytes. Returns false, having given up
+    /// on the connection, once too many lines in a row have needed that.
+    fn handle_line(&mut self, line: &[u8]) -> bool {
+        let line = String::from_utf8_lossy(line);
+        if let std::borrow::Cow::Owned(_) = line {
+            self.garbled_lines += 1;
+            if self.garbled_lines >= MAX_GARBLED_LINES {
+                self.sender.unbounded_send(ReadResult::Utf8Error(self.id)).expect(Self::ERROR);
+                self.keep_running = false;
+                return false;
+            }
+        } else {
+            self.garbled_lines = 0;
+        }
+        self.sender.unbounded_send(ReadResult::Ok(self.id, line.trim().into()))
+            .expect(Self::ERROR);
+        true
+    }
+}
+
+impl SocketId {
+    /// The address the client is connected from, unless they are local.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self.peer {
+            Peer::Remote(address) => Some(address.ip()),
+            Peer::Local => None,
+        }
+    }
+}
+
+impl fmt::Display for SocketId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.peer {
+            Peer::Remote(address) => write!(f, "{}#{}", address, self.number),
+            Peer::Local => write!(f, "local#{}", self.number),
+        }
+    }
 }
 
 impl SocketProxy {
     const ERROR: &'static str = "SocketProxy channel error";
 
+    /// A proxy with no socket behind it, for bots: whatever is sent through it is dropped.
+    pub fn detached() -> Self {
+        let (channel, receiver) = unbounded();
+        #[allow(unused)] {
+            runtime::spawn(receiver.for_each(|_| future::ready(())));
+        }
+        SocketProxy{id: SocketId{ number: 0, peer: Peer::Local }, channel}
+    }
+
+    /// A proxy with a number of its own and no connection behind it, for
+    /// tests. What is sent to it waits in the outbox.
+    #[cfg(test)]
+    pub fn numbered(number: u64) -> (Self, Outbox) {
+        Self::with_peer(number, Peer::Local)
+    }
+
+    /// Like `numbered`, but the client seems to connect from `address`.
+    #[cfg(test)]
+    pub fn remote(number: u64, address: SocketAddr) -> (Self, Outbox) {
+        Self::with_peer(number, Peer::Remote(address))
+    }
+
+    #[cfg(test)]
+    fn with_peer(number: u64, peer: Peer) -> (Self, Outbox) {
+        let (channel, receiver) = unbounded();
+        (SocketProxy{id: SocketId{ number, peer }, channel}, Outbox{receiver, closed: false})
+    }
+
     pub fn get_id(&self) -> SocketId {
         self.id
     }
@@ -257,20 +1093,42 @@ impl SocketProxy {
     }
 
     pub fn send_boxed(&self, message: Box<str>) {
-        self.channel.unbounded_send(SocketRequest::SendMessage(self.id,
-                                                               SocketMessage::Boxed(message)))
-            .expect(Self::ERROR);
+        self.send_message(MessageKind::System, SocketMessage::Boxed(message));
     }
 
     pub fn send_arc(&self, message: Arc<str>) {
-        self.channel.unbounded_send(SocketRequest::SendMessage(self.id,
-                                                               SocketMessage::Arc(message)))
-            .expect(Self::ERROR);
+        self.send_message(MessageKind::System, SocketMessage::Arc(message));
     }
 
     pub fn send_static(&self, message: &'static str) {
-        self.channel.unbounded_send(SocketRequest::SendMessage(self.id,
-                                                               SocketMessage::Static(message)))
+        self.send_message(MessageKind::System, SocketMessage::Static(message));
+    }
+
+    /// Sends a message that JSON clients should see as something other than
+    /// a system message.
+    pub fn send_typed(&self, kind: MessageKind, message: Arc<str>) {
+        self.send_message(kind, SocketMessage::Arc(message));
+    }
+
+    fn send_message(&self, kind: MessageKind, message: SocketMessage) {
+        self.channel.unbounded_send(SocketRequest::SendMessage(self.id, kind, message))
+            .expect(Self::ERROR);
+    }
+
+    /// Turns the client's echo of what the user types on or off, for hiding
+    /// passwords.
+    pub fn set_echo(&self, echo: bool) {
+        self.channel.unbounded_send(SocketRequest::SetEcho(self.id, echo)).expect(Self::ERROR);
+    }
+
+    /// Turns ANSI colour on or off, where the client can show it.
+    pub fn set_color(&self, color: bool) {
+        self.channel.unbounded_send(SocketRequest::SetColor(self.id, color)).expect(Self::ERROR);
+    }
+
+    /// Closes the connection once the client has sent nothing for this long.
+    pub fn set_idle_timeout(&self, timeout: Duration) {
+        self.channel.unbounded_send(SocketRequest::SetIdleTimeout(self.id, timeout))
             .expect(Self::ERROR);
     }
 
@@ -278,3 +1136,52 @@ impl SocketProxy {
         self.channel.unbounded_send(SocketRequest::CloseSocket(self.id)).expect(Self::ERROR);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection(transport: Transport, protocol: Protocol) -> Connection {
+        Connection {
+            queue: mpsc::channel(1).0,
+            transport,
+            protocol,
+            idle_timeout: None,
+            last_read: Instant::now(),
+            idle_epoch: 0,
+            missed_pongs: 0,
+            telnet: false,
+            color: false,
+        }
+    }
+
+    #[test]
+    fn silent_client_is_dropped_after_missing_its_pongs() {
+        let mut connection = connection(Transport::WebSocket, Protocol::Text);
+        for _ in 0..MAX_MISSED_PONGS {
+            assert_eq!(connection.keepalive(), Keepalive::Ping);
+        }
+        assert_eq!(connection.keepalive(), Keepalive::Drop);
+    }
+
+    #[test]
+    fn pong_keeps_the_client() {
+        let mut connection = connection(Transport::Tcp, Protocol::Json);
+        for _ in 0..MAX_MISSED_PONGS * 2 {
+            assert_eq!(connection.keepalive(), Keepalive::Ping);
+            // What `handle_pong` does when the answer comes.
+            connection.missed_pongs = 0;
+        }
+    }
+
+    #[test]
+    fn plain_text_client_is_pinged_only_once_it_speaks_telnet() {
+        let mut connection = connection(Transport::Tcp, Protocol::Text);
+        assert_eq!(connection.keepalive(), Keepalive::Skip);
+        let mut decoder = telnet::Decoder::new();
+        assert!(!decoder.decode(b"hello\r\n").negotiated);
+        assert!(decoder.decode(&[255, 252, 24]).negotiated);
+        connection.telnet = true;
+        assert_eq!(connection.keepalive(), Keepalive::Ping);
+    }
+}
@@ -0,0 +1,122 @@
+use crate::roles::Faction;
+use crate::rating::INITIAL_RATING;
+
+use std::collections::HashMap;
+
+/// Career record of a single login.
+#[derive(Clone, Copy)]
+pub struct PlayerStats {
+    pub games: u32,
+    pub town_wins: u32,
+    pub mafia_wins: u32,
+    pub maniac_wins: u32,
+    pub lovers_wins: u32,
+    /// Games in which the player was the first one lynched, on day one.
+    pub lynched_day_one: u32,
+    /// Games the player lived through to the end.
+    pub survived: u32,
+    pub rating: i32,
+}
+
+/// How one game went for one player.
+pub struct GameResult {
+    /// The faction the player won with, if they won.
+    pub win: Option<Faction>,
+    pub lynched_day_one: bool,
+    pub survived: bool,
+    pub rating_change: i32,
+}
+
+/// Storage for per-login statistics. The in-memory `MemoryStats` is the
+/// default; a persistent backend only has to implement this trait.
+pub trait StatsStore: Send {
+    fn record(&mut self, login: &str, result: &GameResult);
+
+    fn get(&self, login: &str) -> Option<PlayerStats>;
+
+    /// The `count` highest-rated players, best first.
+    fn top(&self, count: usize) -> Vec<(Box<str>, PlayerStats)>;
+}
+
+pub struct MemoryStats {
+    players: HashMap<Box<str>, PlayerStats>,
+}
+
+impl PlayerStats {
+    pub fn wins(&self) -> u32 {
+        self.town_wins + self.mafia_wins + self.maniac_wins + self.lovers_wins
+    }
+
+    fn add(&mut self, result: &GameResult) {
+        self.games += 1;
+        match result.win {
+            Some(Faction::Town) => self.town_wins += 1,
+            Some(Faction::Mafia) => self.mafia_wins += 1,
+            Some(Faction::Maniac) => self.maniac_wins += 1,
+            Some(Faction::Lovers) => self.lovers_wins += 1,
+            None => (),
+        }
+        if result.lynched_day_one {
+            self.lynched_day_one += 1;
+        }
+        if result.survived {
+            self.survived += 1;
+        }
+        self.rating += result.rating_change;
+    }
+
+    pub fn describe(&self, login: &str) -> String {
+        if self.games == 0 {
+            return format!("{} has not played any games yet.\n", login);
+        }
+        format!("Stats for {}: {} games, {} wins (town {}, mafia {}, maniac {}, lovers {}), \
+                 lynched on day one {} times, survived {} games ({}%). Rating: {}.\n",
+                login, self.games, self.wins(), self.town_wins, self.mafia_wins,
+                self.maniac_wins, self.lovers_wins, self.lynched_day_one, self.survived,
+                self.survived * 100 / self.games, self.rating)
+    }
+}
+
+impl Default for PlayerStats {
+    fn default() -> Self {
+        PlayerStats {
+            games: 0,
+            town_wins: 0,
+            mafia_wins: 0,
+            maniac_wins: 0,
+            lovers_wins: 0,
+            lynched_day_one: 0,
+            survived: 0,
+            rating: INITIAL_RATING,
+        }
+    }
+}
+
+impl MemoryStats {
+    pub fn new() -> Self {
+        MemoryStats {
+            players: HashMap::new(),
+        }
+    }
+}
+
+impl StatsStore for MemoryStats {
+    fn record(&mut self, login: &str, result: &GameResult) {
+        self.players.entry(login.into()).or_insert_with(PlayerStats::default).add(result);
+    }
+
+    fn get(&self, login: &str) -> Option<PlayerStats> {
+        self.players.get(login).cloned()
+    }
+
+    fn top(&self, count: usize) -> Vec<(Box<str>, PlayerStats)> {
+        let mut players: Vec<(Box<str>, PlayerStats)> = self.players.iter()
+            .map(|(login, &stats)| (login.clone(), stats))
+            .collect();
+        players.sort_by(|(a_login, a), (b_login, b)| {
+            b.rating.cmp(&a.rating).then_with(|| a_login.cmp(b_login))
+        });
+        players.truncate(count);
+        players
+    }
+}
@@ -0,0 +1,197 @@
+//! The bits of telnet (RFC 854) that plain TCP clients send along with their
+//! text: option negotiation, which is answered, and line editing, which is
+//! applied, so that only the finished lines get through.
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+const ECHO: u8 = 1;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+const TIMING_MARK: u8 = 6;
+const TERMINAL_TYPE: u8 = 24;
+
+/// Subcommands of the terminal type subnegotiation (RFC 1091).
+const IS: u8 = 0;
+const SEND: u8 = 1;
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+
+/// Longer lines are dropped whole, rather than passed on cut short.
+const MAX_LINE: usize = 4096;
+/// Subnegotiations are cut off at this length; none worth having is longer.
+const MAX_SUBNEGOTIATION: usize = 256;
+
+/// Puts lines together across reads, since a line may arrive in any number of
+/// pieces.
+pub struct Decoder {
+    state: State,
+    line: Vec<u8>,
+    /// The line being typed has gone over `MAX_LINE`.
+    overlong: bool,
+    subnegotiation: Vec<u8>,
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Data,
+    /// Just after an IAC.
+    Command,
+    /// Waiting for the option of a WILL, WONT, DO or DONT.
+    Option(u8),
+    Subnegotiation,
+    /// An IAC inside a subnegotiation, which may be the start of its SE.
+    SubnegotiationCommand,
+}
+
+/// What came out of a chunk of data read off the socket.
+pub struct Decoded {
+    /// Finished lines, without their line breaks.
+    pub lines: Vec<Vec<u8>>,
+    /// Answers to the client's negotiation, to be written back as they are.
+    pub reply: Vec<u8>,
+    /// Lines dropped for being too long.
+    pub dropped: usize,
+    /// The client sent a telnet command, so it speaks telnet.
+    pub negotiated: bool,
+    /// The client answered a keepalive ping.
+    pub pong: bool,
+    /// The client told us what terminal it is.
+    pub terminal: Option<String>,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder {
+            state: State::Data,
+            line: Vec::new(),
+            overlong: false,
+            subnegotiation: Vec::new(),
+        }
+    }
+
+    pub fn decode(&mut self, data: &[u8]) -> Decoded {
+        let mut decoded = Decoded {
+            lines: Vec::new(),
+            reply: Vec::new(),
+            dropped: 0,
+            negotiated: false,
+            pong: false,
+            terminal: None,
+        };
+        for &byte in data {
+            self.state = match (self.state, byte) {
+                (State::Data, IAC) => State::Command,
+                (State::Data, byte) => {
+                    self.edit(byte, &mut decoded);
+                    State::Data
+                },
+                (State::Command, IAC) => {
+                    self.edit(IAC, &mut decoded);
+                    State::Data
+                },
+                (State::Command, SB) => {
+                    decoded.negotiated = true;
+                    self.subnegotiation.clear();
+                    State::Subnegotiation
+                },
+                (State::Command, command @ WILL..=DONT) => {
+                    decoded.negotiated = true;
+                    State::Option(command)
+                },
+                (State::Command, _) => State::Data,
+                (State::Option(WILL), TIMING_MARK) | (State::Option(WONT), TIMING_MARK) => {
+                    decoded.pong = true;
+                    State::Data
+                },
+                (State::Option(command), option) => {
+                    decoded.reply.extend_from_slice(&answer(command, option));
+                    State::Data
+                },
+                (State::Subnegotiation, IAC) => State::SubnegotiationCommand,
+                (State::Subnegotiation, byte) | (State::SubnegotiationCommand, byte @ IAC) => {
+                    if self.subnegotiation.len() < MAX_SUBNEGOTIATION {
+                        self.subnegotiation.push(byte);
+                    }
+                    State::Subnegotiation
+                },
+                (State::SubnegotiationCommand, SE) => {
+                    if self.subnegotiation.starts_with(&[TERMINAL_TYPE, IS]) {
+                        let name = String::from_utf8_lossy(&self.subnegotiation[2..]);
+                        decoded.terminal = Some(name.into_owned());
+                    }
+                    State::Data
+                },
+                (State::SubnegotiationCommand, _) => State::Subnegotiation,
+            };
+        }
+        decoded
+    }
+
+    /// Adds a byte of text to the line being typed, or applies it if it's a
+    /// line break or a backspace. Other control characters are dropped.
+    fn edit(&mut self, byte: u8, decoded: &mut Decoded) {
+        match byte {
+            b'\n' if self.overlong => {
+                self.line.clear();
+                self.overlong = false;
+                decoded.dropped += 1;
+            },
+            b'\n' => decoded.lines.push(std::mem::replace(&mut self.line, Vec::new())),
+            BACKSPACE | DELETE => {
+                // Take off a whole character, continuation bytes and all.
+                while let Some(last) = self.line.pop() {
+                    if last & 0xc0 != 0x80 {
+                        break;
+                    }
+                }
+            },
+            byte if byte < 0x20 && byte != b'\t' => (),
+            _ if self.overlong => (),
+            _ if self.line.len() >= MAX_LINE => self.overlong = true,
+            byte => self.line.push(byte),
+        }
+    }
+}
+
+/// A keepalive ping: asking for a timing mark makes any telnet client answer,
+/// whether it supports them or not.
+pub fn ping() -> Vec<u8> {
+    vec![IAC, DO, TIMING_MARK]
+}
+
+/// Asks the client whether it's a terminal. Those that are say so with a WILL,
+/// and then tell which one when asked.
+pub fn request_terminal_type() -> Vec<u8> {
+    vec![IAC, DO, TERMINAL_TYPE]
+}
+
+/// Asks the client to echo what the user types, or to stop echoing it so that
+/// passwords stay off the screen. The server offers to echo instead, and then
+/// doesn't; a line break is added on turning echo back on, since the user's own
+/// didn't show.
+pub fn set_echo(echo: bool) -> Vec<u8> {
+    if echo {
+        vec![IAC, WONT, ECHO, b'\n']
+    } else {
+        vec![IAC, WILL, ECHO]
+    }
+}
+
+/// Refuses every option but echo and go-ahead suppression, which are the
+/// server's to ask for, and never answers a refusal, so that negotiation
+/// can't go round in circles. A client agreeing to give its terminal type is
+/// asked for it.
+fn answer(command: u8, option: u8) -> Vec<u8> {
+    match command {
+        DO if option != ECHO && option != SUPPRESS_GO_AHEAD => vec![IAC, WONT, option],
+        WILL if option == TERMINAL_TYPE => vec![IAC, SB, TERMINAL_TYPE, SEND, IAC, SE],
+        WILL => vec![IAC, DONT, option],
+        _ => Vec::new(),
+    }
+}
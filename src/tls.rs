@@ -0,0 +1,34 @@
+use async_tls::TlsAcceptor;
+
+use rustls::{
+    NoClientAuth, ServerConfig,
+    internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+};
+
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+    sync::Arc,
+};
+
+/// Sets up TLS from a PEM certificate chain and a PEM private key, in either
+/// PKCS #8 or RSA form.
+pub fn load_acceptor(cert: &Path, key: &Path) -> io::Result<TlsAcceptor> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+    let chain = certs(&mut BufReader::new(File::open(cert)?))
+        .map_err(|_| invalid("malformed certificate"))?;
+    if chain.is_empty() {
+        return Err(invalid("no certificates found"));
+    }
+    let mut keys = pkcs8_private_keys(&mut BufReader::new(File::open(key)?))
+        .map_err(|_| invalid("malformed private key"))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut BufReader::new(File::open(key)?))
+            .map_err(|_| invalid("malformed private key"))?;
+    }
+    let key = keys.into_iter().next().ok_or_else(|| invalid("no private key found"))?;
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(chain, key).map_err(|err| invalid(&err.to_string()))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
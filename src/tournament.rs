@@ -0,0 +1,134 @@
+use crate::roles::Role;
+use crate::util::Rng;
+
+use std::collections::HashMap;
+
+/// A series of games between a fixed pool of players, started with
+/// `!tournament start <games>`. Every win scores a point; whoever has the most
+/// points once all the games are played wins the series.
+pub struct Tournament {
+    games: u32,
+    played: u32,
+    /// Keyed by login, since players get a new id when they reconnect.
+    records: HashMap<Box<str>, Record>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Record {
+    wins: u32,
+    /// Games the player spent in the mafia.
+    mafia: u32,
+    /// Games the player had a role other than the mafia's or a plain civilian's.
+    special: u32,
+}
+
+impl Tournament {
+    pub fn new(games: u32, logins: &[&str]) -> Self {
+        Tournament {
+            games,
+            played: 0,
+            records: logins.iter().map(|&login| (login.into(), Record::default())).collect(),
+        }
+    }
+
+    pub fn is_member(&self, login: &str) -> bool {
+        self.records.contains_key(login)
+    }
+
+    /// Number of the game about to be played, counting from one.
+    pub fn next_game(&self) -> u32 {
+        self.played + 1
+    }
+
+    pub fn games(&self) -> u32 {
+        self.games
+    }
+
+    pub fn is_over(&self) -> bool {
+        self.played >= self.games
+    }
+
+    /// Hands out freshly dealt roles so that the mafia's go to whoever has been
+    /// in the mafia least so far in the series, and the other special roles to
+    /// whoever has had one least; ties are broken at random. Returns the roles
+    /// in the order of `logins`.
+    pub fn rotate(&mut self, logins: &[&str], roles: Vec<Role>, rng: &mut Rng) -> Vec<Role> {
+        let mut mafia: Vec<Role> = roles.iter().cloned().filter(|role| role.is_mafia()).collect();
+        let mut special: Vec<Role> = roles.iter().cloned()
+            .filter(|&role| !role.is_mafia() && !is_plain(role))
+            .collect();
+        let mut order: Vec<usize> = (0..logins.len()).collect();
+        rng.shuffle(&mut order);
+        order.sort_by_key(|&index| self.record(logins[index]).mafia);
+        let mut dealt: Vec<Option<Role>> = vec![None; logins.len()];
+        for &index in order.iter().take(mafia.len()) {
+            dealt[index] = mafia.pop();
+        }
+        let mut rest: Vec<usize> = order.into_iter().filter(|&index| dealt[index].is_none()).collect();
+        rest.sort_by_key(|&index| self.record(logins[index]).special);
+        for &index in rest.iter().take(special.len()) {
+            dealt[index] = special.pop();
+        }
+        let plain = roles.iter().cloned().find(|&role| is_plain(role)).unwrap_or(Role::Civilian);
+        let dealt: Vec<Role> = dealt.into_iter().map(|role| role.unwrap_or(plain)).collect();
+        for (&login, &role) in logins.iter().zip(&dealt) {
+            let record = self.records.entry(login.into()).or_insert_with(Record::default);
+            if role.is_mafia() {
+                record.mafia += 1;
+            } else if !is_plain(role) {
+                record.special += 1;
+            }
+        }
+        dealt
+    }
+
+    /// Counts a finished game, given whether each of its players won.
+    pub fn record_game(&mut self, results: &[(&str, bool)]) {
+        self.played += 1;
+        for &(login, won) in results {
+            if let Some(record) = self.records.get_mut(login) {
+                if won {
+                    record.wins += 1;
+                }
+            }
+        }
+    }
+
+    pub fn standings(&self) -> String {
+        let mut standings: Vec<(&str, u32)> = self.records.iter()
+            .map(|(login, record)| (&**login, record.wins))
+            .collect();
+        standings.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        let mut message = format!("Tournament standings after {} of {} games:\n",
+                                  self.played, self.games);
+        for (login, wins) in standings {
+            message.push_str(&format!("  {} - {} {}\n",
+                                      login, wins, if wins == 1 { "win" } else { "wins" }));
+        }
+        message
+    }
+
+    /// Announces whoever has the most wins.
+    pub fn describe_winner(&self) -> String {
+        let top = self.records.values().map(|record| record.wins).max().unwrap_or(0);
+        let mut winners: Vec<&str> = self.records.iter()
+            .filter(|(_, record)| record.wins == top)
+            .map(|(login, _)| &**login)
+            .collect();
+        winners.sort();
+        if winners.len() == 1 {
+            format!("The tournament is over: {} wins the series with {} wins!\n", winners[0], top)
+        } else {
+            format!("The tournament is over: {} share the series win with {} wins each!\n",
+                    winners.join(", "), top)
+        }
+    }
+
+    fn record(&self, login: &str) -> Record {
+        self.records.get(login).cloned().unwrap_or_default()
+    }
+}
+
+fn is_plain(role: Role) -> bool {
+    role == Role::Civilian || role == Role::Villager
+}
@@ -1,5 +1,6 @@
 use futures::{
     prelude::*,
+    select,
     task::{Context, Poll},
     channel::oneshot,
     channel::mpsc::{unbounded, UnboundedSender, UnboundedReceiver},
@@ -8,9 +9,10 @@ use futures::{
 use runtime::time::Delay;
 
 use std::{
+    io,
     ops::{Deref, DerefMut},
     pin::Pin,
-    time::Duration
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 ///////////////////////////////////////////////////////////////////////////////////////
@@ -67,7 +69,9 @@ impl<T: Send + 'static> Timer<T> {
             runtime::spawn(async move {
                 let delay = Delay::new(Duration::from_millis(delay_ms));
                 delay.await;
-                sender.unbounded_send(memo).expect("Timer channel failed");
+                // The receiver is gone if the timer was reset in the meantime;
+                // the alarm is simply dropped then.
+                let _ = sender.unbounded_send(memo);
             });
         }
     }
@@ -86,3 +90,68 @@ impl<T> Stream for Timer<T> {
         self.receiver.poll_next_unpin(cx)
     }
 }
+
+///////////////////////////////////////////////////////////////////////////////////////
+
+/// Runs the future, giving up on it with `TimedOut` if it takes longer than `limit`.
+pub async fn with_timeout<T, F>(limit: Duration, future: F) -> io::Result<T>
+    where F: Future<Output = io::Result<T>>
+{
+    let mut future = Box::pin(future).fuse();
+    let mut timeout = Delay::new(limit).fuse();
+    select! {
+        result = future => result,
+        _ = timeout => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////
+
+/// SplitMix64 generator. Good enough for shuffling roles, not for anything secret.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    pub fn from_time() -> Self {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        Rng::new(now.as_nanos() as u64)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number in `0..bound`. `bound` must be positive.
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = self.below(i + 1);
+            items.swap(i, j);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn reset_drops_earlier_alarms() {
+        let mut timer = Timer::new();
+        timer.add_alarm(10, "stale");
+        timer.reset();
+        timer.add_alarm(50, "current");
+        assert_eq!(block_on(timer.next()), Some("current"));
+    }
+}
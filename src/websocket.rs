@@ -0,0 +1,292 @@
+//! Just enough of WebSocket (RFC 6455) for browsers to connect: the opening
+//! handshake, and text frames in both directions. Each text frame carries what
+//! a TCP client would have sent or been sent as lines.
+
+use futures::prelude::*;
+
+use std::io;
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_HANDSHAKE: usize = 8192;
+const MAX_MESSAGE: usize = 65536;
+
+pub enum Frame {
+    Text(Vec<u8>),
+    /// The client is closing the connection, with this status code, if any.
+    Close(Vec<u8>),
+    /// The client's own ping, with the payload to answer it with.
+    Ping(Vec<u8>),
+    /// An answer to the server's keepalive ping.
+    Pong,
+}
+
+/// Reassembles frames from the bytes read off the socket, which may split or
+/// join them anywhere.
+pub struct Decoder {
+    buffer: Vec<u8>,
+    /// The message being put together out of fragments.
+    message: Vec<u8>,
+}
+
+/// Reads the client's upgrade request and accepts it. Nothing but the handshake
+/// may have been read off the stream when this returns.
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut byte = [0; 1];
+    while !request.ends_with(b"\r\n\r\n") {
+        if request.len() >= MAX_HANDSHAKE || stream.read(&mut byte).await? == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "incomplete handshake"));
+        }
+        request.push(byte[0]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let key = match websocket_key(&request) {
+        Some(key) => key,
+        None => {
+            stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await?;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a WebSocket request"));
+        },
+    };
+    let response = format!("HTTP/1.1 101 Switching Protocols\r\n\
+                            Upgrade: websocket\r\n\
+                            Connection: Upgrade\r\n\
+                            Sec-WebSocket-Accept: {}\r\n\r\n", accept(key));
+    stream.write_all(response.as_bytes()).await
+}
+
+/// The key of a request to upgrade to the version of WebSocket we speak.
+fn websocket_key(request: &str) -> Option<&str> {
+    let upgrade = header(request, "upgrade")?;
+    let version = header(request, "sec-websocket-version")?;
+    if !upgrade.eq_ignore_ascii_case("websocket") || version != "13" {
+        return None;
+    }
+    header(request, "sec-websocket-key")
+}
+
+fn header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    request.lines()
+        .filter_map(|line| {
+            let colon = line.find(':')?;
+            if line[..colon].trim().eq_ignore_ascii_case(name) {
+                Some(line[colon + 1..].trim())
+            } else {
+                None
+            }
+        })
+        .next()
+}
+
+/// What the handshake answers the client's key with.
+fn accept(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, GUID).as_bytes()))
+}
+
+/// Wraps text into a single unmasked frame, as servers send them.
+pub fn encode_text(text: &str) -> Vec<u8> {
+    encode(0x1, text.as_bytes())
+}
+
+/// A keepalive ping, with an empty payload.
+pub fn ping() -> Vec<u8> {
+    encode(0x9, &[])
+}
+
+/// The answer to a client's ping, which carries the same payload.
+pub fn pong(payload: &[u8]) -> Vec<u8> {
+    encode(0xa, payload)
+}
+
+/// The answer to a client's close frame, which repeats its status code.
+pub fn close(status: &[u8]) -> Vec<u8> {
+    encode(0x8, &status[..status.len().min(2)])
+}
+
+/// Wraps the payload into a single, final, unmasked frame.
+fn encode(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    if payload.len() < 126 {
+        frame.push(payload.len() as u8);
+    } else if payload.len() <= 0xffff {
+        frame.push(126);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+        frame.push(127);
+        frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder {
+            buffer: Vec::new(),
+            message: Vec::new(),
+        }
+    }
+
+    /// Takes in newly read bytes and returns the messages they complete.
+    /// Binary messages are refused, and so are unmasked frames, which clients
+    /// must never send.
+    pub fn decode(&mut self, data: &[u8]) -> Result<Vec<Frame>, &'static str> {
+        self.buffer.extend_from_slice(data);
+        let mut frames = Vec::new();
+        while let Some((header, length)) = self.frame_size()? {
+            let frame: Vec<u8> = self.buffer.drain(..header + length).collect();
+            if frame[1] & 0x80 == 0 {
+                return Err("unmasked frame from the client");
+            }
+            if frame[0] & 0x08 != 0 && length > 125 {
+                return Err("control frame too long");
+            }
+            let mut payload = frame[header..].to_vec();
+            let mask = &frame[header - 4..header];
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+            match frame[0] & 0x0f {
+                0x0 | 0x1 => {
+                    if self.message.len() + payload.len() > MAX_MESSAGE {
+                        return Err("message too long");
+                    }
+                    self.message.extend_from_slice(&payload);
+                    if frame[0] & 0x80 != 0 {
+                        frames.push(Frame::Text(std::mem::replace(&mut self.message, Vec::new())));
+                    }
+                },
+                0x2 => return Err("binary messages are not supported"),
+                0x8 => frames.push(Frame::Close(payload)),
+                0x9 => frames.push(Frame::Ping(payload)),
+                0xa => frames.push(Frame::Pong),
+                _ => (),
+            }
+        }
+        Ok(frames)
+    }
+
+    /// The lengths of the header and the payload of the first frame in the
+    /// buffer, once all of it has arrived.
+    fn frame_size(&self) -> Result<Option<(usize, usize)>, &'static str> {
+        let buffer = &self.buffer;
+        if buffer.len() < 2 {
+            return Ok(None);
+        }
+        let (header, length) = match buffer[1] & 0x7f {
+            126 => {
+                if buffer.len() < 4 {
+                    return Ok(None);
+                }
+                (4, u64::from(u16::from_be_bytes([buffer[2], buffer[3]])))
+            },
+            127 => {
+                if buffer.len() < 10 {
+                    return Ok(None);
+                }
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&buffer[2..10]);
+                (10, u64::from_be_bytes(bytes))
+            },
+            length => (2, u64::from(length)),
+        };
+        if length > MAX_MESSAGE as u64 {
+            return Err("message too long");
+        }
+        let header = if buffer[1] & 0x80 != 0 { header + 4 } else { header };
+        let length = length as usize;
+        if buffer.len() < header + length {
+            Ok(None)
+        } else {
+            Ok(Some((header, length)))
+        }
+    }
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476, 0xc3d2_e1f0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut words = [0u32; 80];
+        for (word, bytes) in words.iter_mut().zip(block.chunks(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..80 {
+            words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, &word) in words.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ed9_eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1b_bcdc),
+                _ => (b ^ c ^ d, 0xca62_c1d6),
+            };
+            let next = a.rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = next;
+        }
+        for (total, value) in state.iter_mut().zip(&[a, b, c, d, e]) {
+            *total = total.wrapping_add(*value);
+        }
+    }
+    let mut digest = [0; 20];
+    for (bytes, word) in digest.chunks_mut(4).zip(&state) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate()
+            .fold(0u32, |bits, (i, &byte)| bits | u32::from(byte) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUEST: &str = "GET /chat HTTP/1.1\r\n\
+                           Host: server.example.com\r\n\
+                           Upgrade: websocket\r\n\
+                           Connection: Upgrade\r\n\
+                           Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                           Sec-WebSocket-Version: 13\r\n\r\n";
+
+    #[test]
+    fn accept_matches_the_rfc() {
+        // RFC 6455, section 1.3.
+        assert_eq!(accept("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn only_websocket_13_upgrades_are_accepted() {
+        assert_eq!(websocket_key(REQUEST), Some("dGhlIHNhbXBsZSBub25jZQ=="));
+        assert_eq!(websocket_key(&REQUEST.replace("websocket", "h2c")), None);
+        assert_eq!(websocket_key(&REQUEST.replace("Version: 13", "Version: 8")), None);
+        assert_eq!(websocket_key(&REQUEST.replace("Upgrade: websocket\r\n", "")), None);
+    }
+}